@@ -80,30 +80,30 @@ pub fn sign<'a>(
     let r_sec = Scalar::random(&mut rng);
     let r_pub = Normal::change_mark(g!(r_sec * G));
 
-    let s = scalar_add(
-        &secret_keys
-            .map(|sk| {
-                let pk = Normal::change_mark(g!({ &sk.0 } * G));
-
-                let c = {
-                    let mut c_hasher = Sha256::default();
-                    bincode::serialize_into(&mut c_hasher, &pk).unwrap();
-                    bincode::serialize_into(&mut c_hasher, &r_pub).unwrap();
-                    bincode::serialize_into(&mut c_hasher, &pub_keys).unwrap();
-                    bincode::serialize_into(&mut c_hasher, &msg_hash).unwrap();
-                    Scalar::from_hash(c_hasher)
-                };
-
-                scalar_mul(&scalar_mul(&sk.0, &c), &msg_hash)
-            })
-            .reduce(|a, b| {
-                NonZero::change_mark(scalar_add::<NonZero, NonZero, Secret, Secret>(&a, &b))
-                    .unwrap()
-            })
-            .unwrap(),
-        &r_sec,
-    );
-    let non_zero_s = NonZero::change_mark(s).unwrap();
+    let key_sum = secret_keys
+        .map(|sk| {
+            let pk = Normal::change_mark(g!({ &sk.0 } * G));
+
+            let c = {
+                let mut c_hasher = Sha256::default();
+                bincode::serialize_into(&mut c_hasher, &pk).unwrap();
+                bincode::serialize_into(&mut c_hasher, &r_pub).unwrap();
+                bincode::serialize_into(&mut c_hasher, &pub_keys).unwrap();
+                bincode::serialize_into(&mut c_hasher, &msg_hash).unwrap();
+                Scalar::from_hash(c_hasher)
+            };
+
+            scalar_mul(&scalar_mul(&sk.0, &c), &msg_hash)
+        })
+        .reduce(|a, b| {
+            NonZero::change_mark(scalar_add::<NonZero, NonZero, Secret, Secret>(&a, &b)).unwrap()
+        });
+    // Without any keys the signature only proves knowledge of the nonce, which is exactly what
+    // verifying it against an empty key set checks
+    let non_zero_s = match key_sum {
+        Some(key_sum) => NonZero::change_mark(scalar_add(&key_sum, &r_sec)).unwrap(),
+        None => r_sec,
+    };
     let public_s = Public::change_mark(non_zero_s);
 
     Sig {
@@ -200,4 +200,13 @@ mod tests {
         let sig = sign(hash, secrets.iter(), rng);
         assert!(verify(hash, sig, &pks.iter().collect::<Vec<_>>()));
     }
+
+    #[test]
+    fn round_trip_without_keys() {
+        let rng = RngAdaptor(rand::rngs::OsRng::new().unwrap());
+        let hash = [42; 32];
+
+        let sig = sign(hash, std::iter::empty(), rng);
+        assert!(verify(hash, sig, &[]));
+    }
 }
@@ -0,0 +1,174 @@
+//! Joint-Feldman distributed key generation
+//!
+//! Every participant deals a random polynomial of degree `threshold - 1`, publishes a commitment to
+//! its coefficients and privately sends every participant the evaluation of the polynomial at their
+//! index. Each participant's secret key share is the sum of all the shares it received, the
+//! aggregate public key the sum of the committed constant terms. No single participant ever learns
+//! the aggregate secret key.
+
+use crate::poly::Poly;
+use crate::serde_impl;
+use crate::{AggregatePublicKey, PublicKeyShare, Scalar, SecretKeyShare};
+use bls12_381::{G2Affine, G2Projective};
+use group::Curve;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Commitment to the coefficients of a secret polynomial, allows verifying shares of it and
+/// deriving the corresponding public key shares.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Commitment(Vec<CommitmentPoint>);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct CommitmentPoint(#[serde(with = "serde_impl::g2")] G2Affine);
+
+/// Generates a random polynomial of degree `threshold - 1` and returns the commitment to it as
+/// well as one secret share for each of the `participants`.
+///
+/// * `threshold`: how many signature shares are needed to produce a signature
+/// * `participants`: how many shares to generate
+/// * `rng`: source of the polynomial's coefficients
+pub fn deal(
+    threshold: usize,
+    participants: usize,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> (Commitment, Vec<SecretKeyShare>) {
+    let poly = Poly::<Scalar, Scalar>::random(threshold - 1, rng);
+
+    let commitment = Commitment(
+        poly.coefficients()
+            .iter()
+            .map(|coefficient| {
+                CommitmentPoint((G2Projective::generator() * coefficient).to_affine())
+            })
+            .collect(),
+    );
+    let shares = (1..=participants)
+        .map(|idx| SecretKeyShare(poly.evaluate(idx as u64)))
+        .collect();
+
+    (commitment, shares)
+}
+
+impl Commitment {
+    /// Number of shares needed to reconstruct the committed secret
+    pub fn threshold(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Public key share of the participant with index `idx` (starting at 0)
+    pub fn public_key_share(&self, idx: usize) -> PublicKeyShare {
+        let poly = Poly::<G2Projective, Scalar>::from_coefficients(
+            self.0
+                .iter()
+                .map(|point| G2Projective::from(point.0))
+                .collect(),
+        );
+        PublicKeyShare(poly.evaluate((idx as u64) + 1).to_affine())
+    }
+
+    /// Public key corresponding to the committed secret
+    pub fn public_key(&self) -> AggregatePublicKey {
+        AggregatePublicKey(self.0[0].0)
+    }
+
+    /// Checks if `share` is the share of participant `idx` of the committed polynomial
+    pub fn verify_share(&self, idx: usize, share: &SecretKeyShare) -> bool {
+        share.to_pub_key_share() == self.public_key_share(idx)
+    }
+}
+
+/// Combines the commitments of all dealers into the commitment to the joint polynomial.
+///
+/// # Panics
+/// If no commitments are supplied or they differ in their threshold.
+pub fn combine_commitments<'a>(
+    commitments: impl IntoIterator<Item = &'a Commitment>,
+) -> Commitment {
+    let mut commitments = commitments.into_iter();
+    let first = commitments
+        .next()
+        .expect("At least one commitment is needed");
+
+    let mut coefficients = first
+        .0
+        .iter()
+        .map(|point| G2Projective::from(point.0))
+        .collect::<Vec<_>>();
+    for commitment in commitments {
+        assert_eq!(commitment.threshold(), coefficients.len());
+        for (sum, point) in coefficients.iter_mut().zip(commitment.0.iter()) {
+            *sum += G2Projective::from(point.0);
+        }
+    }
+
+    Commitment(
+        coefficients
+            .into_iter()
+            .map(|coefficient| CommitmentPoint(coefficient.to_affine()))
+            .collect(),
+    )
+}
+
+/// Combines the shares a participant received from all dealers into its secret key share.
+pub fn combine_shares(shares: impl IntoIterator<Item = SecretKeyShare>) -> SecretKeyShare {
+    SecretKeyShare(
+        shares
+            .into_iter()
+            .fold(Scalar::zero(), |sum, share| sum + share.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dkg::{combine_commitments, combine_shares, deal};
+    use crate::{
+        blind_message, combine_valid_shares, sign_blinded_msg, unblind_signature, verify,
+        Aggregatable, Message, SecretKeyShare,
+    };
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_dkg_roundtrip() {
+        let threshold = 3;
+        let participants = 5;
+
+        let dealings = (0..participants)
+            .map(|_| deal(threshold, participants, &mut OsRng))
+            .collect::<Vec<_>>();
+
+        for (commitment, shares) in dealings.iter() {
+            assert_eq!(commitment.threshold(), threshold);
+            for (idx, share) in shares.iter().enumerate() {
+                assert!(commitment.verify_share(idx, share));
+            }
+            assert!(!commitment.verify_share(0, &shares[1]));
+        }
+
+        let commitment = combine_commitments(dealings.iter().map(|(commitment, _)| commitment));
+        let sks = (0..participants)
+            .map(|idx| combine_shares(dealings.iter().map(|(_, shares)| shares[idx])))
+            .collect::<Vec<SecretKeyShare>>();
+        let pks = (0..participants)
+            .map(|idx| commitment.public_key_share(idx))
+            .collect::<Vec<_>>();
+        let pk = commitment.public_key();
+
+        for (sk, pk_share) in sks.iter().zip(pks.iter()) {
+            assert_eq!(&sk.to_pub_key_share(), pk_share);
+        }
+        assert_eq!(pks.aggregate(threshold), pk);
+
+        let msg = Message::from_bytes(b"Hello World!");
+        let (bkey, bmsg) = blind_message(msg);
+        let sigs = sks
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(idx, sk)| (idx, sign_blinded_msg(bmsg, *sk)))
+            .collect::<Vec<_>>();
+        let bsig = combine_valid_shares(sigs, threshold);
+        let sig = unblind_signature(bkey, bsig);
+        assert!(verify(msg, sig, pk));
+    }
+}
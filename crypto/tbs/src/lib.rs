@@ -23,6 +23,7 @@ pub use bls12_381::G1Affine as MessagePoint;
 pub use bls12_381::G2Affine as PubKeyPoint;
 pub use bls12_381::Scalar;
 
+pub mod dkg;
 pub mod hash;
 pub mod poly;
 mod serde_impl;
@@ -27,6 +27,29 @@ where
             _pd: PhantomData,
         }
     }
+}
+
+impl<G, S> Poly<G, S>
+where
+    G: Debug + MulAssign<S> + AddAssign<G> + Copy,
+    S: Copy,
+{
+    /// Constructs a polynomial from its coefficients, starting with the constant term.
+    ///
+    /// # Panics
+    /// If no coefficients are supplied.
+    pub fn from_coefficients(coefficients: Vec<G>) -> Self {
+        assert!(!coefficients.is_empty());
+        Poly {
+            coefficients,
+            _pd: PhantomData,
+        }
+    }
+
+    /// Returns the coefficients of the polynomial, starting with the constant term
+    pub fn coefficients(&self) -> &[G] {
+        &self.coefficients
+    }
 
     pub fn evaluate(&self, x: impl Into<S>) -> G {
         let mut result = *self
@@ -0,0 +1,109 @@
+use async_trait::async_trait;
+use bitcoin::Address;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint::consensus::FediMintConsensus;
+use minimint_api::ln::{ContractAccount, ContractId, IncomingContractOffer};
+use minimint_api::misbehavior::{peer_faults, PeerFaultCount};
+use minimint_api::module::registry::{MODULE_KEY_LN, MODULE_KEY_MINT, MODULE_KEY_WALLET};
+use minimint_api::outcome::TransactionStatus;
+use minimint_api::reserves::SignedReservesReport;
+use minimint_api::transaction::{PegOutFees, Transaction};
+use minimint_api::TransactionId;
+use minimint_ln::LightningModule;
+use minimint_mint::Mint;
+use minimint_wallet::Wallet;
+use mint_client::api::{ApiError, GuardianApi};
+use rand::rngs::OsRng;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Answers client API requests by calling into the consensus of a guardian running in the same
+/// process, the same way the guardian's HTTP API would
+pub struct MemApi {
+    consensus: Arc<FediMintConsensus<OsRng>>,
+    crashed: Arc<AtomicBool>,
+}
+
+impl MemApi {
+    /// Once `crashed` is set all requests fail with [`ApiError::Unavailable`]
+    pub fn new(consensus: Arc<FediMintConsensus<OsRng>>, crashed: Arc<AtomicBool>) -> MemApi {
+        MemApi { consensus, crashed }
+    }
+
+    fn consensus(&self) -> Result<&FediMintConsensus<OsRng>, ApiError> {
+        if self.crashed.load(Ordering::SeqCst) {
+            Err(ApiError::Unavailable)
+        } else {
+            Ok(&self.consensus)
+        }
+    }
+
+    fn wallet(&self) -> Result<&Wallet, ApiError> {
+        Ok(self
+            .consensus()?
+            .modules
+            .get_typed::<Wallet>(MODULE_KEY_WALLET)
+            .expect("Wallet module is always registered"))
+    }
+
+    fn ln(&self) -> Result<&LightningModule, ApiError> {
+        Ok(self
+            .consensus()?
+            .modules
+            .get_typed::<LightningModule>(MODULE_KEY_LN)
+            .expect("Lightning module is always registered"))
+    }
+}
+
+#[async_trait]
+impl GuardianApi for MemApi {
+    async fn submit_transaction(&self, tx: &Transaction) -> Result<(), ApiError> {
+        self.consensus()?
+            .submit_transaction(tx.clone())
+            .map_err(|e| ApiError::Rejected(e.to_string()))
+    }
+
+    async fn fetch_transaction(&self, txid: TransactionId) -> Result<TransactionStatus, ApiError> {
+        self.consensus()?
+            .transaction_status(txid)
+            .ok_or(ApiError::NotFound)
+    }
+
+    async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ApiError> {
+        self.ln()?
+            .contract_account(contract_id)
+            .ok_or(ApiError::NotFound)
+    }
+
+    async fn fetch_offer(&self, payment_hash: Sha256) -> Result<IncomingContractOffer, ApiError> {
+        self.ln()?.offer(payment_hash).ok_or(ApiError::NotFound)
+    }
+
+    async fn fetch_block_height(&self) -> Result<u32, ApiError> {
+        self.wallet()?.consensus_height().ok_or(ApiError::NotFound)
+    }
+
+    async fn fetch_peg_out_fees(&self, address: &Address) -> Result<PegOutFees, ApiError> {
+        Ok(self.wallet()?.peg_out_fees(address))
+    }
+
+    async fn fetch_reserves(&self) -> Result<SignedReservesReport, ApiError> {
+        let liabilities = self
+            .consensus()?
+            .modules
+            .get_typed::<Mint>(MODULE_KEY_MINT)
+            .expect("Mint module is always registered")
+            .outstanding_ecash()
+            .ok_or_else(|| ApiError::Rejected("More e-cash was redeemed than issued".into()))?;
+        self.wallet()?
+            .reserves_report(liabilities)
+            .ok_or(ApiError::NotFound)
+    }
+
+    async fn fetch_misbehavior(&self) -> Result<Vec<PeerFaultCount>, ApiError> {
+        Ok(peer_faults(self.consensus()?.db.as_ref()))
+    }
+}
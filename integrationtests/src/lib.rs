@@ -0,0 +1,304 @@
+//! Runs a whole federation inside the test process: every guardian has its own in-memory
+//! database, they reach consensus over a [`net::MemNetwork`], follow the same
+//! [`FakeBitcoindRpc`] chain and clients talk to them through [`api::MemApi`].
+
+use crate::api::MemApi;
+use crate::net::{MemConnections, MemNetwork};
+use hbbft::honey_badger::{HoneyBadger, Step};
+use minimint::config::{local_peers, ClientConfig, ServerConfig, ServerConfigParams};
+use minimint::consensus::{ConsensusItem, ConsensusOutcome, FediMintConsensus, HoneyBadgerMessage};
+use minimint::{build_consensus, handle_step, honey_badger};
+use minimint_api::config::GenerateConfig;
+use minimint_api::db::mem_impl::MemDatabase;
+use minimint_api::db::staged::StagedDatabase;
+use minimint_api::misbehavior::MisbehaviorLog;
+use minimint_api::module::registry::MODULE_KEY_MINT;
+use minimint_api::transaction::OutPoint;
+use minimint_api::{Amount, PeerId, TxOutProof};
+use minimint_mint::PartiallySignedRequest;
+use minimint_wallet::bitcoind::fake::FakeBitcoindRpc;
+use minimint_wallet::bitcoind::BitcoindRpc;
+use minimint_wallet::Wallet;
+use mint_client::api::GuardianApi;
+use mint_client::{ClientError, MintClient};
+use rand::rngs::OsRng;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+pub mod api;
+pub mod net;
+
+/// Number of epochs after which we give up waiting for the outcome of a transaction
+pub const MAX_EPOCHS: usize = 10;
+
+/// A federation of guardians running in this process. Consensus epochs only happen when the test
+/// calls [`FederationTest::run_epoch`] and all messages are delivered in a fixed order, so apart
+/// from the randomness used by the cryptography every run behaves the same.
+pub struct FederationTest {
+    /// Chain followed by all guardians, tests mine blocks on it
+    pub bitcoind: Arc<FakeBitcoindRpc>,
+    pub client_cfg: ClientConfig,
+    servers: BTreeMap<PeerId, ServerTest>,
+    network: MemNetwork<HoneyBadgerMessage>,
+}
+
+struct ServerTest {
+    consensus: Arc<FediMintConsensus<OsRng>>,
+    hb: HoneyBadger<Vec<ConsensusItem>, PeerId>,
+    connections: MemConnections<HoneyBadgerMessage>,
+    crashed: Arc<AtomicBool>,
+    /// Rewrites the contributions of a Byzantine guardian before it proposes them
+    tamper: Option<Box<dyn FnMut(&mut Vec<ConsensusItem>) + Send>>,
+    /// Consensus outcomes of the current epoch that weren't processed yet
+    outcomes: Vec<ConsensusOutcome>,
+    misbehavior: MisbehaviorLog,
+}
+
+impl FederationTest {
+    /// Creates a federation of `num_peers` guardians with fresh databases, tolerating as many
+    /// crashed or Byzantine guardians as Honey Badger allows
+    pub async fn new(num_peers: u16) -> FederationTest {
+        let peers = (0..num_peers).map(PeerId::from).collect::<Vec<_>>();
+        let max_evil = hbbft::util::max_faulty(peers.len());
+        let params = ServerConfigParams {
+            // Nothing binds to these, they only end up in the configs
+            peers: local_peers(&peers, 5000, 6000),
+            amount_tiers: (0..=12)
+                .map(|exp| Amount::from_msat(10u64.pow(exp)))
+                .collect(),
+            tls: None,
+        };
+        let (server_cfgs, client_cfg) =
+            ServerConfig::trusted_dealer_gen(&peers, max_evil, &params, OsRng::new().unwrap());
+
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let network = MemNetwork::new(&peers);
+        let mut servers = BTreeMap::new();
+        for (peer, cfg) in server_cfgs {
+            let database = Arc::new(StagedDatabase::new(Arc::new(MemDatabase::new())));
+            let wallet =
+                Wallet::new_with_bitcoind(cfg.wallet.clone(), database.clone(), bitcoind.clone())
+                    .await
+                    .expect("Couldn't create wallet");
+
+            let server = ServerTest {
+                hb: honey_badger(&cfg),
+                misbehavior: MisbehaviorLog::new(database.clone()),
+                consensus: Arc::new(build_consensus(cfg, database, wallet)),
+                connections: network.connections(peer),
+                crashed: Default::default(),
+                tamper: None,
+                outcomes: vec![],
+            };
+            servers.insert(peer, server);
+        }
+
+        FederationTest {
+            bitcoind,
+            client_cfg,
+            servers,
+            network,
+        }
+    }
+
+    /// Creates a client with an empty database that talks to all guardians
+    pub fn new_client(&self) -> MintClient {
+        let api = self
+            .servers
+            .values()
+            .map(|server| {
+                let api = MemApi::new(server.consensus.clone(), server.crashed.clone());
+                Box::new(api) as Box<dyn GuardianApi>
+            })
+            .collect();
+        MintClient::new_with_api(
+            self.client_cfg.clone(),
+            Arc::new(MemDatabase::new()),
+            api,
+            Default::default(),
+        )
+    }
+
+    /// Consensus of `peer`, e.g. to inspect its modules
+    pub fn consensus(&self, peer: PeerId) -> &FediMintConsensus<OsRng> {
+        &self.servers[&peer].consensus
+    }
+
+    /// Stops `peer` from taking part in consensus and from answering API requests, as if it
+    /// crashed
+    pub fn crash(&mut self, peer: PeerId) {
+        self.servers[&peer].crashed.store(true, Ordering::SeqCst);
+        self.network.disconnect(peer);
+    }
+
+    /// Lets `peer` pass each of its contributions through `tamper` before proposing it, e.g. to
+    /// withhold or forge consensus items. It still follows the Honey Badger protocol, so the other
+    /// guardians have to cope with whatever it proposes.
+    pub fn make_byzantine<F>(&mut self, peer: PeerId, tamper: F)
+    where
+        F: FnMut(&mut Vec<ConsensusItem>) + Send + 'static,
+    {
+        self.servers.get_mut(&peer).expect("Unknown peer").tamper = Some(Box::new(tamper));
+    }
+
+    /// Makes `peer` propose invalid blind signature shares for all issuances, so the other
+    /// guardians have to issue e-cash without its help
+    pub fn send_bad_shares(&mut self, peer: PeerId) {
+        self.make_byzantine(peer, |proposal| {
+            for item in proposal {
+                let module_item = match item {
+                    ConsensusItem::Module(module_item) if module_item.module == MODULE_KEY_MINT => {
+                        module_item
+                    }
+                    _ => continue,
+                };
+
+                let mut request: PartiallySignedRequest =
+                    bincode::deserialize(&module_item.item).expect("Mint proposed invalid item");
+                for (msg, share) in request.partial_signature.0.coins.values_mut().flatten() {
+                    // Equals a signature with the secret key 1, which no guardian holds
+                    *share = tbs::BlindedSignatureShare(msg.0);
+                }
+                module_item.item = bincode::serialize(&request).expect("Serialization can't fail");
+            }
+        });
+    }
+
+    /// Runs one consensus epoch: every guardian that didn't crash proposes its pending consensus
+    /// items, messages are delivered until all of them agreed on the outcome and then every
+    /// guardian processes it.
+    ///
+    /// Panics if consensus can't make progress, e.g. because too many guardians crashed, or if a
+    /// guardian's books don't balance afterwards.
+    pub async fn run_epoch(&mut self) {
+        let mut rng = OsRng::new().unwrap();
+        for server in self.live_servers() {
+            let mut proposal = server.consensus.get_consensus_proposal().await;
+            if let Some(tamper) = &mut server.tamper {
+                tamper(&mut proposal);
+            }
+
+            let step = server
+                .hb
+                .propose(&proposal, &mut rng)
+                .expect("Failed to process HBBFT input");
+            server.handle_step(step).await;
+        }
+
+        // Peers are served in order of their ids, each one receives everything sent to it so far
+        while self.live_servers().any(|server| server.outcomes.is_empty()) {
+            let mut delivered = false;
+            for server in self.live_servers() {
+                while let Some((peer, msg)) = server.connections.try_receive() {
+                    let step = server
+                        .hb
+                        .handle_message(&peer, msg)
+                        .expect("Failed to process HBBFT input");
+                    server.handle_step(step).await;
+                    delivered = true;
+                }
+            }
+            assert!(delivered, "Consensus is stuck, did too many peers crash?");
+        }
+
+        for server in self.live_servers() {
+            for outcome in std::mem::take(&mut server.outcomes) {
+                debug!("Processing outcome of epoch {}", outcome.epoch);
+                server
+                    .consensus
+                    .process_consensus_outcome(outcome)
+                    .await
+                    .expect("Federation's books don't balance");
+            }
+        }
+    }
+
+    pub async fn run_epochs(&mut self, epochs: usize) {
+        for _ in 0..epochs {
+            self.run_epoch().await;
+        }
+    }
+
+    /// Pays `amount` to `address` and mines enough blocks on top for the guardians to consider the
+    /// payment final in their next epoch. Returns the transaction and the proof a client needs to
+    /// peg it in.
+    pub async fn send_to_address(
+        &self,
+        address: &bitcoin::Address,
+        amount: bitcoin::Amount,
+    ) -> (TxOutProof, bitcoin::Transaction) {
+        // The lock time keeps transactions paying the same amount to the same address apart
+        let height = self.bitcoind.get_block_height().await.unwrap();
+        let transaction = bitcoin::Transaction {
+            version: 2,
+            lock_time: height as u32,
+            input: vec![],
+            output: vec![bitcoin::TxOut {
+                value: amount.as_sat(),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        self.bitcoind
+            .submit_transaction(&transaction)
+            .await
+            .expect("Fake chain accepts all transactions");
+
+        let finality_delay = self.any_server().consensus.cfg.wallet.finalty_delay;
+        self.bitcoind.mine_blocks(1 + finality_delay as u64);
+
+        let proof = self
+            .bitcoind
+            .txout_proof(transaction.txid())
+            .expect("Transaction was just mined");
+        (proof, transaction)
+    }
+
+    /// Pegs `amount` into the federation and runs epochs until `client` received its e-cash
+    pub async fn peg_in(&mut self, client: &MintClient, amount: bitcoin::Amount) {
+        let address = client.get_new_pegin_address(OsRng::new().unwrap());
+        let (proof, transaction) = self.send_to_address(&address, amount).await;
+        self.run_epoch().await;
+
+        let txid = client
+            .peg_in(proof, transaction, OsRng::new().unwrap())
+            .await
+            .expect("Peg-in failed");
+        self.fetch_coins(client, OutPoint { txid, out_idx: 0 })
+            .await;
+    }
+
+    /// Runs epochs until `client` received the e-cash issued to `out_point`. Since a contribution
+    /// doesn't make it into every epoch this can take a varying number of epochs.
+    ///
+    /// Panics if the e-cash isn't issued within [`MAX_EPOCHS`] epochs.
+    pub async fn fetch_coins(&mut self, client: &MintClient, out_point: OutPoint) {
+        for _ in 0..MAX_EPOCHS {
+            self.run_epoch().await;
+            match client.fetch_coins(out_point).await {
+                Ok(()) => return,
+                Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {}
+                Err(e) => panic!("Could not fetch coins issued to {}: {}", out_point, e),
+            }
+        }
+        panic!("No coins were issued to {}", out_point);
+    }
+
+    fn live_servers(&mut self) -> impl Iterator<Item = &mut ServerTest> {
+        self.servers
+            .values_mut()
+            .filter(|server| !server.crashed.load(Ordering::SeqCst))
+    }
+
+    fn any_server(&self) -> &ServerTest {
+        self.servers.values().next().expect("Federation has peers")
+    }
+}
+
+impl ServerTest {
+    async fn handle_step(&mut self, step: Step<Vec<ConsensusItem>, PeerId>) {
+        let output = handle_step(step, &mut self.connections, &self.misbehavior).await;
+        self.outcomes.extend(output);
+    }
+}
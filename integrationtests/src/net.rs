@@ -0,0 +1,109 @@
+use async_trait::async_trait;
+use hbbft::Target;
+use minimint::net::PeerConnections;
+use minimint_api::PeerId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Network between peers running in the same process. Every peer receives messages in the order
+/// they were sent to it. Disconnected peers neither send nor receive any messages anymore, which
+/// simulates them crashing.
+pub struct MemNetwork<T> {
+    inboxes: BTreeMap<PeerId, Arc<Inbox<T>>>,
+    disconnected: Arc<Mutex<BTreeSet<PeerId>>>,
+}
+
+/// Connections of one peer to all others in a [`MemNetwork`]
+pub struct MemConnections<T> {
+    id: PeerId,
+    inboxes: BTreeMap<PeerId, Arc<Inbox<T>>>,
+    disconnected: Arc<Mutex<BTreeSet<PeerId>>>,
+}
+
+struct Inbox<T> {
+    messages: Mutex<VecDeque<(PeerId, T)>>,
+    notify: Notify,
+}
+
+impl<T> MemNetwork<T> {
+    pub fn new(peers: &[PeerId]) -> MemNetwork<T> {
+        MemNetwork {
+            inboxes: peers
+                .iter()
+                .map(|&peer| {
+                    let inbox = Inbox {
+                        messages: Mutex::new(VecDeque::new()),
+                        notify: Notify::new(),
+                    };
+                    (peer, Arc::new(inbox))
+                })
+                .collect(),
+            disconnected: Default::default(),
+        }
+    }
+
+    pub fn connections(&self, peer: PeerId) -> MemConnections<T> {
+        assert!(self.inboxes.contains_key(&peer), "Unknown peer");
+        MemConnections {
+            id: peer,
+            inboxes: self.inboxes.clone(),
+            disconnected: self.disconnected.clone(),
+        }
+    }
+
+    /// Drops all messages sent to or by `peer` from now on, including the ones it didn't receive
+    /// yet
+    pub fn disconnect(&self, peer: PeerId) {
+        self.disconnected.lock().unwrap().insert(peer);
+        self.inboxes[&peer].messages.lock().unwrap().clear();
+    }
+}
+
+impl<T> MemConnections<T> {
+    /// Returns the oldest message sent to us that we didn't receive yet, if any
+    pub fn try_receive(&mut self) -> Option<(PeerId, T)> {
+        self.inboxes[&self.id].messages.lock().unwrap().pop_front()
+    }
+
+    fn deliver(&self, target: PeerId, msg: T) {
+        let disconnected = self.disconnected.lock().unwrap();
+        if disconnected.contains(&self.id) || disconnected.contains(&target) {
+            return;
+        }
+
+        let inbox = self.inboxes.get(&target).expect("Unknown peer");
+        inbox.messages.lock().unwrap().push_back((self.id, msg));
+        inbox.notify.notify_one();
+    }
+}
+
+#[async_trait]
+impl<T> PeerConnections<T> for MemConnections<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Unpin + Send,
+{
+    type Id = PeerId;
+
+    async fn send(&mut self, target: Target<Self::Id>, msg: T) {
+        match target {
+            Target::All => {
+                for &peer in self.inboxes.keys().filter(|&&peer| peer != self.id) {
+                    self.deliver(peer, msg.clone());
+                }
+            }
+            Target::Node(peer) => self.deliver(peer, msg),
+        }
+    }
+
+    async fn receive(&mut self) -> (Self::Id, T) {
+        loop {
+            if let Some(msg) = self.try_receive() {
+                return msg;
+            }
+            self.inboxes[&self.id].notify.notified().await;
+        }
+    }
+}
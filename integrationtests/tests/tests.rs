@@ -0,0 +1,161 @@
+use bitcoin::{Address, Network, Script};
+use integrationtests::{FederationTest, MAX_EPOCHS};
+use minimint::consensus::{ConsensusItem, TransactionSubmissionError};
+use minimint::db::ProposedTransactionKey;
+use minimint_api::db::Database;
+use minimint_api::misbehavior::{peer_faults, PeerFault};
+use minimint_api::module::registry::{ModuleConsensusItem, MODULE_KEY_MINT};
+use minimint_api::outcome::PegOutOutcome;
+use minimint_api::transaction::{Input, OutPoint, Transaction};
+use minimint_api::{Amount, PeerId};
+use musig::rng_adapt::RngAdaptor;
+use rand::rngs::OsRng;
+
+const PEG_IN_AMOUNT: u64 = 1_000_000;
+
+/// Value of the e-cash issued for a peg-in of [`PEG_IN_AMOUNT`] sats
+fn peg_in_ecash(fed: &FederationTest) -> Amount {
+    Amount::from_sat(PEG_IN_AMOUNT).saturating_sub(fed.client_cfg.fee_consensus.fee_peg_in_abs)
+}
+
+async fn peg_in_and_reissue(fed: &mut FederationTest) {
+    let client = fed.new_client();
+    fed.peg_in(&client, bitcoin::Amount::from_sat(PEG_IN_AMOUNT))
+        .await;
+    assert_eq!(client.coins().amount(), peg_in_ecash(fed));
+
+    let coins = client.coins();
+    client.spend_coins(&coins);
+    let txid = client.reissue(coins, OsRng::new().unwrap()).await.unwrap();
+    fed.fetch_coins(&client, OutPoint { txid, out_idx: 0 })
+        .await;
+    assert_eq!(client.coins().amount(), peg_in_ecash(fed));
+}
+
+#[tokio::test]
+async fn peg_in_and_reissue_coins() {
+    let mut fed = FederationTest::new(4).await;
+    peg_in_and_reissue(&mut fed).await;
+}
+
+#[tokio::test]
+async fn peg_out_all_coins() {
+    let mut fed = FederationTest::new(4).await;
+    let client = fed.new_client();
+    fed.peg_in(&client, bitcoin::Amount::from_sat(PEG_IN_AMOUNT))
+        .await;
+
+    // Spend all our coins, fees included
+    let address = Address::p2wsh(&Script::new(), Network::Regtest);
+    let fees = client.fetch_peg_out_fees(&address).await.unwrap();
+    let priority_fee = fed.client_cfg.wallet.peg_out_policy.priority_fee;
+    let peg_out_fee = fed.client_cfg.fee_consensus.fee_peg_out_abs;
+    let amount = bitcoin::Amount::from_sat((peg_in_ecash(&fed) - peg_out_fee).milli_sat / 1000)
+        - priority_fee
+        - fees.fee;
+    let txid = client
+        .peg_out(amount, address, true, OsRng::new().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(client.coins().amount(), Amount::ZERO);
+
+    // Priority peg-outs are paid by the next peg-out transaction, which the guardians sign in the
+    // following epochs
+    let out_point = OutPoint { txid, out_idx: 0 };
+    let mut statuses = vec![];
+    for _ in 0..MAX_EPOCHS {
+        fed.run_epoch().await;
+        match client.fetch_peg_out_status(out_point).await {
+            Ok(PegOutOutcome::Broadcast(txid)) => {
+                assert!(statuses.contains(&PegOutOutcome::Signing(txid)));
+                return;
+            }
+            Ok(status) => statuses.push(status),
+            Err(_) => {}
+        }
+    }
+    panic!("Peg-out wasn't broadcast, its statuses were {:?}", statuses);
+}
+
+#[tokio::test]
+async fn tolerate_crashed_peer() {
+    let mut fed = FederationTest::new(4).await;
+    fed.crash(PeerId::from(3));
+    peg_in_and_reissue(&mut fed).await;
+}
+
+#[tokio::test]
+async fn tolerate_byzantine_peer() {
+    let mut fed = FederationTest::new(4).await;
+    // Withholds all its consensus items and proposes garbage instead
+    fed.make_byzantine(PeerId::from(3), |proposal| {
+        *proposal = vec![ConsensusItem::Module(ModuleConsensusItem {
+            module: MODULE_KEY_MINT,
+            item: vec![0xde, 0xad, 0xbe, 0xef],
+        })];
+    });
+    peg_in_and_reissue(&mut fed).await;
+}
+
+#[tokio::test]
+async fn blame_peer_sending_bad_shares() {
+    let mut fed = FederationTest::new(4).await;
+    fed.send_bad_shares(PeerId::from(3));
+    peg_in_and_reissue(&mut fed).await;
+
+    // The honest guardians only blame the peer that sent bad shares
+    let faults = (0..3)
+        .flat_map(|peer| peer_faults(fed.consensus(PeerId::from(peer)).db.as_ref()))
+        .collect::<Vec<_>>();
+    assert!(!faults.is_empty());
+    assert!(faults
+        .iter()
+        .all(|fault| fault.peer == PeerId::from(3)
+            && fault.fault == PeerFault::InvalidSignatureShare));
+}
+
+/// Transaction spending a single junk input of `size` bytes, it's never valid
+fn junk_transaction(size: usize, fill: u8) -> Transaction {
+    let key = musig::SecKey::random(RngAdaptor(OsRng::new().unwrap()));
+    Transaction {
+        inputs: vec![Input {
+            module: MODULE_KEY_MINT,
+            item: vec![fill; size],
+        }],
+        outputs: vec![],
+        signature: musig::sign(
+            [0; 32],
+            std::iter::once(&key),
+            RngAdaptor(OsRng::new().unwrap()),
+        ),
+    }
+}
+
+#[tokio::test]
+async fn proposals_fit_into_frames() {
+    let fed = FederationTest::new(4).await;
+    let consensus = fed.consensus(PeerId::from(0));
+    let max_size = consensus.max_proposal_size();
+
+    // Each transaction takes up a third of the proposal, so the last one has to wait
+    for fill in 0..3 {
+        let transaction = junk_transaction(max_size / 3, fill);
+        consensus
+            .db
+            .insert_entry(&ProposedTransactionKey(transaction.tx_hash()), &transaction)
+            .unwrap();
+    }
+    let proposal = consensus.get_consensus_proposal().await;
+    let transactions = proposal
+        .iter()
+        .filter(|item| matches!(item, ConsensusItem::Transaction(_)))
+        .count();
+    assert_eq!(transactions, 2);
+    assert!(bincode::serialized_size(&proposal).unwrap() as usize <= max_size);
+
+    // A transaction that could never be proposed is rejected right away
+    assert!(matches!(
+        consensus.submit_transaction(junk_transaction(max_size, 0)),
+        Err(TransactionSubmissionError::TooLarge(_))
+    ));
+}
@@ -0,0 +1,46 @@
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::db::DatabaseKeyPrefixConst;
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::ln::ContractId;
+
+const DB_PREFIX_GATEWAY_KEY: u8 = 0x50;
+const DB_PREFIX_OUTGOING_PAYMENT: u8 = 0x51;
+const DB_PREFIX_INCOMING_PAYMENT: u8 = 0x52;
+
+/// Secret key used by the gateway to claim outgoing and refund incoming contracts
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct GatewayKeyKey;
+
+impl DatabaseKeyPrefixConst for GatewayKeyKey {
+    const DB_PREFIX: u8 = DB_PREFIX_GATEWAY_KEY;
+}
+
+/// Progress of paying an invoice in exchange for an outgoing contract
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct OutgoingPaymentKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for OutgoingPaymentKey {
+    const DB_PREFIX: u8 = DB_PREFIX_OUTGOING_PAYMENT;
+}
+
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct OutgoingPaymentKeyPrefix;
+
+impl DatabaseKeyPrefixConst for OutgoingPaymentKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_OUTGOING_PAYMENT;
+}
+
+/// Progress of forwarding a lightning payment into an incoming contract, indexed by payment hash
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct IncomingPaymentKey(pub Sha256);
+
+impl DatabaseKeyPrefixConst for IncomingPaymentKey {
+    const DB_PREFIX: u8 = DB_PREFIX_INCOMING_PAYMENT;
+}
+
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct IncomingPaymentKeyPrefix;
+
+impl DatabaseKeyPrefixConst for IncomingPaymentKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_INCOMING_PAYMENT;
+}
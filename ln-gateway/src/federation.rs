@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::ln::{
+    ContractAccount, ContractId, IncomingContract, IncomingContractOffer, Preimage,
+};
+use minimint_api::transaction::{OutPoint, Transaction};
+use minimint_api::{Amount, TransactionId};
+use mint_client::{ClientError, MintClient};
+
+/// Federation interactions the gateway needs to claim outgoing and fund incoming contracts
+#[async_trait]
+pub trait FederationApi: Send + Sync {
+    async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ClientError>;
+
+    /// Consensus block height that contract timelocks are compared against
+    async fn fetch_block_height(&self) -> Result<u32, ClientError>;
+
+    /// Creates a transaction claiming the funds of `account`, either by revealing the preimage or,
+    /// without `witness`, as a refund after the timelock. The claimed coins are issued to the
+    /// transaction's first output.
+    fn create_claim(
+        &self,
+        account: ContractAccount,
+        witness: Option<Preimage>,
+        key: &musig::SecKey,
+    ) -> Result<Transaction, ClientError>;
+
+    /// Submits `transaction` to the federation, submitting it again while it's pending is harmless
+    async fn submit_transaction(&self, transaction: Transaction) -> Result<(), ClientError>;
+
+    /// Offer for an incoming contract with `payment_hash` that wasn't used to fund a contract yet
+    async fn fetch_offer(&self, payment_hash: Sha256)
+        -> Result<IncomingContractOffer, ClientError>;
+
+    /// Submits a transaction locking `amount` of our coins in `contract`
+    async fn fund_incoming_contract(
+        &self,
+        contract: IncomingContract,
+        amount: Amount,
+    ) -> Result<TransactionId, ClientError>;
+
+    /// Fetches the coins issued to `out_point`, fails with [`ClientError::OutputNotReadyYet`] if
+    /// they aren't available yet
+    async fn fetch_coins(&self, out_point: OutPoint) -> Result<(), ClientError>;
+}
+
+#[async_trait]
+impl FederationApi for MintClient {
+    async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ClientError> {
+        MintClient::fetch_contract_account(self, contract_id).await
+    }
+
+    async fn fetch_block_height(&self) -> Result<u32, ClientError> {
+        MintClient::fetch_block_height(self).await
+    }
+
+    fn create_claim(
+        &self,
+        account: ContractAccount,
+        witness: Option<Preimage>,
+        key: &musig::SecKey,
+    ) -> Result<Transaction, ClientError> {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        MintClient::create_claim_transaction(self, account, witness, key, rng)
+    }
+
+    async fn submit_transaction(&self, transaction: Transaction) -> Result<(), ClientError> {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        MintClient::send_tx(self, transaction, rng).await
+    }
+
+    async fn fetch_offer(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<IncomingContractOffer, ClientError> {
+        MintClient::fetch_offer(self, payment_hash).await
+    }
+
+    async fn fund_incoming_contract(
+        &self,
+        contract: IncomingContract,
+        amount: Amount,
+    ) -> Result<TransactionId, ClientError> {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        MintClient::fund_incoming_contract(self, contract, amount, rng).await
+    }
+
+    async fn fetch_coins(&self, out_point: OutPoint) -> Result<(), ClientError> {
+        MintClient::fetch_coins(self, out_point).await
+    }
+}
@@ -0,0 +1,1291 @@
+//! The gateway pays lightning invoices on behalf of federation users. Users lock the invoice amount
+//! in an outgoing contract that the gateway can only claim by revealing the invoice's preimage,
+//! so the gateway never has to trust users and users only trust it to not stall their payment
+//! until the contract's timelock expires.
+//!
+//! Incoming payments work the other way round: the user publishes an offer to sell the preimage
+//! of a payment hash, the gateway creates a hold invoice for it and, once HTLCs paying the invoice
+//! arrive, funds an incoming contract. Claiming the contract reveals the preimage to the gateway,
+//! which uses it to settle the held HTLCs.
+//!
+//! Every payment goes through the states of [`OutgoingPaymentState`] or [`IncomingPaymentState`],
+//! each of which is persisted before acting on it. This way a gateway that crashed mid-payment
+//! neither pays twice nor forgets to claim contracts it already paid the invoice for.
+
+pub mod db;
+pub mod federation;
+pub mod ln;
+
+use crate::db::{
+    GatewayKeyKey, IncomingPaymentKey, IncomingPaymentKeyPrefix, OutgoingPaymentKey,
+    OutgoingPaymentKeyPrefix,
+};
+use crate::federation::FederationApi;
+use crate::ln::{LightningRpc, PaymentStatus};
+use bitcoin_hashes::sha256::Hash as Sha256;
+use lightning_invoice::Invoice;
+use minimint_api::db::{Database, RawDatabase};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::ln::{
+    Contract, ContractAccount, ContractId, ContractOutcome, IncomingContract,
+    IncomingContractOffer, Preimage,
+};
+use minimint_api::transaction::{OutPoint, Transaction};
+use minimint_api::Amount;
+use mint_client::ln::{CreateInvoiceResponse, GatewayInfo};
+use mint_client::ClientError;
+use musig::rng_adapt::RngAdaptor;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+const FETCH_COINS_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval in which a funded incoming contract is checked for being claimed
+const CONTRACT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval in which the lightning node is asked about a payment that is still in flight
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Expected time between two blocks, used to compare invoice expiry times with timelocks
+const BLOCK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Time after which unpaid incoming invoices expire
+const INCOMING_INVOICE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+/// Upper bound of blocks found before an incoming invoice expires, the incoming contract's
+/// timelock has to cover this in addition to the minimum timelock delta
+const INCOMING_INVOICE_EXPIRY_BLOCKS: u32 = 12;
+
+pub struct LnGateway<F, L> {
+    federation: F,
+    ln_rpc: L,
+    key: musig::SecKey,
+    db: Arc<dyn RawDatabase>,
+    min_timelock_delta: u32,
+}
+
+/// An invoice the gateway agreed to pay in exchange for the funds of an outgoing contract
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct OutgoingPayment {
+    pub account: ContractAccount,
+    pub invoice: String,
+    pub state: OutgoingPaymentState,
+}
+
+/// A hold invoice the gateway created for an offer, paying it funds an incoming contract
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct IncomingPayment {
+    pub offer: IncomingContractOffer,
+    pub contract: IncomingContract,
+    pub invoice: String,
+    pub state: IncomingPaymentState,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encodable, Decodable)]
+pub enum OutgoingPaymentState {
+    /// The contract can be claimed by us once the invoice is paid
+    Verified,
+    /// The invoice is being paid. After a crash in this state the lightning node is asked whether
+    /// the payment went out before trying to pay again.
+    Paying,
+    /// The invoice was paid, the contract still has to be claimed
+    Paid(Preimage),
+    /// The claim transaction was created and has to be submitted until the contract is claimed.
+    /// Persisting it makes sure the contract is claimed with the same transaction after a crash.
+    Claiming {
+        preimage: Preimage,
+        transaction: Transaction,
+    },
+    /// The claim transaction was accepted, the issued coins still have to be fetched
+    Claimed {
+        preimage: Preimage,
+        out_point: OutPoint,
+    },
+    /// The contract's funds were received
+    Complete(Preimage),
+    /// The lightning node gave up paying the invoice, the user can refund the contract once its
+    /// timelock expired
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Encodable, Decodable)]
+pub enum IncomingPaymentState {
+    /// Waiting for HTLCs paying the invoice
+    InvoiceCreated,
+    /// HTLCs paying the invoice are held, the contract still has to be funded
+    HtlcsAccepted,
+    /// The contract was funded, waiting for the user to claim it
+    Funded,
+    /// The user didn't claim the contract before its timelock, the persisted refund transaction
+    /// has to be submitted and the issued coins fetched
+    Refunding(Transaction),
+    /// The user claimed the contract and the HTLCs were settled using the revealed preimage
+    Settled(Preimage),
+    /// The invoice wasn't paid or the contract wasn't claimed in time, all HTLCs were failed
+    Canceled,
+}
+
+impl<F, L> LnGateway<F, L>
+where
+    F: FederationApi,
+    L: LightningRpc,
+{
+    /// Creates a gateway using the key stored in `db` or, on first start, a newly generated one
+    pub fn new(
+        federation: F,
+        ln_rpc: L,
+        db: Arc<dyn RawDatabase>,
+        min_timelock_delta: u32,
+    ) -> Self {
+        let key = match db
+            .get_value::<_, musig::SecKey>(&GatewayKeyKey)
+            .expect("DB error")
+        {
+            Some(key) => key,
+            None => {
+                let rng = rand::rngs::OsRng::new().unwrap();
+                let key = musig::SecKey::random(RngAdaptor(rng));
+                db.insert_entry(&GatewayKeyKey, &key).expect("DB error");
+                key
+            }
+        };
+
+        LnGateway {
+            federation,
+            ln_rpc,
+            key,
+            db,
+            min_timelock_delta,
+        }
+    }
+
+    pub fn info(&self) -> GatewayInfo {
+        GatewayInfo {
+            public_key: self.key.to_public(),
+            min_timelock_delta: self.min_timelock_delta,
+        }
+    }
+
+    /// Pays `invoice` if the outgoing contract `contract_id` pays us enough for it and claims the
+    /// contract afterwards. Calling this again for the same contract resumes the existing payment
+    /// instead of paying twice.
+    pub async fn pay_invoice(
+        &self,
+        contract_id: ContractId,
+        invoice: String,
+    ) -> Result<Preimage, GatewayError> {
+        let payment = match self
+            .db
+            .get_value::<_, OutgoingPayment>(&OutgoingPaymentKey(contract_id))
+            .expect("DB error")
+        {
+            Some(payment) => {
+                debug!("Resuming payment for contract {}", contract_id);
+                payment
+            }
+            None => {
+                let payment = self.verify_payment(contract_id, invoice).await?;
+                self.save_payment(contract_id, &payment);
+                payment
+            }
+        };
+
+        self.complete_payment(contract_id, payment).await
+    }
+
+    /// Drives all payments that were interrupted, e.g. by a restart, to completion
+    pub async fn resume_pending_payments(&self) {
+        let pending = self
+            .db
+            .find_by_prefix::<_, OutgoingPaymentKey, OutgoingPayment>(&OutgoingPaymentKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .filter(|(_, payment)| payment.state.is_pending())
+            .collect::<Vec<_>>();
+
+        for (OutgoingPaymentKey(contract_id), payment) in pending {
+            info!("Resuming payment for contract {}", contract_id);
+            if let Err(e) = self.complete_payment(contract_id, payment).await {
+                warn!(
+                    "Resuming payment for contract {} failed: {}",
+                    contract_id, e
+                );
+            }
+        }
+    }
+
+    /// Creates a hold invoice for the offer with `payment_hash`. Calling this again for the same
+    /// offer returns the existing invoice. [`LnGateway::receive_payment`] has to be running for the
+    /// payment to be forwarded to the user.
+    pub async fn create_invoice(
+        &self,
+        payment_hash: Sha256,
+        description: String,
+    ) -> Result<CreateInvoiceResponse, GatewayError> {
+        if let Some(payment) = self
+            .db
+            .get_value::<_, IncomingPayment>(&IncomingPaymentKey(payment_hash))
+            .expect("DB error")
+        {
+            return Ok(CreateInvoiceResponse {
+                invoice: payment.invoice,
+                contract: payment.contract,
+            });
+        }
+
+        let offer = self
+            .federation
+            .fetch_offer(payment_hash)
+            .await
+            .map_err(|_| GatewayError::UnknownOffer(payment_hash))?;
+
+        // The offer is needed to fund the contract, so it may not expire before the invoice does
+        let block_height = self.federation.fetch_block_height().await?;
+        if offer.expiry_height <= block_height + INCOMING_INVOICE_EXPIRY_BLOCKS {
+            return Err(GatewayError::OfferExpiresTooSoon(offer.expiry_height));
+        }
+
+        // The contract has to stay locked long enough for us to settle the HTLCs after the user
+        // claimed it, even if the invoice is paid just before it expires
+        let timelock = block_height + INCOMING_INVOICE_EXPIRY_BLOCKS + self.min_timelock_delta;
+        let contract = IncomingContract {
+            hash: payment_hash,
+            gateway_key: self.key.to_public(),
+            timelock,
+            user_key: offer.user_key.clone(),
+        };
+
+        let invoice = self
+            .ln_rpc
+            .create_hold_invoice(
+                payment_hash,
+                offer.amount,
+                &description,
+                INCOMING_INVOICE_EXPIRY,
+                INCOMING_INVOICE_EXPIRY_BLOCKS + 2 * self.min_timelock_delta,
+            )
+            .await
+            .map_err(|e| GatewayError::LightningError(e.to_string()))?
+            .to_string();
+
+        self.save_incoming_payment(
+            payment_hash,
+            &IncomingPayment {
+                offer,
+                contract: contract.clone(),
+                invoice: invoice.clone(),
+                state: IncomingPaymentState::InvoiceCreated,
+            },
+        );
+        Ok(CreateInvoiceResponse { invoice, contract })
+    }
+
+    /// Forwards the payment of the invoice created for `payment_hash` to the user and settles it
+    /// once they claimed the contract. Waits until the payment reached its final state, which is
+    /// returned.
+    pub async fn receive_payment(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<IncomingPaymentState, GatewayError> {
+        let mut payment = self
+            .db
+            .get_value::<_, IncomingPayment>(&IncomingPaymentKey(payment_hash))
+            .expect("DB error")
+            .ok_or(GatewayError::UnknownOffer(payment_hash))?;
+        let contract_id = Contract::Incoming(payment.contract.clone()).contract_id();
+
+        loop {
+            payment.state = match payment.state {
+                IncomingPaymentState::InvoiceCreated => {
+                    match self.ln_rpc.wait_for_htlcs(payment_hash).await {
+                        Ok(()) => IncomingPaymentState::HtlcsAccepted,
+                        Err(e) => {
+                            debug!("Invoice for offer {} not paid: {}", payment_hash, e);
+                            IncomingPaymentState::Canceled
+                        }
+                    }
+                }
+                IncomingPaymentState::HtlcsAccepted => {
+                    let block_height = self.federation.fetch_block_height().await?;
+                    if block_height + self.min_timelock_delta > payment.contract.timelock {
+                        warn!("HTLCs for offer {} arrived too late", payment_hash);
+                        self.cancel_htlcs(payment_hash).await?;
+                        IncomingPaymentState::Canceled
+                    } else if self
+                        .federation
+                        .fetch_contract_account(contract_id)
+                        .await
+                        .is_ok()
+                    {
+                        // We crashed after funding the contract
+                        IncomingPaymentState::Funded
+                    } else {
+                        debug!("Funding incoming contract {}", contract_id);
+                        match self
+                            .federation
+                            .fund_incoming_contract(payment.contract.clone(), payment.offer.amount)
+                            .await
+                        {
+                            Ok(_) => IncomingPaymentState::Funded,
+                            Err(ClientError::NotEnoughCoins) => {
+                                warn!("Not enough coins to fund contract {}", contract_id);
+                                self.cancel_htlcs(payment_hash).await?;
+                                IncomingPaymentState::Canceled
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+                IncomingPaymentState::Funded => {
+                    self.await_incoming_claim(contract_id, &payment).await?
+                }
+                IncomingPaymentState::Refunding(ref transaction) => {
+                    let out_point = self
+                        .submit_claim(contract_id, transaction, ContractOutcome::Refunded)
+                        .await?;
+                    self.fetch_claimed_coins(out_point).await?;
+                    IncomingPaymentState::Canceled
+                }
+                state @ (IncomingPaymentState::Settled(_) | IncomingPaymentState::Canceled) => {
+                    return Ok(state)
+                }
+            };
+            self.save_incoming_payment(payment_hash, &payment);
+        }
+    }
+
+    /// Payment hashes of all incoming payments that haven't reached their final state yet, each
+    /// of them needs a [`LnGateway::receive_payment`] task
+    pub fn pending_incoming_payments(&self) -> Vec<Sha256> {
+        self.db
+            .find_by_prefix::<_, IncomingPaymentKey, IncomingPayment>(&IncomingPaymentKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .filter(|(_, payment)| payment.state.is_pending())
+            .map(|(IncomingPaymentKey(payment_hash), _)| payment_hash)
+            .collect()
+    }
+
+    /// Waits for the user to claim the funded incoming contract and settles the HTLCs with the
+    /// revealed preimage. If the contract isn't claimed before its timelock the HTLCs are failed
+    /// and the contract refunded.
+    async fn await_incoming_claim(
+        &self,
+        contract_id: ContractId,
+        payment: &IncomingPayment,
+    ) -> Result<IncomingPaymentState, GatewayError> {
+        loop {
+            // The contract account doesn't exist until the funding transaction was accepted
+            let account = self
+                .federation
+                .fetch_contract_account(contract_id)
+                .await
+                .ok();
+
+            match account {
+                Some(ContractAccount {
+                    outcome: ContractOutcome::Claimed(preimage),
+                    ..
+                }) => {
+                    debug!("Incoming contract {} claimed, settling HTLCs", contract_id);
+                    self.ln_rpc
+                        .settle_htlcs(preimage)
+                        .await
+                        .map_err(|e| GatewayError::LightningError(e.to_string()))?;
+                    return Ok(IncomingPaymentState::Settled(preimage));
+                }
+                account => {
+                    let block_height = self.federation.fetch_block_height().await?;
+                    if block_height >= payment.contract.timelock {
+                        warn!("Incoming contract {} was not claimed in time", contract_id);
+                        self.cancel_htlcs(payment.contract.hash).await?;
+                        return match account {
+                            Some(account) if account.outcome == ContractOutcome::Funded => {
+                                let transaction =
+                                    self.federation.create_claim(account, None, &self.key)?;
+                                Ok(IncomingPaymentState::Refunding(transaction))
+                            }
+                            _ => Ok(IncomingPaymentState::Canceled),
+                        };
+                    }
+                }
+            }
+
+            tokio::time::sleep(CONTRACT_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn cancel_htlcs(&self, payment_hash: Sha256) -> Result<(), GatewayError> {
+        self.ln_rpc
+            .cancel_htlcs(payment_hash)
+            .await
+            .map_err(|e| GatewayError::LightningError(e.to_string()))
+    }
+
+    /// Checks that we can claim the contract's funds once the invoice is paid and that they cover
+    /// the invoice amount
+    async fn verify_payment(
+        &self,
+        contract_id: ContractId,
+        invoice: String,
+    ) -> Result<OutgoingPayment, GatewayError> {
+        let parsed_invoice =
+            Invoice::from_str(&invoice).map_err(|e| GatewayError::InvalidInvoice(e.to_string()))?;
+        let invoice_amount = parsed_invoice
+            .amount_pico_btc()
+            .map(|pico_btc| Amount::from_msat(pico_btc / 10))
+            .ok_or(GatewayError::InvoiceMissingAmount)?;
+
+        let now = SystemTime::now();
+        let invoice_expiry = *parsed_invoice.timestamp() + parsed_invoice.expiry_time();
+        if invoice_expiry <= now {
+            return Err(GatewayError::InvoiceExpired);
+        }
+
+        let account = self.federation.fetch_contract_account(contract_id).await?;
+        if account.outcome != ContractOutcome::Funded {
+            return Err(GatewayError::ContractNotFunded(contract_id));
+        }
+
+        let contract = match &account.contract {
+            Contract::Outgoing(contract) => contract,
+            Contract::Incoming(_) => return Err(GatewayError::NotOutgoingContract(contract_id)),
+        };
+
+        if contract.gateway_key != self.key.to_public() {
+            return Err(GatewayError::WrongGatewayKey);
+        }
+
+        if contract.hash != *parsed_invoice.payment_hash() {
+            return Err(GatewayError::PaymentHashMismatch);
+        }
+
+        // TODO: charge a fee for routing payments
+        if account.amount < invoice_amount {
+            return Err(GatewayError::Underfunded(invoice_amount, account.amount));
+        }
+
+        let block_height = self.federation.fetch_block_height().await?;
+        let min_timelock = block_height + self.min_timelock_delta;
+        if contract.timelock < min_timelock {
+            return Err(GatewayError::TimelockTooShort(
+                contract.timelock,
+                min_timelock,
+            ));
+        }
+
+        // A payment made after the timelock expired can't be claimed anymore since the user may
+        // have taken back the funds already
+        let timelock_time = now + BLOCK_INTERVAL * (contract.timelock - block_height);
+        if invoice_expiry > timelock_time {
+            return Err(GatewayError::InvoiceExpiresAfterTimelock(contract.timelock));
+        }
+
+        Ok(OutgoingPayment {
+            account,
+            invoice,
+            state: OutgoingPaymentState::Verified,
+        })
+    }
+
+    async fn complete_payment(
+        &self,
+        contract_id: ContractId,
+        mut payment: OutgoingPayment,
+    ) -> Result<Preimage, GatewayError> {
+        loop {
+            payment.state = match payment.state {
+                OutgoingPaymentState::Verified => OutgoingPaymentState::Paying,
+                OutgoingPaymentState::Paying => {
+                    let invoice =
+                        Invoice::from_str(&payment.invoice).expect("Invoice was verified");
+                    match self.pay(contract_id, &invoice).await? {
+                        PaymentStatus::Succeeded(preimage)
+                            if preimage.hash() == *invoice.payment_hash() =>
+                        {
+                            OutgoingPaymentState::Paid(preimage)
+                        }
+                        PaymentStatus::Succeeded(_) => OutgoingPaymentState::Failed(
+                            "Lightning node returned a wrong preimage".into(),
+                        ),
+                        PaymentStatus::Failed(reason) => OutgoingPaymentState::Failed(reason),
+                        PaymentStatus::Pending => {
+                            tokio::time::sleep(PAYMENT_POLL_INTERVAL).await;
+                            OutgoingPaymentState::Paying
+                        }
+                        PaymentStatus::Unknown => {
+                            return Err(GatewayError::LightningError(
+                                "The payment was not started".into(),
+                            ))
+                        }
+                    }
+                }
+                OutgoingPaymentState::Paid(preimage) => {
+                    let transaction = self.federation.create_claim(
+                        payment.account.clone(),
+                        Some(preimage),
+                        &self.key,
+                    )?;
+                    OutgoingPaymentState::Claiming {
+                        preimage,
+                        transaction,
+                    }
+                }
+                OutgoingPaymentState::Claiming {
+                    preimage,
+                    ref transaction,
+                } => {
+                    debug!("Claiming contract {}", contract_id);
+                    let out_point = self
+                        .submit_claim(contract_id, transaction, ContractOutcome::Claimed(preimage))
+                        .await?;
+                    OutgoingPaymentState::Claimed {
+                        preimage,
+                        out_point,
+                    }
+                }
+                OutgoingPaymentState::Claimed {
+                    preimage,
+                    out_point,
+                } => {
+                    self.fetch_claimed_coins(out_point).await?;
+                    OutgoingPaymentState::Complete(preimage)
+                }
+                OutgoingPaymentState::Complete(preimage) => return Ok(preimage),
+                OutgoingPaymentState::Failed(reason) => {
+                    return Err(GatewayError::PaymentFailed(reason))
+                }
+            };
+            self.save_payment(contract_id, &payment);
+        }
+    }
+
+    /// Pays `invoice` unless the lightning node already tried to. Errors returned by the node
+    /// while paying aren't necessarily final, so the node is asked for the payment's status
+    /// afterwards.
+    async fn pay(
+        &self,
+        contract_id: ContractId,
+        invoice: &Invoice,
+    ) -> Result<PaymentStatus, GatewayError> {
+        let payment_hash = *invoice.payment_hash();
+        match self.payment_status(payment_hash).await? {
+            PaymentStatus::Unknown => {}
+            status => return Ok(status),
+        }
+
+        debug!("Paying invoice for contract {}", contract_id);
+        match self.ln_rpc.pay(invoice).await {
+            Ok(preimage) => Ok(PaymentStatus::Succeeded(preimage)),
+            Err(e) => {
+                debug!("Paying invoice for contract {} failed: {}", contract_id, e);
+                self.payment_status(payment_hash).await
+            }
+        }
+    }
+
+    async fn payment_status(&self, payment_hash: Sha256) -> Result<PaymentStatus, GatewayError> {
+        self.ln_rpc
+            .payment_status(payment_hash)
+            .await
+            .map_err(|e| GatewayError::LightningError(e.to_string()))
+    }
+
+    /// Submits `transaction` claiming the contract `contract_id` unless it was accepted already,
+    /// which is the case if the contract's outcome is `claimed`. Returns the out point the claimed
+    /// coins are issued to.
+    async fn submit_claim(
+        &self,
+        contract_id: ContractId,
+        transaction: &Transaction,
+        claimed: ContractOutcome,
+    ) -> Result<OutPoint, GatewayError> {
+        let account = self.federation.fetch_contract_account(contract_id).await?;
+        if account.outcome == ContractOutcome::Funded {
+            debug!(
+                "Submitting transaction {} claiming contract {}",
+                transaction.tx_hash(),
+                contract_id
+            );
+            self.federation
+                .submit_transaction(transaction.clone())
+                .await?;
+        } else if account.outcome != claimed {
+            return Err(GatewayError::ContractSpent(contract_id));
+        }
+
+        Ok(OutPoint {
+            txid: transaction.tx_hash(),
+            out_idx: 0,
+        })
+    }
+
+    async fn fetch_claimed_coins(&self, out_point: OutPoint) -> Result<(), GatewayError> {
+        loop {
+            match self.federation.fetch_coins(out_point).await {
+                Ok(()) => return Ok(()),
+                // TODO: make mint error more expressive (currently any HTTP error) and maybe use custom return type instead of error for retrying
+                Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {
+                    tokio::time::sleep(FETCH_COINS_RETRY_INTERVAL).await
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn save_payment(&self, contract_id: ContractId, payment: &OutgoingPayment) {
+        self.db
+            .insert_entry(&OutgoingPaymentKey(contract_id), payment)
+            .expect("DB error");
+    }
+
+    fn save_incoming_payment(&self, payment_hash: Sha256, payment: &IncomingPayment) {
+        self.db
+            .insert_entry(&IncomingPaymentKey(payment_hash), payment)
+            .expect("DB error");
+    }
+}
+
+impl OutgoingPaymentState {
+    /// Returns `true` if the payment still needs work by the gateway
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self,
+            OutgoingPaymentState::Verified
+                | OutgoingPaymentState::Paying
+                | OutgoingPaymentState::Paid(_)
+                | OutgoingPaymentState::Claiming { .. }
+                | OutgoingPaymentState::Claimed { .. }
+        )
+    }
+}
+
+impl IncomingPaymentState {
+    /// Returns `true` if the payment still needs work by the gateway
+    pub fn is_pending(&self) -> bool {
+        !matches!(
+            self,
+            IncomingPaymentState::Settled(_) | IncomingPaymentState::Canceled
+        )
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum GatewayError {
+    #[error("Invalid invoice: {0}")]
+    InvalidInvoice(String),
+    #[error("The invoice expired")]
+    InvoiceExpired,
+    #[error("The invoice expires after the contract's timelock {0}")]
+    InvoiceExpiresAfterTimelock(u32),
+    #[error("Invoices without an amount are not supported")]
+    InvoiceMissingAmount,
+    #[error("Federation error: {0}")]
+    FederationError(ClientError),
+    #[error("The contract {0} is not funded")]
+    ContractNotFunded(ContractId),
+    #[error("The contract {0} was spent by someone else")]
+    ContractSpent(ContractId),
+    #[error("The contract {0} is not an outgoing contract")]
+    NotOutgoingContract(ContractId),
+    #[error("The contract can't be claimed by this gateway")]
+    WrongGatewayKey,
+    #[error("The contract's hash does not match the invoice's payment hash")]
+    PaymentHashMismatch,
+    #[error("The contract is underfunded: the invoice requires {0}, the contract holds {1}")]
+    Underfunded(Amount, Amount),
+    #[error("The contract's timelock {0} is too short, it has to be at least {1}")]
+    TimelockTooShort(u32, u32),
+    #[error("Paying the invoice failed: {0}")]
+    PaymentFailed(String),
+    #[error("There is no offer for the payment hash {0}")]
+    UnknownOffer(Sha256),
+    #[error("The offer expires at block {0}, before the invoice would")]
+    OfferExpiresTooSoon(u32),
+    #[error("Lightning node error: {0}")]
+    LightningError(String),
+}
+
+impl GatewayError {
+    /// Returns `true` if a request was rejected because the contract, offer or invoice were
+    /// invalid, as opposed to the gateway or federation failing
+    pub fn is_invalid_request(&self) -> bool {
+        !matches!(
+            self,
+            GatewayError::FederationError(_)
+                | GatewayError::ContractSpent(_)
+                | GatewayError::PaymentFailed(_)
+                | GatewayError::LightningError(_)
+        )
+    }
+}
+
+impl From<ClientError> for GatewayError {
+    fn from(e: ClientError) -> Self {
+        GatewayError::FederationError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::OutgoingPaymentKey;
+    use crate::federation::FederationApi;
+    use crate::ln::{HoldInvoiceState, LightningRpc, MockLightning};
+    use crate::{
+        GatewayError, IncomingPaymentState, LnGateway, OutgoingPayment, OutgoingPaymentState,
+    };
+    use async_trait::async_trait;
+    use bitcoin_hashes::sha256::Hash as Sha256;
+    use bitcoin_hashes::Hash as BitcoinHash;
+    use lightning_invoice::Invoice;
+    use minimint_api::db::mem_impl::MemDatabase;
+    use minimint_api::db::{Database, RawDatabase};
+    use minimint_api::ln::{
+        Contract, ContractAccount, ContractId, ContractInput, ContractOutcome, IncomingContract,
+        IncomingContractOffer, OutgoingContract, Preimage,
+    };
+    use minimint_api::module::registry::MODULE_KEY_LN;
+    use minimint_api::transaction::{decode_item, Input, OutPoint, Transaction};
+    use minimint_api::{Amount, TransactionId};
+    use mint_client::ClientError;
+    use musig::rng_adapt::RngAdaptor;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    const BLOCK_HEIGHT: u32 = 100;
+    const MIN_TIMELOCK_DELTA: u32 = 10;
+
+    #[derive(Default)]
+    struct MockFederation {
+        accounts: Mutex<HashMap<ContractId, ContractAccount>>,
+        offers: Mutex<HashMap<Sha256, IncomingContractOffer>>,
+        claims: Mutex<Vec<(ContractId, Option<Preimage>)>>,
+        reject_claims: AtomicBool,
+    }
+
+    #[async_trait]
+    impl FederationApi for MockFederation {
+        async fn fetch_contract_account(
+            &self,
+            contract_id: ContractId,
+        ) -> Result<ContractAccount, ClientError> {
+            self.accounts
+                .lock()
+                .unwrap()
+                .get(&contract_id)
+                .cloned()
+                .ok_or(ClientError::MintError)
+        }
+
+        async fn fetch_block_height(&self) -> Result<u32, ClientError> {
+            Ok(BLOCK_HEIGHT)
+        }
+
+        fn create_claim(
+            &self,
+            account: ContractAccount,
+            witness: Option<Preimage>,
+            key: &musig::SecKey,
+        ) -> Result<Transaction, ClientError> {
+            let inputs = vec![Input::new(
+                MODULE_KEY_LN,
+                &ContractInput {
+                    contract: account.contract,
+                    amount: account.amount,
+                    witness,
+                },
+            )];
+            let signature = musig::sign(
+                Transaction::tx_hash_from_parts(&inputs, &[]).into_inner(),
+                std::iter::once(key),
+                RngAdaptor(rand::rngs::OsRng::new().unwrap()),
+            );
+            Ok(Transaction {
+                inputs,
+                outputs: vec![],
+                signature,
+            })
+        }
+
+        async fn submit_transaction(&self, transaction: Transaction) -> Result<(), ClientError> {
+            if self.reject_claims.load(Ordering::SeqCst) {
+                return Err(ClientError::MintError);
+            }
+
+            let input = decode_item::<ContractInput>(&transaction.inputs[0].item).unwrap();
+            let contract_id = input.contract.contract_id();
+            let mut accounts = self.accounts.lock().unwrap();
+            let stored_account = accounts.get_mut(&contract_id).unwrap();
+            assert!(musig::verify(
+                transaction.tx_hash().into_inner(),
+                transaction.signature.clone(),
+                &[input.authorization_key().clone()],
+            ));
+            assert_eq!(input.contract, stored_account.contract);
+            assert_eq!(stored_account.outcome, ContractOutcome::Funded);
+            stored_account.outcome = match input.witness {
+                Some(preimage) => {
+                    assert_eq!(stored_account.contract.hash(), &preimage.hash());
+                    ContractOutcome::Claimed(preimage)
+                }
+                None => ContractOutcome::Refunded,
+            };
+
+            self.claims
+                .lock()
+                .unwrap()
+                .push((contract_id, input.witness));
+            Ok(())
+        }
+
+        async fn fetch_offer(
+            &self,
+            payment_hash: Sha256,
+        ) -> Result<IncomingContractOffer, ClientError> {
+            self.offers
+                .lock()
+                .unwrap()
+                .get(&payment_hash)
+                .cloned()
+                .ok_or(ClientError::MintError)
+        }
+
+        async fn fund_incoming_contract(
+            &self,
+            contract: IncomingContract,
+            amount: Amount,
+        ) -> Result<TransactionId, ClientError> {
+            let offer = self
+                .offers
+                .lock()
+                .unwrap()
+                .remove(&contract.hash)
+                .expect("Funded contract without offer");
+            assert_eq!(offer.user_key, contract.user_key);
+            assert!(amount >= offer.amount);
+
+            let contract = Contract::Incoming(contract);
+            self.accounts.lock().unwrap().insert(
+                contract.contract_id(),
+                ContractAccount {
+                    amount,
+                    contract,
+                    outcome: ContractOutcome::Funded,
+                },
+            );
+            Ok(Default::default())
+        }
+
+        async fn fetch_coins(&self, _out_point: OutPoint) -> Result<(), ClientError> {
+            Ok(())
+        }
+    }
+
+    impl MockFederation {
+        fn fund_contract(&self, contract: OutgoingContract, amount: Amount) -> ContractId {
+            let contract = Contract::Outgoing(contract);
+            let contract_id = contract.contract_id();
+            self.accounts.lock().unwrap().insert(
+                contract_id,
+                ContractAccount {
+                    amount,
+                    contract,
+                    outcome: ContractOutcome::Funded,
+                },
+            );
+            contract_id
+        }
+    }
+
+    fn user_key() -> musig::PubKey {
+        secret_key().to_public()
+    }
+
+    fn secret_key() -> musig::SecKey {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        musig::SecKey::random(RngAdaptor(rng))
+    }
+
+    fn gateway(
+        db: Arc<dyn RawDatabase>,
+        ln_rpc: MockLightning,
+    ) -> LnGateway<MockFederation, MockLightning> {
+        LnGateway::new(MockFederation::default(), ln_rpc, db, MIN_TIMELOCK_DELTA)
+    }
+
+    /// Creates an invoice and a contract paying for it that the gateway should accept
+    fn valid_payment(
+        gateway: &LnGateway<MockFederation, MockLightning>,
+        amount: Amount,
+    ) -> (ContractId, String) {
+        let invoice = gateway.ln_rpc.create_invoice(amount);
+        let contract = OutgoingContract {
+            hash: *invoice.payment_hash(),
+            gateway_key: gateway.key.to_public(),
+            timelock: BLOCK_HEIGHT + MIN_TIMELOCK_DELTA,
+            user_key: user_key(),
+        };
+        let contract_id = gateway.federation.fund_contract(contract, amount);
+        (contract_id, invoice.to_string())
+    }
+
+    fn payment_state(
+        gateway: &LnGateway<MockFederation, MockLightning>,
+        contract_id: ContractId,
+    ) -> Option<OutgoingPaymentState> {
+        gateway
+            .db
+            .get_value::<_, OutgoingPayment>(&OutgoingPaymentKey(contract_id))
+            .unwrap()
+            .map(|payment| payment.state)
+    }
+
+    #[tokio::test]
+    async fn test_pay_invoice() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+        let (contract_id, invoice) = valid_payment(&gateway, Amount::from_sat(1000));
+
+        let preimage = gateway
+            .pay_invoice(contract_id, invoice.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            *gateway.federation.claims.lock().unwrap(),
+            vec![(contract_id, Some(preimage))]
+        );
+        assert_eq!(
+            payment_state(&gateway, contract_id),
+            Some(OutgoingPaymentState::Complete(preimage))
+        );
+
+        // Repeated requests return the result of the first one
+        let repeated_preimage = gateway.pay_invoice(contract_id, invoice).await.unwrap();
+        assert_eq!(repeated_preimage, preimage);
+        assert_eq!(gateway.ln_rpc.payments(), vec![preimage.hash()]);
+        assert_eq!(gateway.federation.claims.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_contracts() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+        let amount = Amount::from_sat(1000);
+        let invoice = gateway.ln_rpc.create_invoice(amount);
+        let valid_contract = OutgoingContract {
+            hash: *invoice.payment_hash(),
+            gateway_key: gateway.key.to_public(),
+            timelock: BLOCK_HEIGHT + MIN_TIMELOCK_DELTA,
+            user_key: user_key(),
+        };
+
+        let wrong_key = gateway.federation.fund_contract(
+            OutgoingContract {
+                gateway_key: user_key(),
+                ..valid_contract.clone()
+            },
+            amount,
+        );
+        assert!(matches!(
+            gateway.pay_invoice(wrong_key, invoice.to_string()).await,
+            Err(GatewayError::WrongGatewayKey)
+        ));
+
+        let wrong_hash = gateway.federation.fund_contract(
+            OutgoingContract {
+                hash: Default::default(),
+                ..valid_contract.clone()
+            },
+            amount,
+        );
+        assert!(matches!(
+            gateway.pay_invoice(wrong_hash, invoice.to_string()).await,
+            Err(GatewayError::PaymentHashMismatch)
+        ));
+
+        let timelock_too_short = gateway.federation.fund_contract(
+            OutgoingContract {
+                timelock: BLOCK_HEIGHT + MIN_TIMELOCK_DELTA - 1,
+                ..valid_contract.clone()
+            },
+            amount,
+        );
+        assert!(matches!(
+            gateway
+                .pay_invoice(timelock_too_short, invoice.to_string())
+                .await,
+            Err(GatewayError::TimelockTooShort(_, _))
+        ));
+
+        let underfunded = gateway
+            .federation
+            .fund_contract(valid_contract, Amount::from_sat(999));
+        assert!(matches!(
+            gateway.pay_invoice(underfunded, invoice.to_string()).await,
+            Err(GatewayError::Underfunded(_, _))
+        ));
+
+        assert!(gateway.ln_rpc.payments().is_empty());
+        assert_eq!(payment_state(&gateway, underfunded), None);
+    }
+
+    #[tokio::test]
+    async fn test_payment_failure() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+
+        // The invoice of a different node can't be paid by the mock
+        let other_node = MockLightning::new();
+        let invoice = other_node.create_invoice(Amount::from_sat(1000));
+        let contract_id = gateway.federation.fund_contract(
+            OutgoingContract {
+                hash: *invoice.payment_hash(),
+                gateway_key: gateway.key.to_public(),
+                timelock: BLOCK_HEIGHT + MIN_TIMELOCK_DELTA,
+                user_key: user_key(),
+            },
+            Amount::from_sat(1000),
+        );
+
+        assert!(matches!(
+            gateway.pay_invoice(contract_id, invoice.to_string()).await,
+            Err(GatewayError::PaymentFailed(_))
+        ));
+        assert!(matches!(
+            payment_state(&gateway, contract_id),
+            Some(OutgoingPaymentState::Failed(_))
+        ));
+        assert!(gateway.federation.claims.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let db: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let gateway = gateway(db.clone(), MockLightning::new());
+        let (contract_id, invoice) = valid_payment(&gateway, Amount::from_sat(1000));
+
+        // The invoice gets paid but claiming the contract fails
+        gateway
+            .federation
+            .reject_claims
+            .store(true, Ordering::SeqCst);
+        assert!(matches!(
+            gateway.pay_invoice(contract_id, invoice).await,
+            Err(GatewayError::FederationError(_))
+        ));
+        let preimage = match payment_state(&gateway, contract_id) {
+            Some(OutgoingPaymentState::Claiming { preimage, .. }) => preimage,
+            state => panic!("Unexpected payment state {:?}", state),
+        };
+
+        // After restarting with a lightning node that can't pay the invoice anymore the gateway
+        // has to claim the contract without paying again
+        let accounts = std::mem::take(&mut *gateway.federation.accounts.lock().unwrap());
+        let restarted = LnGateway::new(
+            MockFederation {
+                accounts: Mutex::new(accounts),
+                ..Default::default()
+            },
+            MockLightning::new(),
+            db,
+            MIN_TIMELOCK_DELTA,
+        );
+        assert_eq!(restarted.key.to_public(), gateway.key.to_public());
+
+        restarted.resume_pending_payments().await;
+        assert_eq!(
+            *restarted.federation.claims.lock().unwrap(),
+            vec![(contract_id, Some(preimage))]
+        );
+        assert_eq!(
+            payment_state(&restarted, contract_id),
+            Some(OutgoingPaymentState::Complete(preimage))
+        );
+        assert!(restarted.ln_rpc.payments().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_claim_was_accepted() {
+        let db: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let gateway = gateway(db, MockLightning::new());
+        let (contract_id, invoice) = valid_payment(&gateway, Amount::from_sat(1000));
+
+        gateway
+            .federation
+            .reject_claims
+            .store(true, Ordering::SeqCst);
+        assert!(gateway.pay_invoice(contract_id, invoice).await.is_err());
+        let (preimage, transaction) = match payment_state(&gateway, contract_id) {
+            Some(OutgoingPaymentState::Claiming {
+                preimage,
+                transaction,
+            }) => (preimage, transaction),
+            state => panic!("Unexpected payment state {:?}", state),
+        };
+
+        // The claim transaction was accepted, but we crashed before noticing
+        gateway
+            .federation
+            .reject_claims
+            .store(false, Ordering::SeqCst);
+        gateway
+            .federation
+            .submit_transaction(transaction.clone())
+            .await
+            .unwrap();
+
+        // Resuming must not submit another claim transaction
+        gateway.resume_pending_payments().await;
+        assert_eq!(
+            *gateway.federation.claims.lock().unwrap(),
+            vec![(contract_id, Some(preimage))]
+        );
+        assert_eq!(
+            payment_state(&gateway, contract_id),
+            Some(OutgoingPaymentState::Complete(preimage))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resume_while_paying() {
+        let db: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let gateway = gateway(db, MockLightning::new());
+        let (contract_id, invoice) = valid_payment(&gateway, Amount::from_sat(1000));
+
+        // We crashed after the lightning node paid the invoice but before it told us
+        let payment = gateway
+            .verify_payment(contract_id, invoice.clone())
+            .await
+            .unwrap();
+        let preimage = gateway
+            .ln_rpc
+            .pay(&Invoice::from_str(&invoice).unwrap())
+            .await
+            .unwrap();
+        gateway.save_payment(
+            contract_id,
+            &OutgoingPayment {
+                state: OutgoingPaymentState::Paying,
+                ..payment
+            },
+        );
+
+        gateway.resume_pending_payments().await;
+        assert_eq!(gateway.ln_rpc.pay_calls(), 1);
+        assert_eq!(
+            payment_state(&gateway, contract_id),
+            Some(OutgoingPaymentState::Complete(preimage))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reject_expiring_invoices() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+        let amount = Amount::from_sat(1000);
+        let fund = |invoice: &Invoice| {
+            gateway.federation.fund_contract(
+                OutgoingContract {
+                    hash: *invoice.payment_hash(),
+                    gateway_key: gateway.key.to_public(),
+                    timelock: BLOCK_HEIGHT + MIN_TIMELOCK_DELTA,
+                    user_key: user_key(),
+                },
+                amount,
+            )
+        };
+
+        let expired = gateway.ln_rpc.create_invoice_with_expiry(
+            amount,
+            SystemTime::now() - Duration::from_secs(7200),
+            Duration::from_secs(3600),
+        );
+        assert!(matches!(
+            gateway
+                .pay_invoice(fund(&expired), expired.to_string())
+                .await,
+            Err(GatewayError::InvoiceExpired)
+        ));
+
+        // The contract's timelock expires in about 100 minutes
+        let expires_late = gateway.ln_rpc.create_invoice_with_expiry(
+            amount,
+            SystemTime::now(),
+            Duration::from_secs(24 * 3600),
+        );
+        assert!(matches!(
+            gateway
+                .pay_invoice(fund(&expires_late), expires_late.to_string())
+                .await,
+            Err(GatewayError::InvoiceExpiresAfterTimelock(_))
+        ));
+
+        assert!(gateway.ln_rpc.payments().is_empty());
+        assert_eq!(gateway.ln_rpc.pay_calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_receive_payment() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+        let preimage = Preimage([42; 32]);
+        let user_key = secret_key();
+        let amount = Amount::from_sat(1000);
+        gateway.federation.offers.lock().unwrap().insert(
+            preimage.hash(),
+            IncomingContractOffer {
+                amount,
+                hash: preimage.hash(),
+                user_key: user_key.to_public(),
+                expiry_height: BLOCK_HEIGHT + 144,
+            },
+        );
+
+        let response = gateway
+            .create_invoice(preimage.hash(), "test".into())
+            .await
+            .unwrap();
+        let invoice = Invoice::from_str(&response.invoice).unwrap();
+        assert_eq!(*invoice.payment_hash(), preimage.hash());
+        assert_eq!(invoice.amount_pico_btc(), Some(amount.milli_sat * 10));
+        assert_eq!(response.contract.user_key, user_key.to_public());
+        assert_eq!(response.contract.gateway_key, gateway.key.to_public());
+        let contract_id = Contract::Incoming(response.contract).contract_id();
+
+        gateway.ln_rpc.pay_hold_invoice(preimage.hash());
+
+        // The user claims the contract as soon as the gateway funded it
+        let user = async {
+            let account = loop {
+                match gateway.federation.fetch_contract_account(contract_id).await {
+                    Ok(account) => break account,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            assert_eq!(account.amount, amount);
+            let transaction = gateway
+                .federation
+                .create_claim(account, Some(preimage), &user_key)
+                .unwrap();
+            gateway
+                .federation
+                .submit_transaction(transaction)
+                .await
+                .unwrap();
+        };
+        let (state, ()) = tokio::join!(gateway.receive_payment(preimage.hash()), user);
+
+        assert_eq!(state.unwrap(), IncomingPaymentState::Settled(preimage));
+        assert_eq!(
+            gateway.ln_rpc.hold_invoice_state(preimage.hash()),
+            Some(HoldInvoiceState::Settled(preimage))
+        );
+        assert!(gateway.pending_incoming_payments().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_without_offer() {
+        let gateway = gateway(Arc::new(MemDatabase::new()), MockLightning::new());
+        let payment_hash = Preimage([42; 32]).hash();
+
+        assert!(matches!(
+            gateway.create_invoice(payment_hash, "test".into()).await,
+            Err(GatewayError::UnknownOffer(_))
+        ));
+        assert_eq!(gateway.ln_rpc.hold_invoice_state(payment_hash), None);
+    }
+}
@@ -0,0 +1,492 @@
+use async_trait::async_trait;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use clightningrpc::lightningrpc::PayOptions;
+use clightningrpc::LightningRPC;
+use lightning_invoice::{Currency, Invoice, InvoiceBuilder};
+use minimint_api::ln::Preimage;
+use minimint_api::Amount;
+use rand::Rng;
+use secp256k1::{Secp256k1, SecretKey};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Interface to the lightning node used by the gateway to make payments
+#[async_trait]
+pub trait LightningRpc: Send + Sync {
+    /// Pays `invoice` and returns the payment's preimage.
+    ///
+    /// Paying an invoice that was already paid successfully has to return the preimage again
+    /// instead of paying twice. The gateway relies on this to resume payments after a crash.
+    async fn pay(&self, invoice: &Invoice) -> Result<Preimage, LightningRpcError>;
+
+    /// Status of paying the invoice for `payment_hash` as far as the node knows. This tells whether
+    /// a payment that was interrupted or returned an error may still go through.
+    async fn payment_status(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<PaymentStatus, LightningRpcError>;
+
+    /// Creates an invoice for `payment_hash` without knowing its preimage. HTLCs paying it are held
+    /// until they are settled using [`LightningRpc::settle_htlcs`] or failed using
+    /// [`LightningRpc::cancel_htlcs`].
+    async fn create_hold_invoice(
+        &self,
+        payment_hash: Sha256,
+        amount: Amount,
+        description: &str,
+        expiry: Duration,
+        min_final_cltv_expiry: u32,
+    ) -> Result<Invoice, LightningRpcError>;
+
+    /// Waits until HTLCs paying the hold invoice for `payment_hash` are held. Fails if the invoice
+    /// expired or was canceled before.
+    async fn wait_for_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError>;
+
+    /// Settles the held HTLCs of the hold invoice that `preimage` belongs to
+    async fn settle_htlcs(&self, preimage: Preimage) -> Result<(), LightningRpcError>;
+
+    /// Fails all held HTLCs of the hold invoice for `payment_hash` and cancels the invoice
+    async fn cancel_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError>;
+}
+
+/// [`LightningRpc`] implementation talking to c-lightning via its unix socket. Hold invoices
+/// require the `holdinvoice` plugin.
+pub struct CLightningRpc(Arc<LightningRPC>);
+
+/// Interval in which c-lightning is polled for held HTLCs
+const HOLD_INVOICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Expiry of invoices created by [`MockLightning::create_invoice`], the BOLT 11 default
+const DEFAULT_INVOICE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Simulates a lightning node that can only pay invoices it created itself, allows testing the
+/// gateway without a lightning network.
+#[derive(Default)]
+pub struct MockLightning {
+    /// Preimages of all invoices created by the mock
+    preimages: Mutex<HashMap<Sha256, Preimage>>,
+    /// Payment hashes of all successfully paid invoices
+    payments: Mutex<Vec<Sha256>>,
+    /// Payment hashes of all invoices that couldn't be paid
+    failed_payments: Mutex<Vec<Sha256>>,
+    /// Number of times [`LightningRpc::pay`] was called
+    pay_calls: Mutex<usize>,
+    hold_invoices: Mutex<HashMap<Sha256, HoldInvoiceState>>,
+}
+
+/// Outcome of all attempts to pay an invoice
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PaymentStatus {
+    /// The node never tried to pay the invoice
+    Unknown,
+    /// An attempt to pay the invoice is still in flight
+    Pending,
+    /// The invoice was paid
+    Succeeded(Preimage),
+    /// All attempts failed and none is in flight anymore
+    Failed(String),
+}
+
+/// State of a hold invoice created by [`MockLightning`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HoldInvoiceState {
+    Open,
+    Accepted,
+    Settled(Preimage),
+    Canceled,
+}
+
+#[derive(Debug, Error)]
+pub enum LightningRpcError {
+    #[error("Payment failed: {0}")]
+    PaymentFailed(String),
+    #[error("Lightning node returned an invalid preimage: {0}")]
+    InvalidPreimage(String),
+    #[error("The invoice expired or was canceled")]
+    InvoiceCanceled,
+    #[error("Lightning RPC error: {0}")]
+    RpcError(String),
+}
+
+#[derive(Serialize)]
+struct HoldInvoiceRequest {
+    payment_hash: String,
+    amount: u64,
+    description: String,
+    expiry: u64,
+    min_final_cltv_expiry: u32,
+}
+
+#[derive(Deserialize)]
+struct HoldInvoiceResponse {
+    bolt11: String,
+}
+
+#[derive(Serialize)]
+struct ListHoldInvoicesRequest {
+    payment_hash: String,
+}
+
+#[derive(Deserialize)]
+struct ListHoldInvoicesResponse {
+    holdinvoices: Vec<HoldInvoiceStatus>,
+}
+
+#[derive(Deserialize)]
+struct HoldInvoiceStatus {
+    state: String,
+}
+
+#[derive(Serialize)]
+struct ListPaysRequest {
+    payment_hash: String,
+}
+
+#[derive(Deserialize)]
+struct ListPaysResponse {
+    pays: Vec<PayStatus>,
+}
+
+#[derive(Deserialize)]
+struct PayStatus {
+    status: String,
+    preimage: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SettleHoldInvoiceRequest {
+    preimage: String,
+}
+
+#[derive(Serialize)]
+struct CancelHoldInvoiceRequest {
+    payment_hash: String,
+}
+
+impl CLightningRpc {
+    pub fn new(socket: String) -> CLightningRpc {
+        CLightningRpc(Arc::new(LightningRPC::new(socket)))
+    }
+
+    /// Calls `method` of c-lightning or one of its plugins without blocking the executor
+    async fn call<T, U>(&self, method: &'static str, params: T) -> Result<U, LightningRpcError>
+    where
+        T: Serialize + Send + 'static,
+        U: DeserializeOwned + Send + 'static,
+    {
+        let rpc = self.0.clone();
+        async_std::task::spawn_blocking(move || {
+            rpc.client()
+                .send_request(method, params)
+                .and_then(|response| response.into_result())
+        })
+        .await
+        .map_err(|e| LightningRpcError::RpcError(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl LightningRpc for CLightningRpc {
+    async fn pay(&self, invoice: &Invoice) -> Result<Preimage, LightningRpcError> {
+        let rpc = self.0.clone();
+        let bolt11 = invoice.to_string();
+        // c-lightning's pay command returns the existing payment if the invoice was paid before
+        let response =
+            async_std::task::spawn_blocking(move || rpc.pay(&bolt11, PayOptions::default()))
+                .await
+                .map_err(|e| LightningRpcError::PaymentFailed(e.to_string()))?;
+
+        parse_preimage(&response.payment_preimage)
+    }
+
+    async fn payment_status(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<PaymentStatus, LightningRpcError> {
+        let ListPaysResponse { pays } = self
+            .call(
+                "listpays",
+                ListPaysRequest {
+                    payment_hash: payment_hash.to_string(),
+                },
+            )
+            .await?;
+
+        if let Some(pay) = pays.iter().find(|pay| pay.status == "complete") {
+            let preimage = pay.preimage.as_deref().unwrap_or_default();
+            return Ok(PaymentStatus::Succeeded(parse_preimage(preimage)?));
+        }
+
+        if pays.iter().any(|pay| pay.status == "pending") {
+            Ok(PaymentStatus::Pending)
+        } else if pays.is_empty() {
+            Ok(PaymentStatus::Unknown)
+        } else {
+            Ok(PaymentStatus::Failed(format!(
+                "All {} payment attempts failed",
+                pays.len()
+            )))
+        }
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        payment_hash: Sha256,
+        amount: Amount,
+        description: &str,
+        expiry: Duration,
+        min_final_cltv_expiry: u32,
+    ) -> Result<Invoice, LightningRpcError> {
+        let HoldInvoiceResponse { bolt11 } = self
+            .call(
+                "holdinvoice",
+                HoldInvoiceRequest {
+                    payment_hash: payment_hash.to_string(),
+                    amount: amount.milli_sat,
+                    description: description.to_owned(),
+                    expiry: expiry.as_secs(),
+                    min_final_cltv_expiry,
+                },
+            )
+            .await?;
+        bolt11
+            .parse()
+            .map_err(|_| LightningRpcError::RpcError(format!("Invalid invoice: {}", bolt11)))
+    }
+
+    async fn wait_for_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError> {
+        loop {
+            let ListHoldInvoicesResponse { holdinvoices } = self
+                .call(
+                    "listholdinvoices",
+                    ListHoldInvoicesRequest {
+                        payment_hash: payment_hash.to_string(),
+                    },
+                )
+                .await?;
+            let state = holdinvoices
+                .into_iter()
+                .next()
+                .ok_or(LightningRpcError::InvoiceCanceled)?
+                .state;
+
+            match state.as_str() {
+                "accepted" | "paid" => return Ok(()),
+                "cancelled" => return Err(LightningRpcError::InvoiceCanceled),
+                _ => tokio::time::sleep(HOLD_INVOICE_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    async fn settle_htlcs(&self, preimage: Preimage) -> Result<(), LightningRpcError> {
+        let _: IgnoredAny = self
+            .call(
+                "settleholdinvoice",
+                SettleHoldInvoiceRequest {
+                    preimage: hex::encode(preimage.0),
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError> {
+        let _: IgnoredAny = self
+            .call(
+                "cancelholdinvoice",
+                CancelHoldInvoiceRequest {
+                    payment_hash: payment_hash.to_string(),
+                },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn parse_preimage(hex: &str) -> Result<Preimage, LightningRpcError> {
+    let preimage: [u8; 32] = hex::decode(hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| LightningRpcError::InvalidPreimage(hex.to_owned()))?;
+    Ok(Preimage(preimage))
+}
+
+impl MockLightning {
+    pub fn new() -> MockLightning {
+        Default::default()
+    }
+
+    /// Creates an invoice for `amount` that can be paid using this mock
+    pub fn create_invoice(&self, amount: Amount) -> Invoice {
+        self.create_invoice_with_expiry(amount, SystemTime::now(), DEFAULT_INVOICE_EXPIRY)
+    }
+
+    /// Creates an invoice for `amount` created at `timestamp` that expires after `expiry`
+    pub fn create_invoice_with_expiry(
+        &self,
+        amount: Amount,
+        timestamp: SystemTime,
+        expiry: Duration,
+    ) -> Invoice {
+        let preimage = Preimage(rand::rngs::OsRng::new().unwrap().gen());
+        self.preimages
+            .lock()
+            .unwrap()
+            .insert(preimage.hash(), preimage);
+        build_invoice(
+            preimage.hash(),
+            amount,
+            "MockLightning invoice",
+            timestamp,
+            expiry,
+        )
+    }
+
+    /// Simulates HTLCs paying the hold invoice for `payment_hash` arriving
+    pub fn pay_hold_invoice(&self, payment_hash: Sha256) {
+        let mut hold_invoices = self.hold_invoices.lock().unwrap();
+        let state = hold_invoices
+            .get_mut(&payment_hash)
+            .expect("Unknown hold invoice");
+        assert_eq!(*state, HoldInvoiceState::Open);
+        *state = HoldInvoiceState::Accepted;
+    }
+
+    pub fn hold_invoice_state(&self, payment_hash: Sha256) -> Option<HoldInvoiceState> {
+        self.hold_invoices
+            .lock()
+            .unwrap()
+            .get(&payment_hash)
+            .cloned()
+    }
+
+    /// Payment hashes of all invoices paid so far, each invoice is paid at most once
+    pub fn payments(&self) -> Vec<Sha256> {
+        self.payments.lock().unwrap().clone()
+    }
+
+    /// Number of payment attempts including repeated ones for the same invoice
+    pub fn pay_calls(&self) -> usize {
+        *self.pay_calls.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl LightningRpc for MockLightning {
+    async fn pay(&self, invoice: &Invoice) -> Result<Preimage, LightningRpcError> {
+        *self.pay_calls.lock().unwrap() += 1;
+
+        let hash = *invoice.payment_hash();
+        let preimage = match self.preimages.lock().unwrap().get(&hash) {
+            Some(preimage) => *preimage,
+            None => {
+                self.failed_payments.lock().unwrap().push(hash);
+                return Err(LightningRpcError::PaymentFailed("No route found".into()));
+            }
+        };
+
+        let mut payments = self.payments.lock().unwrap();
+        if !payments.contains(&hash) {
+            payments.push(hash);
+        }
+        Ok(preimage)
+    }
+
+    async fn payment_status(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<PaymentStatus, LightningRpcError> {
+        if self.payments.lock().unwrap().contains(&payment_hash) {
+            Ok(PaymentStatus::Succeeded(
+                self.preimages.lock().unwrap()[&payment_hash],
+            ))
+        } else if self.failed_payments.lock().unwrap().contains(&payment_hash) {
+            Ok(PaymentStatus::Failed("No route found".into()))
+        } else {
+            Ok(PaymentStatus::Unknown)
+        }
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        payment_hash: Sha256,
+        amount: Amount,
+        description: &str,
+        expiry: Duration,
+        _min_final_cltv_expiry: u32,
+    ) -> Result<Invoice, LightningRpcError> {
+        self.hold_invoices
+            .lock()
+            .unwrap()
+            .insert(payment_hash, HoldInvoiceState::Open);
+        Ok(build_invoice(
+            payment_hash,
+            amount,
+            description,
+            SystemTime::now(),
+            expiry,
+        ))
+    }
+
+    async fn wait_for_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError> {
+        loop {
+            match self.hold_invoice_state(payment_hash) {
+                Some(HoldInvoiceState::Open) => tokio::time::sleep(Duration::from_millis(10)).await,
+                Some(HoldInvoiceState::Accepted | HoldInvoiceState::Settled(_)) => return Ok(()),
+                Some(HoldInvoiceState::Canceled) | None => {
+                    return Err(LightningRpcError::InvoiceCanceled)
+                }
+            }
+        }
+    }
+
+    async fn settle_htlcs(&self, preimage: Preimage) -> Result<(), LightningRpcError> {
+        let mut hold_invoices = self.hold_invoices.lock().unwrap();
+        match hold_invoices.get_mut(&preimage.hash()) {
+            Some(state @ (HoldInvoiceState::Accepted | HoldInvoiceState::Settled(_))) => {
+                *state = HoldInvoiceState::Settled(preimage);
+                Ok(())
+            }
+            _ => Err(LightningRpcError::RpcError("No HTLCs to settle".into())),
+        }
+    }
+
+    async fn cancel_htlcs(&self, payment_hash: Sha256) -> Result<(), LightningRpcError> {
+        let mut hold_invoices = self.hold_invoices.lock().unwrap();
+        match hold_invoices.get_mut(&payment_hash) {
+            Some(HoldInvoiceState::Settled(_)) | None => {
+                Err(LightningRpcError::RpcError("Can't cancel invoice".into()))
+            }
+            Some(state) => {
+                *state = HoldInvoiceState::Canceled;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Builds an invoice signed by a random node key, which is good enough for the mock since nobody
+/// routes to it
+fn build_invoice(
+    payment_hash: Sha256,
+    amount: Amount,
+    description: &str,
+    timestamp: SystemTime,
+    expiry: Duration,
+) -> Invoice {
+    let node_key = SecretKey::from_slice(&rand::rngs::OsRng::new().unwrap().gen::<[u8; 32]>())
+        .expect("Invalid key");
+    let secp = Secp256k1::new();
+    InvoiceBuilder::new(Currency::Regtest)
+        .description(description.to_owned())
+        .payment_hash(payment_hash)
+        .amount_pico_btc(amount.milli_sat * 10)
+        .timestamp(timestamp)
+        .expiry_time(expiry)
+        .build_signed(|hash| secp.sign_recoverable(hash, &node_key))
+        .expect("Invoice is valid")
+}
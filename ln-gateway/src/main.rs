@@ -1,90 +1,94 @@
-use clightningrpc::lightningrpc::PayOptions;
-use clightningrpc::LightningRPC;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use ln_gateway::ln::CLightningRpc;
+use ln_gateway::LnGateway;
 use minimint::config::{load_from_file, ClientConfig};
-use minimint_api::transaction::OutPoint;
-use minimint_api::Coins;
-use mint_client::{ClientError, MintClient, SpendableCoin};
-use serde::{Deserialize, Serialize};
+use mint_client::ln::{CreateInvoicePayload, PayInvoicePayload, PayInvoiceResponse};
+use mint_client::MintClient;
+use serde::Deserialize;
 use std::path::PathBuf;
-use std::str::FromStr;
 use std::sync::Arc;
 use structopt::StructOpt;
 use tide::Response;
-use tokio::time::Duration;
-use tracing::debug;
+use tracing::{debug, error, info};
 use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct PayRequest {
-    coins: Coins<SpendableCoin>,
-    invoice: String,
-}
-
 #[derive(Debug, Deserialize)]
 struct Config {
     ln_socket: String,
+    /// Minimum number of blocks an outgoing contract has to stay locked after the current
+    /// consensus block height, gives us time to claim it after paying the invoice
+    min_timelock_delta: u32,
     client: ClientConfig,
 }
 
+type Gateway = LnGateway<MintClient, CLightningRpc>;
+
 #[derive(Clone)]
 pub struct State {
-    mint_client: Arc<MintClient>,
-    ln_client: Arc<LightningRPC>,
+    gateway: Arc<Gateway>,
+}
+
+async fn info(req: tide::Request<State>) -> tide::Result {
+    let body = tide::Body::from_json(&req.state().gateway.info())?;
+    Ok(body.into())
 }
 
 async fn pay_invoice(mut req: tide::Request<State>) -> tide::Result {
-    let mut rng = rand::rngs::OsRng::new().unwrap();
+    let PayInvoicePayload {
+        contract_id,
+        invoice,
+    } = req.body_json().await?;
+    debug!(
+        "Received request to pay invoice for contract {}",
+        contract_id
+    );
 
-    let pay_req: PayRequest = req.body_json().await?;
-    let invoice = lightning_invoice::Invoice::from_str(&pay_req.invoice)
-        .map_err(|e| tide::Error::new(400, e))?;
+    let preimage = req
+        .state()
+        .gateway
+        .pay_invoice(contract_id, invoice)
+        .await
+        .map_err(|e| {
+            let status = if e.is_invalid_request() { 400 } else { 500 };
+            tide::Error::from_str(status, e.to_string())
+        })?;
 
-    let amt_ln_invoice_msat = invoice.amount_pico_btc().expect("no amount given") / 10;
-    let amt_coins_msat = pay_req.coins.amount().milli_sat;
+    let body = tide::Body::from_json(&PayInvoiceResponse { preimage })?;
+    Ok(Response::builder(200).body(body).build())
+}
 
+async fn create_invoice(mut req: tide::Request<State>) -> tide::Result {
+    let CreateInvoicePayload {
+        payment_hash,
+        description,
+    } = req.body_json().await?;
     debug!(
-        "Received request to pay invoice of {}msat for tokens of value {}msat",
-        amt_ln_invoice_msat, amt_coins_msat
+        "Received request to create invoice for offer {}",
+        payment_hash
     );
 
-    if amt_coins_msat < amt_ln_invoice_msat {
-        return Err(tide::Error::from_str(400, "Not enough tokens sent"));
-    }
-
-    let State {
-        ref mint_client,
-        ref ln_client,
-    } = req.state();
-
-    debug!("Trying to reissue");
-    let txid = mint_client
-        .reissue(pay_req.coins, &mut rng)
+    let gateway = req.state().gateway.clone();
+    let response = gateway
+        .create_invoice(payment_hash, description)
         .await
-        .expect("error while starting reissuance");
-    debug!("Fetching coins");
-    loop {
-        match mint_client.fetch_coins(OutPoint { txid, out_idx: 0 }).await {
-            Ok(()) => break,
-            // TODO: make mint error more expressive (currently any HTTP error) and maybe use custom return type instead of error for retrying
-            Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {
-                tokio::time::sleep(Duration::from_secs(1)).await
-            }
-            Err(_) => return Err(tide::Error::from_str(500, "fetching reissuance failed")),
-        }
-    }
+        .map_err(|e| {
+            let status = if e.is_invalid_request() { 400 } else { 500 };
+            tide::Error::from_str(status, e.to_string())
+        })?;
+    spawn_receive_payment(gateway, payment_hash);
+
+    let body = tide::Body::from_json(&response)?;
+    Ok(Response::builder(200).body(body).build())
+}
 
-    let invoice = pay_req.invoice;
-    let ln_client = ln_client.clone();
-    async_std::task::spawn_blocking(move || {
-        debug!("Requesting payment from c-lightning");
-        ln_client
-            .pay(&invoice, PayOptions::default())
-            .expect("paying ln invoice failed");
-        debug!("Payment succeeded.");
-    })
-    .await;
-
-    Ok(Response::new(200))
+/// Forwards the payment of an incoming invoice in the background
+fn spawn_receive_payment(gateway: Arc<Gateway>, payment_hash: Sha256) {
+    tokio::spawn(async move {
+        match gateway.receive_payment(payment_hash).await {
+            Ok(state) => info!("Incoming payment {} finished: {:?}", payment_hash, state),
+            Err(e) => error!("Incoming payment {} failed: {}", payment_hash, e),
+        }
+    });
 }
 
 #[derive(StructOpt)]
@@ -104,21 +108,34 @@ async fn main() -> tide::Result<()> {
     let cfg_path = opts.workdir.join("client.json");
     let db_path = opts.workdir.join("client.db");
     let cfg: Config = load_from_file(&cfg_path);
-    let db = sled::open(&db_path)
-        .unwrap()
-        .open_tree("mint-client")
-        .unwrap();
+    let db = sled::open(&db_path).unwrap();
 
-    let client = MintClient::new(cfg.client, Arc::new(db), Default::default());
-    let ln_client = LightningRPC::new(cfg.ln_socket);
+    let client = MintClient::new(
+        cfg.client,
+        Arc::new(db.open_tree("mint-client").unwrap()),
+        Default::default(),
+    );
+    let gateway = LnGateway::new(
+        client,
+        CLightningRpc::new(cfg.ln_socket),
+        Arc::new(db.open_tree("gateway").unwrap()),
+        cfg.min_timelock_delta,
+    );
 
     let state = State {
-        mint_client: Arc::new(client),
-        ln_client: Arc::new(ln_client),
+        gateway: Arc::new(gateway),
     };
 
+    // Finish payments that were interrupted by a shutdown before serving new ones
+    state.gateway.resume_pending_payments().await;
+    for payment_hash in state.gateway.pending_incoming_payments() {
+        spawn_receive_payment(state.gateway.clone(), payment_hash);
+    }
+
     let mut app = tide::with_state(state);
-    app.at("/").post(pay_invoice);
+    app.at("/info").get(info);
+    app.at("/pay_invoice").post(pay_invoice);
+    app.at("/create_invoice").post(create_invoice);
     app.listen("127.0.0.1:8080").await?;
 
     Ok(())
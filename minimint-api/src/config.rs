@@ -1,11 +1,16 @@
 use crate::PeerId;
+use async_trait::async_trait;
 use rand::{CryptoRng, RngCore};
 use std::collections::BTreeMap;
+use thiserror::Error;
 
 /// Part of a config that needs to be generated to bootstrap a new federation.
+#[async_trait(?Send)]
 pub trait GenerateConfig: Sized {
     type Params: ?Sized;
     type ClientConfig;
+    /// Message exchanged between peers during distributed config generation
+    type ConfigMessage;
 
     /// Function that generates the config of all peers locally. This is only meant to be used for
     /// testing as the generating machine would be a single point of failure/compromise.
@@ -16,5 +21,38 @@ pub trait GenerateConfig: Sized {
         rng: impl RngCore + CryptoRng,
     ) -> (BTreeMap<PeerId, Self>, Self::ClientConfig);
 
-    // TODO: impl distributed config gen
+    /// Generates the config of peer `our_id` interactively together with all other `peers`, so
+    /// that no single peer learns the secrets of the others. All peers have to call this function
+    /// with the same `peers`, `max_evil` and `params`. The resulting client config is identical for
+    /// all peers.
+    async fn distributed_gen<'a>(
+        connections: &'a mut (impl ConfigGenConnections<Self::ConfigMessage> + 'a),
+        our_id: &'a PeerId,
+        peers: &'a [PeerId],
+        max_evil: usize,
+        params: &'a Self::Params,
+        rng: impl RngCore + CryptoRng + 'a,
+    ) -> Result<(Self, Self::ClientConfig), ConfigGenError>;
+}
+
+/// Channel to exchange messages with the other peers during distributed config generation
+#[async_trait(?Send)]
+pub trait ConfigGenConnections<M> {
+    /// Sends `msg` to all `peers`
+    async fn send(&mut self, peers: &[PeerId], msg: M);
+
+    /// Receives the next message from any peer
+    async fn receive(&mut self) -> (PeerId, M);
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigGenError {
+    #[error("Peer {0} sent an invalid key share")]
+    InvalidShare(PeerId),
+    #[error("Peer {0} sent an unexpected message")]
+    UnexpectedMessage(PeerId),
+    #[error("Peer {0} ended up with a different config")]
+    InconsistentConfig(PeerId),
+    #[error("Key generation failed: {0}")]
+    KeyGen(String),
 }
@@ -10,6 +10,7 @@ use tracing::trace;
 pub mod batch;
 pub mod mem_impl;
 pub mod sled_impl;
+pub mod staged;
 
 pub trait DatabaseKeyPrefixConst {
     const DB_PREFIX: u8;
@@ -292,15 +293,22 @@ mod tests {
     use crate::encoding::{Decodable, Encodable};
     use std::sync::Arc;
 
-    #[derive(Debug, Encodable, Decodable)]
-    struct TestKey(u64);
+    #[derive(Debug, Encodable, Decodable, Eq, PartialEq)]
+    pub struct TestKey(pub u64);
 
     impl DatabaseKeyPrefixConst for TestKey {
         const DB_PREFIX: u8 = 0x42;
     }
 
+    #[derive(Debug, Encodable, Decodable)]
+    pub struct TestKeyPrefix;
+
+    impl DatabaseKeyPrefixConst for TestKeyPrefix {
+        const DB_PREFIX: u8 = 0x42;
+    }
+
     #[derive(Debug, Encodable, Decodable, Eq, PartialEq)]
-    struct TestVal(u64);
+    pub struct TestVal(pub u64);
 
     pub fn test_db_impl(db: Arc<dyn RawDatabase + 'static>) {
         assert!(db
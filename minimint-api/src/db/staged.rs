@@ -0,0 +1,231 @@
+use super::batch::{BatchItem, DbBatch, Element};
+use super::{DatabaseError, DatabaseKeyPrefix, RawDatabase, SerializableDatabaseValue};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tracing::{error, trace};
+
+/// Keeps the batches applied to it in memory till they are committed to the underlying database
+/// at once or discarded. Reads see the staged changes, which lets consensus process an epoch in
+/// several steps and only persist it once the result passed the audit. Single entries inserted or
+/// removed outside of batches are written through right away.
+pub struct StagedDatabase {
+    inner: Arc<dyn RawDatabase>,
+    /// Staged values by key, `None` if the key is staged to be deleted
+    staged: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+/// Already encoded key or value of a staged change
+#[derive(Debug)]
+struct Encoded(Vec<u8>);
+
+impl DatabaseKeyPrefix for Encoded {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+impl SerializableDatabaseValue for Encoded {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+impl StagedDatabase {
+    pub fn new(inner: Arc<dyn RawDatabase>) -> StagedDatabase {
+        StagedDatabase {
+            inner,
+            staged: Default::default(),
+        }
+    }
+
+    /// Writes all staged changes to the underlying database in one batch
+    pub fn commit(&self) -> Result<(), DatabaseError> {
+        let mut staged = self.staged.lock().unwrap();
+        let mut batch = DbBatch::new();
+        batch.autocommit(|tx| {
+            tx.append_from_iter(staged.iter().map(|(key, value)| match value {
+                Some(value) => BatchItem::InsertElement(Element::new(
+                    Encoded(key.clone()),
+                    Encoded(value.clone()),
+                )),
+                None => BatchItem::MaybeDeleteElement(Box::new(Encoded(key.clone()))),
+            }))
+        });
+        self.inner.raw_apply_batch(batch)?;
+        staged.clear();
+        Ok(())
+    }
+
+    /// Drops all staged changes
+    pub fn discard(&self) {
+        self.staged.lock().unwrap().clear();
+    }
+
+    fn get(
+        &self,
+        staged: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        match staged.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.inner.raw_get_value(key.to_vec()),
+        }
+    }
+}
+
+impl RawDatabase for StagedDatabase {
+    fn raw_insert_entry(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let mut staged = self.staged.lock().unwrap();
+        let previous = self.inner.raw_insert_entry(key.clone(), value)?;
+        Ok(staged.remove(&key).unwrap_or(previous))
+    }
+
+    fn raw_get_value(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let staged = self.staged.lock().unwrap();
+        self.get(&staged, &key)
+    }
+
+    fn raw_remove_entry(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let mut staged = self.staged.lock().unwrap();
+        let previous = self.inner.raw_remove_entry(key.clone())?;
+        Ok(staged.remove(&key).unwrap_or(previous))
+    }
+
+    fn raw_find_by_prefix(
+        &self,
+        key_prefix: Vec<u8>,
+    ) -> Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), DatabaseError>>> {
+        let staged = self.staged.lock().unwrap();
+        let staged_entries = staged
+            .range::<Vec<u8>, _>((&key_prefix)..)
+            .take_while(|(key, _)| key.starts_with(&key_prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        if staged_entries.is_empty() {
+            return self.inner.raw_find_by_prefix(key_prefix);
+        }
+
+        let mut entries = BTreeMap::new();
+        for entry in self.inner.raw_find_by_prefix(key_prefix) {
+            match entry {
+                Ok((key, value)) => {
+                    entries.insert(key, value);
+                }
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            }
+        }
+        for (key, value) in staged_entries {
+            match value {
+                Some(value) => entries.insert(key, value),
+                None => entries.remove(&key),
+            };
+        }
+
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    fn raw_apply_batch(&self, batch: DbBatch) -> Result<(), DatabaseError> {
+        let batch: Vec<_> = batch.into();
+        let mut staged = self.staged.lock().unwrap();
+
+        for change in batch.iter() {
+            match change {
+                BatchItem::InsertNewElement(element) => {
+                    let key = element.key.to_bytes();
+                    if self.get(&staged, &key)?.is_some() {
+                        error!("Database replaced element! This should not happen!");
+                        trace!("Problematic key: {:?}", element.key);
+                    }
+                    staged.insert(key, Some(element.value.to_bytes()));
+                }
+                BatchItem::InsertElement(element) => {
+                    staged.insert(element.key.to_bytes(), Some(element.value.to_bytes()));
+                }
+                BatchItem::DeleteElement(key) => {
+                    let key_bytes = key.to_bytes();
+                    if self.get(&staged, &key_bytes)?.is_none() {
+                        error!("Database deleted absent element! This should not happen!");
+                        trace!("Problematic key: {:?}", key);
+                    }
+                    staged.insert(key_bytes, None);
+                }
+                BatchItem::MaybeDeleteElement(key) => {
+                    staged.insert(key.to_bytes(), None);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StagedDatabase;
+    use crate::db::batch::DbBatch;
+    use crate::db::mem_impl::MemDatabase;
+    use crate::db::tests::{TestKey, TestKeyPrefix, TestVal};
+    use crate::db::{Database, RawDatabase};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_basic_rw() {
+        let inner = Arc::new(MemDatabase::new());
+        crate::db::tests::test_db_impl(Arc::new(StagedDatabase::new(inner)));
+    }
+
+    #[test]
+    fn stage_batches_till_commit() {
+        let inner: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let staged = Arc::new(StagedDatabase::new(inner.clone()));
+        let db: Arc<dyn RawDatabase> = staged.clone();
+        inner.insert_entry(&TestKey(1), &TestVal(1)).unwrap();
+        inner.insert_entry(&TestKey(2), &TestVal(2)).unwrap();
+
+        let mut batch = DbBatch::new();
+        batch.autocommit(|tx| {
+            tx.append_insert(TestKey(1), TestVal(10));
+            tx.append_delete(TestKey(2));
+            tx.append_insert_new(TestKey(3), TestVal(3));
+        });
+        db.apply_batch(batch).unwrap();
+
+        // Reads see the staged changes while the underlying database doesn't
+        let staged_view = vec![(TestKey(1), TestVal(10)), (TestKey(3), TestVal(3))];
+        let entries = |db: &dyn RawDatabase| {
+            db.find_by_prefix::<_, TestKey, TestVal>(&TestKeyPrefix)
+                .map(|res| res.unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(entries(db.as_ref()), staged_view);
+        assert_eq!(db.get_value::<_, TestVal>(&TestKey(2)).unwrap(), None);
+        assert_eq!(
+            entries(inner.as_ref()),
+            vec![(TestKey(1), TestVal(1)), (TestKey(2), TestVal(2))]
+        );
+
+        staged.commit().unwrap();
+        assert_eq!(entries(inner.as_ref()), staged_view);
+        assert_eq!(entries(db.as_ref()), staged_view);
+    }
+
+    #[test]
+    fn discard_staged_batches() {
+        let inner: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let staged = Arc::new(StagedDatabase::new(inner.clone()));
+        let db: Arc<dyn RawDatabase> = staged.clone();
+
+        let mut batch = DbBatch::new();
+        batch.autocommit(|tx| tx.append_insert(TestKey(1), TestVal(1)));
+        db.apply_batch(batch).unwrap();
+        staged.discard();
+        staged.commit().unwrap();
+
+        assert_eq!(db.get_value::<_, TestVal>(&TestKey(1)).unwrap(), None);
+        assert_eq!(inner.get_value::<_, TestVal>(&TestKey(1)).unwrap(), None);
+    }
+}
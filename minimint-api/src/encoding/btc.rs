@@ -60,3 +60,19 @@ impl Decodable for bitcoin::Address {
             .map_err(DecodeError::from_err)
     }
 }
+
+impl Encodable for bitcoin_hashes::sha256::Hash {
+    fn consensus_encode<W: std::io::Write>(&self, mut writer: W) -> Result<usize, Error> {
+        let bytes = &self[..];
+        writer.write_all(bytes)?;
+        Ok(bytes.len())
+    }
+}
+
+impl Decodable for bitcoin_hashes::sha256::Hash {
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, DecodeError> {
+        let mut bytes = [0u8; 32];
+        d.read_exact(&mut bytes).map_err(DecodeError::from_err)?;
+        Ok(bitcoin_hashes::Hash::from_inner(bytes))
+    }
+}
@@ -175,6 +175,18 @@ where
     }
 }
 
+impl Encodable for String {
+    fn consensus_encode<W: std::io::Write>(&self, writer: W) -> Result<usize, std::io::Error> {
+        self.as_bytes().consensus_encode(writer)
+    }
+}
+
+impl Decodable for String {
+    fn consensus_decode<D: std::io::Read>(d: D) -> Result<Self, DecodeError> {
+        String::from_utf8(Vec::<u8>::consensus_decode(d)?).map_err(DecodeError::from_err)
+    }
+}
+
 impl Encodable for () {
     fn consensus_encode<W: std::io::Write>(&self, _writer: W) -> Result<usize, std::io::Error> {
         Ok(0)
@@ -301,4 +313,15 @@ mod tests {
             test_roundtrip_expected(reference, &bytes);
         }
     }
+
+    #[test]
+    fn test_string() {
+        test_roundtrip_expected(
+            "abc".to_string(),
+            &[3, 0, 0, 0, 0, 0, 0, 0, b'a', b'b', b'c'],
+        );
+
+        let invalid_utf8 = vec![2u8, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xfe];
+        assert!(String::consensus_decode(std::io::Cursor::new(invalid_utf8)).is_err());
+    }
 }
@@ -16,8 +16,11 @@ pub mod config;
 pub mod db;
 pub mod encoding;
 mod keys;
-mod module;
+pub mod ln;
+pub mod misbehavior;
+pub mod module;
 pub mod outcome;
+pub mod reserves;
 pub mod transaction;
 mod tweakable;
 mod txoproof;
@@ -125,6 +128,9 @@ pub struct FeeConsensus {
     pub fee_peg_in_abs: Amount,
     pub fee_coin_issuance_abs: Amount,
     pub fee_peg_out_abs: Amount,
+    pub fee_contract_input_abs: Amount,
+    pub fee_contract_output_abs: Amount,
+    pub fee_offer_abs: Amount,
 }
 
 impl PeerId {
@@ -304,6 +310,12 @@ impl Amount {
             milli_sat: self.milli_sat.saturating_sub(other.milli_sat),
         }
     }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Self> {
+        Some(Amount {
+            milli_sat: self.milli_sat.checked_sub(other.milli_sat)?,
+        })
+    }
 }
 
 impl<C> FromIterator<(Amount, C)> for Coins<C> {
@@ -0,0 +1,259 @@
+//! Lightning contracts that can be funded and redeemed through federation transactions
+//!
+//! Coins are locked into a contract by a [`LightningOutput`] and released again by a
+//! [`ContractInput`]. Which key has to sign the input depends on the contract type and on whether
+//! the payment preimage is revealed:
+//!
+//! | Contract | Preimage revealed        | Timeout reached (no preimage) |
+//! |----------|--------------------------|-------------------------------|
+//! | Outgoing | gateway claims the funds | user is refunded              |
+//! | Incoming | user claims the funds    | gateway is refunded           |
+
+use crate::encoding::{Decodable, DecodeError, Encodable};
+use crate::Amount;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use bitcoin_hashes::Hash as BitcoinHash;
+use bitcoin_hashes::{borrow_slice_impl, hash_newtype, hex_fmt_impl, index_impl, serde_impl};
+use musig::PubKey;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+
+/// Maximum number of blocks an offer may stay valid, after that it is deleted
+pub const MAX_OFFER_LIFETIME: u32 = 1008;
+
+hash_newtype!(
+    ContractId,
+    Sha256,
+    32,
+    doc = "The hash of a lightning contract, used to identify its account"
+);
+
+/// Preimage of a lightning payment hash
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct Preimage(pub [u8; 32]);
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum Contract {
+    Outgoing(OutgoingContract),
+    Incoming(IncomingContract),
+}
+
+/// Funded by the user to pay a lightning invoice through a gateway. The gateway can claim the funds
+/// by revealing the preimage of `hash` which it learns by paying the invoice. If it doesn't do so
+/// before block height `timelock` the user can take back the funds.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct OutgoingContract {
+    pub hash: Sha256,
+    pub gateway_key: PubKey,
+    pub timelock: u32,
+    pub user_key: PubKey,
+}
+
+/// Funded by a gateway that received an incoming lightning payment for an
+/// [`IncomingContractOffer`]. The user claims the funds by revealing the preimage of `hash`,
+/// which in turn allows the gateway to settle the lightning payment. If the user doesn't claim
+/// the funds before block height `timelock` the gateway can take them back.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct IncomingContract {
+    pub hash: Sha256,
+    pub gateway_key: PubKey,
+    pub timelock: u32,
+    pub user_key: PubKey,
+}
+
+/// Announces that the user is willing to sell the preimage of `hash` for at least `amount`. Only
+/// incoming contracts matching an offer can be funded. Offers are stored by every guardian, so
+/// publishing one costs a fee and it is deleted at block height `expiry_height`, which may be at
+/// most [`MAX_OFFER_LIFETIME`] blocks in the future.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct IncomingContractOffer {
+    pub amount: Amount,
+    pub hash: Sha256,
+    pub user_key: PubKey,
+    pub expiry_height: u32,
+}
+
+/// Spends the funds locked in a contract account
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ContractInput {
+    pub contract: Contract,
+    /// Has to equal the funds locked in the contract, partial spends aren't supported
+    pub amount: Amount,
+    /// `None` to spend the contract after its timeout
+    pub witness: Option<Preimage>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum LightningOutput {
+    /// Locks `amount` in a new contract account
+    Contract(ContractOutput),
+    /// Registers an offer for incoming payments, it doesn't lock any funds
+    Offer(IncomingContractOffer),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ContractOutput {
+    pub amount: Amount,
+    pub contract: Contract,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum LightningOutputOutcome {
+    Contract {
+        id: ContractId,
+        outcome: ContractOutcome,
+    },
+    Offer {
+        hash: Sha256,
+    },
+}
+
+/// Funds locked in a contract and the contract's current state
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ContractAccount {
+    pub amount: Amount,
+    pub contract: Contract,
+    pub outcome: ContractOutcome,
+}
+
+/// Current state of a contract account
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum ContractOutcome {
+    /// The funds are locked in the contract
+    Funded,
+    /// The funds were claimed by revealing the preimage
+    Claimed(Preimage),
+    /// The funds were returned after the timeout
+    Refunded,
+}
+
+impl Preimage {
+    pub fn hash(&self) -> Sha256 {
+        Sha256::hash(&self.0)
+    }
+}
+
+impl Contract {
+    pub fn contract_id(&self) -> ContractId {
+        let mut engine = ContractId::engine();
+        self.consensus_encode(&mut engine)
+            .expect("write to hash engine can't fail");
+        ContractId::from_engine(engine)
+    }
+
+    /// Payment hash the contract is locked to
+    pub fn hash(&self) -> &Sha256 {
+        match self {
+            Contract::Outgoing(contract) => &contract.hash,
+            Contract::Incoming(contract) => &contract.hash,
+        }
+    }
+
+    /// Block height from which on the contract can be spent without revealing the preimage
+    pub fn timelock(&self) -> u32 {
+        match self {
+            Contract::Outgoing(contract) => contract.timelock,
+            Contract::Incoming(contract) => contract.timelock,
+        }
+    }
+}
+
+impl ContractInput {
+    /// Key that has to sign the transaction spending the contract, see the [module docs](self)
+    pub fn authorization_key(&self) -> &PubKey {
+        match (&self.contract, &self.witness) {
+            (Contract::Outgoing(contract), Some(_)) => &contract.gateway_key,
+            (Contract::Outgoing(contract), None) => &contract.user_key,
+            (Contract::Incoming(contract), Some(_)) => &contract.user_key,
+            (Contract::Incoming(contract), None) => &contract.gateway_key,
+        }
+    }
+}
+
+impl LightningOutput {
+    pub fn amount(&self) -> Amount {
+        match self {
+            LightningOutput::Contract(output) => output.amount,
+            LightningOutput::Offer(_) => Amount::ZERO,
+        }
+    }
+}
+
+impl Encodable for ContractId {
+    fn consensus_encode<W: std::io::Write>(&self, mut writer: W) -> Result<usize, Error> {
+        let bytes = &self[..];
+        writer.write_all(bytes)?;
+        Ok(bytes.len())
+    }
+}
+
+impl Decodable for ContractId {
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, DecodeError> {
+        let mut bytes = [0u8; 32];
+        d.read_exact(&mut bytes).map_err(DecodeError::from_err)?;
+        Ok(ContractId::from_inner(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{Decodable, Encodable};
+    use crate::ln::{Contract, ContractInput, IncomingContract, OutgoingContract, Preimage};
+    use crate::Amount;
+    use musig::SecKey;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_authorization_key() {
+        let mut rng = musig::rng_adapt::RngAdaptor(rand::rngs::OsRng::new().unwrap());
+        let gateway_key = SecKey::random(&mut rng).to_public();
+        let user_key = SecKey::random(&mut rng).to_public();
+        let preimage = Preimage([42; 32]);
+
+        let outgoing = Contract::Outgoing(OutgoingContract {
+            hash: preimage.hash(),
+            gateway_key: gateway_key.clone(),
+            timelock: 100,
+            user_key: user_key.clone(),
+        });
+        let incoming = Contract::Incoming(IncomingContract {
+            hash: preimage.hash(),
+            gateway_key: gateway_key.clone(),
+            timelock: 100,
+            user_key: user_key.clone(),
+        });
+        assert_ne!(outgoing.contract_id(), incoming.contract_id());
+
+        let key = |contract: &Contract, witness: Option<Preimage>| {
+            ContractInput {
+                contract: contract.clone(),
+                amount: Amount::from_sat(1),
+                witness,
+            }
+            .authorization_key()
+            .clone()
+        };
+        assert_eq!(key(&outgoing, Some(preimage)), gateway_key);
+        assert_eq!(key(&outgoing, None), user_key);
+        assert_eq!(key(&incoming, Some(preimage)), user_key);
+        assert_eq!(key(&incoming, None), gateway_key);
+    }
+
+    #[test]
+    fn test_contract_encoding() {
+        let mut rng = musig::rng_adapt::RngAdaptor(rand::rngs::OsRng::new().unwrap());
+        let contract = Contract::Outgoing(OutgoingContract {
+            hash: Preimage([1; 32]).hash(),
+            gateway_key: SecKey::random(&mut rng).to_public(),
+            timelock: 42,
+            user_key: SecKey::random(&mut rng).to_public(),
+        });
+
+        let mut bytes = Vec::new();
+        contract.consensus_encode(&mut bytes).unwrap();
+        let decoded = Contract::consensus_decode(&mut Cursor::new(bytes)).unwrap();
+
+        assert_eq!(contract, decoded);
+        assert_eq!(contract.contract_id(), decoded.contract_id());
+    }
+}
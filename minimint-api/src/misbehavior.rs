@@ -0,0 +1,207 @@
+//! Bookkeeping of faults guardians catch their peers committing, e.g. contributing invalid
+//! signature shares. Faults don't halt consensus as long as few enough peers commit them, the log
+//! lets operators find out which peers are misbehaving anyway.
+
+use crate::db::batch::{BatchTx, DbBatch};
+use crate::db::{Database, DatabaseKeyPrefixConst, RawDatabase};
+use crate::encoding::{Decodable, Encodable};
+use crate::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::warn;
+
+pub const DB_PREFIX_PEER_FAULT: u8 = 0x03;
+
+/// Kinds of misbehavior we can attribute to a single peer
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Encodable,
+    Decodable,
+)]
+pub enum PeerFault {
+    /// Contributed a blind signature share that doesn't verify against its public key share
+    InvalidSignatureShare,
+    /// Contributed signature shares for other amount tiers or another number of coins than
+    /// requested
+    WrongStructureShare,
+    /// Contributed a signature share for another blinded message than requested
+    WrongNonceShare,
+    /// Contributed a malformed or invalid signature for a peg-out transaction
+    InvalidPegOutSignature,
+    /// Violated the Honey Badger protocol, the details are only logged
+    ConsensusFault,
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct PeerFaultKey {
+    pub peer: PeerId,
+    pub fault: PeerFault,
+}
+
+impl DatabaseKeyPrefixConst for PeerFaultKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PEER_FAULT;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct PeerFaultKeyPrefix;
+
+impl DatabaseKeyPrefixConst for PeerFaultKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_PEER_FAULT;
+}
+
+/// Number of times `peer` was caught committing `fault`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerFaultCount {
+    pub peer: PeerId,
+    pub fault: PeerFault,
+    pub count: u64,
+}
+
+/// Counts the faults of peers in the database. Faults caught while processing an epoch are
+/// recorded in the epoch's batch, so they are only counted once even if the epoch is processed
+/// again after a crash. Counters are read from the database when recording, so a batch has to
+/// record all faults of a kind at once and logs sharing a database should record different kinds.
+pub struct MisbehaviorLog {
+    db: Arc<dyn RawDatabase>,
+}
+
+impl MisbehaviorLog {
+    pub fn new(db: Arc<dyn RawDatabase>) -> MisbehaviorLog {
+        MisbehaviorLog { db }
+    }
+
+    /// Increments the counters of all `faults` committed by the respective peers in `batch`
+    pub fn record(
+        &self,
+        mut batch: BatchTx,
+        faults: impl IntoIterator<Item = (PeerId, PeerFault)>,
+    ) {
+        let mut new_faults = BTreeMap::<(PeerId, PeerFault), u64>::new();
+        for (peer, fault) in faults {
+            warn!("Peer {} misbehaved: {:?}", peer, fault);
+            *new_faults.entry((peer, fault)).or_default() += 1;
+        }
+
+        for ((peer, fault), new_count) in new_faults {
+            let key = PeerFaultKey { peer, fault };
+            let count = self
+                .db
+                .get_value::<_, u64>(&key)
+                .expect("DB error")
+                .unwrap_or(0);
+            batch.append_insert(key, count + new_count);
+        }
+        batch.commit();
+    }
+
+    /// Records `faults` caught outside of processing an epoch, e.g. by Honey Badger, right away
+    pub fn record_now(&self, faults: impl IntoIterator<Item = (PeerId, PeerFault)>) {
+        let faults = faults.into_iter().collect::<Vec<_>>();
+        if faults.is_empty() {
+            return;
+        }
+
+        let mut batch = DbBatch::new();
+        self.record(batch.transaction(), faults);
+        self.db.apply_batch(batch).expect("DB error");
+    }
+}
+
+/// Returns the counters of all faults recorded in `db`
+pub fn peer_faults(db: &dyn RawDatabase) -> Vec<PeerFaultCount> {
+    db.find_by_prefix::<_, PeerFaultKey, u64>(&PeerFaultKeyPrefix)
+        .map(|res| {
+            let (key, count) = res.expect("DB error");
+            PeerFaultCount {
+                peer: key.peer,
+                fault: key.fault,
+                count,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::batch::DbBatch;
+    use crate::db::mem_impl::MemDatabase;
+    use crate::db::{Database, RawDatabase};
+    use crate::misbehavior::{peer_faults, MisbehaviorLog, PeerFault, PeerFaultCount};
+    use crate::PeerId;
+    use std::sync::Arc;
+
+    #[test]
+    fn counts_faults_per_peer_and_kind() {
+        let db: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        let log = MisbehaviorLog::new(db.clone());
+        assert!(peer_faults(db.as_ref()).is_empty());
+
+        let mut batch = DbBatch::new();
+        log.record(
+            batch.transaction(),
+            vec![
+                (PeerId::from(2), PeerFault::InvalidSignatureShare),
+                (PeerId::from(2), PeerFault::WrongNonceShare),
+            ],
+        );
+        // Nothing is counted till the batch is applied
+        assert!(peer_faults(db.as_ref()).is_empty());
+        db.apply_batch(batch).unwrap();
+
+        log.record_now(vec![(PeerId::from(1), PeerFault::ConsensusFault)]);
+        let mut batch = DbBatch::new();
+        log.record(
+            batch.transaction(),
+            vec![(PeerId::from(2), PeerFault::InvalidSignatureShare)],
+        );
+        db.apply_batch(batch).unwrap();
+
+        let count = |peer: u16, fault, count| PeerFaultCount {
+            peer: PeerId::from(peer),
+            fault,
+            count,
+        };
+        assert_eq!(
+            peer_faults(db.as_ref()),
+            vec![
+                count(1, PeerFault::ConsensusFault, 1),
+                count(2, PeerFault::InvalidSignatureShare, 2),
+                count(2, PeerFault::WrongNonceShare, 1),
+            ]
+        );
+
+        // A batch that is never applied, e.g. since we crashed during the epoch, counts nothing
+        let mut batch = DbBatch::new();
+        log.record(
+            batch.transaction(),
+            vec![
+                (PeerId::from(1), PeerFault::ConsensusFault),
+                (PeerId::from(1), PeerFault::ConsensusFault),
+            ],
+        );
+        drop(batch);
+        let mut batch = DbBatch::new();
+        log.record(
+            batch.transaction(),
+            vec![
+                (PeerId::from(1), PeerFault::ConsensusFault),
+                (PeerId::from(1), PeerFault::ConsensusFault),
+            ],
+        );
+        db.apply_batch(batch).unwrap();
+        assert_eq!(
+            peer_faults(db.as_ref())[0],
+            count(1, PeerFault::ConsensusFault, 3)
+        );
+    }
+}
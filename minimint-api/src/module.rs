@@ -4,6 +4,41 @@ use async_trait::async_trait;
 use rand::CryptoRng;
 use secp256k1::rand::RngCore;
 
+pub mod registry;
+
+/// Funds a module holds and funds it owes to users according to its database, e.g. the wallet's
+/// UTXOs and pending peg-outs. Summed up over all modules the liabilities must never exceed the
+/// assets, otherwise some module created money out of thin air.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Audit {
+    pub assets: Amount,
+    pub liabilities: Amount,
+}
+
+impl Audit {
+    /// Whether the assets cover all liabilities
+    pub fn is_solvent(&self) -> bool {
+        self.liabilities <= self.assets
+    }
+}
+
+impl std::ops::Add for Audit {
+    type Output = Audit;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Audit {
+            assets: self.assets + rhs.assets,
+            liabilities: self.liabilities + rhs.liabilities,
+        }
+    }
+}
+
+impl std::iter::Sum for Audit {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Audit::default(), |a, b| a + b)
+    }
+}
+
 #[async_trait(?Send)]
 pub trait FederationModule {
     type Error;
@@ -54,6 +89,20 @@ pub trait FederationModule {
     /// and merely generate a warning.
     fn validate_output(&self, output: &Self::TxOutput) -> Result<Amount, Self::Error>;
 
+    /// Transactions of one epoch are applied in parallel against the database state of the
+    /// previous epoch, so two of them may not use the same state. Identical inputs are filtered
+    /// out by the consensus, every other piece of state that only one transaction per epoch may
+    /// use has to be returned as conflict key of the input. Of two transactions sharing a key only
+    /// the first one is processed.
+    fn input_conflicts(&self, _input: &Self::TxInput) -> Vec<Vec<u8>> {
+        vec![]
+    }
+
+    /// See [`FederationModule::input_conflicts`]
+    fn output_conflicts(&self, _output: &Self::TxOutput) -> Vec<Vec<u8>> {
+        vec![]
+    }
+
     /// Try to create an output (e.g. issue coins, peg-out BTC, …). On success all necessary updates
     /// to the database will be part of the `batch`. On failure (e.g. double spend) the batch is
     /// reset and the operation will take no effect.
@@ -86,4 +135,9 @@ pub trait FederationModule {
         &self,
         out_point: crate::transaction::OutPoint,
     ) -> Option<Self::TxOutputOutcome>;
+
+    /// Sums up the assets and liabilities of the module from the database. This is called after
+    /// `end_consensus_epoch` and the results of all modules are checked to balance before the
+    /// epoch is persisted, so it has to reflect the state after all of the epoch's batches.
+    fn audit(&self) -> Audit;
 }
@@ -0,0 +1,545 @@
+//! Type-erased access to [`FederationModule`]s
+//!
+//! The consensus code does not know about the concrete modules it runs. Instead every module is
+//! registered with a [`ModuleKey`] in a [`ModuleRegistry`] and all transaction in- and outputs as
+//! well as consensus items are dispatched to it by that key. Transaction items and consensus items
+//! are exchanged in their serialized form and decoded by the module they belong to. Decoded
+//! transaction items are handed back to the module as [`Any`] and downcast to its concrete types.
+
+use crate::db::batch::BatchTx;
+use crate::encoding::{Decodable, DecodeError};
+use crate::module::Audit;
+use crate::outcome::OutputOutcome;
+use crate::transaction::{
+    decode_item, DecodedInput, DecodedOutput, OutPoint, TransactionInput, TransactionItem,
+};
+use crate::{Amount, FederationModule, FeeConsensus, PeerId};
+use async_trait::async_trait;
+use rand::{CryptoRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::BTreeMap;
+use thiserror::Error;
+use tracing::warn;
+
+/// Identifies a module inside the federation, it has to be the same for all peers
+pub type ModuleKey = u16;
+
+pub const MODULE_KEY_WALLET: ModuleKey = 0;
+pub const MODULE_KEY_MINT: ModuleKey = 1;
+pub const MODULE_KEY_LN: ModuleKey = 2;
+
+/// Consensus item of a module in its serialized form, tagged with the module it belongs to
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ModuleConsensusItem {
+    pub module: ModuleKey,
+    pub item: Vec<u8>,
+}
+
+/// Random number generator that can be used as a trait object
+pub trait CryptoRngCore: RngCore + CryptoRng {}
+
+impl<T: RngCore + CryptoRng + ?Sized> CryptoRngCore for T {}
+
+/// Error returned by a type-erased module, wrapping the module's own error type
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct ModuleError(Box<dyn std::error::Error + Send + Sync>);
+
+/// Object-safe version of [`FederationModule`] that is implemented for every module fulfilling
+/// some additional requirements on its associated types. See [`FederationModule`] for the
+/// semantics of the individual functions.
+///
+/// # Panics
+/// All functions taking transaction items as [`Any`] panic if it isn't of the module's item type.
+/// The caller is responsible for only passing items that belong to the module.
+#[async_trait(?Send)]
+pub trait ServerModule: Send + Sync {
+    /// Allows downcasting to the concrete module type, e.g. to access module-specific APIs
+    fn as_any(&self) -> &dyn Any;
+
+    async fn consensus_proposal<'a>(&'a self, rng: &'a mut dyn CryptoRngCore) -> Vec<Vec<u8>>;
+
+    /// Consensus items that can't be deserialized are dropped
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        consensus_items: Vec<(PeerId, Vec<u8>)>,
+        rng: &'a mut dyn CryptoRngCore,
+    );
+
+    /// Decodes a serialized transaction input of this module
+    fn decode_input(
+        &self,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<DecodedInput, DecodeError>;
+
+    /// Decodes a serialized transaction output of this module
+    fn decode_output(
+        &self,
+        output: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<DecodedOutput, DecodeError>;
+
+    /// Conflict keys of a serialized input, see [`FederationModule::input_conflicts`]. Malformed
+    /// inputs have none since the transaction containing them is rejected anyway.
+    fn input_conflicts(&self, input: &[u8]) -> Vec<Vec<u8>>;
+
+    /// Conflict keys of a serialized output, see [`FederationModule::output_conflicts`]
+    fn output_conflicts(&self, output: &[u8]) -> Vec<Vec<u8>>;
+
+    fn validate_input(&self, input: &dyn Any) -> Result<Amount, ModuleError>;
+
+    fn apply_input<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        input: &'a dyn Any,
+    ) -> Result<Amount, ModuleError>;
+
+    fn validate_output(&self, output: &dyn Any) -> Result<Amount, ModuleError>;
+
+    fn apply_output<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        output: &'a dyn Any,
+        out_point: OutPoint,
+    ) -> Result<Amount, ModuleError>;
+
+    async fn end_consensus_epoch<'a>(&'a self, batch: BatchTx<'a>, rng: &'a mut dyn CryptoRngCore);
+
+    fn output_status(&self, out_point: OutPoint) -> Option<OutputOutcome>;
+
+    fn audit(&self) -> Audit;
+}
+
+#[async_trait(?Send)]
+impl<M> ServerModule for M
+where
+    M: FederationModule + Send + Sync + 'static,
+    M::Error: std::error::Error + Send + Sync + 'static,
+    M::TxInput: TransactionInput + Decodable + 'static,
+    M::TxOutput: TransactionItem + Decodable + 'static,
+    M::TxOutputOutcome: Into<OutputOutcome>,
+    M::ConsensusItem: Serialize + DeserializeOwned,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn consensus_proposal<'a>(&'a self, rng: &'a mut dyn CryptoRngCore) -> Vec<Vec<u8>> {
+        FederationModule::consensus_proposal(self, rng)
+            .await
+            .iter()
+            .map(|item| bincode::serialize(item).expect("Serialization can't fail"))
+            .collect()
+    }
+
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        consensus_items: Vec<(PeerId, Vec<u8>)>,
+        rng: &'a mut dyn CryptoRngCore,
+    ) {
+        let consensus_items = consensus_items
+            .into_iter()
+            .filter_map(|(peer, item)| match bincode::deserialize(&item) {
+                Ok(item) => Some((peer, item)),
+                Err(e) => {
+                    warn!("Peer {} proposed malformed consensus item: {}", peer, e);
+                    None
+                }
+            })
+            .collect();
+        FederationModule::begin_consensus_epoch(self, batch, consensus_items, rng).await
+    }
+
+    fn decode_input(
+        &self,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<DecodedInput, DecodeError> {
+        let input = decode_item::<M::TxInput>(input)?;
+        Ok(DecodedInput {
+            amount: input.amount(),
+            fee: input.fee(fee_consensus),
+            authorization_keys: input.authorization_keys(),
+            item: Box::new(input),
+        })
+    }
+
+    fn decode_output(
+        &self,
+        output: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<DecodedOutput, DecodeError> {
+        let output = decode_item::<M::TxOutput>(output)?;
+        Ok(DecodedOutput {
+            amount: output.amount(),
+            fee: output.fee(fee_consensus),
+            item: Box::new(output),
+        })
+    }
+
+    fn input_conflicts(&self, input: &[u8]) -> Vec<Vec<u8>> {
+        decode_item(input)
+            .map(|input| FederationModule::input_conflicts(self, &input))
+            .unwrap_or_default()
+    }
+
+    fn output_conflicts(&self, output: &[u8]) -> Vec<Vec<u8>> {
+        decode_item(output)
+            .map(|output| FederationModule::output_conflicts(self, &output))
+            .unwrap_or_default()
+    }
+
+    fn validate_input(&self, input: &dyn Any) -> Result<Amount, ModuleError> {
+        FederationModule::validate_input(self, downcast(input)).map_err(ModuleError::from_err)
+    }
+
+    fn apply_input<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        input: &'a dyn Any,
+    ) -> Result<Amount, ModuleError> {
+        FederationModule::apply_input(self, batch, downcast(input)).map_err(ModuleError::from_err)
+    }
+
+    fn validate_output(&self, output: &dyn Any) -> Result<Amount, ModuleError> {
+        FederationModule::validate_output(self, downcast(output)).map_err(ModuleError::from_err)
+    }
+
+    fn apply_output<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        output: &'a dyn Any,
+        out_point: OutPoint,
+    ) -> Result<Amount, ModuleError> {
+        FederationModule::apply_output(self, batch, downcast(output), out_point)
+            .map_err(ModuleError::from_err)
+    }
+
+    async fn end_consensus_epoch<'a>(&'a self, batch: BatchTx<'a>, rng: &'a mut dyn CryptoRngCore) {
+        FederationModule::end_consensus_epoch(self, batch, rng).await
+    }
+
+    fn output_status(&self, out_point: OutPoint) -> Option<OutputOutcome> {
+        FederationModule::output_status(self, out_point).map(Into::into)
+    }
+
+    fn audit(&self) -> Audit {
+        FederationModule::audit(self)
+    }
+}
+
+fn downcast<T: 'static>(item: &dyn Any) -> &T {
+    item.downcast_ref()
+        .expect("Transaction item was dispatched to the wrong module")
+}
+
+impl ModuleError {
+    pub fn from_err<E: std::error::Error + Send + Sync + 'static>(e: E) -> Self {
+        ModuleError(Box::new(e))
+    }
+}
+
+/// Collection of all modules run by the federation, indexed by their [`ModuleKey`]
+#[derive(Default)]
+pub struct ModuleRegistry {
+    modules: BTreeMap<ModuleKey, Box<dyn ServerModule>>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> ModuleRegistry {
+        Default::default()
+    }
+
+    /// Adds `module` to the registry under the key `key`
+    ///
+    /// # Panics
+    /// If another module was already registered with the same key.
+    pub fn register<M: ServerModule + 'static>(&mut self, key: ModuleKey, module: M) {
+        let existing = self.modules.insert(key, Box::new(module));
+        assert!(
+            existing.is_none(),
+            "Module key {} was registered twice",
+            key
+        );
+    }
+
+    /// Returns the module registered under `key` if any
+    pub fn get(&self, key: ModuleKey) -> Option<&dyn ServerModule> {
+        self.modules.get(&key).map(|module| module.as_ref())
+    }
+
+    /// Returns the module registered under `key` if it is of type `M`
+    pub fn get_typed<M: 'static>(&self, key: ModuleKey) -> Option<&M> {
+        self.get(key)?.as_any().downcast_ref()
+    }
+
+    /// Iterates over all registered modules ordered by their key
+    pub fn iter(&self) -> impl Iterator<Item = (ModuleKey, &dyn ServerModule)> {
+        self.modules
+            .iter()
+            .map(|(key, module)| (*key, module.as_ref()))
+    }
+
+    /// Audits of all registered modules ordered by their key
+    pub fn audit(&self) -> BTreeMap<ModuleKey, Audit> {
+        self.iter()
+            .map(|(key, module)| (key, module.audit()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::batch::{BatchTx, DbBatch};
+    use crate::module::registry::{
+        ModuleKey, ModuleRegistry, MODULE_KEY_LN, MODULE_KEY_MINT, MODULE_KEY_WALLET,
+    };
+    use crate::module::Audit;
+    use crate::transaction::{Input, OutPoint, TransactionInput, TransactionItem};
+    use crate::{Amount, BitcoinHash, FederationModule, FeeConsensus, PeerId, TransactionId};
+    use async_trait::async_trait;
+    use rand::{CryptoRng, RngCore};
+    use std::sync::Mutex;
+    use thiserror::Error;
+
+    /// Module whose in- and outputs are plain amounts, only amounts up to `max` are valid. Inputs
+    /// are booked as assets and outputs as liabilities, unless `books_inputs` is unset to simulate
+    /// a module that accepts inputs without actually receiving the funds.
+    struct DummyModule {
+        max: u64,
+        books_inputs: bool,
+        ledger: Mutex<Audit>,
+    }
+
+    impl DummyModule {
+        fn new(max: u64) -> DummyModule {
+            DummyModule {
+                max,
+                books_inputs: true,
+                ledger: Default::default(),
+            }
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error("Amount too big")]
+    struct DummyError;
+
+    impl TransactionItem for u64 {
+        fn amount(&self) -> Amount {
+            Amount::from_msat(*self)
+        }
+
+        fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+            fee_consensus.fee_coin_spend_abs
+        }
+    }
+
+    impl TransactionInput for u64 {
+        fn authorization_keys(&self) -> Vec<musig::PubKey> {
+            vec![]
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl FederationModule for DummyModule {
+        type Error = DummyError;
+        type TxInput = u64;
+        type TxOutput = u64;
+        type TxOutputOutcome = ();
+        type ConsensusItem = u64;
+
+        async fn consensus_proposal<'a>(
+            &'a self,
+            _rng: impl RngCore + CryptoRng + 'a,
+        ) -> Vec<Self::ConsensusItem> {
+            vec![self.max]
+        }
+
+        async fn begin_consensus_epoch<'a>(
+            &'a self,
+            _batch: BatchTx<'a>,
+            _consensus_items: Vec<(PeerId, Self::ConsensusItem)>,
+            _rng: impl RngCore + CryptoRng + 'a,
+        ) {
+        }
+
+        fn validate_input(&self, input: &Self::TxInput) -> Result<Amount, Self::Error> {
+            if *input <= self.max {
+                Ok(Amount::from_msat(*input))
+            } else {
+                Err(DummyError)
+            }
+        }
+
+        fn apply_input<'a>(
+            &'a self,
+            _batch: BatchTx<'a>,
+            input: &'a Self::TxInput,
+        ) -> Result<Amount, Self::Error> {
+            let amount = self.validate_input(input)?;
+            if self.books_inputs {
+                let mut ledger = self.ledger.lock().unwrap();
+                ledger.assets = ledger.assets + amount;
+            }
+            Ok(amount)
+        }
+
+        fn validate_output(&self, output: &Self::TxOutput) -> Result<Amount, Self::Error> {
+            self.validate_input(output)
+        }
+
+        fn apply_output<'a>(
+            &'a self,
+            _batch: BatchTx<'a>,
+            output: &'a Self::TxOutput,
+            _out_point: OutPoint,
+        ) -> Result<Amount, Self::Error> {
+            let amount = self.validate_output(output)?;
+            let mut ledger = self.ledger.lock().unwrap();
+            ledger.liabilities = ledger.liabilities + amount;
+            Ok(amount)
+        }
+
+        async fn end_consensus_epoch<'a>(
+            &'a self,
+            _batch: BatchTx<'a>,
+            _rng: impl RngCore + CryptoRng + 'a,
+        ) {
+        }
+
+        fn output_status(&self, _out_point: OutPoint) -> Option<Self::TxOutputOutcome> {
+            Some(())
+        }
+
+        fn audit(&self) -> Audit {
+            *self.ledger.lock().unwrap()
+        }
+    }
+
+    /// Applies a transaction moving `amount` from an input of module `from` to an output of module
+    /// `to`, which is balanced from the point of view of the transaction
+    fn apply_transaction(registry: &ModuleRegistry, from: ModuleKey, to: ModuleKey, amount: u64) {
+        let mut batch = DbBatch::new();
+        let out_point = OutPoint {
+            txid: TransactionId::from_inner([0; 32]),
+            out_idx: 0,
+        };
+        let input = registry
+            .get(from)
+            .unwrap()
+            .apply_input(batch.transaction(), &amount)
+            .unwrap();
+        let output = registry
+            .get(to)
+            .unwrap()
+            .apply_output(batch.transaction(), &amount, out_point)
+            .unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut registry = ModuleRegistry::new();
+        registry.register(MODULE_KEY_WALLET, DummyModule::new(10));
+        registry.register(MODULE_KEY_MINT, DummyModule::new(20));
+
+        let wallet = registry.get(MODULE_KEY_WALLET).unwrap();
+        assert_eq!(wallet.validate_input(&5u64).unwrap(), Amount::from_msat(5));
+        assert!(wallet.validate_input(&15u64).is_err());
+
+        let mint = registry.get(MODULE_KEY_MINT).unwrap();
+        assert_eq!(mint.validate_output(&15u64).unwrap(), Amount::from_msat(15));
+
+        assert_eq!(
+            registry
+                .get_typed::<DummyModule>(MODULE_KEY_MINT)
+                .unwrap()
+                .max,
+            20
+        );
+        assert!(registry.get_typed::<u64>(MODULE_KEY_MINT).is_none());
+        assert!(registry.get(42).is_none());
+
+        let keys = registry.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys, vec![MODULE_KEY_WALLET, MODULE_KEY_MINT]);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut registry = ModuleRegistry::new();
+        registry.register(MODULE_KEY_MINT, DummyModule::new(20));
+        let mint = registry.get(MODULE_KEY_MINT).unwrap();
+        let fee_consensus = FeeConsensus {
+            fee_coin_spend_abs: Amount::from_msat(1),
+            fee_peg_in_abs: Amount::from_msat(0),
+            fee_coin_issuance_abs: Amount::from_msat(0),
+            fee_peg_out_abs: Amount::from_msat(0),
+            fee_contract_input_abs: Amount::from_msat(0),
+            fee_contract_output_abs: Amount::from_msat(0),
+            fee_offer_abs: Amount::from_msat(0),
+        };
+
+        let input = Input::new(MODULE_KEY_MINT, &15u64);
+        let decoded = mint.decode_input(&input.item, &fee_consensus).unwrap();
+        assert_eq!(decoded.amount, Amount::from_msat(15));
+        assert_eq!(decoded.fee, Amount::from_msat(1));
+        assert_eq!(
+            mint.validate_input(decoded.item.as_ref()).unwrap(),
+            Amount::from_msat(15)
+        );
+
+        // Truncated items and items with trailing bytes are rejected
+        assert!(mint
+            .decode_output(&input.item[..4], &fee_consensus)
+            .is_err());
+        let mut padded = input.item.clone();
+        padded.push(0);
+        assert!(mint.decode_output(&padded, &fee_consensus).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn test_duplicate_key() {
+        let mut registry = ModuleRegistry::new();
+        registry.register(MODULE_KEY_MINT, DummyModule::new(10));
+        registry.register(MODULE_KEY_MINT, DummyModule::new(20));
+    }
+
+    #[test]
+    fn test_audit_unbalanced_transaction() {
+        let mut registry = ModuleRegistry::new();
+        registry.register(MODULE_KEY_WALLET, DummyModule::new(100));
+        registry.register(MODULE_KEY_MINT, DummyModule::new(100));
+        registry.register(
+            MODULE_KEY_LN,
+            DummyModule {
+                books_inputs: false,
+                ..DummyModule::new(100)
+            },
+        );
+
+        apply_transaction(&registry, MODULE_KEY_WALLET, MODULE_KEY_MINT, 50);
+        let audit = registry.audit().values().copied().sum::<Audit>();
+        assert_eq!(
+            audit,
+            Audit {
+                assets: Amount::from_msat(50),
+                liabilities: Amount::from_msat(50),
+            }
+        );
+        assert!(audit.is_solvent());
+
+        // The faulty module accepts the input without booking the funds, so the output isn't backed
+        apply_transaction(&registry, MODULE_KEY_LN, MODULE_KEY_MINT, 30);
+        let audits = registry.audit();
+        assert_eq!(audits[&MODULE_KEY_LN], Audit::default());
+        assert_eq!(audits[&MODULE_KEY_MINT].liabilities, Amount::from_msat(80));
+        assert!(!audits.values().copied().sum::<Audit>().is_solvent());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::ln::LightningOutputOutcome;
 use crate::SigResponse;
 use serde::{Deserialize, Serialize};
 
@@ -19,8 +20,56 @@ pub enum TransactionStatus {
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum OutputOutcome {
     Mint(Option<SigResponse>),
-    // TODO: maybe include the transaction id eventually. But unclear how to propagate it cleanly right now.
-    Wallet(()),
+    Wallet(PegOutOutcome),
+    LN(LightningOutputOutcome),
+}
+
+impl From<Option<SigResponse>> for OutputOutcome {
+    fn from(outcome: Option<SigResponse>) -> Self {
+        OutputOutcome::Mint(outcome)
+    }
+}
+
+/// Progress of a peg-out from being accepted by the federation till the bitcoin transaction paying
+/// it is confirmed
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub enum PegOutOutcome {
+    /// Waiting for enough peg-outs to accumulate to be included in a peg-out transaction
+    Queued,
+    /// Included in the peg-out transaction `txid` which the federation is still signing
+    Signing(bitcoin::Txid),
+    /// The fully signed transaction `txid` is being broadcast and awaits confirmation
+    Broadcast(bitcoin::Txid),
+    /// The transaction `txid` was included in the block at `block_height` and is buried deeply
+    /// enough to be considered final by the federation
+    Confirmed {
+        txid: bitcoin::Txid,
+        block_height: u32,
+    },
+}
+
+impl PegOutOutcome {
+    /// Id of the bitcoin transaction paying the peg-out if it was already created
+    pub fn txid(&self) -> Option<bitcoin::Txid> {
+        match self {
+            PegOutOutcome::Queued => None,
+            PegOutOutcome::Signing(txid) => Some(*txid),
+            PegOutOutcome::Broadcast(txid) => Some(*txid),
+            PegOutOutcome::Confirmed { txid, .. } => Some(*txid),
+        }
+    }
+}
+
+impl From<PegOutOutcome> for OutputOutcome {
+    fn from(outcome: PegOutOutcome) -> Self {
+        OutputOutcome::Wallet(outcome)
+    }
+}
+
+impl From<LightningOutputOutcome> for OutputOutcome {
+    fn from(outcome: LightningOutputOutcome) -> Self {
+        OutputOutcome::LN(outcome)
+    }
 }
 
 pub trait Final {
@@ -32,7 +81,11 @@ impl Final for OutputOutcome {
         match self {
             OutputOutcome::Mint(Some(_)) => true,
             OutputOutcome::Mint(None) => false,
-            OutputOutcome::Wallet(()) => true,
+            OutputOutcome::Wallet(PegOutOutcome::Confirmed { .. }) => true,
+            OutputOutcome::Wallet(_) => false,
+            // A funded contract is final from the perspective of the funding transaction, its
+            // later state changes are caused by other transactions
+            OutputOutcome::LN(_) => true,
         }
     }
 }
@@ -0,0 +1,57 @@
+use crate::encoding::{Decodable, Encodable};
+use crate::{Amount, PeerId};
+use bitcoin_hashes::sha256;
+use bitcoin_hashes::{Hash as BitcoinHash, HashEngine};
+use serde::{Deserialize, Serialize};
+
+/// Tag prepended to reserve reports before signing them, so the signatures can't be mistaken for
+/// signatures of bitcoin transactions made with the same keys
+const RESERVES_REPORT_TAG: &[u8] = b"minimint-reserves-report";
+
+/// Statement of the bitcoin the federation holds and the e-cash it owes to its users at a certain
+/// consensus block height
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ReservesReport {
+    pub block_height: u32,
+    pub utxos: Vec<ReserveUtxo>,
+    /// E-cash issued by the federation that wasn't redeemed yet
+    pub liabilities: Amount,
+}
+
+/// UTXO controlled by the federation. Its script is derived by tweaking the peg-in descriptor with
+/// `tweak`, so anyone can check that it's actually locked to the federation's keys.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ReserveUtxo {
+    pub out_point: bitcoin::OutPoint,
+    pub tweak: Vec<u8>,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+}
+
+/// A [`ReservesReport`] a guardian vouched for by signing it with its peg-in key
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct SignedReservesReport {
+    pub report: ReservesReport,
+    pub peer: PeerId,
+    pub signature: secp256k1::Signature,
+}
+
+impl ReservesReport {
+    /// Total value of the federation's UTXOs
+    pub fn assets(&self) -> bitcoin::Amount {
+        self.utxos
+            .iter()
+            .map(|utxo| utxo.amount)
+            .fold(bitcoin::Amount::from_sat(0), |a, b| a + b)
+    }
+
+    /// Message guardians sign to vouch for the report
+    pub fn message(&self) -> secp256k1::Message {
+        let mut engine = sha256::Hash::engine();
+        engine.input(RESERVES_REPORT_TAG);
+        self.consensus_encode(&mut engine)
+            .expect("write to hash engine can't fail");
+        secp256k1::Message::from_slice(&sha256::Hash::from_engine(engine)[..])
+            .expect("hash has the right length")
+    }
+}
@@ -1,8 +1,11 @@
-use crate::encoding::{Decodable, Encodable};
+use crate::encoding::{Decodable, DecodeError, Encodable};
+use crate::ln::{ContractInput, LightningOutput};
+use crate::module::registry::ModuleKey;
 use crate::{Amount, Coin, Coins, FeeConsensus, PegInProof, TransactionId};
 use bitcoin_hashes::Hash as BitcoinHash;
 use musig::{PubKey, Sig};
 use serde::{Deserialize, Serialize};
+use std::any::Any;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -12,18 +15,44 @@ pub struct Transaction {
     pub signature: Sig,
 }
 
+/// Transaction input in its serialized form, tagged with the module responsible for processing
+/// it. Only that module knows how to decode the item, see [`ServerModule::decode_input`].
+///
+/// [`ServerModule::decode_input`]: crate::module::registry::ServerModule::decode_input
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
-pub enum Input {
-    // TODO: maybe treat every coin as a seperate input?
-    Coins(Coins<Coin>),
-    PegIn(PegInProof),
+pub struct Input {
+    pub module: ModuleKey,
+    pub item: Vec<u8>,
 }
 
+/// Transaction output in its serialized form, tagged with the module responsible for processing
+/// it. Only that module knows how to decode the item, see [`ServerModule::decode_output`].
+///
+/// [`ServerModule::decode_output`]: crate::module::registry::ServerModule::decode_output
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
-pub enum Output {
-    Coins(Coins<BlindToken>),
-    PegOut(PegOut),
-    // TODO: lightning integration goes here
+pub struct Output {
+    pub module: ModuleKey,
+    pub item: Vec<u8>,
+}
+
+/// Transaction input decoded by its module, carrying everything needed to validate the
+/// transaction as a whole
+pub struct DecodedInput {
+    /// The module-specific input type in its type-erased form
+    pub item: Box<dyn Any>,
+    pub amount: Amount,
+    pub fee: Amount,
+    /// Keys that need to sign the transaction for the input to be valid
+    pub authorization_keys: Vec<PubKey>,
+}
+
+/// Transaction output decoded by its module, carrying everything needed to validate the
+/// transaction as a whole
+pub struct DecodedOutput {
+    /// The module-specific output type in its type-erased form
+    pub item: Box<dyn Any>,
+    pub amount: Amount,
+    pub fee: Amount,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -31,6 +60,24 @@ pub struct PegOut {
     pub recipient: bitcoin::Address,
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     pub amount: bitcoin::Amount,
+    /// Extra fee paid to have the peg-out included in the next peg-out transaction, zero for
+    /// regular peg-outs
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub priority_fee: bitcoin::Amount,
+    /// On-chain fee the user pays for their output in the federation's peg-out transaction, has to
+    /// cover at least the fee quoted in [`PegOutFees`]
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
+}
+
+/// Quote of the on-chain fee a peg-out has to pay at the current consensus fee rate
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct PegOutFees {
+    /// Fee rate the federation agreed on for peg-out transactions
+    pub sats_per_kvb: u64,
+    /// Estimated fee for adding the peg-out to a transaction
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -51,72 +98,147 @@ pub trait TransactionItem {
     fn fee(&self, fee_consensus: &FeeConsensus) -> crate::Amount;
 }
 
-impl Input {
+/// Transaction inputs additionally define who is allowed to spend them
+pub trait TransactionInput: TransactionItem {
     // TODO: probably make this a single returned key once coins are separate inputs
-    /// Returns an iterator over all the keys that need to sign the transaction for the input to
-    /// be valid.
-    fn authorization_keys<'a>(&'a self) -> Box<dyn Iterator<Item = &'a PubKey> + 'a> {
-        match self {
-            Input::Coins(coins) => Box::new(coins.iter().map(|(_, coin)| coin.spend_key())),
-            Input::PegIn(proof) => Box::new(std::iter::once(proof.tweak_contract_key())),
+    /// Returns all the keys that need to sign the transaction for the input to be valid
+    fn authorization_keys(&self) -> Vec<PubKey>;
+}
+
+impl Input {
+    /// Serializes the module-specific `item` into an input of the module `module`
+    pub fn new<T: Encodable>(module: ModuleKey, item: &T) -> Input {
+        Input {
+            module,
+            item: encode_item(item),
         }
     }
 }
 
-impl TransactionItem for Input {
-    fn amount(&self) -> Amount {
-        match self {
-            Input::Coins(coins) => coins.amount(),
-            Input::PegIn(peg_in) => Amount::from_sat(peg_in.tx_output().value),
+impl Output {
+    /// Serializes the module-specific `item` into an output of the module `module`
+    pub fn new<T: Encodable>(module: ModuleKey, item: &T) -> Output {
+        Output {
+            module,
+            item: encode_item(item),
         }
     }
+}
+
+fn encode_item<T: Encodable>(item: &T) -> Vec<u8> {
+    let mut bytes = vec![];
+    item.consensus_encode(&mut bytes)
+        .expect("Writing to a vec can't fail");
+    bytes
+}
+
+/// Decodes a serialized transaction item, rejecting trailing bytes. Otherwise the same item could
+/// be submitted with different encodings, which would break deduplicating inputs by their bytes.
+pub fn decode_item<T: Decodable>(mut bytes: &[u8]) -> Result<T, DecodeError> {
+    let item = T::consensus_decode(&mut bytes)?;
+    if bytes.is_empty() {
+        Ok(item)
+    } else {
+        Err(DecodeError::from_str(
+            "Trailing bytes after transaction item",
+        ))
+    }
+}
+
+impl TransactionItem for Coins<Coin> {
+    fn amount(&self) -> Amount {
+        Coins::amount(self)
+    }
 
     fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
-        match self {
-            Input::Coins(coins) => fee_consensus.fee_coin_spend_abs * (coins.coins.len() as u64),
-            Input::PegIn(_) => fee_consensus.fee_peg_in_abs,
-        }
+        fee_consensus.fee_coin_spend_abs * (self.coins.len() as u64)
     }
 }
 
-impl TransactionItem for Output {
+impl TransactionInput for Coins<Coin> {
+    fn authorization_keys(&self) -> Vec<PubKey> {
+        self.iter()
+            .map(|(_, coin)| coin.spend_key().clone())
+            .collect()
+    }
+}
+
+impl TransactionItem for Coins<BlindToken> {
     fn amount(&self) -> Amount {
-        match self {
-            Output::Coins(coins) => coins.amount(),
-            Output::PegOut(peg_out) => peg_out.amount.into(),
-        }
+        Coins::amount(self)
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.fee_coin_spend_abs * (self.coins.len() as u64)
+    }
+}
+
+impl TransactionItem for PegInProof {
+    fn amount(&self) -> Amount {
+        Amount::from_sat(self.tx_output().value)
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.fee_peg_in_abs
+    }
+}
+
+impl TransactionInput for PegInProof {
+    fn authorization_keys(&self) -> Vec<PubKey> {
+        vec![self.tweak_contract_key().clone()]
+    }
+}
+
+impl TransactionItem for PegOut {
+    fn amount(&self) -> Amount {
+        (self.amount + self.priority_fee + self.fee).into()
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.fee_peg_out_abs
+    }
+}
+
+impl TransactionItem for ContractInput {
+    fn amount(&self) -> Amount {
+        self.amount
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.fee_contract_input_abs
+    }
+}
+
+impl TransactionInput for ContractInput {
+    fn authorization_keys(&self) -> Vec<PubKey> {
+        vec![self.authorization_key().clone()]
+    }
+}
+
+impl TransactionItem for LightningOutput {
+    fn amount(&self) -> Amount {
+        LightningOutput::amount(self)
     }
 
     fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
         match self {
-            Output::Coins(coins) => fee_consensus.fee_coin_spend_abs * (coins.coins.len() as u64),
-            Output::PegOut(_) => fee_consensus.fee_peg_out_abs,
+            LightningOutput::Contract(_) => fee_consensus.fee_contract_output_abs,
+            LightningOutput::Offer(_) => fee_consensus.fee_offer_abs,
         }
     }
 }
 
 impl Transaction {
-    pub fn validate_funding(&self, fee_consensus: &FeeConsensus) -> Result<(), TransactionError> {
-        let in_amount = self
-            .inputs
-            .iter()
-            .map(TransactionItem::amount)
-            .sum::<Amount>();
-        let out_amount = self
-            .outputs
-            .iter()
-            .map(TransactionItem::amount)
-            .sum::<Amount>();
-        let fee_amount = self
-            .inputs
-            .iter()
-            .map(|input| input.fee(fee_consensus))
-            .sum::<Amount>()
-            + self
-                .outputs
-                .iter()
-                .map(|output| output.fee(fee_consensus))
-                .sum::<Amount>();
+    /// Checks that the transaction's `inputs` cover its `outputs` and the fees of both, the items
+    /// have to be decoded by their modules first
+    pub fn validate_funding(
+        inputs: &[DecodedInput],
+        outputs: &[DecodedOutput],
+    ) -> Result<(), TransactionError> {
+        let in_amount = inputs.iter().map(|input| input.amount).sum::<Amount>();
+        let out_amount = outputs.iter().map(|output| output.amount).sum::<Amount>();
+        let fee_amount = inputs.iter().map(|input| input.fee).sum::<Amount>()
+            + outputs.iter().map(|output| output.fee).sum::<Amount>();
 
         if in_amount >= (out_amount + fee_amount) {
             Ok(())
@@ -148,11 +270,11 @@ impl Transaction {
         TransactionId::from_engine(engine)
     }
 
-    pub fn validate_signature(&self) -> Result<(), TransactionError> {
-        let public_keys = self
-            .inputs
+    /// Checks that the transaction is signed by all keys its decoded `inputs` require
+    pub fn validate_signature(&self, inputs: &[DecodedInput]) -> Result<(), TransactionError> {
+        let public_keys = inputs
             .iter()
-            .flat_map(|input| input.authorization_keys())
+            .flat_map(|input| input.authorization_keys.iter().cloned())
             .collect::<Vec<_>>();
 
         if musig::verify(
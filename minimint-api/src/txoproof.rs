@@ -151,6 +151,10 @@ impl PegInProof {
         (self.tweak_contract_key.clone(), self.transaction.txid())
     }
 
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
     pub fn tx_output(&self) -> &bitcoin::TxOut {
         self.transaction
             .output
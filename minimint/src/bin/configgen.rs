@@ -1,6 +1,6 @@
-use minimint::config::{ServerConfig, ServerConfigParams};
+use minimint::config::{PeerListOpts, ServerConfig, ServerConfigParams};
 use minimint_api::config::GenerateConfig;
-use minimint_api::{Amount, PeerId};
+use minimint_api::Amount;
 use rand::rngs::OsRng;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -8,32 +8,30 @@ use structopt::StructOpt;
 #[derive(StructOpt)]
 struct Options {
     cfg_path: PathBuf,
-    nodes: u16,
-    hbbft_base_port: u16,
-    api_base_port: u16,
+    #[structopt(flatten)]
+    peers: PeerListOpts,
     amount_tiers: Vec<Amount>,
 }
 
 fn main() {
     let Options {
         cfg_path,
-        nodes,
-        hbbft_base_port,
-        api_base_port,
+        peers: peer_list,
         amount_tiers,
     } = StructOpt::from_args();
     let mut rng = OsRng::new().unwrap();
 
-    let peers = (0..nodes).map(|id| PeerId::from(id)).collect::<Vec<_>>();
+    let peer_addresses = peer_list.peers();
+    let peers = peer_addresses.keys().copied().collect::<Vec<_>>();
     let max_evil = hbbft::util::max_faulty(peers.len());
     println!(
         "Generating keys such that up to {} peers may fail/be evil",
         max_evil
     );
     let params = ServerConfigParams {
-        hbbft_base_port,
-        api_base_port,
+        peers: peer_addresses,
         amount_tiers,
+        tls: None,
     };
 
     let (server_cfg, client_cfg) =
@@ -0,0 +1,141 @@
+use minimint::config::{PeerListOpts, ServerConfig, ServerConfigMessage, ServerConfigParams};
+use minimint::net::connect::Connections;
+use minimint::net::framed::DEFAULT_MAX_FRAME_SIZE;
+use minimint::net::tls::{gen_cert, TlsConfig};
+use minimint_api::config::GenerateConfig;
+use minimint_api::{Amount, PeerId};
+use rand::rngs::OsRng;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[derive(StructOpt)]
+enum Options {
+    /// Generates the TLS certificate we authenticate ourselves to the other peers with. It is
+    /// written to `<cfg_path>/tls-<id>.cert` and has to be copied to the `cfg_path` of every other
+    /// peer before running the config generation.
+    CreateCert { cfg_path: PathBuf, id: u16 },
+    /// Generates the config together with the other peers, expects the TLS certificates of all of
+    /// them in `cfg_path`
+    Run {
+        cfg_path: PathBuf,
+        id: u16,
+        #[structopt(flatten)]
+        peers: PeerListOpts,
+        amount_tiers: Vec<Amount>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    match StructOpt::from_args() {
+        Options::CreateCert { cfg_path, id } => create_cert(&cfg_path, id),
+        Options::Run {
+            cfg_path,
+            id,
+            peers,
+            amount_tiers,
+        } => run(cfg_path, id, peers, amount_tiers).await,
+    }
+}
+
+fn create_cert(cfg_path: &Path, id: u16) {
+    let (cert, key) = gen_cert(PeerId::from(id));
+    std::fs::write(
+        cfg_path.join(format!("tls-{}.key", id)),
+        hex::encode(&key.0),
+    )
+    .expect("Could not write TLS key");
+    std::fs::write(
+        cfg_path.join(format!("tls-{}.cert", id)),
+        hex::encode(&cert.0),
+    )
+    .expect("Could not write TLS certificate");
+    info!("Created TLS certificate tls-{}.cert", id);
+}
+
+fn read_hex_file(path: &Path) -> Vec<u8> {
+    let hex = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Could not read {}: {}", path.display(), e));
+    hex::decode(hex.trim()).unwrap_or_else(|e| panic!("Invalid hex in {}: {}", path.display(), e))
+}
+
+async fn run(cfg_path: PathBuf, id: u16, peer_list: PeerListOpts, amount_tiers: Vec<Amount>) {
+    let mut rng = OsRng::new().unwrap();
+
+    let our_id = PeerId::from(id);
+    let peer_addresses = peer_list.peers();
+    let peers = peer_addresses.keys().copied().collect::<Vec<_>>();
+    let max_evil = hbbft::util::max_faulty(peers.len());
+    info!(
+        "Generating keys such that up to {} peers may fail/be evil",
+        max_evil
+    );
+
+    let tls = TlsConfig {
+        our_private_key: PrivateKey(read_hex_file(&cfg_path.join(format!("tls-{}.key", id)))),
+        peer_certs: peers
+            .iter()
+            .map(|&peer| {
+                let path = cfg_path.join(format!("tls-{}.cert", peer));
+                (peer, Certificate(read_hex_file(&path)))
+            })
+            .collect(),
+    };
+
+    let peer_hbbft_addrs = peer_addresses
+        .iter()
+        .map(|(&peer, addresses)| (peer, addresses.hbbft_addr.clone()))
+        .collect();
+    let mut connections = Connections::<ServerConfigMessage>::connect(
+        our_id,
+        peer_addresses
+            .get(&our_id)
+            .expect("We are missing from the peer list")
+            .hbbft_bind_addr,
+        &peer_hbbft_addrs,
+        &tls,
+        DEFAULT_MAX_FRAME_SIZE,
+    )
+    .await;
+
+    let params = ServerConfigParams {
+        peers: peer_addresses,
+        amount_tiers,
+        tls: Some(tls),
+    };
+
+    let (server_cfg, client_cfg) = ServerConfig::distributed_gen(
+        &mut connections,
+        &our_id,
+        &peers,
+        max_evil,
+        &params,
+        &mut rng,
+    )
+    .await
+    .expect("Distributed config generation failed");
+    // Make sure our last messages reach the other peers before we exit
+    connections.flush().await;
+    info!("Distributed config generation finished successfully");
+
+    let mut server_cfg_file_path: PathBuf = cfg_path.clone();
+    server_cfg_file_path.push(format!("server-{}.json", id));
+    let server_cfg_file =
+        std::fs::File::create(server_cfg_file_path).expect("Could not create cfg file");
+    serde_json::to_writer_pretty(server_cfg_file, &server_cfg).unwrap();
+
+    let mut client_cfg_file_path: PathBuf = cfg_path.clone();
+    client_cfg_file_path.push("client.json");
+    let client_cfg_file =
+        std::fs::File::create(client_cfg_file_path).expect("Could not create cfg file");
+    serde_json::to_writer_pretty(client_cfg_file, &client_cfg).unwrap();
+}
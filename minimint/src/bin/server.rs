@@ -15,4 +15,6 @@ async fn main() {
     let cfg: ServerConfig = load_from_file(&opts.cfg_path);
 
     run_minimint(cfg).await;
+    // Only reached after halting, the exit code lets the operator's supervisor notice
+    std::process::exit(1);
 }
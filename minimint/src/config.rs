@@ -1,26 +1,68 @@
-use bitcoin::secp256k1::rand::{CryptoRng, RngCore};
+use crate::net::framed::DEFAULT_MAX_FRAME_SIZE;
+use crate::net::tls::{gen_cert, TlsConfig};
+use async_trait::async_trait;
+use bitcoin::secp256k1::rand::{CryptoRng, Rng, RngCore};
 use hbbft::crypto::serde_impl::SerdeSecret;
-use minimint_api::config::GenerateConfig;
-use minimint_api::{FeeConsensus, PeerId};
-use minimint_mint::config::{MintClientConfig, MintConfig};
+use hbbft::sync_key_gen::{Ack, AckOutcome, Part, PartOutcome, SyncKeyGen};
+use minimint_api::config::{ConfigGenConnections, ConfigGenError, GenerateConfig};
+use minimint_api::{CompressedPublicKey, FeeConsensus, PeerId};
+use minimint_mint::config::{MintClientConfig, MintConfig, MintConfigMessage};
 use minimint_wallet::config::{WalletClientConfig, WalletConfig};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use sha3::Digest;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
+use tokio_rustls::rustls;
 
 #[derive(StructOpt)]
 pub struct ServerOpts {
     pub cfg_path: PathBuf,
 }
 
+/// Where the config generation learns the addresses of the peers from: either a file listing
+/// them, for federations spanning multiple machines, or, for testing, a number of peers all
+/// running on this machine.
+#[derive(StructOpt)]
+pub struct PeerListOpts {
+    /// JSON file mapping the ids of all peers to their `PeerAddresses`
+    #[structopt(long, conflicts_with = "nodes")]
+    pub peer_list: Option<PathBuf>,
+    /// Number of peers running on this machine
+    #[structopt(long, required_unless = "peer-list")]
+    pub nodes: Option<u16>,
+    /// First of the consecutive ports the local peers accept connections of other peers on
+    #[structopt(long, default_value = "5000")]
+    pub hbbft_base_port: u16,
+    /// First of the consecutive ports the local peers serve the client API on
+    #[structopt(long, default_value = "6000")]
+    pub api_base_port: u16,
+}
+
+impl PeerListOpts {
+    pub fn peers(&self) -> BTreeMap<PeerId, PeerAddresses> {
+        match (&self.peer_list, self.nodes) {
+            (Some(path), _) => load_from_file(path),
+            (None, Some(nodes)) => {
+                let peers = (0..nodes).map(PeerId::from).collect::<Vec<_>>();
+                local_peers(&peers, self.hbbft_base_port, self.api_base_port)
+            }
+            (None, None) => unreachable!("Ensured by structopt"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub identity: PeerId,
-    pub hbbft_port: u16,
-    pub api_port: u16,
+    /// Address we accept the connections of other peers on
+    pub hbbft_bind_addr: SocketAddr,
+    /// Address the client API listens on
+    pub api_bind_addr: SocketAddr,
 
     pub peers: BTreeMap<PeerId, Peer>,
     #[serde(with = "serde_binary_human_readable")]
@@ -29,6 +71,12 @@ pub struct ServerConfig {
     pub hbbft_sks: hbbft::crypto::serde_impl::SerdeSecret<hbbft::crypto::SecretKeyShare>,
     #[serde(with = "serde_binary_human_readable")]
     pub hbbft_pk_set: hbbft::crypto::PublicKeySet,
+    /// Private key of our TLS certificate, see [`TlsConfig`]
+    #[serde(with = "serde_tls_key")]
+    pub tls_key: rustls::PrivateKey,
+    /// Largest message other peers may send us, see [`BincodeCodec`](crate::net::framed::BincodeCodec)
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
 
     pub db_path: PathBuf,
 
@@ -41,17 +89,34 @@ pub struct ServerConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
-    pub hbbft_port: u16,
-    pub api_port: u16,
+    /// Address other peers connect to, e.g. `guardian-1.example.com:5000`
+    pub hbbft_addr: String,
+    /// URL clients reach the peer's API at, e.g. `http://guardian-1.example.com:6000`
+    pub api_url: String,
     #[serde(with = "serde_binary_human_readable")]
     pub hbbft_pk: hbbft::crypto::PublicKey,
+    #[serde(with = "serde_tls_cert")]
+    pub tls_cert: rustls::Certificate,
+}
+
+/// Network addresses of a peer, the advertised ones are what other peers and clients use to reach
+/// it while the bind addresses are what it listens on locally, e.g. behind NAT or a reverse proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerAddresses {
+    pub hbbft_addr: String,
+    pub api_url: String,
+    pub hbbft_bind_addr: SocketAddr,
+    pub api_bind_addr: SocketAddr,
 }
 
 #[derive(Debug)]
 pub struct ServerConfigParams {
-    pub hbbft_base_port: u16,
-    pub api_base_port: u16,
+    pub peers: BTreeMap<PeerId, PeerAddresses>,
     pub amount_tiers: Vec<minimint_api::Amount>,
+    /// Our TLS key and the certificates of all peers, which have to be exchanged before the
+    /// distributed config generation to authenticate its connections. The trusted dealer
+    /// generates them itself.
+    pub tls: Option<TlsConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,9 +127,22 @@ pub struct ClientConfig {
     pub fee_consensus: FeeConsensus,
 }
 
+/// Messages exchanged between peers during distributed config generation
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ServerConfigMessage {
+    HbbftKey(hbbft::crypto::PublicKey),
+    HbbftPart(Part),
+    HbbftAck(Ack),
+    Mint(MintConfigMessage),
+    Wallet(CompressedPublicKey),
+    ConsistencyCheck(Vec<u8>),
+}
+
+#[async_trait(?Send)]
 impl GenerateConfig for ServerConfig {
     type Params = ServerConfigParams;
     type ClientConfig = ClientConfig;
+    type ConfigMessage = ServerConfigMessage;
 
     fn trusted_dealer_gen(
         peers: &[PeerId],
@@ -75,14 +153,19 @@ impl GenerateConfig for ServerConfig {
         let netinfo = hbbft::NetworkInfo::generate_map(peers.to_vec(), &mut rng)
             .expect("Could not generate HBBFT netinfo");
 
+        let tls_keys = peers
+            .iter()
+            .map(|&id| (id, gen_cert(id)))
+            .collect::<BTreeMap<_, _>>();
+
         let cfg_peers = netinfo
             .iter()
             .map(|(&id, netinf)| {
-                let id_u16: u16 = id.into();
                 let peer = Peer {
-                    hbbft_port: params.hbbft_base_port + id_u16,
-                    api_port: params.api_base_port + id_u16,
+                    hbbft_addr: params.peers[&id].hbbft_addr.clone(),
+                    api_url: params.peers[&id].api_url.clone(),
                     hbbft_pk: netinf.public_key(&id).unwrap().clone(),
+                    tls_cert: tls_keys[&id].0.clone(),
                 };
 
                 (id, peer)
@@ -94,25 +177,21 @@ impl GenerateConfig for ServerConfig {
         let (mint_server_cfg, mint_client_cfg) =
             MintConfig::trusted_dealer_gen(peers, max_evil, params.amount_tiers.as_ref(), &mut rng);
 
-        let fee_consensus = FeeConsensus {
-            fee_coin_spend_abs: minimint_api::Amount::ZERO,
-            fee_peg_in_abs: minimint_api::Amount::from_sat(500),
-            fee_coin_issuance_abs: minimint_api::Amount::ZERO,
-            fee_peg_out_abs: minimint_api::Amount::from_sat(500),
-        };
+        let fee_consensus = default_fee_consensus();
 
         let server_config = netinfo
             .iter()
             .map(|(&id, netinf)| {
-                let id_u16: u16 = id.into();
                 let config = ServerConfig {
                     identity: id,
-                    hbbft_port: params.hbbft_base_port + id_u16,
-                    api_port: params.api_base_port + id_u16,
+                    hbbft_bind_addr: params.peers[&id].hbbft_bind_addr,
+                    api_bind_addr: params.peers[&id].api_bind_addr,
                     peers: cfg_peers.clone(),
                     hbbft_sk: SerdeSecret(netinf.secret_key().clone()),
                     hbbft_sks: SerdeSecret(netinf.secret_key_share().unwrap().clone()),
                     hbbft_pk_set: netinf.public_key_set().clone(),
+                    tls_key: tls_keys[&id].1.clone(),
+                    max_frame_size: DEFAULT_MAX_FRAME_SIZE,
                     db_path: format!("cfg/mint-{}.db", id).into(),
                     wallet: wallet_server_cfg[&id].clone(),
                     mint: mint_server_cfg[&id].clone(),
@@ -123,15 +202,7 @@ impl GenerateConfig for ServerConfig {
             .collect();
 
         let client_config = ClientConfig {
-            api_endpoints: peers
-                .iter()
-                .map(|&peer| {
-                    format!(
-                        "http://127.0.0.1:{}",
-                        params.api_base_port + u16::from(peer)
-                    )
-                })
-                .collect(),
+            api_endpoints: api_endpoints(peers, params),
             mint: mint_client_cfg,
             wallet: wallet_client_cfg,
             fee_consensus,
@@ -139,23 +210,362 @@ impl GenerateConfig for ServerConfig {
 
         (server_config, client_config)
     }
+
+    async fn distributed_gen<'a>(
+        connections: &'a mut (impl ConfigGenConnections<Self::ConfigMessage> + 'a),
+        our_id: &'a PeerId,
+        peers: &'a [PeerId],
+        max_evil: usize,
+        params: &'a Self::Params,
+        mut rng: impl RngCore + CryptoRng + 'a,
+    ) -> Result<(Self, Self::ClientConfig), ConfigGenError> {
+        let tls = params
+            .tls
+            .as_ref()
+            .expect("Distributed config generation requires TLS certificates");
+
+        let mut mux = ConfigGenMux {
+            connections,
+            buffer: VecDeque::new(),
+        };
+        let other_peers = peers
+            .iter()
+            .filter(|&peer| peer != our_id)
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Exchange the hbbft node keys, they are also used to encrypt the key shares of the
+        // threshold key generation below
+        let hbbft_sk: hbbft::crypto::SecretKey = rng.gen();
+        mux.send(
+            &other_peers,
+            ServerConfigMessage::HbbftKey(hbbft_sk.public_key()),
+        )
+        .await;
+
+        let mut hbbft_pks = BTreeMap::new();
+        hbbft_pks.insert(*our_id, hbbft_sk.public_key());
+        while hbbft_pks.len() < peers.len() {
+            let (peer, pk) = mux
+                .receive(|msg| match msg {
+                    ServerConfigMessage::HbbftKey(pk) => Ok(pk),
+                    other => Err(other),
+                })
+                .await;
+            if !peers.contains(&peer) || hbbft_pks.insert(peer, pk).is_some() {
+                return Err(ConfigGenError::UnexpectedMessage(peer));
+            }
+        }
+
+        let (mut key_gen, our_part) = SyncKeyGen::new(
+            *our_id,
+            hbbft_sk.clone(),
+            Arc::new(hbbft_pks.clone()),
+            max_evil,
+            &mut rng,
+        )
+        .map_err(|e| ConfigGenError::KeyGen(format!("{:?}", e)))?;
+        let our_part = our_part.expect("We are one of the key generation participants");
+        mux.send(
+            &other_peers,
+            ServerConfigMessage::HbbftPart(our_part.clone()),
+        )
+        .await;
+
+        // All parts have to be handled before any acks, otherwise acks might reference parts we
+        // don't know yet
+        let mut parts = vec![(*our_id, our_part)];
+        while parts.len() < peers.len() {
+            let (peer, part) = mux
+                .receive(|msg| match msg {
+                    ServerConfigMessage::HbbftPart(part) => Ok(part),
+                    other => Err(other),
+                })
+                .await;
+            if !peers.contains(&peer) || parts.iter().any(|(known, _)| *known == peer) {
+                return Err(ConfigGenError::UnexpectedMessage(peer));
+            }
+            parts.push((peer, part));
+        }
+
+        let mut our_acks = Vec::with_capacity(peers.len());
+        for (peer, part) in parts {
+            match key_gen
+                .handle_part(&peer, part, &mut rng)
+                .map_err(|e| ConfigGenError::KeyGen(format!("{:?}", e)))?
+            {
+                PartOutcome::Valid(Some(ack)) => {
+                    mux.send(&other_peers, ServerConfigMessage::HbbftAck(ack.clone()))
+                        .await;
+                    our_acks.push(ack);
+                }
+                PartOutcome::Valid(None) => {}
+                PartOutcome::Invalid(_) => return Err(ConfigGenError::InvalidShare(peer)),
+            }
+        }
+
+        let mut acks = our_acks
+            .into_iter()
+            .map(|ack| (*our_id, ack))
+            .collect::<VecDeque<_>>();
+        let mut remaining_acks = other_peers.len() * peers.len();
+        loop {
+            let (peer, ack) = match acks.pop_front() {
+                Some(ack) => ack,
+                None if remaining_acks == 0 => break,
+                None => {
+                    remaining_acks -= 1;
+                    mux.receive(|msg| match msg {
+                        ServerConfigMessage::HbbftAck(ack) => Ok(ack),
+                        other => Err(other),
+                    })
+                    .await
+                }
+            };
+
+            match key_gen
+                .handle_ack(&peer, ack)
+                .map_err(|e| ConfigGenError::KeyGen(format!("{:?}", e)))?
+            {
+                AckOutcome::Valid => {}
+                AckOutcome::Invalid(_) => return Err(ConfigGenError::InvalidShare(peer)),
+            }
+        }
+
+        if !key_gen.is_ready() {
+            return Err(ConfigGenError::KeyGen(
+                "Not enough valid parts to generate hbbft keys".into(),
+            ));
+        }
+        let (hbbft_pk_set, hbbft_sks) = key_gen
+            .generate()
+            .map_err(|e| ConfigGenError::KeyGen(format!("{:?}", e)))?;
+        let hbbft_sks = hbbft_sks.expect("We are one of the key generation participants");
+
+        let (wallet_cfg, wallet_client_cfg) = WalletConfig::distributed_gen(
+            &mut ModuleConfigGenConnections {
+                mux: &mut mux,
+                wrap: ServerConfigMessage::Wallet,
+                unwrap: |msg| match msg {
+                    ServerConfigMessage::Wallet(msg) => Ok(msg),
+                    other => Err(other),
+                },
+            },
+            our_id,
+            peers,
+            max_evil,
+            &(),
+            &mut rng,
+        )
+        .await?;
+        let (mint_cfg, mint_client_cfg) = MintConfig::distributed_gen(
+            &mut ModuleConfigGenConnections {
+                mux: &mut mux,
+                wrap: ServerConfigMessage::Mint,
+                unwrap: |msg| match msg {
+                    ServerConfigMessage::Mint(msg) => Ok(msg),
+                    other => Err(other),
+                },
+            },
+            our_id,
+            peers,
+            max_evil,
+            params.amount_tiers.as_ref(),
+            &mut rng,
+        )
+        .await?;
+
+        let server_config = ServerConfig {
+            identity: *our_id,
+            hbbft_bind_addr: params.peers[our_id].hbbft_bind_addr,
+            api_bind_addr: params.peers[our_id].api_bind_addr,
+            peers: hbbft_pks
+                .into_iter()
+                .map(|(id, hbbft_pk)| {
+                    let peer = Peer {
+                        hbbft_addr: params.peers[&id].hbbft_addr.clone(),
+                        api_url: params.peers[&id].api_url.clone(),
+                        hbbft_pk,
+                        tls_cert: tls.peer_certs[&id].clone(),
+                    };
+                    (id, peer)
+                })
+                .collect(),
+            hbbft_sk: SerdeSecret(hbbft_sk),
+            hbbft_sks: SerdeSecret(hbbft_sks),
+            hbbft_pk_set,
+            tls_key: tls.our_private_key.clone(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            db_path: format!("cfg/mint-{}.db", our_id).into(),
+            wallet: wallet_cfg,
+            mint: mint_cfg,
+            fee_consensus: default_fee_consensus(),
+        };
+
+        let client_config = ClientConfig {
+            api_endpoints: api_endpoints(peers, params),
+            mint: mint_client_cfg,
+            wallet: wallet_client_cfg,
+            fee_consensus: default_fee_consensus(),
+        };
+
+        // Make sure no peer was tricked into a different config by a malicious peer sending
+        // different public data to different peers
+        let our_hash = public_config_hash(&server_config, &client_config);
+        mux.send(
+            &other_peers,
+            ServerConfigMessage::ConsistencyCheck(our_hash.clone()),
+        )
+        .await;
+        for _ in 0..other_peers.len() {
+            let (peer, hash) = mux
+                .receive(|msg| match msg {
+                    ServerConfigMessage::ConsistencyCheck(hash) => Ok(hash),
+                    other => Err(other),
+                })
+                .await;
+            if hash != our_hash {
+                return Err(ConfigGenError::InconsistentConfig(peer));
+            }
+        }
+
+        Ok((server_config, client_config))
+    }
 }
 
-impl ServerConfig {
-    pub fn get_hbbft_port(&self) -> u16 {
-        self.hbbft_port
+fn default_fee_consensus() -> FeeConsensus {
+    FeeConsensus {
+        fee_coin_spend_abs: minimint_api::Amount::ZERO,
+        fee_peg_in_abs: minimint_api::Amount::from_sat(500),
+        fee_coin_issuance_abs: minimint_api::Amount::ZERO,
+        fee_peg_out_abs: minimint_api::Amount::from_sat(500),
+        fee_contract_input_abs: minimint_api::Amount::ZERO,
+        fee_contract_output_abs: minimint_api::Amount::ZERO,
+        // Offers occupy storage until they expire without moving any funds
+        fee_offer_abs: minimint_api::Amount::from_sat(10),
+    }
+}
+
+fn api_endpoints(peers: &[PeerId], params: &ServerConfigParams) -> Vec<String> {
+    peers
+        .iter()
+        .map(|peer| params.peers[peer].api_url.clone())
+        .collect()
+}
+
+/// Addresses of `peers` all running on this machine, each using the base ports plus its id
+pub fn local_peers(
+    peers: &[PeerId],
+    hbbft_base_port: u16,
+    api_base_port: u16,
+) -> BTreeMap<PeerId, PeerAddresses> {
+    peers
+        .iter()
+        .map(|&peer| {
+            let hbbft_port = hbbft_base_port + u16::from(peer);
+            let api_port = api_base_port + u16::from(peer);
+            let addresses = PeerAddresses {
+                hbbft_addr: format!("127.0.0.1:{}", hbbft_port),
+                api_url: format!("http://127.0.0.1:{}", api_port),
+                hbbft_bind_addr: SocketAddr::from(([127, 0, 0, 1], hbbft_port)),
+                api_bind_addr: SocketAddr::from(([127, 0, 0, 1], api_port)),
+            };
+            (peer, addresses)
+        })
+        .collect()
+}
+
+/// Hashes all parts of the config that have to be the same for all peers
+fn public_config_hash(server_cfg: &ServerConfig, client_cfg: &ClientConfig) -> Vec<u8> {
+    let public_data = (
+        &server_cfg.peers,
+        &server_cfg.hbbft_pk_set,
+        &server_cfg.wallet.peer_peg_in_keys,
+        &server_cfg.mint.peer_tbs_pks,
+        client_cfg,
+    );
+    let bytes = bincode::serialize(&public_data).expect("Serialization can't fail");
+    sha3::Sha3_256::digest(&bytes).to_vec()
+}
+
+/// Splits the connections used during distributed config generation into independent channels
+/// for the individual steps, buffering messages that arrive before they can be processed.
+struct ConfigGenMux<'a, C> {
+    connections: &'a mut C,
+    buffer: VecDeque<(PeerId, ServerConfigMessage)>,
+}
+
+impl<'a, C> ConfigGenMux<'a, C>
+where
+    C: ConfigGenConnections<ServerConfigMessage>,
+{
+    async fn send(&mut self, peers: &[PeerId], msg: ServerConfigMessage) {
+        self.connections.send(peers, msg).await
+    }
+
+    /// Returns the next message for which `extract` succeeds, all other messages are buffered
+    async fn receive<T>(
+        &mut self,
+        extract: fn(ServerConfigMessage) -> Result<T, ServerConfigMessage>,
+    ) -> (PeerId, T) {
+        for idx in 0..self.buffer.len() {
+            let (peer, msg) = self.buffer.remove(idx).expect("Index is in bounds");
+            match extract(msg) {
+                Ok(item) => return (peer, item),
+                Err(msg) => self.buffer.insert(idx, (peer, msg)),
+            }
+        }
+
+        loop {
+            let (peer, msg) = self.connections.receive().await;
+            match extract(msg) {
+                Ok(item) => return (peer, item),
+                Err(msg) => self.buffer.push_back((peer, msg)),
+            }
+        }
     }
-    pub fn get_api_port(&self) -> u16 {
-        self.api_port
+}
+
+struct ModuleConfigGenConnections<'a, 'b, C, M> {
+    mux: &'b mut ConfigGenMux<'a, C>,
+    wrap: fn(M) -> ServerConfigMessage,
+    unwrap: fn(ServerConfigMessage) -> Result<M, ServerConfigMessage>,
+}
+
+#[async_trait(?Send)]
+impl<'a, 'b, C, M> ConfigGenConnections<M> for ModuleConfigGenConnections<'a, 'b, C, M>
+where
+    C: ConfigGenConnections<ServerConfigMessage>,
+    M: 'static,
+{
+    async fn send(&mut self, peers: &[PeerId], msg: M) {
+        self.mux.send(peers, (self.wrap)(msg)).await
     }
 
-    pub fn get_incoming_count(&self) -> u16 {
-        self.identity.into()
+    async fn receive(&mut self) -> (PeerId, M) {
+        self.mux.receive(self.unwrap).await
     }
+}
 
+impl ServerConfig {
     pub fn max_faulty(&self) -> usize {
         hbbft::util::max_faulty(self.peers.len())
     }
+
+    pub fn tls_config(&self) -> TlsConfig {
+        TlsConfig {
+            our_private_key: self.tls_key.clone(),
+            peer_certs: self
+                .peers
+                .iter()
+                .map(|(id, peer)| (*id, peer.tls_cert.clone()))
+                .collect(),
+        }
+    }
+}
+
+fn default_max_frame_size() -> usize {
+    DEFAULT_MAX_FRAME_SIZE
 }
 
 pub fn load_from_file<T: DeserializeOwned>(path: &Path) -> T {
@@ -187,3 +597,149 @@ mod serde_binary_human_readable {
         }
     }
 }
+
+mod serde_tls_cert {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use tokio_rustls::rustls::Certificate;
+
+    pub fn serialize<S: Serializer>(cert: &Certificate, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(&cert.0))
+    }
+
+    pub fn deserialize<'d, D: Deserializer<'d>>(d: D) -> Result<Certificate, D::Error> {
+        let bytes = hex::decode::<String>(Deserialize::deserialize(d)?)
+            .map_err(serde::de::Error::custom)?;
+        Ok(Certificate(bytes))
+    }
+}
+
+mod serde_tls_key {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use tokio_rustls::rustls::PrivateKey;
+
+    pub fn serialize<S: Serializer>(key: &PrivateKey, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(&key.0))
+    }
+
+    pub fn deserialize<'d, D: Deserializer<'d>>(d: D) -> Result<PrivateKey, D::Error> {
+        let bytes = hex::decode::<String>(Deserialize::deserialize(d)?)
+            .map_err(serde::de::Error::custom)?;
+        Ok(PrivateKey(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        local_peers, public_config_hash, ServerConfig, ServerConfigMessage, ServerConfigParams,
+    };
+    use crate::net::tls::{gen_cert, TlsConfig};
+    use async_trait::async_trait;
+    use futures::future::join_all;
+    use minimint_api::config::{ConfigGenConnections, GenerateConfig};
+    use minimint_api::{Amount, Keys, PeerId};
+    use rand::rngs::OsRng;
+    use std::collections::BTreeMap;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+    struct MemConnections {
+        id: PeerId,
+        peers: BTreeMap<PeerId, UnboundedSender<(PeerId, ServerConfigMessage)>>,
+        inbox: UnboundedReceiver<(PeerId, ServerConfigMessage)>,
+    }
+
+    #[async_trait(?Send)]
+    impl ConfigGenConnections<ServerConfigMessage> for MemConnections {
+        async fn send(&mut self, peers: &[PeerId], msg: ServerConfigMessage) {
+            for peer in peers {
+                // Peers that finished already don't need any more messages
+                let _ = self.peers[peer].send((self.id, msg.clone()));
+            }
+        }
+
+        async fn receive(&mut self) -> (PeerId, ServerConfigMessage) {
+            self.inbox.recv().await.expect("All peers hung up")
+        }
+    }
+
+    #[tokio::test]
+    async fn distributed_gen_agrees_on_public_keys() {
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let max_evil = hbbft::util::max_faulty(peers.len());
+        let certs = peers
+            .iter()
+            .map(|&peer| (peer, gen_cert(peer)))
+            .collect::<BTreeMap<_, _>>();
+
+        let (senders, inboxes): (BTreeMap<_, _>, Vec<_>) = peers
+            .iter()
+            .map(|&peer| {
+                let (sender, inbox) = unbounded_channel();
+                ((peer, sender), inbox)
+            })
+            .unzip();
+        let mut connections = peers
+            .iter()
+            .zip(inboxes)
+            .map(|(&id, inbox)| MemConnections {
+                id,
+                peers: senders.clone(),
+                inbox,
+            })
+            .collect::<Vec<_>>();
+        let params = peers
+            .iter()
+            .map(|peer| ServerConfigParams {
+                peers: local_peers(&peers, 5000, 6000),
+                amount_tiers: vec![Amount::from_msat(1), Amount::from_msat(10)],
+                tls: Some(TlsConfig {
+                    our_private_key: certs[peer].1.clone(),
+                    peer_certs: certs
+                        .iter()
+                        .map(|(id, (cert, _))| (*id, cert.clone()))
+                        .collect(),
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        let configs = join_all(
+            peers
+                .iter()
+                .zip(connections.iter_mut())
+                .zip(params.iter())
+                .map(|((peer, connections), params)| {
+                    ServerConfig::distributed_gen(
+                        connections,
+                        peer,
+                        &peers,
+                        max_evil,
+                        params,
+                        OsRng::new().unwrap(),
+                    )
+                }),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Config generation failed");
+
+        let hash = public_config_hash(&configs[0].0, &configs[0].1);
+        for (server_cfg, client_cfg) in &configs {
+            assert_eq!(public_config_hash(server_cfg, client_cfg), hash);
+
+            // Our secret key shares have to match the public key shares everyone agreed on
+            let idx = server_cfg.identity.to_usize();
+            assert_eq!(
+                server_cfg.hbbft_sks.0.public_key_share(),
+                server_cfg.hbbft_pk_set.public_key_share(idx)
+            );
+            let tbs_pks = server_cfg
+                .mint
+                .tbs_sks
+                .iter()
+                .map(|(amount, sk)| (amount, sk.to_pub_key_share()))
+                .collect::<Keys<_>>();
+            assert_eq!(server_cfg.mint.peer_tbs_pks[&server_cfg.identity], tbs_pks);
+        }
+    }
+}
@@ -1,45 +1,58 @@
+use minimint_api::module::registry::{ModuleKey, ModuleRegistry};
 use minimint_api::transaction::{Input, Transaction};
-use minimint_api::{Coin, Coins, PegInProof};
 use std::collections::HashSet;
+use tracing::debug;
 
-pub trait ConflictFilterable<T>
+pub trait ConflictFilterable<'a, T>
 where
     Self: Iterator<Item = T> + Sized,
 {
-    fn filter_conflicts<F>(self, map: F) -> ConflictFilter<Self, T, F>
+    fn filter_conflicts<F>(
+        self,
+        modules: &'a ModuleRegistry,
+        map: F,
+    ) -> ConflictFilter<'a, Self, T, F>
     where
         F: Fn(&T) -> &Transaction;
 }
 
-pub struct ConflictFilter<I, T, F>
+/// Drops every transaction that spends an input or uses module state (see
+/// [`minimint_api::FederationModule::input_conflicts`]) already used by an earlier transaction
+pub struct ConflictFilter<'a, I, T, F>
 where
     I: Iterator<Item = T>,
     F: Fn(&T) -> &Transaction,
 {
     inner_iter: I,
+    modules: &'a ModuleRegistry,
     tx_accessor: F,
-    coin_set: HashSet<Coins<Coin>>,
-    peg_in_set: HashSet<PegInProof>,
+    input_set: HashSet<Input>,
+    conflict_set: HashSet<(ModuleKey, Vec<u8>)>,
 }
 
-impl<I, T> ConflictFilterable<T> for I
+impl<'a, I, T> ConflictFilterable<'a, T> for I
 where
     I: Iterator<Item = T>,
 {
-    fn filter_conflicts<F>(self, tx_accessor: F) -> ConflictFilter<Self, T, F>
+    fn filter_conflicts<F>(
+        self,
+        modules: &'a ModuleRegistry,
+        tx_accessor: F,
+    ) -> ConflictFilter<'a, Self, T, F>
     where
         F: Fn(&T) -> &Transaction,
     {
         ConflictFilter {
             inner_iter: self,
+            modules,
             tx_accessor,
-            coin_set: Default::default(),
-            peg_in_set: Default::default(),
+            input_set: Default::default(),
+            conflict_set: Default::default(),
         }
     }
 }
 
-impl<I, T, F> Iterator for ConflictFilter<I, T, F>
+impl<'a, I, T, F> Iterator for ConflictFilter<'a, I, T, F>
 where
     I: Iterator<Item = T>,
     F: Fn(&T) -> &Transaction,
@@ -47,23 +60,64 @@ where
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let next = self.inner_iter.next()?;
-        let tx = (self.tx_accessor)(&next);
-        for input in &tx.inputs {
-            match input {
-                Input::Coins(ref coins) => {
-                    // TODO: can this be done without cloning? E.g. hashing?
-                    if !self.coin_set.insert(coins.clone()) {
-                        return None;
-                    }
-                }
-                Input::PegIn(ref peg_in) => {
-                    if !self.peg_in_set.insert(peg_in.clone()) {
-                        return None;
-                    }
-                }
+        loop {
+            let next = self.inner_iter.next()?;
+            let tx = (self.tx_accessor)(&next);
+            if self.reserve(tx) {
+                return Some(next);
             }
+            debug!("Dropping conflicting transaction {}", tx.tx_hash());
         }
-        Some(next)
     }
 }
+
+impl<'a, I, T, F> ConflictFilter<'a, I, T, F>
+where
+    I: Iterator<Item = T>,
+    F: Fn(&T) -> &Transaction,
+{
+    /// Marks the inputs and conflict keys of `tx` as used, unless one of them was used before
+    fn reserve(&mut self, tx: &Transaction) -> bool {
+        // TODO: can this be done without cloning? E.g. hashing?
+        let inputs = tx.inputs.iter().cloned().collect::<HashSet<_>>();
+        let conflicts = conflict_keys(self.modules, tx).collect::<Vec<_>>();
+        let conflict_count = conflicts.len();
+        let conflicts = conflicts.into_iter().collect::<HashSet<_>>();
+
+        // Items of one transaction are applied against the same state as well, so they may not
+        // conflict with each other either
+        let conflict_free = inputs.len() == tx.inputs.len()
+            && conflicts.len() == conflict_count
+            && inputs.is_disjoint(&self.input_set)
+            && conflicts.is_disjoint(&self.conflict_set);
+        if conflict_free {
+            self.input_set.extend(inputs);
+            self.conflict_set.extend(conflicts);
+        }
+        conflict_free
+    }
+}
+
+/// Conflict keys of all in- and outputs of `tx` tagged with the module they belong to
+fn conflict_keys<'a>(
+    modules: &'a ModuleRegistry,
+    tx: &'a Transaction,
+) -> impl Iterator<Item = (ModuleKey, Vec<u8>)> + 'a {
+    // Items of unknown modules can't conflict with anything since the transaction will be
+    // rejected anyway
+    let inputs = tx.inputs.iter().flat_map(move |input| {
+        let keys = modules
+            .get(input.module)
+            .map(|module| module.input_conflicts(&input.item))
+            .unwrap_or_default();
+        keys.into_iter().map(move |key| (input.module, key))
+    });
+    let outputs = tx.outputs.iter().flat_map(move |output| {
+        let keys = modules
+            .get(output.module)
+            .map(|module| module.output_conflicts(&output.item))
+            .unwrap_or_default();
+        keys.into_iter().map(move |key| (output.module, key))
+    });
+    inputs.chain(outputs)
+}
@@ -6,17 +6,22 @@ use crate::db::{AcceptedTransactionKey, ProposedTransactionKey, ProposedTransact
 use crate::rng::RngGenerator;
 use hbbft::honey_badger::Batch;
 use minimint_api::db::batch::{BatchTx, DbBatch};
+use minimint_api::db::staged::StagedDatabase;
 use minimint_api::db::{Database, RawDatabase};
-use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::outcome::OutputOutcome;
-use minimint_api::transaction::{Input, OutPoint, Output, Transaction, TransactionError};
-use minimint_api::{FederationModule, PeerId, TransactionId};
+use minimint_api::encoding::{Decodable, DecodeError, Encodable};
+use minimint_api::module::registry::{
+    ModuleConsensusItem, ModuleError, ModuleKey, ModuleRegistry, ServerModule,
+};
+use minimint_api::module::Audit;
+use minimint_api::transaction::{
+    DecodedInput, DecodedOutput, OutPoint, Transaction, TransactionError,
+};
+use minimint_api::{Amount, PeerId, TransactionId};
 use minimint_derive::UnzipConsensus;
-use minimint_mint::{Mint, MintError};
-use minimint_wallet::{Wallet, WalletError};
 use rand::{CryptoRng, RngCore};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use thiserror::Error;
 use tracing::{debug, error, info, trace, warn};
@@ -24,8 +29,7 @@ use tracing::{debug, error, info, trace, warn};
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, UnzipConsensus)]
 pub enum ConsensusItem {
     Transaction(Transaction),
-    Mint(<Mint as FederationModule>::ConsensusItem),
-    Wallet(<Wallet as FederationModule>::ConsensusItem),
+    Module(ModuleConsensusItem),
 }
 
 pub type HoneyBadgerMessage = hbbft::honey_badger::Message<PeerId>;
@@ -40,12 +44,13 @@ where
     /// Configuration describing the federation and containing our secrets
     pub cfg: ServerConfig, // TODO: make custom config
 
-    /// Our local mint
-    pub mint: Mint, // TODO: generate consensus code using Macro, making modules replaceable for testing and easy adaptability
-    pub wallet: Wallet,
+    /// All modules run by the federation, e.g. the mint and the wallet
+    pub modules: ModuleRegistry,
 
     /// KV Database into which all state is persisted to recover from in case of a crash
     pub db: Arc<dyn RawDatabase>,
+    /// Same database as `db`, holds back the batches of an epoch till it passed the audit
+    pub staged_db: Arc<StagedDatabase>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -65,37 +70,26 @@ where
         let tx_hash = transaction.tx_hash();
         debug!("Received mint transaction {}", tx_hash);
 
-        transaction.validate_funding(&self.cfg.fee_consensus)?;
-        transaction.validate_signature()?;
+        // A transaction that doesn't fit into any proposal would wait for consensus forever
+        let size = proposal_item_size(&ConsensusItem::Transaction(transaction.clone()));
+        if size > self.max_proposal_size() {
+            return Err(TransactionSubmissionError::TooLarge(size));
+        }
 
-        for input in &transaction.inputs {
-            match input {
-                Input::Coins(coins) => {
-                    self.mint
-                        .validate_input(coins)
-                        .map_err(TransactionSubmissionError::InputCoinError)?;
-                }
-                Input::PegIn(peg_in) => {
-                    self.wallet
-                        .validate_input(peg_in)
-                        .map_err(TransactionSubmissionError::InputPegIn)?;
-                }
-            }
+        let (inputs, outputs) = self.decode_transaction(&transaction)?;
+        Transaction::validate_funding(&inputs, &outputs)?;
+        transaction.validate_signature(&inputs)?;
+
+        for (input, decoded) in transaction.inputs.iter().zip(&inputs) {
+            self.module(input.module)?
+                .validate_input(decoded.item.as_ref())
+                .map_err(|e| TransactionSubmissionError::InputError(input.module, e))?;
         }
 
-        for output in &transaction.outputs {
-            match output {
-                Output::Coins(coins) => {
-                    self.mint
-                        .validate_output(coins)
-                        .map_err(TransactionSubmissionError::OutputCoinError)?;
-                }
-                Output::PegOut(peg_out) => {
-                    self.wallet
-                        .validate_output(peg_out)
-                        .map_err(TransactionSubmissionError::OutputPegOut)?;
-                }
-            }
+        for (output, decoded) in transaction.outputs.iter().zip(&outputs) {
+            self.module(output.module)?
+                .validate_output(decoded.item.as_ref())
+                .map_err(|e| TransactionSubmissionError::OutputError(output.module, e))?;
         }
 
         let new = self
@@ -110,38 +104,61 @@ where
         Ok(())
     }
 
-    pub async fn process_consensus_outcome(&self, consensus_outcome: ConsensusOutcome) {
+    /// Applies the consensus items of an epoch and audits the result before persisting it. If the
+    /// federation's books don't balance afterwards some module misbehaved, the epoch is discarded
+    /// and processing must not continue.
+    pub async fn process_consensus_outcome(
+        &self,
+        consensus_outcome: ConsensusOutcome,
+    ) -> Result<(), AuditError> {
         let epoch = consensus_outcome.epoch;
         info!("Processing output of epoch {}", epoch);
 
         let UnzipConsensusItem {
             transaction: transaction_cis,
-            wallet: wallet_cis,
-            mint: mint_cis,
+            module: module_cis,
         } = consensus_outcome
             .contributions
             .into_iter()
             .flat_map(|(peer, cis)| cis.into_iter().map(move |ci| (peer, ci)))
             .unzip_consensus_item();
 
+        let mut module_cis_by_key = BTreeMap::<ModuleKey, Vec<(PeerId, Vec<u8>)>>::new();
+        for (peer, ModuleConsensusItem { module, item }) in module_cis {
+            if self.modules.get(module).is_some() {
+                module_cis_by_key
+                    .entry(module)
+                    .or_default()
+                    .push((peer, item));
+            } else {
+                warn!(
+                    "Peer {} proposed consensus item for unknown module {}",
+                    peer, module
+                );
+            }
+        }
+
         let mut db_batch = DbBatch::new();
-        self.wallet
-            .begin_consensus_epoch(db_batch.transaction(), wallet_cis, self.rng_gen.get_rng())
-            .await;
-        self.mint
-            .begin_consensus_epoch(db_batch.transaction(), mint_cis, self.rng_gen.get_rng())
-            .await;
+        for (key, module) in self.modules.iter() {
+            let module_cis = module_cis_by_key.remove(&key).unwrap_or_default();
+            module
+                .begin_consensus_epoch(
+                    db_batch.transaction(),
+                    module_cis,
+                    &mut self.rng_gen.get_rng(),
+                )
+                .await;
+        }
         self.db.apply_batch(db_batch).expect("DB error");
 
         // Since the changes to the database will happen all at once we won't be able to handle
         // conflicts between consensus items in one batch there. Thus we need to make sure that
         // all items in a batch are consistent/deterministically filter out inconsistent ones.
-        // There are two item types that need checking:
-        //  * peg-ins that each peg-in tx is only used to issue coins once
-        //  * coin spends to avoid double spends in one batch
+        // Identical inputs (e.g. double spends of coins or peg-in proofs) are always conflicting,
+        // modules can declare further conflicts like funding the same lightning contract twice.
         let filtered_transactions = transaction_cis
             .into_iter()
-            .filter_conflicts(|(_, tx)| tx)
+            .filter_conflicts(&self.modules, |(_, tx)| tx)
             .collect::<Vec<_>>();
 
         // TODO: implement own parallel execution to avoid allocations and get rid of rayon
@@ -181,37 +198,102 @@ where
         self.db.apply_batch(db_batch).expect("DB error");
 
         let mut db_batch = DbBatch::new();
-        self.wallet
-            .end_consensus_epoch(db_batch.transaction(), self.rng_gen.get_rng())
-            .await;
-        self.mint
-            .end_consensus_epoch(db_batch.transaction(), self.rng_gen.get_rng())
-            .await;
+        for (_, module) in self.modules.iter() {
+            module
+                .end_consensus_epoch(db_batch.transaction(), &mut self.rng_gen.get_rng())
+                .await;
+        }
         self.db.apply_batch(db_batch).expect("DB error");
+
+        // The epoch's batches were only staged so far, they are never persisted if the federation
+        // ends up owing more than it holds
+        let audit = match self.audit() {
+            Ok(audit) => audit,
+            Err(e) => {
+                self.staged_db.discard();
+                return Err(e);
+            }
+        };
+        self.staged_db.commit().expect("DB error");
+        debug!(
+            "Federation holds {} and owes {} after epoch {}",
+            audit.assets, audit.liabilities, epoch
+        );
+        Ok(())
+    }
+
+    /// Sums up the assets and liabilities of all modules and checks that the federation doesn't
+    /// owe its users more than it holds, which would mean some module issued unbacked funds
+    pub fn audit(&self) -> Result<Audit, AuditError> {
+        let audits = self.modules.audit();
+        let total = audits.values().copied().sum::<Audit>();
+        if total.is_solvent() {
+            return Ok(total);
+        }
+
+        for (key, audit) in &audits {
+            error!(
+                "Module {} holds {} and owes {}",
+                key, audit.assets, audit.liabilities
+            );
+        }
+        Err(AuditError::LiabilitiesExceedAssets {
+            assets: total.assets,
+            liabilities: total.liabilities,
+        })
     }
 
+    /// Collects the consensus items we contribute to the next epoch. Their encoded size is
+    /// limited to [`Self::max_proposal_size`], items that don't fit are proposed in later epochs.
+    /// Module items come first since they finish processing already accepted transactions.
     pub async fn get_consensus_proposal(&self) -> Vec<ConsensusItem> {
-        self.db
-            .find_by_prefix::<_, ProposedTransactionKey, _>(&ProposedTransactionKeyPrefix)
-            .map(|res| {
-                let (_key, value) = res.expect("DB error");
-                ConsensusItem::Transaction(value)
+        let mut candidates = Vec::new();
+        for (key, module) in self.modules.iter() {
+            let module_cis = module
+                .consensus_proposal(&mut self.rng_gen.get_rng())
+                .await
+                .into_iter()
+                .map(|item| ConsensusItem::Module(ModuleConsensusItem { module: key, item }));
+            candidates.extend(module_cis);
+        }
+
+        candidates.extend(
+            self.db
+                .find_by_prefix::<_, ProposedTransactionKey, _>(&ProposedTransactionKeyPrefix)
+                .map(|res| {
+                    let (_key, value) = res.expect("DB error");
+                    ConsensusItem::Transaction(value)
+                }),
+        );
+
+        let mut remaining_size = self.max_proposal_size();
+        let num_candidates = candidates.len();
+        let proposal = candidates
+            .into_iter()
+            .filter(|item| {
+                let size = proposal_item_size(item);
+                let fits = size <= remaining_size;
+                if fits {
+                    remaining_size -= size;
+                }
+                fits
             })
-            .chain(
-                self.wallet
-                    .consensus_proposal(self.rng_gen.get_rng())
-                    .await
-                    .into_iter()
-                    .map(|wci| ConsensusItem::Wallet(wci)),
-            )
-            .chain(
-                self.mint
-                    .consensus_proposal(self.rng_gen.get_rng())
-                    .await
-                    .into_iter()
-                    .map(|mci| ConsensusItem::Mint(mci)),
-            )
-            .collect()
+            .collect::<Vec<_>>();
+        if proposal.len() < num_candidates {
+            debug!(
+                "Postponing {} consensus items that don't fit into the proposal",
+                num_candidates - proposal.len()
+            );
+        }
+        proposal
+    }
+
+    /// Maximum encoded size of our contribution to an epoch. Honey Badger encrypts contributions
+    /// and sends them to peers split into shards with Merkle proofs, keeping contributions at half
+    /// the maximum frame size leaves plenty of room for that overhead, so no peer ever has to
+    /// reject a message carrying them.
+    pub fn max_proposal_size(&self) -> usize {
+        self.cfg.max_frame_size / 2
     }
 
     fn process_transaction(
@@ -219,53 +301,26 @@ where
         mut batch: BatchTx,
         transaction: Transaction,
     ) -> Result<(), TransactionSubmissionError> {
-        transaction.validate_funding(&self.cfg.fee_consensus)?;
-        transaction.validate_signature()?;
+        let (inputs, outputs) = self.decode_transaction(&transaction)?;
+        Transaction::validate_funding(&inputs, &outputs)?;
+        transaction.validate_signature(&inputs)?;
 
         let tx_hash = transaction.tx_hash();
 
-        for input in transaction.inputs {
-            match input {
-                Input::Coins(coins) => {
-                    self.mint
-                        .apply_input(batch.subtransaction(), &coins)
-                        .map_err(TransactionSubmissionError::InputCoinError)?;
-                }
-                Input::PegIn(peg_in) => {
-                    self.wallet
-                        .apply_input(batch.subtransaction(), &peg_in)
-                        .map_err(TransactionSubmissionError::InputPegIn)?;
-                }
-            }
+        for (input, decoded) in transaction.inputs.iter().zip(&inputs) {
+            self.module(input.module)?
+                .apply_input(batch.subtransaction(), decoded.item.as_ref())
+                .map_err(|e| TransactionSubmissionError::InputError(input.module, e))?;
         }
 
-        for (idx, output) in transaction.outputs.into_iter().enumerate() {
-            match output {
-                Output::Coins(new_tokens) => {
-                    self.mint
-                        .apply_output(
-                            batch.subtransaction(),
-                            &new_tokens,
-                            OutPoint {
-                                txid: tx_hash,
-                                out_idx: idx as u64,
-                            },
-                        )
-                        .map_err(TransactionSubmissionError::OutputCoinError)?;
-                }
-                Output::PegOut(peg_out) => {
-                    self.wallet
-                        .apply_output(
-                            batch.subtransaction(),
-                            &peg_out,
-                            OutPoint {
-                                txid: tx_hash,
-                                out_idx: idx as u64,
-                            },
-                        )
-                        .map_err(TransactionSubmissionError::OutputPegOut)?;
-                }
-            }
+        for (idx, (output, decoded)) in transaction.outputs.iter().zip(&outputs).enumerate() {
+            let out_point = OutPoint {
+                txid: tx_hash,
+                out_idx: idx as u64,
+            };
+            self.module(output.module)?
+                .apply_output(batch.subtransaction(), decoded.item.as_ref(), out_point)
+                .map_err(|e| TransactionSubmissionError::OutputError(output.module, e))?;
         }
 
         batch.commit();
@@ -298,22 +353,11 @@ where
                         txid,
                         out_idx: out_idx as u64,
                     };
-                    match output {
-                        Output::Coins(_) => {
-                            let outcome = self
-                                .mint
-                                .output_status(outpoint)
-                                .expect("the transaction was processed, so should be known");
-                            OutputOutcome::Mint(outcome)
-                        }
-                        Output::PegOut(_) => {
-                            let outcome = self
-                                .wallet
-                                .output_status(outpoint)
-                                .expect("the transaction was processed, so should be known");
-                            OutputOutcome::Wallet(outcome)
-                        }
-                    }
+                    self.modules
+                        .get(output.module)
+                        .expect("the transaction was processed, so its modules exist")
+                        .output_status(outpoint)
+                        .expect("the transaction was processed, so should be known")
                 })
                 .collect();
 
@@ -327,20 +371,68 @@ where
             None
         }
     }
+
+    /// Decodes all in- and outputs of `transaction` using the modules they belong to
+    fn decode_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<(Vec<DecodedInput>, Vec<DecodedOutput>), TransactionSubmissionError> {
+        let fee_consensus = &self.cfg.fee_consensus;
+        let inputs = transaction
+            .inputs
+            .iter()
+            .map(|input| {
+                self.module(input.module)?
+                    .decode_input(&input.item, fee_consensus)
+                    .map_err(|e| TransactionSubmissionError::MalformedInput(input.module, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let outputs = transaction
+            .outputs
+            .iter()
+            .map(|output| {
+                self.module(output.module)?
+                    .decode_output(&output.item, fee_consensus)
+                    .map_err(|e| TransactionSubmissionError::MalformedOutput(output.module, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((inputs, outputs))
+    }
+
+    fn module(&self, key: ModuleKey) -> Result<&dyn ServerModule, TransactionSubmissionError> {
+        self.modules
+            .get(key)
+            .ok_or(TransactionSubmissionError::UnknownModule(key))
+    }
+}
+
+/// Size of `item` within the contribution Honey Badger encodes
+fn proposal_item_size(item: &ConsensusItem) -> usize {
+    bincode::serialized_size(item).expect("Serialization can't fail") as usize
 }
 
 #[derive(Debug, Error)]
 pub enum TransactionSubmissionError {
     #[error("High level transaction error: {0}")]
     TransactionError(TransactionError),
-    #[error("Input coin error: {0}")]
-    InputCoinError(MintError),
-    #[error("Input peg-in error: {0}")]
-    InputPegIn(WalletError),
-    #[error("Output coin error: {0}")]
-    OutputCoinError(MintError),
-    #[error("Output coin error: {0}")]
-    OutputPegOut(WalletError),
+    #[error("Transaction references unknown module {0}")]
+    UnknownModule(ModuleKey),
+    #[error("Malformed input of module {0}: {1}")]
+    MalformedInput(ModuleKey, DecodeError),
+    #[error("Malformed output of module {0}: {1}")]
+    MalformedOutput(ModuleKey, DecodeError),
+    #[error("Input error in module {0}: {1}")]
+    InputError(ModuleKey, ModuleError),
+    #[error("Output error in module {0}: {1}")]
+    OutputError(ModuleKey, ModuleError),
+    #[error("Transaction of {0} bytes is too large to be proposed to consensus")]
+    TooLarge(usize),
+}
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("Federation liabilities of {liabilities} exceed its assets of {assets}")]
+    LiabilitiesExceedAssets { assets: Amount, liabilities: Amount },
 }
 
 impl From<TransactionError> for TransactionSubmissionError {
@@ -1,8 +1,11 @@
 #![feature(async_closure)]
+#![feature(test)]
 
 extern crate minimint_api;
+#[cfg(test)]
+extern crate test;
 
-use crate::consensus::{ConsensusItem, FediMintConsensus};
+use crate::consensus::{ConsensusItem, FediMintConsensus, HoneyBadgerMessage};
 use crate::net::connect::Connections;
 use crate::net::PeerConnections;
 use crate::rng::RngGenerator;
@@ -10,14 +13,22 @@ use config::ServerConfig;
 use consensus::ConsensusOutcome;
 use hbbft::honey_badger::{HoneyBadger, Step};
 use hbbft::{Epoched, NetworkInfo};
+use minimint_api::db::staged::StagedDatabase;
 use minimint_api::db::RawDatabase;
+use minimint_api::misbehavior::{MisbehaviorLog, PeerFault};
+use minimint_api::module::registry::{
+    ModuleRegistry, MODULE_KEY_LN, MODULE_KEY_MINT, MODULE_KEY_WALLET,
+};
 use minimint_api::PeerId;
+use minimint_ln::{BlockHeightSource, LightningModule};
+use minimint_wallet::Wallet;
+use rand::rngs::OsRng;
 use rand::{CryptoRng, RngCore};
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::{spawn, JoinHandle};
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, error, info, trace};
 
 /// The actual implementation of the federated mint
 pub mod consensus;
@@ -34,7 +45,18 @@ pub mod config;
 /// Some abstractions to handle randomness
 mod rng;
 
-/// Start all the components of the mintan d plug them together
+/// Lets the lightning module use the wallet's consensus block height for contract timelocks
+struct WalletBlockHeight(Arc<dyn RawDatabase>);
+
+impl BlockHeightSource for WalletBlockHeight {
+    fn block_height(&self) -> u32 {
+        minimint_wallet::consensus_height(self.0.as_ref()).unwrap_or(0)
+    }
+}
+
+/// Start all the components of the mintan d plug them together. Only returns after halting
+/// because the federation's books don't balance, the API server and consensus tasks are stopped
+/// by then.
 pub async fn run_minimint(cfg: ServerConfig) {
     assert_eq!(
         cfg.peers.keys().max().copied().map(|id| id.to_usize()),
@@ -42,36 +64,28 @@ pub async fn run_minimint(cfg: ServerConfig) {
     );
     assert_eq!(cfg.peers.keys().min().copied(), Some(PeerId::from(0)));
 
-    let threshold = cfg.peers.len() - cfg.max_faulty();
-
-    let database: Arc<dyn RawDatabase> =
+    let sled_db: Arc<dyn RawDatabase> =
         Arc::new(sled::open(&cfg.db_path).unwrap().open_tree("mint").unwrap());
-
-    let mint = minimint_mint::Mint::new(cfg.mint.clone(), threshold, database.clone());
+    let database = Arc::new(StagedDatabase::new(sled_db));
 
     let wallet = minimint_wallet::Wallet::new(cfg.wallet.clone(), database.clone())
         .await
         .expect("Couldn't create wallet");
 
-    let mint_consensus = Arc::new(FediMintConsensus {
-        rng_gen: Box::new(CloneRngGen(Mutex::new(rand::rngs::OsRng::new().unwrap()))), //FIXME
-        cfg: cfg.clone(),
-        mint,
-        wallet,
-        db: database,
-    });
+    let mint_consensus = Arc::new(build_consensus(cfg.clone(), database, wallet));
 
-    spawn(net::api::run_server(cfg.clone(), mint_consensus.clone()));
+    let api_server = spawn(net::api::run_server(cfg.clone(), mint_consensus.clone()));
 
     let (output_sender, mut output_receiver) = channel::<ConsensusOutcome>(1);
     let (proposal_sender, proposal_receiver) = channel::<Vec<ConsensusItem>>(1);
 
     info!("Spawning consensus with first proposal");
-    spawn_hbbft(
+    let hbbft = spawn_hbbft(
         output_sender,
         proposal_receiver,
         cfg.clone(),
         mint_consensus.get_consensus_proposal().await,
+        MisbehaviorLog::new(mint_consensus.db.clone()),
         rand::rngs::OsRng::new().unwrap(),
     )
     .await;
@@ -92,7 +106,7 @@ pub async fn run_minimint(cfg: ServerConfig) {
                 .values()
                 .flatten()
                 .filter(|ci| match ci {
-                    ConsensusItem::Wallet(_) => false,
+                    ConsensusItem::Module(mci) => mci.module != MODULE_KEY_WALLET,
                     _ => true,
                 })
                 .collect::<HashSet<_>>();
@@ -111,13 +125,23 @@ pub async fn run_minimint(cfg: ServerConfig) {
         };
 
         let we_contributed = outcome.contributions.contains_key(&cfg.identity);
+        let epoch = outcome.epoch;
 
         debug!(
             "Processing consensus outcome from epoch {} with {} items",
             outcome.epoch,
             outcome.contributions.values().flatten().count()
         );
-        mint_consensus.process_consensus_outcome(outcome).await;
+        if let Err(e) = mint_consensus.process_consensus_outcome(outcome).await {
+            error!(
+                "Halting after epoch {} since the federation's books don't balance: {}",
+                epoch, e
+            );
+            // Neither serve clients nor take part in consensus on top of the unbalanced state
+            api_server.abort();
+            hbbft.abort();
+            return;
+        }
 
         if we_contributed {
             // TODO: define latency target for consensus rounds and monitor it
@@ -129,29 +153,99 @@ pub async fn run_minimint(cfg: ServerConfig) {
     }
 }
 
+/// Plugs the mint, `wallet` and lightning modules together into the consensus of one federation
+/// member which persists its state in `database`. The wallet has to use the same database, so its
+/// changes are only persisted together with the rest of their epoch.
+pub fn build_consensus(
+    cfg: ServerConfig,
+    database: Arc<StagedDatabase>,
+    wallet: Wallet,
+) -> FediMintConsensus<OsRng> {
+    let threshold = cfg.peers.len() - cfg.max_faulty();
+
+    let mint = minimint_mint::Mint::new(cfg.mint.clone(), threshold, database.clone());
+
+    let ln = LightningModule::new(
+        Box::new(WalletBlockHeight(database.clone())),
+        database.clone(),
+    );
+
+    let mut modules = ModuleRegistry::new();
+    modules.register(MODULE_KEY_WALLET, wallet);
+    modules.register(MODULE_KEY_MINT, mint);
+    modules.register(MODULE_KEY_LN, ln);
+
+    FediMintConsensus {
+        rng_gen: Box::new(CloneRngGen(Mutex::new(OsRng::new().unwrap()))), //FIXME
+        cfg,
+        modules,
+        db: database.clone(),
+        staged_db: database,
+    }
+}
+
+/// Creates the Honey Badger instance that agrees on consensus outcomes with the other peers
+pub fn honey_badger(cfg: &ServerConfig) -> HoneyBadger<Vec<ConsensusItem>, PeerId> {
+    let net_info = NetworkInfo::new(
+        cfg.identity,
+        cfg.hbbft_sks.inner().clone(),
+        cfg.hbbft_pk_set.clone(),
+        cfg.hbbft_sk.inner().clone(),
+        cfg.peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.hbbft_pk.clone()))
+            .collect(),
+    );
+
+    HoneyBadger::builder(Arc::new(net_info)).build()
+}
+
+/// Sends the messages of a Honey Badger `step` to their targets and records the consensus faults
+/// it revealed. Returns the consensus outcomes of the epochs the step finished.
+pub async fn handle_step<C>(
+    step: Step<Vec<ConsensusItem>, PeerId>,
+    connections: &mut C,
+    misbehavior: &MisbehaviorLog,
+) -> Vec<ConsensusOutcome>
+where
+    C: PeerConnections<HoneyBadgerMessage, Id = PeerId>,
+{
+    let Step {
+        output,
+        fault_log,
+        messages,
+    } = step;
+
+    for msg in messages {
+        trace!("sending message to {:?}", msg.target);
+        connections.send(msg.target, msg.message).await;
+    }
+
+    // Consensus faults aren't caught while processing an epoch, they have no batch to be recorded
+    // in
+    misbehavior.record_now(fault_log.0.into_iter().map(|fault| {
+        debug!(
+            "Consensus fault of peer {}: {:?}",
+            fault.node_id, fault.kind
+        );
+        (fault.node_id, PeerFault::ConsensusFault)
+    }));
+
+    output
+}
+
 async fn spawn_hbbft(
     outcome_sender: Sender<ConsensusOutcome>,
     mut proposal_receiver: Receiver<Vec<ConsensusItem>>,
     cfg: ServerConfig,
     initial_cis: Vec<ConsensusItem>,
+    misbehavior: MisbehaviorLog,
     mut rng: impl RngCore + CryptoRng + Clone + Send + 'static,
 ) -> JoinHandle<()> {
     spawn(async move {
         let mut connections = Connections::connect_to_all(&cfg).await;
 
-        let net_info = NetworkInfo::new(
-            cfg.identity,
-            cfg.hbbft_sks.inner().clone(),
-            cfg.hbbft_pk_set.clone(),
-            cfg.hbbft_sk.inner().clone(),
-            cfg.peers
-                .iter()
-                .map(|(id, peer)| (*id, peer.hbbft_pk.clone()))
-                .collect(),
-        );
-
-        let mut hb: HoneyBadger<Vec<ConsensusItem>, _> =
-            HoneyBadger::builder(Arc::new(net_info)).build();
+        let mut hb = honey_badger(&cfg);
         info!("Created Honey Badger instance");
 
         let mut next_consensus_items = Some(initial_cis);
@@ -174,11 +268,7 @@ async fn spawn_hbbft(
             let outcome = 'inner: loop {
                 // We either want to handle the initial step or generate a new one by receiving a
                 // message from a peer
-                let Step {
-                    output,
-                    fault_log,
-                    messages,
-                } = match initial_step.take() {
+                let step = match initial_step.take() {
                     Some(step) => step,
                     None => {
                         let (peer, peer_msg) = connections.receive().await;
@@ -188,21 +278,14 @@ async fn spawn_hbbft(
                     }
                 };
 
-                for msg in messages {
-                    trace!("sending message to {:?}", msg.target);
-                    connections.send(msg.target, msg.message).await;
-                }
-
-                if !fault_log.is_empty() {
-                    warn!("Faults: {:?}", fault_log);
-                }
-
+                let output = handle_step(step, &mut connections, &misbehavior).await;
                 if !output.is_empty() {
                     trace!("Processed step had an output, handing it off");
                     break 'inner output;
                 }
             };
 
+            debug!("Peer connections: {:?}", connections.metrics());
             for batch in outcome {
                 debug!("Exchanging consensus outcome of epoch {}", batch.epoch);
                 // Old consensus contributions are overwritten on case of multiple batches arriving
@@ -1,7 +1,15 @@
 use crate::config::ServerConfig;
 use crate::consensus::FediMintConsensus;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::Address;
+use minimint_api::ln::ContractId;
+use minimint_api::misbehavior::peer_faults;
+use minimint_api::module::registry::{MODULE_KEY_LN, MODULE_KEY_MINT, MODULE_KEY_WALLET};
 use minimint_api::transaction::Transaction;
 use minimint_api::TransactionId;
+use minimint_ln::LightningModule;
+use minimint_mint::Mint;
+use minimint_wallet::Wallet;
 use std::fmt::Formatter;
 use std::sync::Arc;
 use tide::{Body, Request, Response};
@@ -23,8 +31,14 @@ pub async fn run_server(cfg: ServerConfig, fedimint: Arc<FediMintConsensus<rand:
     let mut server = tide::with_state(state);
     server.at("/transaction").put(submit_transaction);
     server.at("/transaction/:txid").get(fetch_outcome);
+    server.at("/contract/:contract_id").get(fetch_contract);
+    server.at("/offer/:payment_hash").get(fetch_offer);
+    server.at("/block_height").get(fetch_block_height);
+    server.at("/peg_out_fees/:address").get(fetch_peg_out_fees);
+    server.at("/reserves").get(fetch_reserves);
+    server.at("/misbehavior").get(fetch_misbehavior);
     server
-        .listen(format!("127.0.0.1:{}", cfg.get_api_port()))
+        .listen(cfg.api_bind_addr.to_string())
         .await
         .expect("Could not start API server");
 }
@@ -60,3 +74,118 @@ async fn fetch_outcome(req: Request<State>) -> tide::Result {
     let body = Body::from_json(&tx_status).expect("encoding error");
     Ok(body.into())
 }
+
+async fn fetch_contract(req: Request<State>) -> tide::Result {
+    let contract_id: ContractId = match req
+        .param("contract_id")
+        .expect("Contract id not supplied")
+        .parse()
+    {
+        Ok(id) => id,
+        Err(_) => return Ok(Response::new(400)),
+    };
+
+    debug!("Got req for contract {}", contract_id);
+
+    let account = req
+        .state()
+        .fedimint
+        .modules
+        .get_typed::<LightningModule>(MODULE_KEY_LN)
+        .expect("Lightning module is always registered")
+        .contract_account(contract_id)
+        .ok_or(tide::Error::from_str(404, "Not found"))?;
+
+    let body = Body::from_json(&account).expect("encoding error");
+    Ok(body.into())
+}
+
+async fn fetch_offer(req: Request<State>) -> tide::Result {
+    let payment_hash: Sha256 = match req
+        .param("payment_hash")
+        .expect("Payment hash not supplied")
+        .parse()
+    {
+        Ok(hash) => hash,
+        Err(_) => return Ok(Response::new(400)),
+    };
+
+    debug!("Got req for offer {}", payment_hash);
+
+    let offer = req
+        .state()
+        .fedimint
+        .modules
+        .get_typed::<LightningModule>(MODULE_KEY_LN)
+        .expect("Lightning module is always registered")
+        .offer(payment_hash)
+        .ok_or(tide::Error::from_str(404, "Not found"))?;
+
+    let body = Body::from_json(&offer).expect("encoding error");
+    Ok(body.into())
+}
+
+async fn fetch_block_height(req: Request<State>) -> tide::Result {
+    let block_height = req
+        .state()
+        .fedimint
+        .modules
+        .get_typed::<Wallet>(MODULE_KEY_WALLET)
+        .expect("Wallet module is always registered")
+        .consensus_height()
+        .ok_or(tide::Error::from_str(404, "Wallet not initialized yet"))?;
+
+    let body = Body::from_json(&block_height).expect("encoding error");
+    Ok(body.into())
+}
+
+async fn fetch_peg_out_fees(req: Request<State>) -> tide::Result {
+    let address: Address = match req.param("address").expect("Address not supplied").parse() {
+        Ok(address) => address,
+        Err(_) => return Ok(Response::new(400)),
+    };
+
+    debug!("Got req for peg-out fees to {}", address);
+
+    let fees = req
+        .state()
+        .fedimint
+        .modules
+        .get_typed::<Wallet>(MODULE_KEY_WALLET)
+        .expect("Wallet module is always registered")
+        .peg_out_fees(&address);
+
+    let body = Body::from_json(&fees).expect("encoding error");
+    Ok(body.into())
+}
+
+async fn fetch_reserves(req: Request<State>) -> tide::Result {
+    debug!("Got req for reserves report");
+
+    let modules = &req.state().fedimint.modules;
+    let liabilities = modules
+        .get_typed::<Mint>(MODULE_KEY_MINT)
+        .expect("Mint module is always registered")
+        .outstanding_ecash()
+        .ok_or(tide::Error::from_str(
+            500,
+            "More e-cash was redeemed than issued",
+        ))?;
+    let report = modules
+        .get_typed::<Wallet>(MODULE_KEY_WALLET)
+        .expect("Wallet module is always registered")
+        .reserves_report(liabilities)
+        .ok_or(tide::Error::from_str(404, "Wallet not initialized yet"))?;
+
+    let body = Body::from_json(&report).expect("encoding error");
+    Ok(body.into())
+}
+
+async fn fetch_misbehavior(req: Request<State>) -> tide::Result {
+    debug!("Got req for misbehavior of peers");
+
+    let faults = peer_faults(req.state().fedimint.db.as_ref());
+
+    let body = Body::from_json(&faults).expect("encoding error");
+    Ok(body.into())
+}
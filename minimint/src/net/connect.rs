@@ -1,155 +1,1513 @@
 use crate::config::ServerConfig;
-use crate::net::framed::Framed;
+use crate::net::framed::{negotiate_version, BincodeCodec, FrameError, WireFormat};
+use crate::net::tls::{server_name, TlsConfig};
 use crate::net::PeerConnections;
 use async_trait::async_trait;
-use futures::future::select_all;
-use futures::future::try_join_all;
-use futures::StreamExt;
-use futures::{FutureExt, SinkExt};
+use futures::future::join_all;
+use futures::{Future, SinkExt, StreamExt};
 use hbbft::Target;
+use minimint_api::config::ConfigGenConnections;
 use minimint_api::PeerId;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::spawn;
-use tokio::time::sleep;
-use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
-use tracing::{debug, error, info, trace};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout};
+use tokio_rustls::rustls::Certificate;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, error, info, trace, warn};
 
-// FIXME: make connections dynamically managed
+/// Delay before the first reconnection attempt, doubled after every failed attempt
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+/// Upper bound for the delay between reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// Time a peer has to complete the handshake before we give up on the connection
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval in which peers acknowledge received messages so they can be removed from the resend
+/// buffer
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of messages to a peer that can be queued before sending to it has to wait for its task
+const OUTGOING_QUEUE_SIZE: usize = 1024;
+/// Number of received messages that can be queued before we stop reading from the connections
+const INCOMING_QUEUE_SIZE: usize = 1024;
+/// Number of messages handed to a connection's writer task that it didn't write yet
+const WRITE_QUEUE_SIZE: usize = 64;
+/// Number of unacknowledged messages kept per peer, so an offline peer can't exhaust our memory. A
+/// peer falling further behind is resynced: all of them are lost for good and it only receives
+/// the messages sent afterwards. Consensus isn't told about the gap, to Honey Badger the peer looks
+/// like one that crashed and it may never finish the epochs the lost messages belonged to.
+const MAX_UNACKED_MESSAGES: usize = 100_000;
+
+/// Connections to all other peers that survive peers going offline.
+///
+/// Every peer connection is managed by its own task that reconnects with exponential backoff
+/// (peers with a lower id connect to ones with a higher id) and keeps all messages the peer
+/// hasn't acknowledged yet, resending them once the connection is re-established. A peer being
+/// unreachable thus only delays the messages to it, which consensus tolerates as long as no more
+/// than the maximum number of faulty peers are offline. Only a peer that stays offline for
+/// [`MAX_UNACKED_MESSAGES`] messages loses them, see there.
+///
+/// Each connection is read and written by separate tasks, so a slow peer neither blocks receiving
+/// from the others nor sending to them. Received messages of all peers are multiplexed into one
+/// queue. Queues are bounded, see [`Connections::metrics`] to find out which peers cause
+/// backpressure.
 pub struct Connections<T> {
-    connections: HashMap<PeerId, Framed<Compat<TcpStream>, T>>,
+    peers: HashMap<PeerId, PeerHandle<T>>,
+    incoming: Receiver<(PeerId, T)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct PeerHandle<T> {
+    outgoing: Sender<T>,
+    /// Number of messages sent to the peer
+    sent: u64,
+    /// Id of the last message written to the peer's connection, message ids start at 1
+    written: watch::Receiver<u64>,
+    stats: Arc<PeerStats>,
+}
+
+/// Counters describing the connection to a peer, shared between its task and its handle
+#[derive(Debug, Default)]
+struct PeerStats {
+    connected: AtomicBool,
+    queue_full: AtomicU64,
+    unacked: AtomicUsize,
+    unwritten: AtomicUsize,
+    dropped: AtomicU64,
+    resyncs: AtomicU64,
+    oversized: AtomicU64,
+    reconnects: AtomicU64,
+    protocol_violations: AtomicU64,
+}
+
+/// Snapshot of the state of the connection to a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerMetrics {
+    pub connected: bool,
+    /// Number of times sending to the peer had to wait because its queue was full
+    pub queue_full: u64,
+    /// Number of messages the peer didn't acknowledge yet
+    pub unacked: usize,
+    /// Number of messages that weren't written to the peer's connection yet
+    pub unwritten: usize,
+    /// Number of messages that were dropped because the peer fell too far behind, they are never
+    /// delivered
+    pub dropped: u64,
+    /// Number of times the peer fell so far behind that we dropped all messages it didn't
+    /// acknowledge and started a new session with it
+    pub resyncs: u64,
+    /// Number of our messages that exceeded the maximum frame size, each one made us resync the
+    /// peer since it could never be delivered
+    pub oversized: u64,
+    /// Number of times the connection was re-established
+    pub reconnects: u64,
+    /// Number of times the peer didn't follow the wire format, which only faulty peers do
+    pub protocol_violations: u64,
+}
+
+impl PeerStats {
+    fn snapshot(&self) -> PeerMetrics {
+        PeerMetrics {
+            connected: self.connected.load(Ordering::Relaxed),
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            unacked: self.unacked.load(Ordering::Relaxed),
+            unwritten: self.unwritten.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+            oversized: self.oversized.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            protocol_violations: self.protocol_violations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records that `peer` broke the wire protocol, the connection is dropped afterwards
+    fn report_violation(&self, peer: PeerId, error: &FrameError) {
+        self.protocol_violations.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Peer {} violated the wire protocol, treating it as faulty: {}",
+            peer, error
+        );
+    }
 }
 
-impl<T: 'static> Connections<T>
+/// Wire format of the messages exchanged between peers
+#[derive(Debug, Serialize, Deserialize)]
+enum PeerMessage<T> {
+    Message {
+        id: u64,
+        msg: T,
+    },
+    /// Acknowledges all messages up to and including the given id
+    Ack(u64),
+}
+
+type PeerStream = TlsStream<TcpStream>;
+
+type PeerReader<T> = FramedRead<ReadHalf<PeerStream>, BincodeCodec<PeerMessage<T>>>;
+
+type PeerWriter<T> = FramedWrite<WriteHalf<PeerStream>, BincodeCodec<PeerMessage<T>>>;
+
+/// Authenticated connection together with the negotiated wire format and the session ids the peer
+/// and we sent in the handshake
+type NewConnection = (PeerStream, WireFormat, u64, u64);
+
+impl<T> Connections<T>
 where
-    T: Serialize + DeserializeOwned + Unpin + Send,
+    T: Serialize + DeserializeOwned + Clone + Unpin + Send + Sync + 'static,
 {
     pub async fn connect_to_all(cfg: &ServerConfig) -> Self {
-        info!("Starting mint {}", cfg.identity);
-        let listener = spawn(Self::await_peers(
-            cfg.get_hbbft_port(),
-            cfg.get_incoming_count(),
-        ));
+        let peers = cfg
+            .peers
+            .iter()
+            .map(|(id, peer)| (*id, peer.hbbft_addr.clone()))
+            .collect();
+        Self::connect(
+            cfg.identity,
+            cfg.hbbft_bind_addr,
+            &peers,
+            &cfg.tls_config(),
+            cfg.max_frame_size,
+        )
+        .await
+    }
+
+    /// Starts managing connections to all `peers` (given as peer id and address), peers with a
+    /// higher id are actively connected to while connections from ones with a lower id are awaited
+    /// on `bind_addr`. Returns immediately, messages to peers that aren't connected yet are
+    /// buffered.
+    ///
+    /// All connections are encrypted and peers are identified by the TLS certificate they present,
+    /// see [`TlsConfig`]. Peers sending frames larger than `max_frame_size` are considered faulty.
+    /// Our own messages have to stay below it as well, see
+    /// [`FediMintConsensus::max_proposal_size`](crate::consensus::FediMintConsensus::max_proposal_size).
+    /// If one doesn't the peer is resynced, see [`PeerMetrics::oversized`].
+    pub async fn connect(
+        identity: PeerId,
+        bind_addr: SocketAddr,
+        peers: &BTreeMap<PeerId, String>,
+        tls: &TlsConfig,
+        max_frame_size: usize,
+    ) -> Self {
+        info!("Starting mint {}", identity);
+        let listener = TcpListener::bind(bind_addr)
+            .await
+            .expect("Couldn't bind to address.");
+        debug!("Listening for incoming connections on {}", bind_addr);
+        Self::with_listener(identity, listener, peers, tls, max_frame_size)
+    }
+
+    /// Like [`Connections::connect`], but awaits the connections of peers on an already bound
+    /// `listener`
+    pub fn with_listener(
+        identity: PeerId,
+        listener: TcpListener,
+        peers: &BTreeMap<PeerId, String>,
+        tls: &TlsConfig,
+        max_frame_size: usize,
+    ) -> Self {
+        // Lets peers tell a restart of ours, which loses our connection state, from a reconnect.
+        // Every peer gets its own copy since we start a new session with peers that fell behind.
+        let session = rand::random::<u64>();
+        let connector = tls.connector(identity);
+
+        let (incoming_sender, incoming) = channel(INCOMING_QUEUE_SIZE);
+        let mut handles = HashMap::new();
+        let mut accepted_senders = HashMap::new();
+        let mut tasks = Vec::new();
+        for (&peer, peer_addr) in peers.iter().filter(|(id, _)| **id != identity) {
+            let (outgoing_sender, outgoing) = channel(OUTGOING_QUEUE_SIZE);
+            let (accepted_sender, accepted) = unbounded_channel();
+            let (written_sender, written) = watch::channel(0);
+            let stats = Arc::new(PeerStats::default());
+
+            let peer_session = Arc::new(AtomicU64::new(session));
+
+            let task = PeerTask {
+                peer,
+                session: peer_session.clone(),
+                dial_addr: if identity < peer {
+                    Some(peer_addr.clone())
+                } else {
+                    None
+                },
+                connector: connector.clone(),
+                peer_cert: tls.peer_certs.get(&peer).expect("Unknown peer").clone(),
+                max_frame_size,
+                accepted,
+                outgoing,
+                incoming: incoming_sender.clone(),
+                written: Arc::new(written_sender),
+                stats: stats.clone(),
+                resend_buffer: VecDeque::new(),
+                next_unwritten: 0,
+                next_id: 1,
+                remote_session: None,
+                last_received: Arc::new(AtomicU64::new(0)),
+                peer_acked: Arc::new(AtomicU64::new(0)),
+                last_acked: 0,
+            };
+            tasks.push(spawn(task.run()));
+
+            handles.insert(
+                peer,
+                PeerHandle {
+                    outgoing: outgoing_sender,
+                    sent: 0,
+                    written,
+                    stats: stats.clone(),
+                },
+            );
+            accepted_senders.insert(peer, (accepted_sender, stats, peer_session));
+        }
+        tasks.push(spawn(accept_peers(
+            listener,
+            tls.acceptor(identity),
+            Arc::new(tls.clone()),
+            accepted_senders,
+        )));
+
+        Connections {
+            peers: handles,
+            incoming,
+            tasks,
+        }
+    }
+
+    /// Waits till all messages sent so far were written to the connections of their peers or
+    /// dropped by resyncing them, e.g. before shutting down
+    pub async fn flush(&mut self) {
+        for (peer, handle) in self.peers.iter_mut() {
+            while *handle.written.borrow() < handle.sent {
+                trace!("Waiting for messages to peer {} to be written", peer);
+                handle
+                    .written
+                    .changed()
+                    .await
+                    .expect("Peer connection task died");
+            }
+        }
+    }
+
+    /// Returns the current state of the connections to all peers
+    pub fn metrics(&self) -> BTreeMap<PeerId, PeerMetrics> {
+        self.peers
+            .iter()
+            .map(|(peer, handle)| (*peer, handle.stats.snapshot()))
+            .collect()
+    }
+
+    /// Sends `msg` to all peers for which `selected` is true, waiting for the ones with a full
+    /// queue concurrently
+    async fn send_to_peers(&mut self, selected: impl Fn(&PeerId) -> bool, msg: T) {
+        join_all(
+            self.peers
+                .iter_mut()
+                .filter(|(peer, _)| selected(peer))
+                .map(|(_, handle)| handle.send(msg.clone())),
+        )
+        .await;
+    }
+}
+
+impl<T> PeerHandle<T> {
+    /// Hands `msg` to the peer's task, waiting if its queue is full
+    async fn send(&mut self, msg: T) {
+        match self.outgoing.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+                self.stats.queue_full.fetch_add(1, Ordering::Relaxed);
+                if self.outgoing.send(msg).await.is_err() {
+                    panic!("Peer connection task died");
+                }
+            }
+            Err(TrySendError::Closed(_)) => panic!("Peer connection task died"),
+        }
+        self.sent += 1;
+    }
+}
+
+impl<T> Drop for Connections<T> {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
 
-        sleep(Duration::from_millis(5000)).await;
+/// Accepts connections on `listener` and hands them to the task of the peer that connected after
+/// authenticating it in the TLS handshake. Peers that fail the handshake by violating the wire
+/// protocol are reported in their stats.
+async fn accept_peers(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    tls: Arc<TlsConfig>,
+    peers: HashMap<
+        PeerId,
+        (
+            UnboundedSender<NewConnection>,
+            Arc<PeerStats>,
+            Arc<AtomicU64>,
+        ),
+    >,
+) {
+    let peers = Arc::new(peers);
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                sleep(MIN_RECONNECT_DELAY).await;
+                continue;
+            }
+        };
 
-        debug!("Beginning to connect to peers");
+        let acceptor = acceptor.clone();
+        let tls = tls.clone();
+        let peers = peers.clone();
+        spawn(async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {} failed: {}", address, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {} timed out", address);
+                    return;
+                }
+            };
+
+            let (peer, (sender, stats, session)) = match tls
+                .authenticate(stream.get_ref().1.peer_certificates())
+                .and_then(|peer| Some((peer, peers.get(&peer)?)))
+            {
+                Some(peer) => peer,
+                None => {
+                    warn!(
+                        "Rejecting connection from {} with unknown certificate",
+                        address
+                    );
+                    return;
+                }
+            };
 
-        let out_conns = try_join_all(cfg.peers.iter().filter_map(|(id, peer)| {
-            if cfg.identity < *id {
-                info!("Connecting to mint {}", id);
-                Some(Self::connect_to_peer(peer.hbbft_port, *id))
-            } else {
-                None
+            let mut stream = TlsStream::from(stream);
+            let session = session.load(Ordering::SeqCst);
+            match handshake(&mut stream, session).await {
+                Ok((format, remote_session)) => {
+                    debug!("Peer {} connected", peer);
+                    // Fails only if we are shutting down
+                    let _ = sender.send((stream, format, remote_session, session));
+                }
+                Err(e) if e.is_protocol_violation() => stats.report_violation(peer, &e),
+                Err(e) => warn!("Handshake with peer {} failed: {}", peer, e),
             }
-        }))
+        });
+    }
+}
+
+/// Agrees on the wire format version with the already authenticated peer at the other end of
+/// `stream` and exchanges session ids with it
+async fn handshake(stream: &mut PeerStream, session: u64) -> Result<(WireFormat, u64), FrameError> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let format = negotiate_version(stream).await?;
+        trace!("Using wire format version {}", format.version());
+        stream.write_u64(session).await?;
+        stream.flush().await?;
+        Ok::<_, FrameError>((format, stream.read_u64().await?))
+    })
+    .await
+    .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))?
+}
+
+/// State of the connection to a single peer, owned by the task managing it. While connected it
+/// feeds the messages to the connection's writer task, the reader task forwards received
+/// messages directly.
+struct PeerTask<T> {
+    peer: PeerId,
+    /// Our current session with the peer, shared with the task accepting connections
+    session: Arc<AtomicU64>,
+    /// Address to connect to if we are responsible for (re-)establishing the connection
+    dial_addr: Option<String>,
+    connector: TlsConnector,
+    /// Certificate the peer has to authenticate itself with
+    peer_cert: Certificate,
+    max_frame_size: usize,
+    /// Connections the peer opened to us
+    accepted: UnboundedReceiver<NewConnection>,
+    outgoing: Receiver<T>,
+    incoming: Sender<(PeerId, T)>,
+    written: Arc<watch::Sender<u64>>,
+    stats: Arc<PeerStats>,
+    /// Messages sent to the peer that it hasn't acknowledged yet
+    resend_buffer: VecDeque<Arc<PeerMessage<T>>>,
+    /// Index of the first message in `resend_buffer` not handed to the current connection yet
+    next_unwritten: usize,
+    next_id: u64,
+    remote_session: Option<u64>,
+    /// Id of the last message received from the peer's current session, updated by the reader
+    last_received: Arc<AtomicU64>,
+    /// Id of the last of our messages the peer acknowledged, updated by the reader
+    peer_acked: Arc<AtomicU64>,
+    /// Id of the last message we acknowledged to the peer
+    last_acked: u64,
+}
+
+impl<T> PeerTask<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    async fn run(mut self) {
+        let mut connected_before = false;
+        let mut next_connection = self.next_connection().await;
+        while let Some((stream, format, remote_session, session)) = next_connection.take() {
+            if session != self.session.load(Ordering::SeqCst) {
+                debug!(
+                    "Discarding connection to peer {} of a session we gave up on",
+                    self.peer
+                );
+                next_connection = self.next_connection().await;
+                continue;
+            }
+
+            let result = match self.resume(stream, remote_session).await {
+                Ok(stream) => {
+                    info!("Connected to peer {}", self.peer);
+                    if connected_before {
+                        self.stats.reconnects.fetch_add(1, Ordering::Relaxed);
+                    }
+                    connected_before = true;
+
+                    self.stats.connected.store(true, Ordering::Relaxed);
+                    let result = self.communicate(stream, format).await;
+                    self.stats.connected.store(false, Ordering::Relaxed);
+                    result
+                }
+                Err(e) => Err(e),
+            };
+
+            next_connection = match result {
+                Ok(Some(connection)) => {
+                    debug!("Peer {} replaced its connection", self.peer);
+                    Some(connection)
+                }
+                Ok(None) => None,
+                // Resending the message would fail again, so we give up on it like on a peer that
+                // fell too far behind
+                Err(e @ FrameError::MessageTooLarge(_)) => {
+                    error!("Can't send message to peer {}: {}", self.peer, e);
+                    self.stats.oversized.fetch_add(1, Ordering::Relaxed);
+                    self.resync();
+                    self.next_connection().await
+                }
+                Err(e) => {
+                    if e.is_protocol_violation() {
+                        self.stats.report_violation(self.peer, &e);
+                    } else {
+                        warn!(
+                            "Lost connection to peer {}, treating it as faulty till it reconnects: {}",
+                            self.peer, e
+                        );
+                    }
+                    self.next_connection().await
+                }
+            };
+        }
+        debug!("Stopped managing connection to peer {}", self.peer);
+    }
+
+    /// Waits for the peer to connect to us or, if it's our turn, connects to it with exponential
+    /// backoff. Messages sent in the meantime are buffered. Returns `None` if we are shutting
+    /// down.
+    async fn next_connection(&mut self) -> Option<NewConnection> {
+        let connect = dial_with_backoff(
+            self.dial_addr.clone(),
+            self.peer,
+            self.connector.clone(),
+            self.peer_cert.clone(),
+            self.session.clone(),
+            self.stats.clone(),
+        );
+        tokio::pin!(connect);
+
+        loop {
+            self.update_stats();
+            tokio::select! {
+                connection = &mut connect => return Some(connection),
+                connection = self.accepted.recv() => return connection,
+                msg = self.outgoing.recv() => match msg {
+                    Some(msg) => {
+                        self.buffer(msg);
+                    }
+                    None => return None,
+                },
+            }
+        }
+    }
+
+    /// Tells the peer which of its messages we received and learns which of ours it received so
+    /// we only resend the missing ones
+    async fn resume(
+        &mut self,
+        mut stream: PeerStream,
+        remote_session: u64,
+    ) -> Result<PeerStream, FrameError> {
+        if self.remote_session != Some(remote_session) {
+            // The peer restarted and numbers its messages from scratch
+            self.remote_session = Some(remote_session);
+            self.last_received.store(0, Ordering::SeqCst);
+        }
+
+        let last_received = self.last_received.load(Ordering::SeqCst);
+        let acked = timeout(HANDSHAKE_TIMEOUT, async {
+            stream.write_u64(last_received).await?;
+            stream.flush().await?;
+            stream.read_u64().await
+        })
         .await
-        .expect("Failed to connect to peer");
+        .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
 
-        let in_conns = listener
-            .await
-            .unwrap()
-            .expect("Failed to accept connection");
+        self.last_acked = last_received;
+        self.peer_acked.store(acked, Ordering::SeqCst);
+        self.remove_acked(acked);
+        Ok(stream)
+    }
 
-        let identity = &cfg.identity;
-        let handshakes = out_conns.into_iter().chain(in_conns).map(
-            async move |mut stream| -> Result<_, std::io::Error> {
-                stream.write_u16((*identity).into()).await?;
-                let peer = stream.read_u16().await?.into();
-                Ok((peer, stream))
-            },
+    /// Exchanges messages with the peer till the connection fails. Returns the new connection if
+    /// the peer replaced the current one and `None` if we are shutting down.
+    async fn communicate(
+        &mut self,
+        stream: PeerStream,
+        format: WireFormat,
+    ) -> Result<Option<NewConnection>, FrameError> {
+        let (read, write) = split(stream);
+        let (write_sender, write_queue) = channel(WRITE_QUEUE_SIZE);
+        let (failure_sender, mut failure) = unbounded_channel();
+        let reader = spawn_half(
+            read_messages(
+                self.peer,
+                FramedRead::new(read, BincodeCodec::new(format, self.max_frame_size)),
+                self.incoming.clone(),
+                self.last_received.clone(),
+                self.peer_acked.clone(),
+            ),
+            failure_sender.clone(),
+        );
+        let writer = spawn_half(
+            write_messages(
+                FramedWrite::new(write, BincodeCodec::new(format, self.max_frame_size)),
+                write_queue,
+                self.written.clone(),
+            ),
+            failure_sender,
         );
 
-        let peers = try_join_all(handshakes)
-            .await
-            .expect("Error during peer handshakes")
-            .into_iter()
-            .map(|(id, stream)| (id, Framed::new(stream.compat())))
-            .collect::<HashMap<_, _>>();
+        let mut ack_interval = interval(ACK_INTERVAL);
+        let result = loop {
+            self.update_stats();
+            let unwritten = self.next_unwritten < self.resend_buffer.len();
+            tokio::select! {
+                msg = self.outgoing.recv() => match msg {
+                    // The peer only learns about the new session when reconnecting
+                    Some(msg) => {
+                        if self.buffer(msg) {
+                            break Err(std::io::Error::new(
+                                ErrorKind::Other,
+                                "Peer fell too far behind, starting a new session",
+                            )
+                            .into());
+                        }
+                    }
+                    None => break Ok(None),
+                },
+                permit = write_sender.reserve(), if unwritten => match permit {
+                    Ok(permit) => {
+                        permit.send(self.resend_buffer[self.next_unwritten].clone());
+                        self.next_unwritten += 1;
+                    }
+                    Err(_) => break Err(std::io::Error::from(ErrorKind::BrokenPipe).into()),
+                },
+                e = failure.recv() => match e {
+                    Some(e) => break Err(e),
+                    None => break Ok(None),
+                },
+                connection = self.accepted.recv() => break Ok(connection),
+                _ = ack_interval.tick() => self.acknowledge(&write_sender),
+            }
+        };
+
+        // The old connection must not interfere with the next one
+        reader.stop().await;
+        writer.stop().await;
+        self.remove_acked(self.peer_acked.load(Ordering::SeqCst));
+        self.next_unwritten = 0;
+        result
+    }
 
-        info!("Successfully connected to all peers");
+    /// Adds `msg` to the messages to be sent to the peer. Returns `true` if the peer fell too far
+    /// behind and we started a new session with it, the current connection must be dropped then.
+    fn buffer(&mut self, msg: T) -> bool {
+        let resynced = self.resend_buffer.len() >= MAX_UNACKED_MESSAGES;
+        if resynced {
+            warn!("Peer {} fell too far behind", self.peer);
+            self.resync();
+        }
 
-        Connections { connections: peers }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.resend_buffer
+            .push_back(Arc::new(PeerMessage::Message { id, msg }));
+        resynced
     }
 
-    async fn await_peers(port: u16, num_awaited: u16) -> Result<Vec<TcpStream>, std::io::Error> {
-        let listener = TcpListener::bind(("127.0.0.1", port))
-            .await
-            .expect("Couldn't bind to port.");
+    /// Drops all messages the peer didn't acknowledge and starts a new session with it. The dropped
+    /// messages are lost, once it reconnects the peer only learns from the new session id that it
+    /// missed some. Message ids keep increasing, so acknowledgements of the old session can't refer
+    /// to new messages.
+    fn resync(&mut self) {
+        warn!(
+            "Dropping {} unacknowledged messages to peer {} and starting a new session",
+            self.resend_buffer.len(),
+            self.peer
+        );
+        self.stats
+            .dropped
+            .fetch_add(self.resend_buffer.len() as u64, Ordering::Relaxed);
+        self.stats.resyncs.fetch_add(1, Ordering::Relaxed);
+        self.resend_buffer.clear();
+        self.next_unwritten = 0;
+        self.session.store(rand::random(), Ordering::SeqCst);
 
-        debug!("Listening for incoming connections on port {}", port);
+        // Dropped messages are never written, flushing mustn't wait for them
+        let last_dropped = self.next_id - 1;
+        if last_dropped > *self.written.borrow() {
+            let _ = self.written.send(last_dropped);
+        }
+    }
 
-        let peers = (0..num_awaited).map(|_| listener.accept());
-        let connections = try_join_all(peers)
-            .await?
-            .into_iter()
-            .map(|(socket, _)| socket)
-            .collect::<Vec<_>>();
+    /// Removes the messages the peer acknowledged and acknowledges the ones we received. If the
+    /// writer is busy our acknowledgement waits for the next interval.
+    fn acknowledge(&mut self, write_sender: &Sender<Arc<PeerMessage<T>>>) {
+        self.remove_acked(self.peer_acked.load(Ordering::SeqCst));
 
-        debug!("Received all {} connections", connections.len());
-        Ok(connections)
+        let last_received = self.last_received.load(Ordering::SeqCst);
+        if self.last_acked < last_received
+            && write_sender
+                .try_send(Arc::new(PeerMessage::Ack(last_received)))
+                .is_ok()
+        {
+            self.last_acked = last_received;
+        }
     }
 
-    async fn connect_to_peer(port: u16, peer: PeerId) -> Result<TcpStream, std::io::Error> {
-        debug!("Connecting to peer {}", peer);
-        let res = TcpStream::connect(("127.0.0.1", port)).await;
-        if res.is_err() {
-            error!("Could not connect to peer {}", peer);
+    fn remove_acked(&mut self, acked: u64) {
+        while let Some(PeerMessage::Message { id, .. }) =
+            self.resend_buffer.front().map(|msg| msg.as_ref())
+        {
+            if *id > acked {
+                break;
+            }
+            self.resend_buffer.pop_front();
+            self.next_unwritten = self.next_unwritten.saturating_sub(1);
         }
-        res
     }
 
-    async fn receive_from_peer(id: PeerId, peer: &mut Framed<Compat<TcpStream>, T>) -> (PeerId, T) {
-        let msg = peer
-            .next()
-            .await
-            .expect("Stream closed unexpectedly")
-            .expect("Error receiving peer message");
+    fn update_stats(&self) {
+        let unacked = self.resend_buffer.len();
+        self.stats.unacked.store(unacked, Ordering::Relaxed);
+        self.stats
+            .unwritten
+            .store(unacked - self.next_unwritten, Ordering::Relaxed);
+    }
+}
+
+/// Reader or writer task of a connection, aborted when dropped so it can't outlive the task of
+/// its peer
+struct HalfTask(JoinHandle<()>);
+
+impl HalfTask {
+    /// Aborts the task and waits till it stopped
+    async fn stop(mut self) {
+        self.0.abort();
+        let _ = (&mut self.0).await;
+    }
+}
+
+impl Drop for HalfTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the reader or writer of a connection, reporting to the peer's task if it fails
+fn spawn_half<F>(half: F, failure: UnboundedSender<FrameError>) -> HalfTask
+where
+    F: Future<Output = Result<(), FrameError>> + Send + 'static,
+{
+    HalfTask(spawn(async move {
+        if let Err(e) = half.await {
+            // Fails only if the connection was already given up on
+            let _ = failure.send(e);
+        }
+    }))
+}
+
+/// Forwards messages from the peer to `incoming`, waiting if it's full, and records which of our
+/// messages the peer acknowledged. Returns `Ok` if we are shutting down.
+async fn read_messages<T>(
+    peer: PeerId,
+    mut reader: PeerReader<T>,
+    incoming: Sender<(PeerId, T)>,
+    last_received: Arc<AtomicU64>,
+    peer_acked: Arc<AtomicU64>,
+) -> Result<(), FrameError>
+where
+    T: DeserializeOwned + Unpin,
+{
+    while let Some(msg) = reader.next().await {
+        match msg? {
+            PeerMessage::Message { id, msg } => {
+                if id <= last_received.load(Ordering::SeqCst) {
+                    trace!("Ignoring resent message {} from peer {}", id, peer);
+                    continue;
+                }
+                trace!("Received msg from peer {}", peer);
+                if incoming.send((peer, msg)).await.is_err() {
+                    return Ok(());
+                }
+                // Only acknowledge messages that were delivered
+                last_received.store(id, Ordering::SeqCst);
+            }
+            PeerMessage::Ack(id) => {
+                peer_acked.fetch_max(id, Ordering::SeqCst);
+            }
+        }
+    }
+    Err(std::io::Error::from(ErrorKind::UnexpectedEof).into())
+}
+
+/// Writes the messages handed over by the peer's task, flushing once no more are queued. Returns
+/// `Ok` if the peer's task gave up on the connection and [`FrameError::MessageTooLarge`] if a
+/// message can't be written since it exceeds the maximum frame size.
+async fn write_messages<T>(
+    mut writer: PeerWriter<T>,
+    mut queue: Receiver<Arc<PeerMessage<T>>>,
+    written: Arc<watch::Sender<u64>>,
+) -> Result<(), FrameError>
+where
+    T: Serialize + Unpin,
+{
+    while let Some(mut msg) = queue.recv().await {
+        let mut last_written = 0;
+        loop {
+            writer.feed(msg.as_ref()).await?;
+            if let PeerMessage::Message { id, .. } = *msg {
+                last_written = id;
+            }
+            msg = match queue.try_recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+        }
+        writer.flush().await?;
+
+        // Resent messages have lower ids than the ones written on previous connections
+        if last_written > *written.borrow() {
+            let _ = written.send(last_written);
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the peer at `addr` with exponential backoff. Never returns if `addr` is `None`
+/// since then the peer connects to us.
+async fn dial_with_backoff(
+    addr: Option<String>,
+    peer: PeerId,
+    connector: TlsConnector,
+    peer_cert: Certificate,
+    session: Arc<AtomicU64>,
+    stats: Arc<PeerStats>,
+) -> NewConnection {
+    let addr = match addr {
+        Some(addr) => addr,
+        None => return futures::future::pending().await,
+    };
 
-        trace!("Received msg from peer {}", id);
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let session = session.load(Ordering::SeqCst);
+        match dial(&addr, peer, &connector, &peer_cert, session).await {
+            Ok(connection) => return connection,
+            Err(e) if e.is_protocol_violation() => stats.report_violation(peer, &e),
+            Err(e) => debug!("Could not connect to peer {}: {}", peer, e),
+        }
 
-        (id, msg)
+        sleep(delay).await;
+        delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
     }
 }
 
+/// Connects to `peer` at `addr` and makes sure it presents `peer_cert`
+async fn dial(
+    addr: &str,
+    peer: PeerId,
+    connector: &TlsConnector,
+    peer_cert: &Certificate,
+    session: u64,
+) -> Result<NewConnection, FrameError> {
+    let stream = TcpStream::connect(addr).await?;
+    let stream = timeout(
+        HANDSHAKE_TIMEOUT,
+        connector.connect(server_name(peer), stream),
+    )
+    .await
+    .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+
+    // See `TlsConfig::authenticate` on why the certificate chain being valid isn't enough
+    let certs = stream.get_ref().1.peer_certificates();
+    if certs.and_then(|certs| certs.first()) != Some(peer_cert) {
+        return Err(std::io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Peer at {} isn't peer {}", addr, peer),
+        )
+        .into());
+    }
+
+    let mut stream = TlsStream::from(stream);
+    let (format, remote_session) = handshake(&mut stream, session).await?;
+    Ok((stream, format, remote_session, session))
+}
+
 #[async_trait]
 impl<T> PeerConnections<T> for Connections<T>
 where
-    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+    T: Serialize + DeserializeOwned + Clone + Unpin + Send + Sync + 'static,
 {
     type Id = PeerId;
 
     async fn send(&mut self, target: Target<Self::Id>, msg: T) {
         trace!("Sending message to {:?}", target);
         match target {
-            Target::All => {
-                for peer in self.connections.values_mut() {
-                    peer.send(&msg)
-                        .await
-                        .expect("Failed to send message to peer");
-                }
-            }
+            Target::All => self.send_to_peers(|_| true, msg).await,
             Target::Node(peer_id) => {
-                let peer = self.connections.get_mut(&peer_id).expect("Unknown peer");
-                peer.send(&msg)
+                self.peers
+                    .get_mut(&peer_id)
+                    .expect("Unknown peer")
+                    .send(msg)
                     .await
-                    .expect("Failed to send message to peer");
             }
         }
     }
 
     async fn receive(&mut self) -> (Self::Id, T) {
-        // TODO: optimize, don't throw away remaining futures
-        select_all(
-            self.connections
-                .iter_mut()
-                .map(|(id, peer)| Self::receive_from_peer(id.clone(), peer).boxed()),
-        )
-        .map(|(msg, _, _)| msg)
+        self.incoming
+            .recv()
+            .await
+            .expect("Peer connection tasks died")
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> ConfigGenConnections<T> for Connections<T>
+where
+    T: Serialize + DeserializeOwned + Clone + Unpin + Send + Sync + 'static,
+{
+    async fn send(&mut self, peers: &[PeerId], msg: T) {
+        self.send_to_peers(|peer| peers.contains(peer), msg).await
+    }
+
+    async fn receive(&mut self) -> (PeerId, T) {
+        PeerConnections::receive(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::connect::{Connections, PeerMetrics, MAX_UNACKED_MESSAGES};
+    use crate::net::framed::{negotiate_version, BincodeCodec, WireFormat, DEFAULT_MAX_FRAME_SIZE};
+    use crate::net::tls::{gen_cert, server_name, TlsConfig};
+    use crate::net::PeerConnections;
+    use futures::future::{join_all, select_all};
+    use futures::{FutureExt, SinkExt, StreamExt};
+    use hbbft::Target;
+    use minimint_api::PeerId;
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use test::Bencher;
+    use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::spawn;
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout};
+    use tokio_rustls::TlsStream;
+    use tokio_util::codec::Framed;
+
+    /// Upper bound for conditions the tests wait for, only reached if they fail
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Forwards connections to another address and can cut them to simulate network failures
+    struct Proxy {
+        addr: SocketAddr,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl Proxy {
+        async fn new(target: SocketAddr) -> Proxy {
+            let (listener, addr) = listen().await;
+            let connections = Arc::new(Mutex::new(Vec::new()));
+            let proxied = connections.clone();
+            spawn(async move {
+                loop {
+                    let (mut inbound, _) = listener.accept().await.unwrap();
+                    let mut outbound = match TcpStream::connect(target).await {
+                        Ok(outbound) => outbound,
+                        Err(_) => continue,
+                    };
+                    proxied.lock().unwrap().push(spawn(async move {
+                        let _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+                    }));
+                }
+            });
+            Proxy { addr, connections }
+        }
+
+        fn cut(&self) {
+            for connection in self.connections.lock().unwrap().drain(..) {
+                connection.abort();
+            }
+        }
+    }
+
+    /// Generates the TLS configs of peers `0..num_peers`
+    fn tls_configs(num_peers: u16) -> Vec<TlsConfig> {
+        let (certs, keys): (Vec<_>, Vec<_>) = (0..num_peers)
+            .map(|peer| gen_cert(PeerId::from(peer)))
+            .unzip();
+        let peer_certs = (0..num_peers)
+            .map(PeerId::from)
+            .zip(certs)
+            .collect::<BTreeMap<_, _>>();
+        keys.into_iter()
+            .map(|our_private_key| TlsConfig {
+                our_private_key,
+                peer_certs: peer_certs.clone(),
+            })
+            .collect()
+    }
+
+    /// Binds a listener to a free local port
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    /// Binds listeners for peers `0..num_peers`
+    async fn listen_all(num_peers: u16) -> (Vec<TcpListener>, Vec<SocketAddr>) {
+        join_all((0..num_peers).map(|_| listen()))
+            .await
+            .into_iter()
+            .unzip()
+    }
+
+    /// Binds `addr` again once the listener of a dropped [`Connections`] released it
+    async fn rebind(addr: SocketAddr) -> TcpListener {
+        timeout(WAIT_TIMEOUT, async {
+            loop {
+                match TcpListener::bind(addr).await {
+                    Ok(listener) => return listener,
+                    Err(_) => sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("Address wasn't released")
+    }
+
+    /// Peer addresses by id, starting with peer 0
+    fn peer_addrs(addrs: &[SocketAddr]) -> BTreeMap<PeerId, String> {
+        addrs
+            .iter()
+            .enumerate()
+            .map(|(peer, addr)| (PeerId::from(peer as u16), addr.to_string()))
+            .collect()
+    }
+
+    /// Waits till the metrics of the connection to `peer` satisfy `condition`
+    async fn wait_for(
+        conn: &Connections<u64>,
+        peer: PeerId,
+        condition: impl Fn(&PeerMetrics) -> bool,
+    ) {
+        timeout(WAIT_TIMEOUT, async {
+            while !condition(&conn.metrics()[&peer]) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
         .await
+        .unwrap_or_else(|_| panic!("Unexpected metrics {:?}", conn.metrics()[&peer]));
+    }
+
+    #[tokio::test]
+    async fn resend_after_connection_drop() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+
+        // Peer 0 connects to peer 1 through the proxy
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let proxy = Proxy::new(addrs[1]).await;
+        let mut conn1 = Connections::<u64>::with_listener(
+            peer1,
+            listeners.pop().unwrap(),
+            &peer_addrs(&addrs),
+            &tls[1],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut conn0 = Connections::<u64>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peer_addrs(&[addrs[0], proxy.addr]),
+            &tls[0],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+
+        let mut received0 = Vec::new();
+        let mut received1 = Vec::new();
+        for round in 0..3 {
+            for i in 0..10 {
+                conn0.send(Target::All, round * 10 + i).await;
+                conn1.send(Target::Node(peer0), round * 10 + i).await;
+            }
+
+            // Drop the connection while messages are in flight
+            let (peer, msg) = conn1.receive().await;
+            assert_eq!(peer, peer0);
+            received1.push(msg);
+            let (peer, msg) = conn0.receive().await;
+            assert_eq!(peer, peer1);
+            received0.push(msg);
+            proxy.cut();
+        }
+
+        while received0.len() < 30 {
+            received0.push(conn0.receive().await.1);
+        }
+        while received1.len() < 30 {
+            received1.push(conn1.receive().await.1);
+        }
+
+        // All messages arrive exactly once and in order
+        assert_eq!(received0, (0..30u64).collect::<Vec<_>>());
+        assert_eq!(received1, (0..30u64).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn buffer_while_peer_restarts() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let peers = peer_addrs(&addrs);
+
+        let mut conn1 = Connections::<u64>::with_listener(
+            peer1,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[1],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut conn0 = Connections::<u64>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[0],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+
+        conn0.send(Target::Node(peer1), 0).await;
+        conn1.send(Target::Node(peer0), 0).await;
+        assert_eq!(conn1.receive().await, (peer0, 0));
+        assert_eq!(conn0.receive().await, (peer1, 0));
+
+        // Make sure the messages are acknowledged before the restart
+        wait_for(&conn0, peer1, |metrics| metrics.unacked == 0).await;
+        wait_for(&conn1, peer0, |metrics| metrics.unacked == 0).await;
+        drop(conn1);
+        wait_for(&conn0, peer1, |metrics| !metrics.connected).await;
+
+        // Messages to the offline peer are buffered
+        for i in 1..5 {
+            conn0.send(Target::All, i).await;
+        }
+        wait_for(&conn0, peer1, |metrics| {
+            metrics.unacked == 4 && metrics.unwritten == 4
+        })
+        .await;
+        assert!(!conn0.metrics()[&peer1].connected);
+
+        let mut conn1 = Connections::<u64>::with_listener(
+            peer1,
+            rebind(addrs[1]).await,
+            &peers,
+            &tls[1],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        for i in 1..5 {
+            assert_eq!(conn1.receive().await, (peer0, i));
+        }
+
+        // The restarted peer numbers its messages from scratch, which must not be mistaken for
+        // resent ones
+        conn1.send(Target::Node(peer0), 1).await;
+        assert_eq!(conn0.receive().await, (peer1, 1));
+    }
+
+    #[tokio::test]
+    async fn resync_peer_that_fell_behind() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let peers = peer_addrs(&addrs);
+        // Peer 1 doesn't accept connections till it comes online
+        let listener1 = listeners.pop().unwrap();
+
+        let mut conn0 = Connections::<u64>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[0],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+
+        // Peer 1 is offline for so long that peer 0 gives up on the messages it didn't receive
+        let num_messages = MAX_UNACKED_MESSAGES as u64 + 1;
+        for i in 0..num_messages {
+            conn0.send(Target::Node(peer1), i).await;
+        }
+        wait_for(&conn0, peer1, |metrics| metrics.resyncs == 1).await;
+        wait_for(&conn0, peer1, |metrics| metrics.unacked == 1).await;
+        assert_eq!(conn0.metrics()[&peer1].dropped, MAX_UNACKED_MESSAGES as u64);
+
+        // Once back online peer 1 only receives the messages of the new session, the dropped ones
+        // are lost: messages arrive in order, so none of them can show up later
+        let mut conn1 = Connections::<u64>::with_listener(
+            peer1,
+            listener1,
+            &peers,
+            &tls[1],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        assert_eq!(conn1.receive().await, (peer0, num_messages - 1));
+        conn0.send(Target::Node(peer1), num_messages).await;
+        assert_eq!(conn1.receive().await, (peer0, num_messages));
+
+        // Dropped messages don't hold up flushing
+        timeout(Duration::from_secs(1), conn0.flush())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn resync_on_oversized_message() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let peers = peer_addrs(&addrs);
+        let max_frame_size = 64;
+
+        let mut conn1 = Connections::<Vec<u8>>::with_listener(
+            peer1,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[1],
+            max_frame_size,
+        );
+        let mut conn0 = Connections::<Vec<u8>>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[0],
+            max_frame_size,
+        );
+
+        // The message can never be delivered, so peer 0 gives up on it and resyncs peer 1
+        conn0
+            .send(Target::Node(peer1), vec![0; max_frame_size * 2])
+            .await;
+        timeout(Duration::from_secs(5), conn0.flush())
+            .await
+            .unwrap();
+        let metrics = conn0.metrics()[&peer1].clone();
+        assert_eq!(metrics.oversized, 1);
+        assert_eq!(metrics.resyncs, 1);
+
+        conn0.send(Target::Node(peer1), vec![1]).await;
+        assert_eq!(conn1.receive().await, (peer0, vec![1]));
+    }
+
+    #[tokio::test]
+    async fn reject_impersonation() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let peers = peer_addrs(&addrs);
+
+        // Peer 0 uses a key that doesn't belong to the certificate peer 1 knows for it
+        let (_, other_key) = gen_cert(peer0);
+        let impostor_tls = TlsConfig {
+            our_private_key: other_key,
+            peer_certs: tls[0].peer_certs.clone(),
+        };
+
+        let mut conn1 = Connections::<u64>::with_listener(
+            peer1,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[1],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+        let mut impostor = Connections::<u64>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peers,
+            &impostor_tls,
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+
+        impostor.send(Target::All, 42).await;
+        conn1.send(Target::All, 42).await;
+        assert!(timeout(Duration::from_secs(2), conn1.receive())
+            .await
+            .is_err());
+        assert!(timeout(Duration::from_secs(2), impostor.receive())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn report_protocol_violations() {
+        let peer0 = PeerId::from(0);
+        let peer1 = PeerId::from(1);
+        let tls = tls_configs(2);
+        let (mut listeners, addrs) = listen_all(2).await;
+        let peers = peer_addrs(&addrs);
+        // Peer 1 impersonates itself below instead of accepting connections
+        let _listener1 = listeners.pop().unwrap();
+
+        let conn0 = Connections::<u64>::with_listener(
+            peer0,
+            listeners.pop().unwrap(),
+            &peers,
+            &tls[0],
+            DEFAULT_MAX_FRAME_SIZE,
+        );
+
+        // Peer 1 authenticates itself correctly but doesn't follow the wire format
+        let tls1 = &tls[1];
+        let addr0 = addrs[0];
+        let connect_as_peer1 = || async move {
+            let stream = TcpStream::connect(addr0).await.unwrap();
+            let stream = tls1
+                .connector(peer1)
+                .connect(server_name(peer0), stream)
+                .await
+                .unwrap();
+            TlsStream::from(stream)
+        };
+
+        let mut stream = connect_as_peer1().await;
+        stream.write_all(b"HTTP/1").await.unwrap();
+        stream.flush().await.unwrap();
+        wait_for(&conn0, peer1, |metrics| metrics.protocol_violations == 1).await;
+
+        // A frame length above the maximum after a successful handshake
+        let mut stream = connect_as_peer1().await;
+        negotiate_version(&mut stream).await.unwrap();
+        stream.write_u64(1).await.unwrap();
+        stream.flush().await.unwrap();
+        stream.read_u64().await.unwrap();
+        stream.write_u64(0).await.unwrap();
+        stream.flush().await.unwrap();
+        stream.read_u64().await.unwrap();
+        stream.write_u32(u32::MAX).await.unwrap();
+        stream.flush().await.unwrap();
+        wait_for(&conn0, peer1, |metrics| metrics.protocol_violations == 2).await;
+    }
+
+    /// Message handling as it was before every peer connection got its own task, kept as the
+    /// baseline for the benchmarks: receiving rebuilds a `select_all` over all connections every
+    /// time and broadcasting writes to one peer after the other.
+    struct SelectAllConnections {
+        connections: BTreeMap<PeerId, Framed<TlsStream<TcpStream>, BincodeCodec<Vec<u8>>>>,
+    }
+
+    impl SelectAllConnections {
+        async fn connect(
+            identity: PeerId,
+            listener: TcpListener,
+            peers: &BTreeMap<PeerId, String>,
+            tls: &TlsConfig,
+        ) -> Self {
+            let connector = tls.connector(identity);
+            let dial = join_all(peers.iter().filter(|(peer, _)| identity < **peer).map(
+                |(peer, addr)| {
+                    let connector = connector.clone();
+                    async move {
+                        let stream = TcpStream::connect(addr).await.unwrap();
+                        let stream = connector.connect(server_name(*peer), stream).await.unwrap();
+                        (*peer, TlsStream::from(stream))
+                    }
+                },
+            ));
+
+            let acceptor = tls.acceptor(identity);
+            let num_accepted = peers.keys().filter(|peer| **peer < identity).count();
+            let accept = async {
+                let mut accepted = Vec::new();
+                for _ in 0..num_accepted {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let stream = acceptor.accept(stream).await.unwrap();
+                    let peer = tls
+                        .authenticate(stream.get_ref().1.peer_certificates())
+                        .unwrap();
+                    accepted.push((peer, TlsStream::from(stream)));
+                }
+                accepted
+            };
+
+            let (dialed, accepted) = futures::join!(dial, accept);
+            let connections = dialed
+                .into_iter()
+                .chain(accepted)
+                .map(|(peer, stream)| {
+                    let codec = BincodeCodec::new(WireFormat::V1, DEFAULT_MAX_FRAME_SIZE);
+                    (peer, Framed::new(stream, codec))
+                })
+                .collect();
+            SelectAllConnections { connections }
+        }
+
+        async fn broadcast(&mut self, msg: Vec<u8>) {
+            for connection in self.connections.values_mut() {
+                connection.send(&msg).await.unwrap();
+            }
+        }
+
+        async fn receive(&mut self) -> (PeerId, Vec<u8>) {
+            let (msg, _, _) = select_all(self.connections.iter_mut().map(|(peer, connection)| {
+                async move { (*peer, connection.next().await.unwrap().unwrap()) }.boxed()
+            }))
+            .await;
+            msg
+        }
+    }
+
+    const BENCH_BATCH: usize = 100;
+    const BENCH_MSG_SIZE: usize = 1024;
+
+    /// Every peer broadcasts a batch of messages and receives the ones of all other peers
+    fn bench_broadcast(bencher: &mut Bencher, num_peers: u16) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let tls = tls_configs(num_peers);
+        let mut connections = runtime.block_on(async {
+            let (listeners, addrs) = listen_all(num_peers).await;
+            let peers = peer_addrs(&addrs);
+            listeners
+                .into_iter()
+                .enumerate()
+                .map(|(peer, listener)| {
+                    Connections::<Vec<u8>>::with_listener(
+                        PeerId::from(peer as u16),
+                        listener,
+                        &peers,
+                        &tls[peer],
+                        DEFAULT_MAX_FRAME_SIZE,
+                    )
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let received_per_peer = BENCH_BATCH * (num_peers as usize - 1);
+        bencher.bytes = (received_per_peer * num_peers as usize * BENCH_MSG_SIZE) as u64;
+        bencher.iter(|| {
+            runtime.block_on(join_all(connections.iter_mut().map(|conn| async move {
+                for _ in 0..BENCH_BATCH {
+                    conn.send(Target::All, vec![0u8; BENCH_MSG_SIZE]).await;
+                }
+                for _ in 0..received_per_peer {
+                    conn.receive().await;
+                }
+            })))
+        });
+    }
+
+    /// Same as [`bench_broadcast`] with the [`SelectAllConnections`] baseline
+    fn bench_broadcast_baseline(bencher: &mut Bencher, num_peers: u16) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let tls = tls_configs(num_peers);
+        let mut connections = runtime.block_on(async {
+            let (listeners, addrs) = listen_all(num_peers).await;
+            let peers = peer_addrs(&addrs);
+            join_all(listeners.into_iter().zip(tls.iter()).enumerate().map(
+                |(peer, (listener, tls))| {
+                    SelectAllConnections::connect(PeerId::from(peer as u16), listener, &peers, tls)
+                },
+            ))
+            .await
+        });
+
+        let received_per_peer = BENCH_BATCH * (num_peers as usize - 1);
+        bencher.bytes = (received_per_peer * num_peers as usize * BENCH_MSG_SIZE) as u64;
+        bencher.iter(|| {
+            runtime.block_on(join_all(connections.iter_mut().map(|conn| async move {
+                for _ in 0..BENCH_BATCH {
+                    conn.broadcast(vec![0u8; BENCH_MSG_SIZE]).await;
+                }
+                for _ in 0..received_per_peer {
+                    conn.receive().await;
+                }
+            })))
+        });
+    }
+
+    #[bench]
+    fn bench_broadcast_4_peers(bencher: &mut Bencher) {
+        bench_broadcast(bencher, 4);
+    }
+
+    #[bench]
+    fn bench_broadcast_10_peers(bencher: &mut Bencher) {
+        bench_broadcast(bencher, 10);
+    }
+
+    #[bench]
+    fn bench_broadcast_4_peers_baseline(bencher: &mut Bencher) {
+        bench_broadcast_baseline(bencher, 4);
+    }
+
+    #[bench]
+    fn bench_broadcast_10_peers_baseline(bencher: &mut Bencher) {
+        bench_broadcast_baseline(bencher, 10);
     }
 }
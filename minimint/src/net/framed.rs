@@ -1,157 +1,190 @@
-use futures::{AsyncRead, AsyncWrite};
-use futures::{Sink, Stream};
+use bincode::Options;
+use bytes::{Buf, BufMut, BytesMut};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::cmp::min;
+use std::convert::TryInto;
 use std::marker::PhantomData;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
 use tracing::{debug, trace};
 
-// FIXME: look into using tokio for that, I  just didn't know it was a core component by now
-pub struct Framed<S, T> {
-    stream: S,
-    write_buffer: Vec<u8>,
-    read_len_len: usize,
-    read_len_buffer: [u8; 8],
-    read_len_actual: usize,
-    read_buffer: Vec<u8>,
-    _phantom: PhantomData<T>,
+/// Identifies connections that speak our wire format
+pub const WIRE_MAGIC: [u8; 4] = *b"MINT";
+/// Version of the wire format, has to be increased on every incompatible change
+pub const WIRE_VERSION: u16 = 1;
+/// Maximum frame size used if none is configured, big enough for any honest consensus message
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the big-endian length prefix of every frame
+const LEN_PREFIX_SIZE: usize = 4;
+
+/// Wire format agreed on with a peer by [`negotiate_version`], one variant per version we can
+/// still speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Fixed size integers, frames limited by the configured maximum frame size
+    V1,
 }
 
-impl<S, T> Framed<S, T>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-    T: Serialize + DeserializeOwned + Unpin,
-{
-    pub fn new(stream: S) -> Self {
-        Framed {
-            stream,
-            write_buffer: Vec::new(),
-            read_len_len: 0,
-            read_len_buffer: [0u8; 8],
-            read_len_actual: 0,
-            read_buffer: Vec::new(),
-            _phantom: PhantomData,
+impl WireFormat {
+    fn from_version(version: u16) -> Option<WireFormat> {
+        match version {
+            1 => Some(WireFormat::V1),
+            _ => None,
         }
     }
-}
-
-impl<S, T> Sink<&T> for Framed<S, T>
-where
-    S: AsyncWrite + Unpin,
-    T: Serialize + Unpin,
-{
-    type Error = FrameError;
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let mut_self = self.get_mut();
-
-        if mut_self.write_buffer.is_empty() {
-            return Poll::Ready(Ok(()));
+    pub fn version(self) -> u16 {
+        match self {
+            WireFormat::V1 => 1,
         }
+    }
+}
 
-        match Pin::new(&mut mut_self.stream).poll_write(cx, &mut_self.write_buffer) {
-            Poll::Ready(Ok(len)) => mut_self.write_buffer = mut_self.write_buffer[len..].to_vec(),
-            Poll::Ready(Err(e)) => return Poll::Ready(Err(FrameError::IOError(e))),
-            Poll::Pending => return Poll::Pending,
-        };
+/// Encodes messages of type `T` as length-prefixed bincode frames in the given [`WireFormat`].
+///
+/// The length prefix is checked against the maximum frame size before anything else, so a peer
+/// can't make us buffer more than that. Malformed input results in errors that
+/// [`FrameError::is_protocol_violation`] identifies as the other side's fault.
+#[derive(Debug)]
+pub struct BincodeCodec<T> {
+    format: WireFormat,
+    max_frame_size: usize,
+    _phantom: PhantomData<T>,
+}
 
-        if mut_self.write_buffer.is_empty() {
-            Poll::Ready(Ok(()))
-        } else {
-            Poll::Pending
+impl<T> BincodeCodec<T> {
+    pub fn new(format: WireFormat, max_frame_size: usize) -> Self {
+        BincodeCodec {
+            format,
+            max_frame_size,
+            _phantom: PhantomData,
         }
     }
+}
 
-    fn start_send(self: Pin<&mut Self>, item: &T) -> Result<(), Self::Error> {
-        match bincode::serialize(item) {
-            Ok(encoded) => {
-                let mut frame = Vec::with_capacity(encoded.len() + 8);
-                frame.extend_from_slice(&encoded.len().to_be_bytes());
-                frame.extend_from_slice(&encoded);
-                debug!("Sending  {} bytes", encoded.len());
-                trace!("Sending  {:x?}", encoded);
-                self.get_mut().write_buffer = frame;
-                Ok(())
-            }
-            Err(e) => Err(FrameError::CodingError(e)),
+impl<T> BincodeCodec<T> {
+    /// Encoding of frame contents, neither side ever encodes or decodes more than a frame
+    fn bincode_options(&self) -> impl Options {
+        match self.format {
+            WireFormat::V1 => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .with_limit(self.max_frame_size as u64),
         }
     }
+}
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Sink::<&T>::poll_ready(self, cx)
-    }
+impl<'a, T> Encoder<&'a T> for BincodeCodec<T>
+where
+    T: Serialize,
+{
+    type Error = FrameError;
+
+    fn encode(&mut self, item: &'a T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // The other side would reject it anyway
+        let encoded = self.bincode_options().serialize(item).map_err(|e| {
+            if matches!(*e, bincode::ErrorKind::SizeLimit) {
+                FrameError::MessageTooLarge(self.max_frame_size)
+            } else {
+                FrameError::CodingError(e)
+            }
+        })?;
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Sink::<&T>::poll_ready(self, cx)
+        debug!("Sending  {} bytes", encoded.len());
+        trace!("Sending  {:x?}", encoded);
+        dst.reserve(LEN_PREFIX_SIZE + encoded.len());
+        dst.put_u32(encoded.len() as u32);
+        dst.extend_from_slice(&encoded);
+        Ok(())
     }
 }
 
-impl<S, T> Stream for Framed<S, T>
+impl<T> Decoder for BincodeCodec<T>
 where
-    S: AsyncRead + Unpin,
-    T: DeserializeOwned + Unpin,
+    T: DeserializeOwned,
 {
-    type Item = Result<T, FrameError>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut_self = self.get_mut();
+    type Item = T;
+    type Error = FrameError;
 
-        if mut_self.read_len_len != 8 {
-            match Pin::new(&mut mut_self.stream)
-                .poll_read(cx, &mut mut_self.read_len_buffer[mut_self.read_len_len..])
-            {
-                Poll::Ready(Ok(len)) => {
-                    mut_self.read_len_len += len;
-                }
-                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FrameError::IOError(e)))),
-                Poll::Pending => return Poll::Pending,
-            }
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < LEN_PREFIX_SIZE {
+            return Ok(None);
         }
 
-        if mut_self.read_len_len == 8 {
-            let exp_len = u64::from_be_bytes(mut_self.read_len_buffer) as usize;
-            if exp_len != mut_self.read_buffer.len() {
-                mut_self.read_buffer = vec![0; exp_len as usize];
-            }
-
-            if exp_len > mut_self.read_len_actual {
-                match Pin::new(&mut mut_self.stream)
-                    .poll_read(cx, &mut mut_self.read_buffer[mut_self.read_len_actual..])
-                {
-                    Poll::Ready(Ok(len)) => {
-                        mut_self.read_len_actual += len;
-                    }
-                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FrameError::IOError(e)))),
-                    Poll::Pending => return Poll::Pending,
-                }
-            }
-
-            if exp_len == mut_self.read_len_actual {
-                debug!("Received {} bytes", exp_len);
-                trace!("Received {:x?}", mut_self.read_buffer);
-                let res = match bincode::deserialize(&mut_self.read_buffer) {
-                    Ok(decoded) => Ok(decoded),
-                    Err(e) => Err(FrameError::CodingError(e)),
-                };
+        let size = u32::from_be_bytes(
+            src[..LEN_PREFIX_SIZE]
+                .try_into()
+                .expect("Has the right length"),
+        ) as usize;
+        if size > self.max_frame_size {
+            return Err(FrameError::FrameTooLarge(size));
+        }
+        if src.len() < LEN_PREFIX_SIZE + size {
+            src.reserve(LEN_PREFIX_SIZE + size - src.len());
+            return Ok(None);
+        }
 
-                mut_self.read_len_len = 0;
-                mut_self.read_len_actual = 0;
+        src.advance(LEN_PREFIX_SIZE);
+        let frame = src.split_to(size);
+        debug!("Received {} bytes", size);
+        trace!("Received {:x?}", frame);
+        Ok(Some(self.bincode_options().deserialize(&frame)?))
+    }
+}
 
-                return Poll::Ready(Some(res));
-            }
-        }
+/// Exchanges wire format headers with the other end of `stream` and returns the format of the
+/// newest version both sides support
+pub async fn negotiate_version<S>(stream: &mut S) -> Result<WireFormat, FrameError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 6];
+    header[..4].copy_from_slice(&WIRE_MAGIC);
+    header[4..].copy_from_slice(&WIRE_VERSION.to_be_bytes());
+    stream.write_all(&header).await?;
+    stream.flush().await?;
 
-        Poll::Pending
+    let mut remote_header = [0u8; 6];
+    stream.read_exact(&mut remote_header).await?;
+    if remote_header[..4] != WIRE_MAGIC {
+        return Err(FrameError::InvalidMagic);
     }
+
+    let remote_version = u16::from_be_bytes([remote_header[4], remote_header[5]]);
+    WireFormat::from_version(min(WIRE_VERSION, remote_version))
+        .ok_or(FrameError::UnsupportedVersion(remote_version))
 }
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum FrameError {
+    #[error("Invalid message encoding: {0}")]
     CodingError(bincode::Error),
+    #[error("Frame of {0} bytes exceeds the maximum frame size")]
+    FrameTooLarge(usize),
+    /// One of our own messages exceeds the maximum frame size, so it can't be sent at all
+    #[error("Our message exceeds the maximum frame size of {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("Peer doesn't speak our wire format")]
+    InvalidMagic,
+    #[error("Unsupported wire format version {0}")]
+    UnsupportedVersion(u16),
+    #[error("IO error: {0}")]
     IOError(std::io::Error),
 }
 
+impl FrameError {
+    /// Returns true if the other side didn't follow the wire format, which only faulty peers do,
+    /// as opposed to the connection failing
+    pub fn is_protocol_violation(&self) -> bool {
+        !matches!(
+            self,
+            FrameError::IOError(_) | FrameError::MessageTooLarge(_)
+        )
+    }
+}
+
 impl From<bincode::Error> for FrameError {
     fn from(e: bincode::Error) -> Self {
         FrameError::CodingError(e)
@@ -163,3 +196,161 @@ impl From<std::io::Error> for FrameError {
         FrameError::IOError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::net::framed::{
+        negotiate_version, BincodeCodec, FrameError, WireFormat, LEN_PREFIX_SIZE, WIRE_MAGIC,
+        WIRE_VERSION,
+    };
+    use bytes::{BufMut, BytesMut};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    const MAX_FRAME_SIZE: usize = 1024;
+
+    fn random_msg(rng: &mut StdRng) -> (u64, Vec<u8>) {
+        let len = rng.gen_range(0, 512);
+        (rng.gen(), (0..len).map(|_| rng.gen()).collect())
+    }
+
+    #[test]
+    fn roundtrip_with_partial_reads() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut codec = BincodeCodec::<(u64, Vec<u8>)>::new(WireFormat::V1, MAX_FRAME_SIZE);
+
+        let msgs = (0..100).map(|_| random_msg(&mut rng)).collect::<Vec<_>>();
+        let mut encoded = BytesMut::new();
+        for msg in &msgs {
+            codec.encode(msg, &mut encoded).unwrap();
+        }
+
+        // The connection may hand us the frames in chunks of any size
+        let mut decoded = Vec::new();
+        let mut buffer = BytesMut::new();
+        while !encoded.is_empty() {
+            let chunk = std::cmp::min(rng.gen_range(1, 64), encoded.len());
+            buffer.extend_from_slice(&encoded.split_to(chunk));
+            while let Some(msg) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert_eq!(decoded, msgs);
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn random_input_never_panics() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut codec = BincodeCodec::<(u64, Vec<u8>)>::new(WireFormat::V1, MAX_FRAME_SIZE);
+
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0, 2 * MAX_FRAME_SIZE);
+            let mut buffer = (0..len).map(|_| rng.gen::<u8>()).collect::<BytesMut>();
+            // Random length prefixes would almost always be too large
+            if len >= LEN_PREFIX_SIZE && rng.gen() {
+                let size = rng.gen_range(0, len - LEN_PREFIX_SIZE + 1) as u32;
+                buffer[..LEN_PREFIX_SIZE].copy_from_slice(&size.to_be_bytes());
+            }
+
+            loop {
+                match codec.decode(&mut buffer) {
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(e) => {
+                        assert!(e.is_protocol_violation());
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reject_oversized_frames() {
+        let mut codec = BincodeCodec::<Vec<u8>>::new(WireFormat::V1, MAX_FRAME_SIZE);
+
+        // Announcing a huge frame is rejected before its content arrives
+        let mut buffer = BytesMut::new();
+        buffer.put_u32(u32::MAX);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(FrameError::FrameTooLarge(size)) if size == u32::MAX as usize
+        ));
+        assert!(buffer.capacity() < MAX_FRAME_SIZE);
+
+        // We don't send frames the other side would reject either, which isn't its fault
+        let mut encoded = BytesMut::new();
+        let err = codec
+            .encode(&vec![0u8; MAX_FRAME_SIZE], &mut encoded)
+            .unwrap_err();
+        assert!(matches!(err, FrameError::MessageTooLarge(MAX_FRAME_SIZE)));
+        assert!(!err.is_protocol_violation());
+        assert!(encoded.is_empty());
+    }
+
+    #[test]
+    fn reject_malformed_frames() {
+        let mut codec = BincodeCodec::<(u64, Vec<u8>)>::new(WireFormat::V1, MAX_FRAME_SIZE);
+        let mut encoded = BytesMut::new();
+        codec.encode(&(1, vec![1, 2, 3]), &mut encoded).unwrap();
+
+        // Trailing bytes within the frame
+        let mut buffer = encoded.clone();
+        buffer[LEN_PREFIX_SIZE - 1] += 1;
+        buffer.put_u8(0);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(FrameError::CodingError(_))
+        ));
+
+        // Vector length pointing past the end of the frame
+        let mut buffer = encoded.clone();
+        buffer[LEN_PREFIX_SIZE + 8] = 0xff;
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(FrameError::CodingError(_))
+        ));
+
+        // Connection closed in the middle of a frame
+        let mut buffer = encoded.split_to(encoded.len() - 1);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(codec.decode_eof(&mut buffer).is_err());
+    }
+
+    #[tokio::test]
+    async fn negotiate_wire_version() {
+        let (mut a, mut b) = duplex(64);
+        let (version_a, version_b) =
+            tokio::join!(negotiate_version(&mut a), negotiate_version(&mut b));
+        assert_eq!(version_a.unwrap().version(), WIRE_VERSION);
+        assert_eq!(version_b.unwrap().version(), WIRE_VERSION);
+
+        // A newer peer talks to us in our version
+        let (mut a, mut b) = duplex(64);
+        let newer_peer = async {
+            b.write_all(&WIRE_MAGIC).await.unwrap();
+            b.write_u16(WIRE_VERSION + 1).await.unwrap();
+            let mut header = [0u8; 6];
+            b.read_exact(&mut header).await.unwrap();
+        };
+        let (version, _) = tokio::join!(negotiate_version(&mut a), newer_peer);
+        assert_eq!(version.unwrap().version(), WIRE_VERSION);
+
+        // Something that isn't a peer is rejected
+        let (mut a, mut b) = duplex(64);
+        let (version, _) = tokio::join!(negotiate_version(&mut a), b.write_all(b"GET / "));
+        assert!(matches!(version, Err(FrameError::InvalidMagic)));
+
+        // So is a peer that is too old
+        let (mut a, mut b) = duplex(64);
+        let old_peer = async {
+            b.write_all(&WIRE_MAGIC).await.unwrap();
+            b.write_u16(0).await.unwrap();
+        };
+        let (version, _) = tokio::join!(negotiate_version(&mut a), old_peer);
+        assert!(matches!(version, Err(FrameError::UnsupportedVersion(0))));
+    }
+}
@@ -0,0 +1,90 @@
+use minimint_api::PeerId;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Key and certificates used to authenticate and encrypt the connections between peers.
+///
+/// Every peer has a self-signed certificate that all other peers know from the config. Both ends
+/// of a connection have to present the certificate of a peer, which is how we learn whom we are
+/// talking to.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub our_private_key: PrivateKey,
+    /// Certificates of all peers including our own
+    pub peer_certs: BTreeMap<PeerId, Certificate>,
+}
+
+impl TlsConfig {
+    pub(crate) fn acceptor(&self, identity: PeerId) -> TlsAcceptor {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(self.root_cert_store()))
+            .with_single_cert(vec![self.our_cert(identity)], self.our_private_key.clone())
+            .expect("Invalid TLS key");
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    pub(crate) fn connector(&self, identity: PeerId) -> TlsConnector {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(self.root_cert_store())
+            .with_single_cert(vec![self.our_cert(identity)], self.our_private_key.clone())
+            .expect("Invalid TLS key");
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Returns the peer the end-entity certificate `cert` belongs to.
+    ///
+    /// Verifying the certificate chain isn't enough to authenticate a peer: any peer's certificate
+    /// is a trust anchor, so its key could sign certificates for the names of other peers.
+    pub(crate) fn authenticate(&self, certs: Option<&[Certificate]>) -> Option<PeerId> {
+        let cert = certs?.first()?;
+        self.peer_certs
+            .iter()
+            .find(|(_, peer_cert)| *peer_cert == cert)
+            .map(|(peer, _)| *peer)
+    }
+
+    fn our_cert(&self, identity: PeerId) -> Certificate {
+        self.peer_certs
+            .get(&identity)
+            .expect("Our own certificate is missing")
+            .clone()
+    }
+
+    fn root_cert_store(&self) -> RootCertStore {
+        let mut store = RootCertStore::empty();
+        for cert in self.peer_certs.values() {
+            store.add(cert).expect("Invalid peer certificate");
+        }
+        store
+    }
+}
+
+/// Name a peer's certificate is issued for, TLS needs one to verify the certificate against
+pub fn dns_name(peer: PeerId) -> String {
+    format!("peer-{}", peer)
+}
+
+pub(crate) fn server_name(peer: PeerId) -> ServerName {
+    ServerName::try_from(dns_name(peer).as_str()).expect("Is a valid DNS name")
+}
+
+/// Generates a self-signed certificate and the corresponding private key for `peer`
+pub fn gen_cert(peer: PeerId) -> (Certificate, PrivateKey) {
+    let cert = rcgen::generate_simple_self_signed(vec![dns_name(peer)])
+        .expect("Can't fail for valid DNS names");
+    (
+        Certificate(
+            cert.serialize_der()
+                .expect("Can't fail for self-signed certificates"),
+        ),
+        PrivateKey(cert.serialize_private_key_der()),
+    )
+}
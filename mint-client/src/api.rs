@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use bitcoin::Address;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::ln::{ContractAccount, ContractId, IncomingContractOffer};
+use minimint_api::misbehavior::PeerFaultCount;
+use minimint_api::outcome::TransactionStatus;
+use minimint_api::reserves::SignedReservesReport;
+use minimint_api::transaction::{PegOutFees, Transaction};
+use minimint_api::TransactionId;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+/// Client API of a single guardian. [`crate::MintClient`] talks to the federation by querying the
+/// API of every guardian through this trait.
+#[async_trait]
+pub trait GuardianApi: Send + Sync {
+    /// Hands `tx` to the guardian, which proposes it to the federation if it is valid
+    async fn submit_transaction(&self, tx: &Transaction) -> Result<(), ApiError>;
+
+    async fn fetch_transaction(&self, txid: TransactionId) -> Result<TransactionStatus, ApiError>;
+
+    async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ApiError>;
+
+    async fn fetch_offer(&self, payment_hash: Sha256) -> Result<IncomingContractOffer, ApiError>;
+
+    /// Consensus block height that contract timelocks are compared against
+    async fn fetch_block_height(&self) -> Result<u32, ApiError>;
+
+    async fn fetch_peg_out_fees(&self, address: &Address) -> Result<PegOutFees, ApiError>;
+
+    /// Reserves report signed by this guardian
+    async fn fetch_reserves(&self) -> Result<SignedReservesReport, ApiError>;
+
+    /// Faults this guardian caught its peers committing
+    async fn fetch_misbehavior(&self) -> Result<Vec<PeerFaultCount>, ApiError>;
+}
+
+/// Talks to the HTTP API a guardian serves at `url`
+pub struct HttpApi {
+    url: String,
+    http_client: reqwest::Client,
+}
+
+impl HttpApi {
+    pub fn new(url: String) -> HttpApi {
+        HttpApi {
+            url,
+            http_client: Default::default(),
+        }
+    }
+
+    async fn get<O: DeserializeOwned>(&self, path: &str) -> Result<O, ApiError> {
+        self.request(self.http_client.get(&format!("{}/{}", self.url, path)))
+            .await?
+            .json()
+            .await
+            .map_err(ApiError::HttpError)
+    }
+
+    async fn request(&self, request: RequestBuilder) -> Result<reqwest::Response, ApiError> {
+        let response = request.send().await?;
+        match response.status() {
+            StatusCode::OK => Ok(response),
+            StatusCode::NOT_FOUND => Err(ApiError::NotFound),
+            status => {
+                let error = response.text().await.unwrap_or_default();
+                Err(ApiError::Rejected(format!("{}: {}", status, error)))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl GuardianApi for HttpApi {
+    async fn submit_transaction(&self, tx: &Transaction) -> Result<(), ApiError> {
+        let request = self
+            .http_client
+            .put(&format!("{}/transaction", self.url))
+            .json(tx);
+        self.request(request).await?;
+        Ok(())
+    }
+
+    async fn fetch_transaction(&self, txid: TransactionId) -> Result<TransactionStatus, ApiError> {
+        self.get(&format!("transaction/{}", txid)).await
+    }
+
+    async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ApiError> {
+        self.get(&format!("contract/{}", contract_id)).await
+    }
+
+    async fn fetch_offer(&self, payment_hash: Sha256) -> Result<IncomingContractOffer, ApiError> {
+        self.get(&format!("offer/{}", payment_hash)).await
+    }
+
+    async fn fetch_block_height(&self) -> Result<u32, ApiError> {
+        self.get("block_height").await
+    }
+
+    async fn fetch_peg_out_fees(&self, address: &Address) -> Result<PegOutFees, ApiError> {
+        self.get(&format!("peg_out_fees/{}", address)).await
+    }
+
+    async fn fetch_reserves(&self) -> Result<SignedReservesReport, ApiError> {
+        self.get("reserves").await
+    }
+
+    async fn fetch_misbehavior(&self) -> Result<Vec<PeerFaultCount>, ApiError> {
+        self.get("misbehavior").await
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("HTTP error: {0}")]
+    HttpError(reqwest::Error),
+    #[error("The guardian doesn't know the requested item")]
+    NotFound,
+    #[error("The guardian rejected the request: {0}")]
+    Rejected(String),
+    #[error("The guardian can't be reached")]
+    Unavailable,
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::HttpError(e)
+    }
+}
@@ -1,27 +1,43 @@
+pub mod api;
+pub mod ln;
+
+use crate::api::{ApiError, GuardianApi, HttpApi};
+use crate::ln::{CreateInvoicePayload, CreateInvoiceResponse};
 use bitcoin::{Address, Script, Transaction};
+use bitcoin_hashes::sha256::Hash as Sha256;
 use bitcoin_hashes::Hash as BitcoinHash;
 use futures::future::JoinAll;
+use futures::Future;
+use lightning_invoice::Invoice;
 use minimint::config::ClientConfig;
 use minimint_api::db::batch::{BatchItem, DbBatch};
 use minimint_api::db::{
     Database, DatabaseKey, DatabaseKeyPrefix, DatabaseKeyPrefixConst, DecodingError, RawDatabase,
 };
 use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::outcome::{Final, OutputOutcome, TransactionStatus};
+use minimint_api::ln::{
+    Contract, ContractAccount, ContractId, ContractInput, ContractOutcome, ContractOutput,
+    IncomingContract, IncomingContractOffer, LightningOutput, OutgoingContract, Preimage,
+};
+use minimint_api::module::registry::{MODULE_KEY_LN, MODULE_KEY_MINT, MODULE_KEY_WALLET};
+use minimint_api::outcome::{OutputOutcome, PegOutOutcome, TransactionStatus};
+use minimint_api::reserves::{ReserveUtxo, ReservesReport};
 use minimint_api::transaction as mint_tx;
-use minimint_api::transaction::OutPoint;
+use minimint_api::transaction::{OutPoint, PegOutFees};
 use minimint_api::{
-    Amount, Coin, CoinNonce, Coins, InvalidAmountTierError, Keys, PegInProof, PegInProofError,
-    SigResponse, SignRequest, TransactionId, Tweakable, TxOutProof,
+    Amount, Coin, CoinNonce, Coins, InvalidAmountTierError, Keys, PeerId, PegInProof,
+    PegInProofError, SigResponse, SignRequest, TransactionId, Tweakable, TxOutProof,
 };
+use minimint_wallet::bitcoind::{BitcoindRpc, RpcError};
 use miniscript::DescriptorTrait;
 use musig::rng_adapt::RngAdaptor;
 use rand::seq::SliceRandom;
 use rand::{CryptoRng, RngCore};
-use reqwest::{RequestBuilder, StatusCode};
+use reqwest::StatusCode;
 use secp256k1::{All, Secp256k1};
-use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
 use std::sync::Arc;
 use tbs::{blind_message, unblind_signature, AggregatePublicKey, BlindedMessage, BlindingKey};
 use thiserror::Error;
@@ -31,11 +47,19 @@ use tracing::debug;
 pub const DB_PREFIX_COIN: u8 = 0x20;
 pub const DB_PREFIX_OUTPUT_FINALIZATION_DATA: u8 = 0x21;
 pub const DB_PREFIX_PEG_IN: u8 = 0x22;
+pub const DB_PREFIX_OUTGOING_CONTRACT: u8 = 0x23;
+pub const DB_PREFIX_INCOMING_PAYMENT: u8 = 0x24;
+
+/// Number of blocks offers published by the client stay valid, about a day
+const OFFER_LIFETIME: u32 = 144;
 
 pub struct MintClient {
     cfg: ClientConfig,
     db: Arc<dyn RawDatabase>,
-    http_client: reqwest::Client, // TODO: use trait object
+    /// API of every guardian, in the same order as the config's API endpoints
+    api: Vec<Box<dyn GuardianApi>>,
+    /// Used to talk to lightning gateways
+    http_client: reqwest::Client,
     secp: Secp256k1<All>,
 }
 
@@ -67,6 +91,23 @@ pub struct SpendableCoin {
     pub spend_key: musig::SecKey,
 }
 
+/// Result of checking the federation's reserves report against the bitcoin chain
+#[derive(Debug, Clone)]
+pub struct ReservesAudit {
+    pub report: ReservesReport,
+    /// Reported UTXOs that aren't unspent on-chain or don't match the reported script or amount
+    pub missing: Vec<ReserveUtxo>,
+    /// Value of the reported UTXOs that were found on-chain
+    pub confirmed_assets: bitcoin::Amount,
+}
+
+impl ReservesAudit {
+    /// Whether the confirmed on-chain assets cover all outstanding e-cash
+    pub fn is_backed(&self) -> bool {
+        Amount::from_sat(self.confirmed_assets.as_sat()) >= self.report.liabilities
+    }
+}
+
 #[derive(Debug, Clone, Encodable, Decodable)]
 pub struct OutputFinalizationKey(OutPoint);
 
@@ -81,6 +122,42 @@ impl DatabaseKeyPrefixConst for OutputFinalizationKeyPrefix {
     const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_FINALIZATION_DATA;
 }
 
+/// Outgoing contracts funded by us, kept to be able to refund them
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct OutgoingContractKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for OutgoingContractKey {
+    const DB_PREFIX: u8 = DB_PREFIX_OUTGOING_CONTRACT;
+}
+
+/// Everything needed to refund an outgoing contract after its timelock expired
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct OutgoingContractData {
+    pub contract: OutgoingContract,
+    pub amount: Amount,
+    /// Secret key corresponding to the contract's user key
+    pub user_key: musig::SecKey,
+}
+
+/// Incoming lightning payments we published an offer for, indexed by payment hash
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct IncomingPaymentKey(pub Sha256);
+
+impl DatabaseKeyPrefixConst for IncomingPaymentKey {
+    const DB_PREFIX: u8 = DB_PREFIX_INCOMING_PAYMENT;
+}
+
+/// Everything needed to claim an incoming contract once the gateway funded it
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct IncomingPaymentData {
+    pub preimage: Preimage,
+    pub amount: Amount,
+    /// Secret key corresponding to the offer's user key
+    pub user_key: musig::SecKey,
+    /// Contract the gateway promised to fund, known once we requested an invoice
+    pub contract: Option<IncomingContract>,
+}
+
 #[derive(Debug, Clone)]
 pub struct CoinKey {
     amount: Amount,
@@ -100,9 +177,26 @@ pub struct PegInPrefixKey;
 
 impl MintClient {
     pub fn new(cfg: ClientConfig, db: Arc<dyn RawDatabase>, secp: Secp256k1<All>) -> Self {
+        let api = cfg
+            .api_endpoints
+            .iter()
+            .map(|url| Box::new(HttpApi::new(url.clone())) as Box<dyn GuardianApi>)
+            .collect();
+        MintClient::new_with_api(cfg, db, api, secp)
+    }
+
+    /// Creates a client that reaches the guardians through `api` instead of their HTTP endpoints,
+    /// e.g. to talk to a federation running in the same process
+    pub fn new_with_api(
+        cfg: ClientConfig,
+        db: Arc<dyn RawDatabase>,
+        api: Vec<Box<dyn GuardianApi>>,
+        secp: Secp256k1<All>,
+    ) -> Self {
         MintClient {
             cfg,
             db,
+            api,
             http_client: Default::default(),
             secp,
         }
@@ -115,21 +209,10 @@ impl MintClient {
     ) -> Result<(), ClientError> {
         // Try all mints in random order, break early if enough could be reached
         let mut successes: usize = 0;
-        for url in self
-            .cfg
-            .api_endpoints
-            .choose_multiple(&mut rng, self.cfg.api_endpoints.len())
-        {
-            let res = self
-                .http_client
-                .put(&format!("{}/transaction", url))
-                .json(&tx)
-                .send()
-                .await
-                .expect("API error");
-
-            if res.status() == StatusCode::OK {
-                successes += 1;
+        for api in self.api.choose_multiple(&mut rng, self.api.len()) {
+            match api.submit_transaction(&tx).await {
+                Ok(()) => successes += 1,
+                Err(e) => debug!("Could not submit transaction {}: {}", tx.tx_hash(), e),
             }
 
             if successes >= 2 {
@@ -188,14 +271,13 @@ impl MintClient {
         let (coin_finalization_data, sig_req) =
             CoinFinalizationData::new(amount, &self.cfg.mint.tbs_pks, &mut rng);
 
-        let inputs = vec![mint_tx::Input::PegIn(peg_in_proof)];
-        let outputs = vec![mint_tx::Output::Coins(
-            sig_req
-                .0
-                .into_iter()
-                .map(|(amt, token)| (amt, mint_tx::BlindToken(token)))
-                .collect(),
-        )];
+        let blind_tokens: Coins<mint_tx::BlindToken> = sig_req
+            .0
+            .into_iter()
+            .map(|(amt, token)| (amt, mint_tx::BlindToken(token)))
+            .collect();
+        let inputs = vec![mint_tx::Input::new(MODULE_KEY_WALLET, &peg_in_proof)];
+        let outputs = vec![mint_tx::Output::new(MODULE_KEY_MINT, &blind_tokens)];
 
         let peg_in_req_sig = {
             let hash = mint_tx::Transaction::tx_hash_from_parts(&inputs, &outputs);
@@ -236,20 +318,15 @@ impl MintClient {
             ))?;
 
         let tx_outcome = self
-            .query_any_mint::<TransactionStatus, _>(|client, mint| {
-                let url = format!("{}/transaction/{}", mint, outpoint.txid);
-                client.get(&url)
-            })
+            .query_any_mint(|api| api.fetch_transaction(outpoint.txid))
             .await?;
 
         // TODO: check another mint if the answer was malicious
-        if !tx_outcome.is_final() {
-            return Err(ClientError::OutputNotReadyYet(outpoint));
-        }
-
+        // Only our own output has to be final, other outputs of the same transaction (e.g. a
+        // peg-out waiting for confirmation) may take a lot longer.
         let outputs = match tx_outcome {
             TransactionStatus::AwaitingConsensus => {
-                unreachable!()
+                return Err(ClientError::OutputNotReadyYet(outpoint));
             }
             TransactionStatus::Error(e) => {
                 panic!("Mint error: {}", e)
@@ -263,6 +340,7 @@ impl MintClient {
             .and_then(|outcome| match outcome {
                 OutputOutcome::Mint(mo) => Some(mo),
                 OutputOutcome::Wallet(_) => None,
+                OutputOutcome::LN(_) => None,
             })
             .ok_or(ClientError::InvalidOutcomeWrongStructure(outpoint))?
             .clone()
@@ -289,19 +367,18 @@ impl MintClient {
         Ok(())
     }
 
-    async fn query_any_mint<O, F>(&self, query_builder: F) -> Result<O, ClientError>
+    async fn query_any_mint<'a, O, F, Fut>(&'a self, query: F) -> Result<O, ClientError>
     where
-        F: Fn(&reqwest::Client, &str) -> RequestBuilder,
-        O: DeserializeOwned,
+        F: Fn(&'a dyn GuardianApi) -> Fut,
+        Fut: Future<Output = Result<O, ApiError>>,
     {
-        assert!(!self.cfg.api_endpoints.is_empty());
+        assert!(!self.api.is_empty());
 
         // TODO: add per mint timeout
         let mut requests = self
-            .cfg
-            .api_endpoints
+            .api
             .iter()
-            .map(|mint| query_builder(&self.http_client, mint.as_str()).send())
+            .map(|api| Box::pin(query(api.as_ref())))
             .collect::<Vec<_>>();
 
         loop {
@@ -310,15 +387,9 @@ impl MintClient {
             requests = remaining_requests;
 
             match res {
-                Ok(resp) => match resp.json().await {
-                    Ok(val) => return Ok(val),
-                    Err(_) => {
-                        if requests.is_empty() {
-                            return Err(ClientError::MintError);
-                        }
-                    }
-                },
-                Err(_) => {
+                Ok(val) => return Ok(val),
+                Err(e) => {
+                    debug!("Guardian API request failed: {}", e);
                     if requests.is_empty() {
                         return Err(ClientError::MintError);
                     }
@@ -393,8 +464,9 @@ impl MintClient {
             .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
             .unzip();
 
-        let inputs = vec![mint_tx::Input::Coins(coins)];
-        let outputs = vec![mint_tx::Output::Coins(sig_req.into())];
+        let blind_tokens: Coins<mint_tx::BlindToken> = sig_req.into();
+        let inputs = vec![mint_tx::Input::new(MODULE_KEY_MINT, &coins)];
+        let outputs = vec![mint_tx::Output::new(MODULE_KEY_MINT, &blind_tokens)];
 
         // TODO: abstract away tx building somehow
         let signature = {
@@ -422,15 +494,27 @@ impl MintClient {
         Ok(tx_id)
     }
 
+    /// Withdraws `amt` to `address`, paying the currently quoted on-chain fee on top. Priority
+    /// peg-outs additionally pay the federation's priority fee to be included in the next peg-out
+    /// transaction.
     pub async fn peg_out<R: RngCore + CryptoRng>(
         &self,
         amt: bitcoin::Amount,
         address: bitcoin::Address,
+        priority: bool,
         mut rng: R,
     ) -> Result<TransactionId, ClientError> {
+        let priority_fee = if priority {
+            self.cfg.wallet.peg_out_policy.priority_fee
+        } else {
+            bitcoin::Amount::from_sat(0)
+        };
+        let fee = self.fetch_peg_out_fees(&address).await?.fee;
         let coins = self
             .coins()
-            .select_coins(Amount::from(amt) + self.cfg.fee_consensus.fee_peg_out_abs)
+            .select_coins(
+                Amount::from(amt + priority_fee + fee) + self.cfg.fee_consensus.fee_peg_out_abs,
+            )
             .ok_or(ClientError::NotEnoughCoins)?;
 
         // mark spent in DB
@@ -442,11 +526,237 @@ impl MintClient {
             .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
             .unzip();
 
-        let inputs = vec![mint_tx::Input::Coins(coins)];
-        let outputs = vec![mint_tx::Output::PegOut(mint_tx::PegOut {
-            recipient: address,
-            amount: amt,
-        })];
+        let inputs = vec![mint_tx::Input::new(MODULE_KEY_MINT, &coins)];
+        let outputs = vec![mint_tx::Output::new(
+            MODULE_KEY_WALLET,
+            &mint_tx::PegOut {
+                recipient: address,
+                amount: amt,
+                priority_fee,
+                fee,
+            },
+        )];
+
+        let signature = {
+            let hash = mint_tx::Transaction::tx_hash_from_parts(&inputs, &outputs);
+            musig::sign(hash.into_inner(), spend_keys.iter(), RngAdaptor(&mut rng))
+        };
+
+        let transaction = mint_tx::Transaction {
+            inputs,
+            outputs,
+            signature,
+        };
+        let tx_id = transaction.tx_hash();
+
+        self.send_tx(transaction, &mut rng).await?;
+        Ok(tx_id)
+    }
+
+    /// Fetches the progress of the peg-out at `out_point`, i.e. whether it was already included in a
+    /// bitcoin transaction and if that transaction confirmed
+    pub async fn fetch_peg_out_status(
+        &self,
+        out_point: OutPoint,
+    ) -> Result<PegOutOutcome, ClientError> {
+        let tx_outcome = self
+            .query_any_mint(|api| api.fetch_transaction(out_point.txid))
+            .await?;
+
+        let outputs = match tx_outcome {
+            TransactionStatus::AwaitingConsensus => {
+                return Err(ClientError::OutputNotReadyYet(out_point));
+            }
+            TransactionStatus::Error(e) => return Err(ClientError::TransactionError(e)),
+            TransactionStatus::Accepted { outputs, .. } => outputs,
+        };
+
+        match outputs.get(out_point.out_idx as usize) {
+            Some(OutputOutcome::Wallet(outcome)) => Ok(*outcome),
+            _ => Err(ClientError::InvalidOutcomeWrongStructure(out_point)),
+        }
+    }
+
+    /// Fetches the on-chain fee a peg-out to `address` currently has to pay
+    pub async fn fetch_peg_out_fees(&self, address: &Address) -> Result<PegOutFees, ClientError> {
+        self.query_any_mint(|api| api.fetch_peg_out_fees(address))
+            .await
+    }
+
+    /// Fetches the federation's reserves report from all guardians and returns it if a threshold
+    /// of them signed the same report
+    pub async fn fetch_reserves(&self) -> Result<ReservesReport, ClientError> {
+        let responses = self
+            .api
+            .iter()
+            .map(|api| api.fetch_reserves())
+            .collect::<JoinAll<_>>()
+            .await;
+
+        let mut signers = HashMap::<ReservesReport, BTreeSet<PeerId>>::new();
+        for signed in responses.into_iter().filter_map(Result::ok) {
+            let peer_key = match self.cfg.wallet.peer_peg_in_keys.get(&signed.peer) {
+                Some(key) => key,
+                None => continue,
+            };
+            if self
+                .secp
+                .verify(&signed.report.message(), &signed.signature, &peer_key.key)
+                .is_err()
+            {
+                debug!("Guardian {} sent an invalid reserves report", signed.peer);
+                continue;
+            }
+            signers
+                .entry(signed.report)
+                .or_default()
+                .insert(signed.peer);
+        }
+
+        signers
+            .into_iter()
+            .find(|(_, peers)| peers.len() >= self.cfg.wallet.threshold)
+            .map(|(report, _)| report)
+            .ok_or(ClientError::ReservesNotAttested)
+    }
+
+    /// Checks the federation's reserves report against the bitcoin chain as seen by `rpc`. Every
+    /// reported UTXO's script is derived from the peg-in descriptor and its tweak, so UTXOs that
+    /// are unspent and locked to that script are provably controlled by the federation.
+    ///
+    /// UTXOs spent by peg-out transactions that confirmed after the report's block height show up
+    /// as missing, so the report should be fetched shortly before auditing it.
+    pub async fn audit(&self, rpc: &dyn BitcoindRpc) -> Result<ReservesAudit, ClientError> {
+        let report = self.fetch_reserves().await?;
+
+        let mut missing = Vec::new();
+        let mut confirmed_assets = bitcoin::Amount::from_sat(0);
+        for utxo in &report.utxos {
+            let script_pubkey = self
+                .cfg
+                .wallet
+                .peg_in_descriptor
+                .tweak(&utxo.tweak, &self.secp)
+                .script_pubkey();
+            let on_chain = rpc
+                .get_unspent_output(&utxo.out_point, &script_pubkey)
+                .await
+                .map_err(ClientError::BitcoinBackendError)?;
+
+            match on_chain {
+                Some(tx_out)
+                    if tx_out.script_pubkey == script_pubkey
+                        && tx_out.value == utxo.amount.as_sat() =>
+                {
+                    confirmed_assets = confirmed_assets + utxo.amount;
+                }
+                _ => missing.push(utxo.clone()),
+            }
+        }
+
+        Ok(ReservesAudit {
+            report,
+            missing,
+            confirmed_assets,
+        })
+    }
+
+    /// Fetches the consensus block height of the federation that lightning contract timelocks are
+    /// compared against
+    pub async fn fetch_block_height(&self) -> Result<u32, ClientError> {
+        self.query_any_mint(|api| api.fetch_block_height()).await
+    }
+
+    pub async fn fetch_contract_account(
+        &self,
+        contract_id: ContractId,
+    ) -> Result<ContractAccount, ClientError> {
+        self.query_any_mint(|api| api.fetch_contract_account(contract_id))
+            .await
+    }
+
+    /// Locks `amount` in an outgoing contract that `gateway_key` can claim by revealing the
+    /// preimage of `payment_hash` and that we can take back once `timelock` is reached.
+    pub async fn fund_outgoing_contract<R: RngCore + CryptoRng>(
+        &self,
+        gateway_key: musig::PubKey,
+        payment_hash: Sha256,
+        amount: Amount,
+        timelock: u32,
+        mut rng: R,
+    ) -> Result<(ContractId, TransactionId), ClientError> {
+        let coins = self
+            .coins()
+            .select_coins(amount + self.cfg.fee_consensus.fee_contract_output_abs)
+            .ok_or(ClientError::NotEnoughCoins)?;
+
+        let user_key = musig::SecKey::random(RngAdaptor(&mut rng));
+        let contract = OutgoingContract {
+            hash: payment_hash,
+            gateway_key,
+            timelock,
+            user_key: user_key.to_public(),
+        };
+        let contract_id = Contract::Outgoing(contract.clone()).contract_id();
+
+        // Store the refund data before spending any coins so the funds can't get lost
+        self.db
+            .insert_entry(
+                &OutgoingContractKey(contract_id),
+                &OutgoingContractData {
+                    contract: contract.clone(),
+                    amount,
+                    user_key,
+                },
+            )
+            .expect("DB error");
+
+        let tx_id = self
+            .fund_contract(coins, Contract::Outgoing(contract), amount, rng)
+            .await?;
+        Ok((contract_id, tx_id))
+    }
+
+    /// Locks `amount` in an incoming contract for an offer published by a user. The user can claim
+    /// it by revealing the preimage, the owner of the contract's gateway key can take it back once
+    /// its timelock is reached.
+    pub async fn fund_incoming_contract<R: RngCore + CryptoRng>(
+        &self,
+        contract: IncomingContract,
+        amount: Amount,
+        rng: R,
+    ) -> Result<TransactionId, ClientError> {
+        let coins = self
+            .coins()
+            .select_coins(amount + self.cfg.fee_consensus.fee_contract_output_abs)
+            .ok_or(ClientError::NotEnoughCoins)?;
+
+        self.fund_contract(coins, Contract::Incoming(contract), amount, rng)
+            .await
+    }
+
+    /// Spends `coins` to lock `amount` in `contract`, the coins have to cover the output fee
+    async fn fund_contract<R: RngCore + CryptoRng>(
+        &self,
+        coins: Coins<SpendableCoin>,
+        contract: Contract,
+        amount: Amount,
+        mut rng: R,
+    ) -> Result<TransactionId, ClientError> {
+        // mark spent in DB
+        // TODO: make contingent on success of payment
+        self.spend_coins(&coins);
+
+        let (spend_keys, coins): (Vec<_>, Coins<_>) = coins
+            .into_iter()
+            .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
+            .unzip();
+
+        let inputs = vec![mint_tx::Input::new(MODULE_KEY_MINT, &coins)];
+        let outputs = vec![mint_tx::Output::new(
+            MODULE_KEY_LN,
+            &LightningOutput::Contract(ContractOutput { amount, contract }),
+        )];
 
         let signature = {
             let hash = mint_tx::Transaction::tx_hash_from_parts(&inputs, &outputs);
@@ -464,6 +774,299 @@ impl MintClient {
         Ok(tx_id)
     }
 
+    /// Spends a contract account into newly issued coins. `witness` and `key` have to fit the
+    /// contract, see [`minimint_api::ln`]. The coins can be fetched from the returned out point
+    /// using [`MintClient::fetch_coins`] once the transaction was accepted.
+    pub async fn claim_contract<R: RngCore + CryptoRng>(
+        &self,
+        account: ContractAccount,
+        witness: Option<Preimage>,
+        key: &musig::SecKey,
+        mut rng: R,
+    ) -> Result<OutPoint, ClientError> {
+        let transaction = self.create_claim_transaction(account, witness, key, &mut rng)?;
+        let out_point = OutPoint {
+            txid: transaction.tx_hash(),
+            out_idx: 0,
+        };
+        self.send_tx(transaction, &mut rng).await?;
+        Ok(out_point)
+    }
+
+    /// Creates the transaction [`MintClient::claim_contract`] submits without submitting it. The
+    /// coins are issued to its first output and can be fetched once the transaction was accepted.
+    /// Submitting the same transaction again is harmless, which allows callers to persist it and
+    /// retry after a crash without claiming twice.
+    pub fn create_claim_transaction<R: RngCore + CryptoRng>(
+        &self,
+        account: ContractAccount,
+        witness: Option<Preimage>,
+        key: &musig::SecKey,
+        mut rng: R,
+    ) -> Result<mint_tx::Transaction, ClientError> {
+        let amount = account
+            .amount
+            .saturating_sub(self.cfg.fee_consensus.fee_contract_input_abs);
+        if amount == Amount::ZERO {
+            return Err(ClientError::ContractAmountTooSmall);
+        }
+
+        let (coin_finalization_data, sig_req) =
+            CoinFinalizationData::new(amount, &self.cfg.mint.tbs_pks, &mut rng);
+
+        let blind_tokens: Coins<mint_tx::BlindToken> = sig_req.into();
+        let inputs = vec![mint_tx::Input::new(
+            MODULE_KEY_LN,
+            &ContractInput {
+                contract: account.contract,
+                amount: account.amount,
+                witness,
+            },
+        )];
+        let outputs = vec![mint_tx::Output::new(MODULE_KEY_MINT, &blind_tokens)];
+
+        let signature = {
+            let hash = mint_tx::Transaction::tx_hash_from_parts(&inputs, &outputs);
+            musig::sign(
+                hash.into_inner(),
+                std::iter::once(key),
+                RngAdaptor(&mut rng),
+            )
+        };
+
+        let transaction = mint_tx::Transaction {
+            inputs,
+            outputs,
+            signature,
+        };
+
+        let out_point = OutPoint {
+            txid: transaction.tx_hash(),
+            out_idx: 0,
+        };
+        self.db
+            .insert_entry(&OutputFinalizationKey(out_point), &coin_finalization_data)
+            .expect("DB error");
+
+        Ok(transaction)
+    }
+
+    /// Takes back the funds of an outgoing contract whose timelock expired without the gateway
+    /// claiming it
+    pub async fn refund_outgoing_contract<R: RngCore + CryptoRng>(
+        &self,
+        contract_id: ContractId,
+        rng: R,
+    ) -> Result<OutPoint, ClientError> {
+        let data = self
+            .db
+            .get_value::<_, OutgoingContractData>(&OutgoingContractKey(contract_id))
+            .expect("DB error")
+            .ok_or(ClientError::UnknownOutgoingContract(contract_id))?;
+
+        let account = ContractAccount {
+            amount: data.amount,
+            contract: Contract::Outgoing(data.contract),
+            outcome: ContractOutcome::Funded,
+        };
+        self.claim_contract(account, None, &data.user_key, rng)
+            .await
+    }
+
+    pub async fn fetch_offer(
+        &self,
+        payment_hash: Sha256,
+    ) -> Result<IncomingContractOffer, ClientError> {
+        self.query_any_mint(|api| api.fetch_offer(payment_hash))
+            .await
+    }
+
+    /// Publishes an offer to sell the preimage of a new payment hash for `amount`, which allows a
+    /// gateway to fund an incoming contract for it. Returns the payment hash and the id of the
+    /// transaction publishing the offer.
+    pub async fn create_offer<R: RngCore + CryptoRng>(
+        &self,
+        amount: Amount,
+        mut rng: R,
+    ) -> Result<(Sha256, TransactionId), ClientError> {
+        let mut preimage = [0u8; 32];
+        rng.fill_bytes(&mut preimage);
+        let preimage = Preimage(preimage);
+        let payment_hash = preimage.hash();
+        let user_key = musig::SecKey::random(RngAdaptor(&mut rng));
+
+        let offer = IncomingContractOffer {
+            amount,
+            hash: payment_hash,
+            user_key: user_key.to_public(),
+            expiry_height: self.fetch_block_height().await? + OFFER_LIFETIME,
+        };
+
+        // Offers don't move funds, so unless there is a fee to pay the transaction has no inputs
+        let coins = self
+            .coins()
+            .select_coins(self.cfg.fee_consensus.fee_offer_abs)
+            .ok_or(ClientError::NotEnoughCoins)?;
+
+        self.db
+            .insert_entry(
+                &IncomingPaymentKey(payment_hash),
+                &IncomingPaymentData {
+                    preimage,
+                    amount,
+                    user_key,
+                    contract: None,
+                },
+            )
+            .expect("DB error");
+
+        self.spend_coins(&coins);
+
+        let (spend_keys, coins): (Vec<_>, Coins<_>) = coins
+            .into_iter()
+            .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
+            .unzip();
+
+        let inputs = if coins.coin_count() == 0 {
+            vec![]
+        } else {
+            vec![mint_tx::Input::new(MODULE_KEY_MINT, &coins)]
+        };
+        let outputs = vec![mint_tx::Output::new(
+            MODULE_KEY_LN,
+            &LightningOutput::Offer(offer),
+        )];
+
+        let signature = {
+            let hash = mint_tx::Transaction::tx_hash_from_parts(&inputs, &outputs);
+            musig::sign(hash.into_inner(), spend_keys.iter(), RngAdaptor(&mut rng))
+        };
+
+        let transaction = mint_tx::Transaction {
+            inputs,
+            outputs,
+            signature,
+        };
+        let tx_id = transaction.tx_hash();
+
+        self.send_tx(transaction, &mut rng).await?;
+        Ok((payment_hash, tx_id))
+    }
+
+    /// Asks `gateway` for an invoice paying into an incoming contract for our offer with
+    /// `payment_hash`. The offer has to be accepted by the federation already.
+    pub async fn request_invoice(
+        &self,
+        gateway: &str,
+        payment_hash: Sha256,
+        description: String,
+    ) -> Result<(Invoice, ContractId), ClientError> {
+        let mut data = self
+            .db
+            .get_value::<_, IncomingPaymentData>(&IncomingPaymentKey(payment_hash))
+            .expect("DB error")
+            .ok_or(ClientError::UnknownIncomingPayment(payment_hash))?;
+
+        let response = self
+            .http_client
+            .post(&format!("{}/create_invoice", gateway))
+            .json(&CreateInvoicePayload {
+                payment_hash,
+                description,
+            })
+            .send()
+            .await
+            .map_err(|e| ClientError::GatewayError(e.to_string()))?;
+        if response.status() != StatusCode::OK {
+            let error = response.text().await.unwrap_or_default();
+            return Err(ClientError::GatewayError(error));
+        }
+        let CreateInvoiceResponse { invoice, contract } = response
+            .json()
+            .await
+            .map_err(|e| ClientError::GatewayError(e.to_string()))?;
+
+        let invoice = Invoice::from_str(&invoice)
+            .map_err(|_| ClientError::InvalidGatewayResponse("Could not parse invoice".into()))?;
+        if *invoice.payment_hash() != payment_hash || contract.hash != payment_hash {
+            return Err(ClientError::InvalidGatewayResponse(
+                "Wrong payment hash".into(),
+            ));
+        }
+        if invoice.amount_pico_btc() != Some(data.amount.milli_sat * 10) {
+            return Err(ClientError::InvalidGatewayResponse(
+                "Wrong invoice amount".into(),
+            ));
+        }
+        if contract.user_key != data.user_key.to_public() {
+            return Err(ClientError::InvalidGatewayResponse(
+                "Contract can't be claimed by us".into(),
+            ));
+        }
+
+        let contract_id = Contract::Incoming(contract.clone()).contract_id();
+        data.contract = Some(contract);
+        self.db
+            .insert_entry(&IncomingPaymentKey(payment_hash), &data)
+            .expect("DB error");
+
+        Ok((invoice, contract_id))
+    }
+
+    /// Publishes an offer for `amount` and obtains an invoice for it from `gateway`. Once the
+    /// invoice is paid the gateway funds the returned contract, which can then be claimed using
+    /// [`MintClient::claim_incoming_contract`].
+    pub async fn create_invoice<R: RngCore + CryptoRng>(
+        &self,
+        gateway: &str,
+        amount: Amount,
+        description: String,
+        rng: R,
+    ) -> Result<(Invoice, ContractId), ClientError> {
+        let (payment_hash, tx_id) = self.create_offer(amount, rng).await?;
+
+        loop {
+            let status = self
+                .query_any_mint(|api| api.fetch_transaction(tx_id))
+                .await;
+            match status {
+                Ok(TransactionStatus::Accepted { .. }) => break,
+                Ok(TransactionStatus::Error(e)) => return Err(ClientError::TransactionError(e)),
+                _ => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        }
+
+        self.request_invoice(gateway, payment_hash, description)
+            .await
+    }
+
+    /// Claims an incoming contract funded by a gateway for one of our offers by revealing the
+    /// preimage. The coins can be fetched from the returned out point using
+    /// [`MintClient::fetch_coins`] once the transaction was accepted.
+    pub async fn claim_incoming_contract<R: RngCore + CryptoRng>(
+        &self,
+        account: ContractAccount,
+        rng: R,
+    ) -> Result<OutPoint, ClientError> {
+        let payment_hash = match &account.contract {
+            Contract::Incoming(contract) => contract.hash,
+            Contract::Outgoing(_) => return Err(ClientError::NotIncomingContract),
+        };
+        let data = self
+            .db
+            .get_value::<_, IncomingPaymentData>(&IncomingPaymentKey(payment_hash))
+            .expect("DB error")
+            .ok_or(ClientError::UnknownIncomingPayment(payment_hash))?;
+
+        let out_point = self
+            .claim_contract(account, Some(data.preimage), &data.user_key, rng)
+            .await?;
+        self.db
+            .remove_entry::<_, IncomingPaymentData>(&IncomingPaymentKey(payment_hash))
+            .expect("DB error");
+        Ok(out_point)
+    }
+
     pub fn get_new_pegin_address<R: RngCore + CryptoRng>(&self, mut rng: R) -> Address {
         let peg_in_sec_key = musig::SecKey::random(musig::rng_adapt::RngAdaptor(&mut rng));
         let peg_in_pub_key = peg_in_sec_key.to_public();
@@ -613,6 +1216,24 @@ pub enum ClientError {
     InvalidOutcomeWrongStructure(OutPoint),
     #[error("The transaction outcome returned by the mint has an invalid type (output {0})")]
     InvalidOutcomeType(OutPoint),
+    #[error("The contract's funds don't cover the fee for spending it")]
+    ContractAmountTooSmall,
+    #[error("We did not fund an outgoing contract with id {0}")]
+    UnknownOutgoingContract(ContractId),
+    #[error("We did not create an offer for the payment hash {0}")]
+    UnknownIncomingPayment(Sha256),
+    #[error("The contract is not an incoming contract")]
+    NotIncomingContract,
+    #[error("The federation rejected the transaction: {0}")]
+    TransactionError(String),
+    #[error("The gateway returned an error: {0}")]
+    GatewayError(String),
+    #[error("The gateway's response is invalid: {0}")]
+    InvalidGatewayResponse(String),
+    #[error("Not enough guardians signed the same reserves report")]
+    ReservesNotAttested,
+    #[error("Bitcoin backend error: {0}")]
+    BitcoinBackendError(RpcError),
 }
 
 impl From<InvalidAmountTierError> for CoinFinalizationError {
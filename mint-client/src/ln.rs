@@ -0,0 +1,45 @@
+//! Types exchanged between clients and lightning gateways
+
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::ln::{ContractId, IncomingContract, Preimage};
+use serde::{Deserialize, Serialize};
+
+/// Information a gateway publishes about itself so clients can fund contracts it will accept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GatewayInfo {
+    /// Key the gateway uses to claim outgoing contracts
+    pub public_key: musig::PubKey,
+    /// Minimum number of blocks between the current consensus block height and the timelock of an
+    /// outgoing contract for the gateway to accept it
+    pub min_timelock_delta: u32,
+}
+
+/// Asks the gateway to pay `invoice` in exchange for the funds locked in the outgoing contract
+/// `contract_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayInvoicePayload {
+    pub contract_id: ContractId,
+    pub invoice: String,
+}
+
+/// Successful response to a [`PayInvoicePayload`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayInvoiceResponse {
+    pub preimage: Preimage,
+}
+
+/// Asks the gateway for an invoice that, once paid, funds an incoming contract matching the offer
+/// for `payment_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateInvoicePayload {
+    pub payment_hash: Sha256,
+    pub description: String,
+}
+
+/// Successful response to a [`CreateInvoicePayload`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateInvoiceResponse {
+    pub invoice: String,
+    /// Contract the gateway will fund once the invoice is paid
+    pub contract: IncomingContract,
+}
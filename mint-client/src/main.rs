@@ -2,13 +2,20 @@ use bitcoin::{Address, Transaction};
 use bitcoin_hashes::hex::ToHex;
 use minimint::config::{load_from_file, ClientConfig};
 use minimint_api::encoding::Decodable;
-use minimint_api::{Amount, Coins, TxOutProof};
-use mint_client::{MintClient, SpendableCoin};
+use minimint_api::ln::ContractId;
+use minimint_api::outcome::PegOutOutcome;
+use minimint_api::transaction::OutPoint;
+use minimint_api::{Amount, Coins, TransactionId, TxOutProof};
+use minimint_wallet::bitcoind::bitcoin_core::BitcoindClient;
+use minimint_wallet::bitcoind::esplora::EsploraClient;
+use minimint_wallet::bitcoind::BitcoindRpc;
+use mint_client::ln::{GatewayInfo, PayInvoicePayload, PayInvoiceResponse};
+use mint_client::{ClientError, MintClient, SpendableCoin};
 use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
@@ -44,23 +51,59 @@ enum Command {
     PegOut {
         address: Address,
         amount: bitcoin::Amount,
+        #[structopt(
+            long,
+            help = "Pay the priority fee to be included in the next peg-out transaction"
+        )]
+        priority: bool,
+    },
+    #[structopt(about = "Show the on-chain fee a peg-out to an address currently has to pay")]
+    PegOutFees { address: Address },
+    #[structopt(about = "Check whether the bitcoin transaction paying a peg-out was sent yet")]
+    PegOutStatus { txid: TransactionId },
+    #[structopt(
+        about = "Check that the federation's bitcoin reserves back all outstanding e-cash",
+        group = structopt::clap::ArgGroup::with_name("backend").required(true)
+    )]
+    Audit {
+        #[structopt(long, group = "backend", help = "Esplora API to look up UTXOs with")]
+        esplora: Option<String>,
+        #[structopt(
+            long,
+            group = "backend",
+            help = "bitcoind RPC address to look up UTXOs with"
+        )]
+        bitcoind: Option<String>,
+        #[structopt(long, default_value = "bitcoin")]
+        rpc_user: String,
+        #[structopt(long, default_value = "bitcoin")]
+        rpc_pass: String,
     },
     #[structopt(about = "Pay a lightning invoice via a gateway")]
     LnPay {
         gateway: String,
         bolt11: lightning_invoice::Invoice,
     },
+    #[structopt(
+        about = "Take back the funds of an unclaimed outgoing contract after its timelock"
+    )]
+    LnRefund { contract_id: ContractId },
+    #[structopt(about = "Create a lightning invoice via a gateway and wait for it to be paid")]
+    LnReceive {
+        gateway: String,
+        amount: Amount,
+        #[structopt(default_value = "")]
+        description: String,
+    },
     #[structopt(about = "Fetch (re-)issued coins and finalize issuance process")]
     Fetch,
     #[structopt(about = "Display wallet info (holdings, tiers)")]
     Info,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct PayRequest {
-    coins: Coins<SpendableCoin>,
-    invoice: String,
-}
+/// Blocks added to the gateway's minimum timelock delta in case new blocks are found before the
+/// gateway verifies the contract
+const TIMELOCK_SLACK: u32 = 6;
 
 #[tokio::main]
 async fn main() {
@@ -135,29 +178,214 @@ async fn main() {
                 info!("We own {} coins of denomination {}", coins.len(), amount);
             }
         }
-        Command::PegOut { address, amount } => {
-            client.peg_out(amount, address, &mut rng).await.unwrap();
+        Command::PegOut {
+            address,
+            amount,
+            priority,
+        } => {
+            let id = client
+                .peg_out(amount, address, priority, &mut rng)
+                .await
+                .unwrap();
+            info!(
+                "Started peg-out {}, query its status with peg-out-status",
+                id.to_hex()
+            );
+        }
+        Command::PegOutFees { address } => {
+            let fees = client.fetch_peg_out_fees(&address).await.unwrap();
+            info!(
+                "A peg-out to {} has to pay a fee of {} at the current fee rate of {} sat/kvB",
+                address, fees.fee, fees.sats_per_kvb
+            );
+        }
+        Command::PegOutStatus { txid } => {
+            // Peg-out transactions created by this client only contain a single output
+            let out_point = OutPoint { txid, out_idx: 0 };
+            match client.fetch_peg_out_status(out_point).await {
+                Ok(PegOutOutcome::Queued) => {
+                    info!("Peg-out is queued for the next federation transaction")
+                }
+                Ok(PegOutOutcome::Signing(btc_txid)) => {
+                    info!(
+                        "Peg-out is included in transaction {} which is being signed",
+                        btc_txid
+                    )
+                }
+                Ok(PegOutOutcome::Broadcast(btc_txid)) => {
+                    info!(
+                        "Peg-out transaction {} was broadcast, waiting for confirmation",
+                        btc_txid
+                    )
+                }
+                Ok(PegOutOutcome::Confirmed {
+                    txid: btc_txid,
+                    block_height,
+                }) => {
+                    info!(
+                        "Peg-out transaction {} confirmed in block {}",
+                        btc_txid, block_height
+                    )
+                }
+                Err(ClientError::OutputNotReadyYet(_)) => {
+                    info!("Peg-out wasn't accepted by the federation yet")
+                }
+                Err(e) => error!("Could not fetch peg-out status: {}", e),
+            }
+        }
+        Command::Audit {
+            esplora,
+            bitcoind,
+            rpc_user,
+            rpc_pass,
+        } => {
+            let rpc: Box<dyn BitcoindRpc> = match (esplora, bitcoind) {
+                (Some(url), _) => Box::new(EsploraClient::new(&url)),
+                (None, Some(address)) => Box::new(
+                    BitcoindClient::new(&address, &rpc_user, &rpc_pass)
+                        .await
+                        .expect("Could not connect to bitcoind"),
+                ),
+                (None, None) => unreachable!("structopt requires a backend"),
+            };
+
+            let audit = client.audit(rpc.as_ref()).await.unwrap();
+            for utxo in &audit.missing {
+                error!(
+                    "UTXO {} of {} is not unspent on-chain",
+                    utxo.out_point, utxo.amount
+                );
+            }
+            info!(
+                "At block {} the federation holds {} in {} UTXOs, {} of them confirmed on-chain",
+                audit.report.block_height,
+                audit.report.assets(),
+                audit.report.utxos.len(),
+                audit.confirmed_assets
+            );
+            if audit.is_backed() {
+                info!(
+                    "Outstanding e-cash of {} is fully backed",
+                    audit.report.liabilities
+                );
+            } else {
+                error!(
+                    "Outstanding e-cash of {} exceeds the confirmed reserves",
+                    audit.report.liabilities
+                );
+            }
         }
         Command::LnPay { gateway, bolt11 } => {
-            let amt = Amount::from_msat(bolt11.amount_pico_btc().unwrap() / 10);
+            let amt = Amount::from_msat(
+                bolt11
+                    .amount_pico_btc()
+                    .expect("Invoices without amount are not supported")
+                    / 10,
+            );
             let http = reqwest::Client::new();
 
-            let coins = client.coins().select_coins(amt).expect("Not enough funds");
-            client.spend_coins(&coins);
-            let success = http
-                .post(&gateway)
-                .json(&PayRequest {
-                    coins,
+            let gateway_info: GatewayInfo = http
+                .get(&format!("{}/info", gateway))
+                .send()
+                .await
+                .expect("Could not reach gateway")
+                .json()
+                .await
+                .expect("Invalid gateway info");
+            let block_height = client
+                .fetch_block_height()
+                .await
+                .expect("Could not fetch block height");
+            let timelock = block_height + gateway_info.min_timelock_delta + TIMELOCK_SLACK;
+
+            let (contract_id, _) = client
+                .fund_outgoing_contract(
+                    gateway_info.public_key,
+                    *bolt11.payment_hash(),
+                    amt,
+                    timelock,
+                    &mut rng,
+                )
+                .await
+                .expect("Could not fund outgoing contract");
+            info!(
+                "Funded outgoing contract {}, waiting for it to be accepted",
+                contract_id
+            );
+            while client.fetch_contract_account(contract_id).await.is_err() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+
+            let response = http
+                .post(&format!("{}/pay_invoice", gateway))
+                .json(&PayInvoicePayload {
+                    contract_id,
                     invoice: bolt11.to_string(),
                 })
                 .send()
+                .await;
+
+            match response {
+                Ok(response) if response.status() == StatusCode::OK => {
+                    let PayInvoiceResponse { preimage } =
+                        response.json().await.expect("Invalid gateway response");
+                    info!("Payment succeeded, preimage: {}", preimage.0.to_hex());
+                }
+                _ => error!(
+                    "Payment failed, the funds can be taken back from block {} on using ln-refund {}",
+                    timelock, contract_id
+                ),
+            }
+        }
+        Command::LnReceive {
+            gateway,
+            amount,
+            description,
+        } => {
+            let (invoice, contract_id) = client
+                .create_invoice(&gateway, amount, description, &mut rng)
                 .await
-                .map(|response| response.status() == StatusCode::OK)
-                .unwrap_or(false);
+                .expect("Could not create invoice");
+            println!("{}", invoice);
+
+            info!("Waiting for the gateway to fund contract {}", contract_id);
+            let account = loop {
+                match client.fetch_contract_account(contract_id).await {
+                    Ok(account) => break account,
+                    Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+                }
+            };
 
-            if !success {
-                error!("Payment failed")
+            let out_point = client
+                .claim_incoming_contract(account, &mut rng)
+                .await
+                .expect("Could not claim incoming contract");
+            loop {
+                match client.fetch_coins(out_point).await {
+                    Ok(()) => break,
+                    Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await
+                    }
+                    Err(e) => {
+                        error!(
+                            "Fetching coins failed, please fetch the result later: {}",
+                            e
+                        );
+                        return;
+                    }
+                }
             }
+            info!("Received {}", amount);
+        }
+        Command::LnRefund { contract_id } => {
+            let out_point = client
+                .refund_outgoing_contract(contract_id, &mut rng)
+                .await
+                .unwrap();
+            info!(
+                "Started refund {}, please fetch the result later",
+                out_point.txid.to_hex()
+            );
         }
     }
 }
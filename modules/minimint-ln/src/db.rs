@@ -0,0 +1,46 @@
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::db::DatabaseKeyPrefixConst;
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::ln::ContractId;
+use minimint_api::transaction::OutPoint;
+
+const DB_PREFIX_CONTRACT: u8 = 0x40;
+const DB_PREFIX_OFFER: u8 = 0x41;
+const DB_PREFIX_OUTPUT_OUTCOME: u8 = 0x42;
+
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct ContractKey(pub ContractId);
+
+impl DatabaseKeyPrefixConst for ContractKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CONTRACT;
+}
+
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct ContractKeyPrefix;
+
+impl DatabaseKeyPrefixConst for ContractKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_CONTRACT;
+}
+
+/// Offers for incoming contracts indexed by their payment hash
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct OfferKey(pub Sha256);
+
+impl DatabaseKeyPrefixConst for OfferKey {
+    const DB_PREFIX: u8 = DB_PREFIX_OFFER;
+}
+
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct OfferKeyPrefix;
+
+impl DatabaseKeyPrefixConst for OfferKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_OFFER;
+}
+
+/// Transaction id and output index identifying an output outcome
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct OutputOutcomeKey(pub OutPoint);
+
+impl DatabaseKeyPrefixConst for OutputOutcomeKey {
+    const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_OUTCOME;
+}
@@ -0,0 +1,671 @@
+//! Federation module locking coins in lightning contracts, see [`minimint_api::ln`] for the
+//! contract types and the rules for spending them.
+
+mod db;
+
+use crate::db::{ContractKey, ContractKeyPrefix, OfferKey, OfferKeyPrefix, OutputOutcomeKey};
+use async_trait::async_trait;
+use bitcoin_hashes::sha256::Hash as Sha256;
+use minimint_api::db::batch::BatchTx;
+use minimint_api::db::{Database, RawDatabase};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::ln::{
+    Contract, ContractAccount, ContractId, ContractInput, ContractOutcome, IncomingContractOffer,
+    LightningOutput, LightningOutputOutcome, MAX_OFFER_LIFETIME,
+};
+use minimint_api::module::Audit;
+use minimint_api::transaction::OutPoint;
+use minimint_api::{Amount, FederationModule, PeerId};
+use rand::{CryptoRng, RngCore};
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::debug;
+
+pub struct LightningModule {
+    block_height: Box<dyn BlockHeightSource>,
+    db: Arc<dyn RawDatabase>,
+}
+
+/// Supplies the block height that contract timelocks are compared against. It has to be agreed on
+/// by the federation, e.g. the wallet's consensus height, since it influences transaction validity.
+pub trait BlockHeightSource: Send + Sync {
+    fn block_height(&self) -> u32;
+}
+
+/// Lightning state that can only be used by one transaction per epoch. Spending a contract with
+/// and without preimage results in different inputs, so these can't be found by comparing inputs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Encodable, Decodable)]
+enum LightningConflict {
+    Contract(ContractId),
+    Offer(Sha256),
+}
+
+impl LightningConflict {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.consensus_encode(&mut bytes)
+            .expect("Writing to a vec can't fail");
+        bytes
+    }
+}
+
+#[async_trait(?Send)]
+impl FederationModule for LightningModule {
+    type Error = LightningModuleError;
+    type TxInput = ContractInput;
+    type TxOutput = LightningOutput;
+    type TxOutputOutcome = LightningOutputOutcome;
+    type ConsensusItem = ();
+
+    async fn consensus_proposal<'a>(
+        &'a self,
+        _rng: impl RngCore + CryptoRng + 'a,
+    ) -> Vec<Self::ConsensusItem> {
+        vec![]
+    }
+
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        _batch: BatchTx<'a>,
+        _consensus_items: Vec<(PeerId, Self::ConsensusItem)>,
+        _rng: impl RngCore + CryptoRng + 'a,
+    ) {
+    }
+
+    fn validate_input(&self, input: &Self::TxInput) -> Result<Amount, Self::Error> {
+        let contract_id = input.contract.contract_id();
+        let account = self
+            .contract_account(contract_id)
+            .ok_or(LightningModuleError::UnknownContract(contract_id))?;
+
+        if account.outcome != ContractOutcome::Funded {
+            return Err(LightningModuleError::ContractAlreadySpent(contract_id));
+        }
+
+        if account.amount != input.amount {
+            return Err(LightningModuleError::InvalidAmount(
+                account.amount,
+                input.amount,
+            ));
+        }
+
+        match input.witness {
+            Some(preimage) => {
+                if &preimage.hash() != input.contract.hash() {
+                    return Err(LightningModuleError::InvalidPreimage);
+                }
+            }
+            None => {
+                let block_height = self.block_height.block_height();
+                if block_height < input.contract.timelock() {
+                    return Err(LightningModuleError::ContractTimelocked(
+                        input.contract.timelock(),
+                        block_height,
+                    ));
+                }
+            }
+        }
+
+        Ok(input.amount)
+    }
+
+    fn input_conflicts(&self, input: &Self::TxInput) -> Vec<Vec<u8>> {
+        vec![LightningConflict::Contract(input.contract.contract_id()).to_bytes()]
+    }
+
+    fn apply_input<'a>(
+        &'a self,
+        mut batch: BatchTx<'a>,
+        input: &'a Self::TxInput,
+    ) -> Result<Amount, Self::Error> {
+        let amount = self.validate_input(input)?;
+        let contract_id = input.contract.contract_id();
+
+        let outcome = match input.witness {
+            Some(preimage) => ContractOutcome::Claimed(preimage),
+            None => ContractOutcome::Refunded,
+        };
+        debug!("Spending contract {}: {:?}", contract_id, outcome);
+
+        batch.append_insert(
+            ContractKey(contract_id),
+            ContractAccount {
+                amount,
+                contract: input.contract.clone(),
+                outcome,
+            },
+        );
+        batch.commit();
+
+        Ok(amount)
+    }
+
+    fn validate_output(&self, output: &Self::TxOutput) -> Result<Amount, Self::Error> {
+        match output {
+            LightningOutput::Contract(output) => {
+                let contract_id = output.contract.contract_id();
+                if self.contract_account(contract_id).is_some() {
+                    return Err(LightningModuleError::ContractExists(contract_id));
+                }
+
+                if let Contract::Incoming(incoming) = &output.contract {
+                    let offer = self
+                        .offer(incoming.hash)
+                        .ok_or(LightningModuleError::NoOffer(incoming.hash))?;
+
+                    if offer.user_key != incoming.user_key {
+                        return Err(LightningModuleError::OfferKeyMismatch);
+                    }
+
+                    if output.amount < offer.amount {
+                        return Err(LightningModuleError::InsufficientIncomingFunding(
+                            offer.amount,
+                            output.amount,
+                        ));
+                    }
+                }
+
+                Ok(output.amount)
+            }
+            LightningOutput::Offer(offer) => {
+                if self.offer(offer.hash).is_some() {
+                    return Err(LightningModuleError::OfferExists(offer.hash));
+                }
+
+                let block_height = self.block_height.block_height();
+                if offer.expiry_height <= block_height
+                    || offer.expiry_height - block_height > MAX_OFFER_LIFETIME
+                {
+                    return Err(LightningModuleError::InvalidOfferExpiry(
+                        offer.expiry_height,
+                        block_height,
+                    ));
+                }
+
+                Ok(Amount::ZERO)
+            }
+        }
+    }
+
+    fn output_conflicts(&self, output: &Self::TxOutput) -> Vec<Vec<u8>> {
+        let conflicts = match output {
+            LightningOutput::Contract(output) => {
+                let contract = LightningConflict::Contract(output.contract.contract_id());
+                match &output.contract {
+                    Contract::Incoming(incoming) => {
+                        vec![contract, LightningConflict::Offer(incoming.hash)]
+                    }
+                    Contract::Outgoing(_) => vec![contract],
+                }
+            }
+            LightningOutput::Offer(offer) => vec![LightningConflict::Offer(offer.hash)],
+        };
+        conflicts
+            .into_iter()
+            .map(LightningConflict::to_bytes)
+            .collect()
+    }
+
+    fn apply_output<'a>(
+        &'a self,
+        mut batch: BatchTx<'a>,
+        output: &'a Self::TxOutput,
+        out_point: OutPoint,
+    ) -> Result<Amount, Self::Error> {
+        let amount = self.validate_output(output)?;
+
+        let outcome = match output {
+            LightningOutput::Contract(output) => {
+                let contract_id = output.contract.contract_id();
+                debug!("Funding contract {} with {}", contract_id, output.amount);
+
+                batch.append_insert_new(
+                    ContractKey(contract_id),
+                    ContractAccount {
+                        amount: output.amount,
+                        contract: output.contract.clone(),
+                        outcome: ContractOutcome::Funded,
+                    },
+                );
+
+                // Every offer can only be used to fund one incoming contract
+                if let Contract::Incoming(incoming) = &output.contract {
+                    batch.append_delete(OfferKey(incoming.hash));
+                }
+
+                LightningOutputOutcome::Contract {
+                    id: contract_id,
+                    outcome: ContractOutcome::Funded,
+                }
+            }
+            LightningOutput::Offer(offer) => {
+                debug!("Registering offer for {} of {}", offer.hash, offer.amount);
+                // An expired offer for the same hash may not have been deleted yet
+                batch.append_insert(OfferKey(offer.hash), offer.clone());
+                LightningOutputOutcome::Offer { hash: offer.hash }
+            }
+        };
+
+        batch.append_insert_new(OutputOutcomeKey(out_point), outcome);
+        batch.commit();
+
+        Ok(amount)
+    }
+
+    async fn end_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        _rng: impl RngCore + CryptoRng + 'a,
+    ) {
+        self.delete_expired_offers(batch);
+    }
+
+    fn output_status(&self, out_point: OutPoint) -> Option<Self::TxOutputOutcome> {
+        let outcome = self
+            .db
+            .get_value::<_, LightningOutputOutcome>(&OutputOutcomeKey(out_point))
+            .expect("DB error")?;
+
+        // The stored outcome reflects the contract state at funding time, it may have been spent
+        // since then
+        match outcome {
+            LightningOutputOutcome::Contract { id, .. } => Some(LightningOutputOutcome::Contract {
+                id,
+                outcome: self
+                    .contract_account(id)
+                    .expect("Funded contracts are never deleted")
+                    .outcome,
+            }),
+            offer @ LightningOutputOutcome::Offer { .. } => Some(offer),
+        }
+    }
+
+    /// Funds locked in contracts that weren't claimed or refunded yet are owed to their users
+    fn audit(&self) -> Audit {
+        let liabilities = self
+            .db
+            .find_by_prefix::<_, ContractKey, ContractAccount>(&ContractKeyPrefix)
+            .map(|res| res.expect("DB error").1)
+            .filter(|account| account.outcome == ContractOutcome::Funded)
+            .map(|account| account.amount)
+            .sum();
+
+        Audit {
+            assets: Amount::ZERO,
+            liabilities,
+        }
+    }
+}
+
+impl LightningModule {
+    pub fn new(
+        block_height: Box<dyn BlockHeightSource>,
+        db: Arc<dyn RawDatabase>,
+    ) -> LightningModule {
+        LightningModule { block_height, db }
+    }
+
+    pub fn contract_account(&self, contract_id: ContractId) -> Option<ContractAccount> {
+        self.db
+            .get_value::<_, ContractAccount>(&ContractKey(contract_id))
+            .expect("DB error")
+    }
+
+    /// Returns the offer for `hash` if it exists, didn't expire and wasn't used to fund a contract
+    /// yet
+    pub fn offer(&self, hash: Sha256) -> Option<IncomingContractOffer> {
+        self.db
+            .get_value::<_, IncomingContractOffer>(&OfferKey(hash))
+            .expect("DB error")
+            .filter(|offer| offer.expiry_height > self.block_height.block_height())
+    }
+
+    fn delete_expired_offers(&self, mut batch: BatchTx) {
+        let block_height = self.block_height.block_height();
+        let expired = self
+            .db
+            .find_by_prefix::<_, OfferKey, IncomingContractOffer>(&OfferKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .filter(|(_, offer)| offer.expiry_height <= block_height)
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            debug!("Deleting expired offer for {}", key.0);
+            batch.append_delete(key);
+        }
+        batch.commit();
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum LightningModuleError {
+    #[error("The contract {0} does not exist")]
+    UnknownContract(ContractId),
+    #[error("The contract {0} was already spent")]
+    ContractAlreadySpent(ContractId),
+    #[error("The input amount doesn't match the contract's funds: expected {0}, got {1}")]
+    InvalidAmount(Amount, Amount),
+    #[error("The preimage doesn't match the contract's payment hash")]
+    InvalidPreimage,
+    #[error("The contract is timelocked until block {0}, current block height is {1}")]
+    ContractTimelocked(u32, u32),
+    #[error("The contract {0} already exists")]
+    ContractExists(ContractId),
+    #[error("No offer exists for payment hash {0}")]
+    NoOffer(Sha256),
+    #[error("The incoming contract's user key doesn't match the offer")]
+    OfferKeyMismatch,
+    #[error("The incoming contract is underfunded: offer requires {0}, got {1}")]
+    InsufficientIncomingFunding(Amount, Amount),
+    #[error("An offer for payment hash {0} already exists")]
+    OfferExists(Sha256),
+    #[error("The offer's expiry height {0} isn't within the allowed lifetime at block height {1}")]
+    InvalidOfferExpiry(u32, u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{OfferKey, OfferKeyPrefix};
+    use crate::{BlockHeightSource, LightningModule, LightningModuleError};
+    use minimint_api::db::batch::DbBatch;
+    use minimint_api::db::mem_impl::MemDatabase;
+    use minimint_api::db::Database;
+    use minimint_api::ln::{
+        Contract, ContractInput, ContractOutcome, ContractOutput, IncomingContract,
+        IncomingContractOffer, LightningOutput, LightningOutputOutcome, OutgoingContract, Preimage,
+        MAX_OFFER_LIFETIME,
+    };
+    use minimint_api::transaction::OutPoint;
+    use minimint_api::{Amount, FederationModule, TransactionId};
+    use musig::{PubKey, SecKey};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    struct TestBlockHeight(Arc<AtomicU32>);
+
+    impl BlockHeightSource for TestBlockHeight {
+        fn block_height(&self) -> u32 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    fn setup() -> (LightningModule, Arc<AtomicU32>) {
+        let height = Arc::new(AtomicU32::new(0));
+        let module = LightningModule::new(
+            Box::new(TestBlockHeight(height.clone())),
+            Arc::new(MemDatabase::new()),
+        );
+        (module, height)
+    }
+
+    fn keys() -> (PubKey, PubKey) {
+        let mut rng = musig::rng_adapt::RngAdaptor(rand::rngs::OsRng::new().unwrap());
+        (
+            SecKey::random(&mut rng).to_public(),
+            SecKey::random(&mut rng).to_public(),
+        )
+    }
+
+    fn out_point(idx: u64) -> OutPoint {
+        OutPoint {
+            txid: TransactionId::default(),
+            out_idx: idx,
+        }
+    }
+
+    fn apply_output(module: &LightningModule, output: &LightningOutput, out_point: OutPoint) {
+        let mut batch = DbBatch::new();
+        module
+            .apply_output(batch.transaction(), output, out_point)
+            .unwrap();
+        module.db.apply_batch(batch).unwrap();
+    }
+
+    fn apply_input(module: &LightningModule, input: &ContractInput) {
+        let mut batch = DbBatch::new();
+        module.apply_input(batch.transaction(), input).unwrap();
+        module.db.apply_batch(batch).unwrap();
+    }
+
+    #[test]
+    fn test_outgoing_contract() {
+        let (module, height) = setup();
+        let (gateway_key, user_key) = keys();
+        let preimage = Preimage([1; 32]);
+        let amount = Amount::from_sat(1000);
+
+        let contract = Contract::Outgoing(OutgoingContract {
+            hash: preimage.hash(),
+            gateway_key,
+            timelock: 10,
+            user_key,
+        });
+        let output = LightningOutput::Contract(ContractOutput {
+            amount,
+            contract: contract.clone(),
+        });
+        apply_output(&module, &output, out_point(0));
+        assert_eq!(
+            module.validate_output(&output),
+            Err(LightningModuleError::ContractExists(contract.contract_id()))
+        );
+        assert_eq!(module.audit().liabilities, amount);
+
+        let refund = ContractInput {
+            contract: contract.clone(),
+            amount,
+            witness: None,
+        };
+        assert_eq!(
+            module.validate_input(&refund),
+            Err(LightningModuleError::ContractTimelocked(10, 0))
+        );
+        assert_eq!(
+            module.validate_input(&ContractInput {
+                witness: Some(Preimage([2; 32])),
+                ..refund.clone()
+            }),
+            Err(LightningModuleError::InvalidPreimage)
+        );
+        assert_eq!(
+            module.validate_input(&ContractInput {
+                amount: Amount::from_sat(1),
+                ..refund.clone()
+            }),
+            Err(LightningModuleError::InvalidAmount(
+                amount,
+                Amount::from_sat(1)
+            ))
+        );
+
+        height.store(10, Ordering::Relaxed);
+        assert_eq!(module.validate_input(&refund), Ok(amount));
+
+        let claim = ContractInput {
+            witness: Some(preimage),
+            ..refund.clone()
+        };
+        apply_input(&module, &claim);
+        assert_eq!(module.audit().liabilities, Amount::ZERO);
+        assert_eq!(
+            module.output_status(out_point(0)),
+            Some(LightningOutputOutcome::Contract {
+                id: contract.contract_id(),
+                outcome: ContractOutcome::Claimed(preimage),
+            })
+        );
+        assert_eq!(
+            module.validate_input(&refund),
+            Err(LightningModuleError::ContractAlreadySpent(
+                contract.contract_id()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_incoming_contract() {
+        let (module, _height) = setup();
+        let (gateway_key, user_key) = keys();
+        let preimage = Preimage([3; 32]);
+        let amount = Amount::from_sat(500);
+
+        let contract = Contract::Incoming(IncomingContract {
+            hash: preimage.hash(),
+            gateway_key,
+            timelock: 10,
+            user_key: user_key.clone(),
+        });
+        let funding = |amount| {
+            LightningOutput::Contract(ContractOutput {
+                amount,
+                contract: contract.clone(),
+            })
+        };
+        assert_eq!(
+            module.validate_output(&funding(amount)),
+            Err(LightningModuleError::NoOffer(preimage.hash()))
+        );
+
+        let offer = LightningOutput::Offer(IncomingContractOffer {
+            amount,
+            hash: preimage.hash(),
+            user_key,
+            expiry_height: 100,
+        });
+        apply_output(&module, &offer, out_point(0));
+        assert_eq!(
+            module.validate_output(&offer),
+            Err(LightningModuleError::OfferExists(preimage.hash()))
+        );
+        assert_eq!(
+            module.validate_output(&funding(Amount::from_sat(499))),
+            Err(LightningModuleError::InsufficientIncomingFunding(
+                amount,
+                Amount::from_sat(499)
+            ))
+        );
+
+        apply_output(&module, &funding(amount), out_point(1));
+        assert!(module.offer(preimage.hash()).is_none());
+        assert_eq!(
+            module.output_status(out_point(1)),
+            Some(LightningOutputOutcome::Contract {
+                id: contract.contract_id(),
+                outcome: ContractOutcome::Funded,
+            })
+        );
+
+        apply_input(
+            &module,
+            &ContractInput {
+                contract: contract.clone(),
+                amount,
+                witness: Some(preimage),
+            },
+        );
+        assert_eq!(
+            module
+                .contract_account(contract.contract_id())
+                .unwrap()
+                .outcome,
+            ContractOutcome::Claimed(preimage)
+        );
+    }
+
+    #[test]
+    fn test_offer_expiry() {
+        let (module, height) = setup();
+        let (_, user_key) = keys();
+        let offer = |preimage: u8, expiry_height| IncomingContractOffer {
+            amount: Amount::from_sat(100),
+            hash: Preimage([preimage; 32]).hash(),
+            user_key: user_key.clone(),
+            expiry_height,
+        };
+
+        assert_eq!(
+            module.validate_output(&LightningOutput::Offer(offer(1, 0))),
+            Err(LightningModuleError::InvalidOfferExpiry(0, 0))
+        );
+        assert_eq!(
+            module.validate_output(&LightningOutput::Offer(offer(1, MAX_OFFER_LIFETIME + 1))),
+            Err(LightningModuleError::InvalidOfferExpiry(
+                MAX_OFFER_LIFETIME + 1,
+                0
+            ))
+        );
+
+        apply_output(&module, &LightningOutput::Offer(offer(1, 10)), out_point(0));
+        apply_output(&module, &LightningOutput::Offer(offer(2, 20)), out_point(1));
+
+        height.store(10, Ordering::Relaxed);
+        assert!(module.offer(offer(1, 10).hash).is_none());
+        assert_eq!(module.offer(offer(2, 20).hash), Some(offer(2, 20)));
+
+        let mut batch = DbBatch::new();
+        module.delete_expired_offers(batch.transaction());
+        module.db.apply_batch(batch).unwrap();
+        let stored = module
+            .db
+            .find_by_prefix::<_, OfferKey, IncomingContractOffer>(&OfferKeyPrefix)
+            .map(|res| res.unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(stored, vec![offer(2, 20)]);
+
+        // The payment hash of an expired offer can be offered again
+        assert_eq!(
+            module.validate_output(&LightningOutput::Offer(offer(1, 30))),
+            Ok(Amount::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let (module, _height) = setup();
+        let (gateway_key, user_key) = keys();
+        let preimage = Preimage([4; 32]);
+        let amount = Amount::from_sat(100);
+
+        let contract = Contract::Incoming(IncomingContract {
+            hash: preimage.hash(),
+            gateway_key,
+            timelock: 10,
+            user_key: user_key.clone(),
+        });
+        let funding = LightningOutput::Contract(ContractOutput {
+            amount,
+            contract: contract.clone(),
+        });
+        let offer = LightningOutput::Offer(IncomingContractOffer {
+            amount,
+            hash: preimage.hash(),
+            user_key,
+            expiry_height: 10,
+        });
+        let refund = ContractInput {
+            contract,
+            amount,
+            witness: None,
+        };
+        let claim = ContractInput {
+            witness: Some(preimage),
+            ..refund.clone()
+        };
+
+        // Funding the contract uses both the contract account and the offer
+        let funding_conflicts = module.output_conflicts(&funding);
+        assert_eq!(funding_conflicts.len(), 2);
+        for conflict in module
+            .output_conflicts(&offer)
+            .into_iter()
+            .chain(module.input_conflicts(&refund))
+        {
+            assert!(funding_conflicts.contains(&conflict));
+        }
+        assert_eq!(
+            module.input_conflicts(&refund),
+            module.input_conflicts(&claim)
+        );
+    }
+}
@@ -1,8 +1,12 @@
-use minimint_api::config::GenerateConfig;
+use async_trait::async_trait;
+use minimint_api::config::{ConfigGenConnections, ConfigGenError, GenerateConfig};
 use minimint_api::{Amount, Keys, PeerId};
 use rand::{CryptoRng, RngCore};
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaChaRng;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
+use tbs::dkg::Commitment;
 use tbs::{dealer_keygen, AggregatePublicKey};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -16,9 +20,48 @@ pub struct MintClientConfig {
     pub tbs_pks: Keys<AggregatePublicKey>,
 }
 
+/// Dealing of one peer during distributed key generation, containing the commitments to its
+/// polynomials and the recipient's share of them for every amount tier
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MintConfigMessage {
+    pub commitments: Keys<Commitment>,
+    pub shares: Keys<tbs::SecretKeyShare>,
+}
+
+impl MintConfig {
+    /// Config of the peer holding `tbs_sks` given the public key shares of every amount tier,
+    /// indexed by peer, regardless of how the keys were generated
+    fn new(
+        peers: &[PeerId],
+        tbs_sks: Keys<tbs::SecretKeyShare>,
+        tbs_pk_shares: &BTreeMap<Amount, Vec<tbs::PublicKeyShare>>,
+    ) -> MintConfig {
+        MintConfig {
+            tbs_sks,
+            peer_tbs_pks: peers
+                .iter()
+                .map(|&peer| {
+                    let keys = tbs_pk_shares
+                        .iter()
+                        .map(|(amount, shares)| (*amount, shares[peer.to_usize()]))
+                        .collect();
+                    (peer, keys)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Number of peers that have to sign a coin of any amount tier
+fn tbs_threshold(peers: &[PeerId], max_evil: usize) -> usize {
+    peers.len() - max_evil
+}
+
+#[async_trait(?Send)]
 impl GenerateConfig for MintConfig {
     type Params = [Amount];
     type ClientConfig = MintClientConfig;
+    type ConfigMessage = MintConfigMessage;
 
     fn trusted_dealer_gen(
         peers: &[PeerId],
@@ -26,7 +69,7 @@ impl GenerateConfig for MintConfig {
         params: &Self::Params,
         _rng: impl RngCore + CryptoRng,
     ) -> (BTreeMap<PeerId, Self>, Self::ClientConfig) {
-        let tbs_threshold = peers.len() - max_evil;
+        let tbs_threshold = tbs_threshold(peers, max_evil);
 
         let tbs_keys = params
             .iter()
@@ -34,30 +77,20 @@ impl GenerateConfig for MintConfig {
                 let (tbs_pk, tbs_pks, tbs_sks) = dealer_keygen(tbs_threshold, peers.len());
                 (amount, (tbs_pk, tbs_pks, tbs_sks))
             })
-            .collect::<HashMap<_, _>>();
+            .collect::<BTreeMap<_, _>>();
+        let tbs_pk_shares = tbs_keys
+            .iter()
+            .map(|(amount, (_, pks, _))| (*amount, pks.clone()))
+            .collect();
 
         let mint_cfg = peers
             .iter()
             .map(|&peer| {
-                let config = MintConfig {
-                    tbs_sks: params
-                        .iter()
-                        .map(|amount| (*amount, tbs_keys[amount].2[peer.to_usize()].clone()))
-                        .collect(),
-                    peer_tbs_pks: peers
-                        .iter()
-                        .map(|&key_peer| {
-                            let keys = params
-                                .iter()
-                                .map(|amount| {
-                                    (*amount, tbs_keys[amount].1[key_peer.to_usize()].clone())
-                                })
-                                .collect();
-                            (key_peer, keys)
-                        })
-                        .collect(),
-                };
-                (peer, config)
+                let tbs_sks = tbs_keys
+                    .iter()
+                    .map(|(amount, (_, _, sks))| (*amount, sks[peer.to_usize()].clone()))
+                    .collect();
+                (peer, MintConfig::new(peers, tbs_sks, &tbs_pk_shares))
             })
             .collect();
 
@@ -70,4 +103,119 @@ impl GenerateConfig for MintConfig {
 
         (mint_cfg, client_cfg)
     }
+
+    async fn distributed_gen<'a>(
+        connections: &'a mut (impl ConfigGenConnections<Self::ConfigMessage> + 'a),
+        our_id: &'a PeerId,
+        peers: &'a [PeerId],
+        max_evil: usize,
+        params: &'a Self::Params,
+        mut rng: impl RngCore + CryptoRng + 'a,
+    ) -> Result<(Self, Self::ClientConfig), ConfigGenError> {
+        let tbs_threshold = tbs_threshold(peers, max_evil);
+
+        // tbs uses a newer version of rand, so we seed an rng of that version from ours
+        let mut seed = <ChaChaRng as SeedableRng>::Seed::default();
+        rng.fill_bytes(&mut seed);
+        let mut tbs_rng = ChaChaRng::from_seed(seed);
+
+        let dealings = params
+            .iter()
+            .map(|&amount| {
+                let dealing = tbs::dkg::deal(tbs_threshold, peers.len(), &mut tbs_rng);
+                (amount, dealing)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let commitments: Keys<Commitment> = dealings
+            .iter()
+            .map(|(amount, (commitment, _))| (*amount, commitment.clone()))
+            .collect();
+        let shares_for = |peer: PeerId| -> Keys<tbs::SecretKeyShare> {
+            dealings
+                .iter()
+                .map(|(amount, (_, shares))| (*amount, shares[peer.to_usize()]))
+                .collect()
+        };
+
+        for &peer in peers.iter().filter(|&peer| peer != our_id) {
+            let msg = MintConfigMessage {
+                commitments: commitments.clone(),
+                shares: shares_for(peer),
+            };
+            connections.send(&[peer], msg).await;
+        }
+
+        let mut received = BTreeMap::new();
+        received.insert(
+            *our_id,
+            MintConfigMessage {
+                commitments,
+                shares: shares_for(*our_id),
+            },
+        );
+        while received.len() < peers.len() {
+            let (peer, msg) = connections.receive().await;
+            if !peers.contains(&peer) || received.contains_key(&peer) {
+                return Err(ConfigGenError::UnexpectedMessage(peer));
+            }
+
+            let valid = params.iter().all(|amount| {
+                match (msg.commitments.tier(amount), msg.shares.tier(amount)) {
+                    (Ok(commitment), Ok(share)) => {
+                        commitment.threshold() == tbs_threshold
+                            && commitment.verify_share(our_id.to_usize(), share)
+                    }
+                    _ => false,
+                }
+            });
+            if !valid {
+                return Err(ConfigGenError::InvalidShare(peer));
+            }
+
+            received.insert(peer, msg);
+        }
+
+        let tbs_sks = params
+            .iter()
+            .map(|amount| {
+                let sk = tbs::dkg::combine_shares(
+                    received
+                        .values()
+                        .map(|msg| *msg.shares.tier(amount).expect("validated above")),
+                );
+                (*amount, sk)
+            })
+            .collect();
+        let joint_commitments = params
+            .iter()
+            .map(|amount| {
+                let commitment = tbs::dkg::combine_commitments(
+                    received
+                        .values()
+                        .map(|msg| msg.commitments.tier(amount).expect("validated above")),
+                );
+                (*amount, commitment)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let tbs_pk_shares = joint_commitments
+            .iter()
+            .map(|(amount, commitment)| {
+                let shares = (0..peers.len())
+                    .map(|idx| commitment.public_key_share(idx))
+                    .collect();
+                (*amount, shares)
+            })
+            .collect();
+        let mint_cfg = MintConfig::new(peers, tbs_sks, &tbs_pk_shares);
+
+        let client_cfg = MintClientConfig {
+            tbs_pks: joint_commitments
+                .iter()
+                .map(|(amount, commitment)| (*amount, commitment.public_key()))
+                .collect(),
+        };
+
+        Ok((mint_cfg, client_cfg))
+    }
 }
@@ -1,12 +1,15 @@
 use minimint_api::db::DatabaseKeyPrefixConst;
 use minimint_api::encoding::{Decodable, Encodable};
 use minimint_api::transaction::OutPoint;
-use minimint_api::{CoinNonce, PeerId};
+use minimint_api::{Amount, CoinNonce, PeerId};
 
 const DB_PREFIX_COIN_NONCE: u8 = 0x10;
 const DB_PREFIX_PROPOSED_PARTIAL_SIG: u8 = 0x11;
 const DB_PREFIX_RECEIVED_PARTIAL_SIG: u8 = 0x12;
 const DB_PREFIX_OUTPUT_OUTCOME: u8 = 0x13;
+const DB_PREFIX_ISSUANCE: u8 = 0x14;
+const DB_PREFIX_REDEMPTION: u8 = 0x15;
+const DB_PREFIX_ECASH_TOTALS: u8 = 0x16;
 
 #[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash)]
 pub struct NonceKey(pub CoinNonce);
@@ -64,3 +67,50 @@ pub struct OutputOutcomeKey(pub OutPoint);
 impl DatabaseKeyPrefixConst for OutputOutcomeKey {
     const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_OUTCOME;
 }
+
+/// Amount of e-cash issued by an output accepted in the current epoch, added to [`EcashTotals`]
+/// at the end of the epoch
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct IssuanceKey(pub OutPoint);
+
+impl DatabaseKeyPrefixConst for IssuanceKey {
+    const DB_PREFIX: u8 = DB_PREFIX_ISSUANCE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct IssuanceKeyPrefix;
+
+impl DatabaseKeyPrefixConst for IssuanceKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_ISSUANCE;
+}
+
+/// Amount of e-cash redeemed in the current epoch by spending the coin with the given nonce, added
+/// to [`EcashTotals`] at the end of the epoch. Kept apart from [`NonceKey`] whose entries have no
+/// value in databases created before amounts were tracked.
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct RedemptionKey(pub CoinNonce);
+
+impl DatabaseKeyPrefixConst for RedemptionKey {
+    const DB_PREFIX: u8 = DB_PREFIX_REDEMPTION;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct RedemptionKeyPrefix;
+
+impl DatabaseKeyPrefixConst for RedemptionKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_REDEMPTION;
+}
+
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct EcashTotalsKey;
+
+impl DatabaseKeyPrefixConst for EcashTotalsKey {
+    const DB_PREFIX: u8 = DB_PREFIX_ECASH_TOTALS;
+}
+
+/// Running totals of the e-cash issued and redeemed in all finished epochs
+#[derive(Debug, Clone, Copy, Default, Encodable, Decodable)]
+pub struct EcashTotals {
+    pub issued: Amount,
+    pub redeemed: Amount,
+}
@@ -3,14 +3,17 @@ mod db;
 
 use crate::config::MintConfig;
 use crate::db::{
-    NonceKey, OutputOutcomeKey, ProposedPartialSignatureKey, ProposedPartialSignaturesKeyPrefix,
-    ReceivedPartialSignatureKey, ReceivedPartialSignatureKeyOutputPrefix,
-    ReceivedPartialSignaturesKeyPrefix,
+    EcashTotals, EcashTotalsKey, IssuanceKey, IssuanceKeyPrefix, NonceKey, OutputOutcomeKey,
+    ProposedPartialSignatureKey, ProposedPartialSignaturesKeyPrefix, ReceivedPartialSignatureKey,
+    ReceivedPartialSignatureKeyOutputPrefix, ReceivedPartialSignaturesKeyPrefix, RedemptionKey,
+    RedemptionKeyPrefix,
 };
 use async_trait::async_trait;
 use itertools::Itertools;
 use minimint_api::db::batch::{BatchItem, BatchTx, DbBatch};
 use minimint_api::db::{Database, RawDatabase};
+use minimint_api::misbehavior::{MisbehaviorLog, PeerFault};
+use minimint_api::module::Audit;
 use minimint_api::transaction::{BlindToken, OutPoint};
 use minimint_api::util::TieredMultiZip;
 use minimint_api::{
@@ -20,7 +23,7 @@ use minimint_api::{
 use rand::{CryptoRng, RngCore};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::Hash;
 use std::sync::Arc;
 use tbs::{
@@ -38,12 +41,15 @@ pub struct Mint {
     pub_key: HashMap<Amount, AggregatePublicKey>,
     threshold: usize, // TODO: move to cfg
     db: Arc<dyn RawDatabase>,
+    misbehavior: MisbehaviorLog,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct PartiallySignedRequest {
-    out_point: OutPoint,
-    partial_signature: minimint_api::PartialSigResponse,
+    /// Issuance the signature shares belong to
+    pub out_point: OutPoint,
+    /// Blind signature shares of the proposing peer, one per requested coin
+    pub partial_signature: minimint_api::PartialSigResponse,
 }
 
 #[async_trait(?Send)]
@@ -129,6 +135,11 @@ impl FederationModule for Mint {
                 .iter()
                 .map(|(_, coin)| BatchItem::insert_new(NonceKey(coin.0.clone()), ())),
         );
+        batch.append_from_iter(
+            input
+                .iter()
+                .map(|(amount, coin)| BatchItem::insert_new(RedemptionKey(coin.0.clone()), amount)),
+        );
         batch.commit();
 
         Ok(amount)
@@ -170,6 +181,7 @@ impl FederationModule for Mint {
             },
             PartialSigResponse(partial_sig),
         );
+        batch.append_insert_new(IssuanceKey(out_point), output.amount());
 
         batch.commit();
         Ok(output.amount())
@@ -180,6 +192,38 @@ impl FederationModule for Mint {
         mut batch: BatchTx<'a>,
         _rng: impl RngCore + CryptoRng + 'a,
     ) {
+        // Only the issuances and redemptions of this epoch are left, so adding them to the totals
+        // doesn't require scanning all coins ever issued or spent
+        let issuances = self
+            .db
+            .find_by_prefix::<_, IssuanceKey, Amount>(&IssuanceKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .collect::<Vec<_>>();
+        let redemptions = self
+            .db
+            .find_by_prefix::<_, RedemptionKey, Amount>(&RedemptionKeyPrefix)
+            .map(|res| res.expect("DB error"))
+            .collect::<Vec<_>>();
+        let issued = issuances.iter().map(|(_, amount)| *amount).sum::<Amount>();
+        let redeemed = redemptions
+            .iter()
+            .map(|(_, amount)| *amount)
+            .sum::<Amount>();
+        let totals = self.ecash_totals();
+        batch.append_insert(
+            EcashTotalsKey,
+            EcashTotals {
+                issued: totals.issued + issued,
+                redeemed: totals.redeemed + redeemed,
+            },
+        );
+        batch.append_from_iter(issuances.into_iter().map(|(key, _)| BatchItem::delete(key)));
+        batch.append_from_iter(
+            redemptions
+                .into_iter()
+                .map(|(key, _)| BatchItem::delete(key)),
+        );
+
         // Finalize partial signatures for which we now have enough shares
         let req_psigs = self
             .db
@@ -193,22 +237,28 @@ impl FederationModule for Mint {
             .into_group_map();
 
         // TODO: use own par iter impl that allows efficient use of accumulators or just decouple it entirely (doesn't need consensus)
-        let par_batches = req_psigs
+        let (par_batches, faults): (Vec<_>, Vec<_>) = req_psigs
             .into_par_iter()
             .filter_map(|(issuance_id, shares)| {
                 let mut batch = DbBatch::new();
                 let mut batch_tx = batch.transaction();
 
-                if shares.len() > self.threshold {
+                // Exactly `threshold` shares suffice, waiting for more would stall issuance while
+                // the maximum number of faulty peers is offline
+                if shares.len() >= self.threshold {
                     debug!(
                         "Trying to combine sig shares for issuance request {}",
                         issuance_id
                     );
                     let (bsig, errors) = self.combine(shares.clone());
                     // FIXME: validate shares before writing to DB to make combine infallible
-                    if !errors.0.is_empty() {
-                        warn!("Peer sent faulty share: {:?}", errors);
-                    }
+                    // A peer is blamed once per kind of fault, even if several of its shares are
+                    // faulty
+                    let faults = errors
+                        .0
+                        .iter()
+                        .map(|(peer, error)| (*peer, error.fault()))
+                        .collect::<BTreeSet<_>>();
 
                     match bsig {
                         Ok(blind_signature) => {
@@ -226,18 +276,39 @@ impl FederationModule for Mint {
 
                             batch_tx.append_insert(OutputOutcomeKey(issuance_id), blind_signature);
                             batch_tx.commit();
-                            Some(batch)
+                            Some((batch, faults))
                         }
                         Err(e) => {
                             error!("Could not combine shares: {}", e);
-                            None
+                            if faults.is_empty() {
+                                return None;
+                            }
+
+                            // Faulty shares would make the next attempt fail again and get their
+                            // peers blamed once more, so we only wait for correct shares
+                            let faulty_peers = faults
+                                .iter()
+                                .map(|(peer, _)| *peer)
+                                .collect::<BTreeSet<_>>();
+                            batch_tx.append_from_iter(faulty_peers.into_iter().map(|peer| {
+                                BatchItem::delete(ReceivedPartialSignatureKey {
+                                    request_id: issuance_id,
+                                    peer_id: peer,
+                                })
+                            }));
+                            batch_tx.commit();
+                            Some((batch, faults))
                         }
                     }
                 } else {
                     None
                 }
             })
-            .collect::<Vec<_>>();
+            .unzip();
+        // Faults are counted in the epoch's batch at once since the parallel batches would
+        // overwrite each other's counters
+        self.misbehavior
+            .record(batch.subtransaction(), faults.into_iter().flatten());
         batch.append_from_accumulators(par_batches.into_iter());
         batch.commit();
     }
@@ -272,6 +343,17 @@ impl FederationModule for Mint {
             None
         }
     }
+
+    /// All e-cash issued by the mint has to be backed by other modules, except for the e-cash
+    /// already returned to it. Counting the latter as assets instead of subtracting it from the
+    /// liabilities makes redeeming more than was issued fail the audit rather than underflow.
+    fn audit(&self) -> Audit {
+        let totals = self.ecash_totals();
+        Audit {
+            assets: totals.redeemed,
+            liabilities: totals.issued,
+        }
+    }
 }
 
 impl Mint {
@@ -327,9 +409,24 @@ impl Mint {
             pub_key_shares: cfg.peer_tbs_pks,
             pub_key: aggregate_pub_keys,
             threshold,
+            misbehavior: MisbehaviorLog::new(db.clone()),
             db,
         }
     }
+
+    /// E-cash issued by the federation in finished epochs that wasn't redeemed yet. `None` if more
+    /// e-cash was redeemed than issued, which the audit after every epoch rules out.
+    pub fn outstanding_ecash(&self) -> Option<Amount> {
+        let totals = self.ecash_totals();
+        totals.issued.checked_sub(totals.redeemed)
+    }
+
+    fn ecash_totals(&self) -> EcashTotals {
+        self.db
+            .get_value(&EcashTotalsKey)
+            .expect("DB error")
+            .unwrap_or_default()
+    }
 }
 
 impl Mint {
@@ -505,6 +602,19 @@ pub enum PeerErrorType {
     InvalidAmountTier,
 }
 
+impl PeerErrorType {
+    /// Kind of misbehavior the faulty share is recorded as
+    pub fn fault(&self) -> PeerFault {
+        match self {
+            PeerErrorType::InvalidSignature => PeerFault::InvalidSignatureShare,
+            PeerErrorType::DifferentStructureSigShare | PeerErrorType::InvalidAmountTier => {
+                PeerFault::WrongStructureShare
+            }
+            PeerErrorType::DifferentNonce => PeerFault::WrongNonceShare,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Error)]
 pub enum CombineError {
     #[error(
@@ -539,6 +649,114 @@ impl From<InvalidAmountTierError> for MintError {
 
 #[cfg(test)]
 mod test {
+    use crate::config::MintConfig;
+    use crate::Mint;
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::db::batch::DbBatch;
+    use minimint_api::db::mem_impl::MemDatabase;
+    use minimint_api::db::Database;
+    use minimint_api::module::Audit;
+    use minimint_api::transaction::{BlindToken, OutPoint};
+    use minimint_api::{Amount, Coins, FederationModule, PeerId, TransactionId};
+    use rand::rngs::OsRng;
+    use std::sync::Arc;
+    use tbs::{blind_message, Message};
+
+    #[tokio::test]
+    async fn combine_exactly_threshold_shares() {
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let max_evil = 1;
+        let threshold = peers.len() - max_evil;
+        let (cfgs, _) = MintConfig::trusted_dealer_gen(
+            &peers,
+            max_evil,
+            &[Amount::from_sat(1)],
+            OsRng::new().unwrap(),
+        );
+        let mints = cfgs
+            .into_iter()
+            .map(|(_, cfg)| Mint::new(cfg, threshold, Arc::new(MemDatabase::new())))
+            .collect::<Vec<_>>();
+
+        let (_, blinded_msg) = blind_message(Message::from_bytes(b"test coin"));
+        let output = vec![(Amount::from_sat(1), BlindToken(blinded_msg))]
+            .into_iter()
+            .collect::<Coins<_>>();
+        let out_point = OutPoint {
+            txid: TransactionId::default(),
+            out_idx: 0,
+        };
+        for mint in &mints {
+            let mut batch = DbBatch::new();
+            mint.apply_output(batch.transaction(), &output, out_point)
+                .unwrap();
+            mint.db.apply_batch(batch).unwrap();
+        }
+
+        // The last peer is offline, so only the shares of the others arrive
+        let mut shares = Vec::new();
+        for (peer, mint) in peers.iter().zip(&mints).take(threshold) {
+            let proposal = mint.consensus_proposal(OsRng::new().unwrap()).await;
+            shares.extend(proposal.into_iter().map(|item| (*peer, item)));
+        }
+        assert_eq!(shares.len(), threshold);
+
+        let mint = &mints[0];
+        let mut batch = DbBatch::new();
+        mint.begin_consensus_epoch(batch.transaction(), shares, OsRng::new().unwrap())
+            .await;
+        mint.db.apply_batch(batch).unwrap();
+        let mut batch = DbBatch::new();
+        mint.end_consensus_epoch(batch.transaction(), OsRng::new().unwrap())
+            .await;
+        mint.db.apply_batch(batch).unwrap();
+
+        assert!(matches!(mint.output_status(out_point), Some(Some(_))));
+    }
+
+    #[tokio::test]
+    async fn count_issuances_once() {
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let (cfgs, _) = MintConfig::trusted_dealer_gen(
+            &peers,
+            1,
+            &[Amount::from_sat(1)],
+            OsRng::new().unwrap(),
+        );
+        let cfg = cfgs.into_iter().next().unwrap().1;
+        let mint = Mint::new(cfg, 3, Arc::new(MemDatabase::new()));
+
+        let (_, blinded_msg) = blind_message(Message::from_bytes(b"test coin"));
+        let output = vec![(Amount::from_sat(1), BlindToken(blinded_msg))]
+            .into_iter()
+            .collect::<Coins<_>>();
+        let out_point = OutPoint {
+            txid: TransactionId::default(),
+            out_idx: 0,
+        };
+        let mut batch = DbBatch::new();
+        mint.apply_output(batch.transaction(), &output, out_point)
+            .unwrap();
+        mint.db.apply_batch(batch).unwrap();
+        assert_eq!(mint.outstanding_ecash(), Some(Amount::ZERO));
+
+        // Later epochs don't add the issuance to the totals again
+        for _ in 0..2 {
+            let mut batch = DbBatch::new();
+            mint.end_consensus_epoch(batch.transaction(), OsRng::new().unwrap())
+                .await;
+            mint.db.apply_batch(batch).unwrap();
+            assert_eq!(mint.outstanding_ecash(), Some(Amount::from_sat(1)));
+            assert_eq!(
+                mint.audit(),
+                Audit {
+                    assets: Amount::ZERO,
+                    liabilities: Amount::from_sat(1),
+                }
+            );
+        }
+    }
+
     // TODO: reactivate
     /*
     use crate::{CombineError, Mint, MintError, MintShareErrors, PeerErrorType};
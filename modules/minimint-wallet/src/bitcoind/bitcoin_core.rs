@@ -0,0 +1,238 @@
+use crate::bitcoind::{filter_transactions, BitcoindRpc, RpcError};
+use crate::Feerate;
+use async_trait::async_trait;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::hashes::Hash as BitcoinHash;
+use bitcoin::{BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use bitcoincore_rpc_async::{Auth, RpcApi};
+use minimint_api::encoding::Encodable;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Error code bitcoind returns for `getblockhash` if the height is above its tip
+const RPC_INVALID_PARAMETER: i64 = -8;
+
+/// Chain backend using the JSON-RPC interface of a bitcoind full node
+pub struct BitcoindClient {
+    rpc: bitcoincore_rpc_async::Client,
+    /// Used for batch requests, which the RPC client doesn't support
+    http: reqwest::Client,
+    url: String,
+    user: String,
+    pass: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    id: u64,
+    result: Option<Value>,
+    error: Option<BatchError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchError {
+    code: i64,
+    message: String,
+}
+
+/// Unspent output as returned by `gettxout`
+#[derive(Debug, Deserialize)]
+struct TxOutResult {
+    /// Value in BTC
+    value: f64,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: ScriptPubKeyResult,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScriptPubKeyResult {
+    hex: String,
+}
+
+impl BitcoindClient {
+    /// Connects to the bitcoind RPC interface at `address`, e.g. `127.0.0.1:18443`
+    pub async fn new(address: &str, user: &str, pass: &str) -> Result<Self, RpcError> {
+        let rpc = bitcoincore_rpc_async::Client::new(
+            address.to_owned(),
+            Auth::UserPass(user.to_owned(), pass.to_owned()),
+        )
+        .await?;
+
+        let url = if address.contains("://") {
+            address.to_owned()
+        } else {
+            format!("http://{}", address)
+        };
+
+        Ok(BitcoindClient {
+            rpc,
+            http: reqwest::Client::new(),
+            url,
+            user: user.to_owned(),
+            pass: pass.to_owned(),
+        })
+    }
+}
+
+#[async_trait]
+impl BitcoindRpc for BitcoindClient {
+    async fn get_network(&self) -> Result<Network, RpcError> {
+        let bc = self.rpc.get_blockchain_info().await?;
+        match bc.chain.as_str() {
+            "main" => Ok(Network::Bitcoin),
+            "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            "signet" => Ok(Network::Signet),
+            _ => Err(RpcError::UnknownNetwork(bc.chain)),
+        }
+    }
+
+    async fn get_block_height(&self) -> Result<u64, RpcError> {
+        Ok(self.rpc.get_block_count().await?)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        let block_hash = self.rpc.get_block_hash(height).await?;
+        Ok(BlockHash::from_inner(block_hash.into_inner()))
+    }
+
+    /// Fetches all hashes in a single JSON-RPC batch request
+    async fn get_block_hashes(&self, heights: Range<u64>) -> Result<Vec<BlockHash>, RpcError> {
+        if heights.start >= heights.end {
+            return Ok(vec![]);
+        }
+
+        let requests = heights
+            .clone()
+            .map(|height| {
+                json!({
+                    "jsonrpc": "1.0",
+                    "id": height,
+                    "method": "getblockhash",
+                    "params": [height],
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut responses = self
+            .http
+            .post(&self.url)
+            .basic_auth(&self.user, Some(&self.pass))
+            .json(&requests)
+            .send()
+            .await?
+            .json::<Vec<BatchResponse>>()
+            .await?;
+
+        // Responses of batch requests may arrive in any order
+        responses.sort_by_key(|response| response.id);
+        if responses
+            .iter()
+            .map(|response| response.id)
+            .ne(heights.clone())
+        {
+            return Err(RpcError::MalformedResponse(format!(
+                "Expected responses for heights {:?}",
+                heights
+            )));
+        }
+
+        responses
+            .into_iter()
+            .map(|response| match response {
+                BatchResponse {
+                    id,
+                    error: Some(error),
+                    ..
+                } => {
+                    if error.code == RPC_INVALID_PARAMETER {
+                        Err(RpcError::UnknownBlockHeight(id))
+                    } else {
+                        Err(RpcError::MalformedResponse(error.message))
+                    }
+                }
+                BatchResponse {
+                    result: Some(Value::String(hash)),
+                    ..
+                } => BlockHash::from_str(&hash)
+                    .map_err(|e| RpcError::MalformedResponse(format!("Invalid block hash: {}", e))),
+                BatchResponse { id, .. } => Err(RpcError::MalformedResponse(format!(
+                    "No block hash returned for height {}",
+                    id
+                ))),
+            })
+            .collect()
+    }
+
+    async fn find_transactions_in_block(
+        &self,
+        hash: &BlockHash,
+        _height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError> {
+        let hash = bitcoincore_rpc_async::bitcoin::BlockHash::from_inner(hash.into_inner());
+        let block_txids = self
+            .rpc
+            .get_block_info(&hash)
+            .await?
+            .tx
+            .into_iter()
+            .map(|txid| Txid::from_inner(txid.into_inner()))
+            .collect::<Vec<_>>();
+        Ok(filter_transactions(transactions, &block_txids))
+    }
+
+    async fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, RpcError> {
+        Ok(self
+            .rpc
+            .estimate_smart_fee(confirmation_target, None)
+            .await?
+            .fee_rate
+            .map(|per_kb| Feerate {
+                sats_per_kvb: per_kb.as_sat(),
+            }))
+    }
+
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        let mut raw_tx = Vec::new();
+        transaction
+            .consensus_encode(&mut raw_tx)
+            .expect("Nothing can go wrong with a vec");
+        self.rpc.send_raw_transaction(&raw_tx).await?;
+        Ok(())
+    }
+
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        _script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError> {
+        // Mempool outputs are excluded, only confirmed ones count
+        let tx_out: Option<TxOutResult> = self
+            .rpc
+            .call(
+                "gettxout",
+                &[
+                    json!(out_point.txid.to_string()),
+                    json!(out_point.vout),
+                    json!(false),
+                ],
+            )
+            .await?;
+
+        tx_out
+            .map(|tx_out| {
+                let value = bitcoin::Amount::from_btc(tx_out.value)
+                    .map_err(|e| RpcError::MalformedResponse(format!("Invalid value: {}", e)))?;
+                let script = Vec::<u8>::from_hex(&tx_out.script_pubkey.hex)
+                    .map_err(|e| RpcError::MalformedResponse(format!("Invalid script: {}", e)))?;
+                Ok(TxOut {
+                    value: value.as_sat(),
+                    script_pubkey: Script::from(script),
+                })
+            })
+            .transpose()
+    }
+}
@@ -0,0 +1,390 @@
+use crate::bitcoind::{network_from_genesis, BitcoindRpc, RpcError};
+use crate::Feerate;
+use async_trait::async_trait;
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::ops::Range;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Chain backend using the JSON-RPC protocol of an [Electrum](https://electrumx.readthedocs.io)
+/// server.
+///
+/// Every call opens a new plain TCP connection, so the server should be reachable through a
+/// trusted network (e.g. run locally).
+pub struct ElectrumClient {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerFeatures {
+    genesis_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeaderNotification {
+    height: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Headers {
+    count: u64,
+    /// Concatenated serialized block headers
+    hex: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryEntry {
+    tx_hash: String,
+    /// Confirmation height, 0 or negative for unconfirmed transactions
+    height: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnspentEntry {
+    tx_hash: String,
+    tx_pos: u32,
+    /// Confirmation height, 0 for unconfirmed transactions
+    height: i64,
+    value: u64,
+}
+
+impl ElectrumClient {
+    /// Creates a client for the Electrum server at `address`, e.g. `127.0.0.1:50001`
+    pub fn new(address: &str) -> Self {
+        ElectrumClient {
+            address: address.to_owned(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T, RpcError> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let (read, mut write) = stream.into_split();
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 0,
+            "method": method,
+            "params": params,
+        });
+        let mut request = serde_json::to_vec(&request)?;
+        request.push(b'\n');
+        write.write_all(&request).await?;
+
+        let mut response = String::new();
+        BufReader::new(read).read_line(&mut response).await?;
+        let response: Response = serde_json::from_str(&response)?;
+
+        match response {
+            Response {
+                error: Some(error), ..
+            } => Err(RpcError::Electrum(error.to_string())),
+            Response {
+                result: Some(result),
+                ..
+            } => Ok(serde_json::from_value(result)?),
+            _ => Err(RpcError::MalformedResponse(format!(
+                "Neither result nor error returned for {}",
+                method
+            ))),
+        }
+    }
+}
+
+/// Maximum number of headers Electrum servers return for a single `blockchain.block.headers` call
+const MAX_HEADERS_PER_REQUEST: u64 = 2016;
+
+/// Size of a serialized block header
+const HEADER_SIZE: usize = 80;
+
+/// Electrum indexes transactions by the reversed SHA256 hash of output scripts
+fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hash.to_hex()
+}
+
+#[async_trait]
+impl BitcoindRpc for ElectrumClient {
+    async fn get_network(&self) -> Result<Network, RpcError> {
+        let features: ServerFeatures = self.call("server.features", json!([])).await?;
+        let genesis_hash = BlockHash::from_str(&features.genesis_hash)
+            .map_err(|e| RpcError::MalformedResponse(format!("Invalid genesis hash: {}", e)))?;
+        network_from_genesis(&genesis_hash)
+    }
+
+    async fn get_block_height(&self) -> Result<u64, RpcError> {
+        let tip: HeaderNotification = self.call("blockchain.headers.subscribe", json!([])).await?;
+        Ok(tip.height)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        let header: String = self
+            .call("blockchain.block.header", json!([height]))
+            .await
+            .map_err(|e| match e {
+                RpcError::Electrum(_) => RpcError::UnknownBlockHeight(height),
+                e => e,
+            })?;
+        let header: BlockHeader = Vec::<u8>::from_hex(&header)
+            .ok()
+            .and_then(|bytes| deserialize(&bytes).ok())
+            .ok_or_else(|| RpcError::MalformedResponse("Invalid block header".to_owned()))?;
+        Ok(header.block_hash())
+    }
+
+    async fn get_block_hashes(&self, heights: Range<u64>) -> Result<Vec<BlockHash>, RpcError> {
+        let mut block_hashes = Vec::with_capacity((heights.end - heights.start) as usize);
+        let mut start = heights.start;
+        while start < heights.end {
+            let count = std::cmp::min(heights.end - start, MAX_HEADERS_PER_REQUEST);
+            let headers: Headers = self
+                .call("blockchain.block.headers", json!([start, count]))
+                .await?;
+            // The server returns fewer headers if the chain ends before
+            if headers.count < count {
+                return Err(RpcError::UnknownBlockHeight(start + headers.count));
+            }
+
+            let bytes = Vec::<u8>::from_hex(&headers.hex)
+                .map_err(|e| RpcError::MalformedResponse(format!("Invalid headers: {}", e)))?;
+            if bytes.len() != count as usize * HEADER_SIZE {
+                return Err(RpcError::MalformedResponse(
+                    "Wrong length of headers".to_owned(),
+                ));
+            }
+            for header in bytes.chunks(HEADER_SIZE) {
+                let header: BlockHeader = deserialize(header).map_err(|e| {
+                    RpcError::MalformedResponse(format!("Invalid block header: {}", e))
+                })?;
+                block_hashes.push(header.block_hash());
+            }
+
+            start += count;
+        }
+        Ok(block_hashes)
+    }
+
+    /// Electrum can't list the transactions of a block, so instead we look up the history of each
+    /// transaction's first output script. Since the server only reports heights this relies on
+    /// `hash` being part of the server's best chain.
+    async fn find_transactions_in_block(
+        &self,
+        _hash: &BlockHash,
+        height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError> {
+        let mut included = Vec::new();
+        for tx in transactions {
+            let txid = tx.txid();
+            let script = match tx.output.first() {
+                Some(output) => &output.script_pubkey,
+                None => continue,
+            };
+
+            let history: Vec<HistoryEntry> = self
+                .call(
+                    "blockchain.scripthash.get_history",
+                    json!([script_hash(script)]),
+                )
+                .await?;
+            let confirmed = history.iter().any(|entry| {
+                entry.height == height as i64
+                    && Txid::from_str(&entry.tx_hash).map_or(false, |hash| hash == txid)
+            });
+            if confirmed {
+                included.push(txid);
+            }
+        }
+        Ok(included)
+    }
+
+    async fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, RpcError> {
+        // Fee rate in BTC/kvB, negative if the server can't estimate fees
+        let btc_per_kvb: f64 = self
+            .call("blockchain.estimatefee", json!([confirmation_target]))
+            .await?;
+
+        if btc_per_kvb < 0.0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Feerate {
+            sats_per_kvb: (btc_per_kvb * 100_000_000.0).round() as u64,
+        }))
+    }
+
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        self.call::<String>(
+            "blockchain.transaction.broadcast",
+            json!([serialize_hex(transaction)]),
+        )
+        .await
+        .map_err(|e| match e {
+            RpcError::Electrum(reason) => RpcError::TransactionRejected(reason),
+            e => e,
+        })?;
+        Ok(())
+    }
+
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError> {
+        let unspent: Vec<UnspentEntry> = self
+            .call(
+                "blockchain.scripthash.listunspent",
+                json!([script_hash(script_pubkey)]),
+            )
+            .await?;
+        Ok(unspent
+            .into_iter()
+            .find(|entry| {
+                entry.height > 0
+                    && entry.tx_pos == out_point.vout
+                    && Txid::from_str(&entry.tx_hash).map_or(false, |hash| hash == out_point.txid)
+            })
+            .map(|entry| TxOut {
+                value: entry.value,
+                script_pubkey: script_pubkey.clone(),
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoind::electrum::{script_hash, ElectrumClient};
+    use crate::bitcoind::BitcoindRpc;
+    use crate::Feerate;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize_hex;
+    use bitcoin::{Network, Script, Transaction, TxOut};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Answers each request with the result or error `handler` returns for its method and params
+    async fn mock_electrum<F>(handler: F) -> ElectrumClient
+    where
+        F: Fn(&str, &Value) -> Result<Value, Value> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handler = Arc::new(handler);
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (read, mut write) = socket.into_split();
+                let mut request = String::new();
+                BufReader::new(read).read_line(&mut request).await.unwrap();
+                let request: Value = serde_json::from_str(&request).unwrap();
+
+                let response =
+                    match handler(request["method"].as_str().unwrap(), &request["params"]) {
+                        Ok(result) => {
+                            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                        }
+                        Err(error) => {
+                            json!({"jsonrpc": "2.0", "id": request["id"], "error": error})
+                        }
+                    };
+                let mut response = serde_json::to_vec(&response).unwrap();
+                response.push(b'\n');
+                write.write_all(&response).await.unwrap();
+            }
+        });
+
+        ElectrumClient::new(&address)
+    }
+
+    #[tokio::test]
+    async fn query_electrum() {
+        let genesis = genesis_block(Network::Regtest);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let unconfirmed_tx = Transaction {
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::from(vec![0x52]),
+            }],
+            ..tx.clone()
+        };
+
+        let txid = tx.txid().to_string();
+        let tx_script_hash = script_hash(&tx.output[0].script_pubkey);
+        let genesis_hash = genesis.block_hash().to_string();
+        let genesis_header = serialize_hex(&genesis.header);
+        let raw_tx = serialize_hex(&tx);
+        let client = mock_electrum(move |method, params| match method {
+            "server.features" => Ok(json!({ "genesis_hash": genesis_hash })),
+            "blockchain.headers.subscribe" => Ok(json!({"height": 7, "hex": genesis_header})),
+            "blockchain.block.header" if params[0] == 0 => Ok(json!(genesis_header)),
+            "blockchain.block.header" => Err(json!({"code": 1, "message": "height out of range"})),
+            "blockchain.scripthash.get_history" if params[0] == tx_script_hash.as_str() => {
+                Ok(json!([{"tx_hash": txid, "height": 5}]))
+            }
+            "blockchain.scripthash.get_history" => Ok(json!([])),
+            "blockchain.estimatefee" if params[0] == 6 => Ok(json!(0.0001)),
+            "blockchain.estimatefee" => Ok(json!(-1)),
+            "blockchain.transaction.broadcast" if params[0] == raw_tx.as_str() => Ok(json!(txid)),
+            _ => Err(json!({"code": 1, "message": "unexpected request"})),
+        })
+        .await;
+
+        assert_eq!(client.get_network().await.unwrap(), Network::Regtest);
+        assert_eq!(client.get_block_height().await.unwrap(), 7);
+        assert_eq!(
+            client.get_block_hash(0).await.unwrap(),
+            genesis.block_hash()
+        );
+        assert!(client.get_block_hash(8).await.is_err());
+
+        let transactions = [tx.clone(), unconfirmed_tx.clone()];
+        let block_hash = genesis.block_hash();
+        assert_eq!(
+            client
+                .find_transactions_in_block(&block_hash, 5, &transactions)
+                .await
+                .unwrap(),
+            vec![tx.txid()]
+        );
+        assert!(client
+            .find_transactions_in_block(&block_hash, 4, &transactions)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            client.get_fee_rate(6).await.unwrap(),
+            Some(Feerate {
+                sats_per_kvb: 10000
+            })
+        );
+        assert_eq!(client.get_fee_rate(1).await.unwrap(), None);
+
+        assert!(client.submit_transaction(&tx).await.is_ok());
+        assert!(client.submit_transaction(&unconfirmed_tx).await.is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use crate::bitcoind::{filter_transactions, network_from_genesis, BitcoindRpc, RpcError};
+use crate::Feerate;
+use async_trait::async_trait;
+use bitcoin::consensus::encode::{deserialize, serialize_hex};
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::{BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Summary of a block as returned by the `/blocks` endpoint
+#[derive(Debug, Deserialize)]
+struct BlockSummary {
+    id: BlockHash,
+    height: u64,
+}
+
+/// Spending status of an output as returned by the `/tx/:txid/outspend/:vout` endpoint
+#[derive(Debug, Deserialize)]
+struct OutSpend {
+    spent: bool,
+}
+
+/// Confirmation status of a transaction as returned by the `/tx/:txid/status` endpoint
+#[derive(Debug, Deserialize)]
+struct TxStatus {
+    confirmed: bool,
+}
+
+/// Chain backend using the REST API of an [Esplora](https://github.com/Blockstream/esplora)
+/// server
+pub struct EsploraClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl EsploraClient {
+    /// Creates a client for the Esplora API at `url`, e.g. `https://blockstream.info/api`
+    pub fn new(url: &str) -> Self {
+        EsploraClient {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    /// Fetches `path`, returns `None` if the server doesn't know the requested resource
+    async fn get(&self, path: &str) -> Result<Option<reqwest::Response>, RpcError> {
+        let response = self
+            .client
+            .get(format!("{}{}", self.url, path))
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?))
+    }
+
+    async fn get_block_hash_text(&self, height: u64) -> Result<BlockHash, RpcError> {
+        let hash = self
+            .get(&format!("/block-height/{}", height))
+            .await?
+            .ok_or(RpcError::UnknownBlockHeight(height))?
+            .text()
+            .await?;
+        BlockHash::from_str(hash.trim())
+            .map_err(|e| RpcError::MalformedResponse(format!("Invalid block hash: {}", e)))
+    }
+}
+
+#[async_trait]
+impl BitcoindRpc for EsploraClient {
+    async fn get_network(&self) -> Result<Network, RpcError> {
+        let genesis_hash = self.get_block_hash_text(0).await?;
+        network_from_genesis(&genesis_hash)
+    }
+
+    async fn get_block_height(&self) -> Result<u64, RpcError> {
+        let height = self
+            .get("/blocks/tip/height")
+            .await?
+            .ok_or(RpcError::Unavailable)?
+            .text()
+            .await?;
+        height
+            .trim()
+            .parse()
+            .map_err(|e| RpcError::MalformedResponse(format!("Invalid block height: {}", e)))
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        self.get_block_hash_text(height).await
+    }
+
+    async fn get_block_hashes(&self, heights: Range<u64>) -> Result<Vec<BlockHash>, RpcError> {
+        let mut block_hashes = Vec::with_capacity((heights.end - heights.start) as usize);
+        // Each request returns up to 10 blocks going down from the requested height
+        let mut end = heights.end;
+        while end > heights.start {
+            let blocks = self
+                .get(&format!("/blocks/{}", end - 1))
+                .await?
+                .ok_or(RpcError::UnknownBlockHeight(end - 1))?
+                .json::<Vec<BlockSummary>>()
+                .await?;
+
+            let before = block_hashes.len();
+            for block in blocks {
+                if end == heights.start || block.height != end - 1 {
+                    break;
+                }
+                block_hashes.push(block.id);
+                end -= 1;
+            }
+            if block_hashes.len() == before {
+                return Err(RpcError::UnknownBlockHeight(end - 1));
+            }
+        }
+        block_hashes.reverse();
+        Ok(block_hashes)
+    }
+
+    async fn find_transactions_in_block(
+        &self,
+        hash: &BlockHash,
+        _height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError> {
+        let block_txids = self
+            .get(&format!("/block/{}/txids", hash))
+            .await?
+            .ok_or(RpcError::UnknownBlock(*hash))?
+            .json::<Vec<Txid>>()
+            .await?;
+        Ok(filter_transactions(transactions, &block_txids))
+    }
+
+    async fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, RpcError> {
+        // Maps confirmation targets to fee rates in sat/vB, only a few targets are included
+        let estimates = self
+            .get("/fee-estimates")
+            .await?
+            .ok_or(RpcError::Unavailable)?
+            .json::<HashMap<String, f64>>()
+            .await?;
+
+        let mut estimates = estimates
+            .into_iter()
+            .filter_map(|(target, rate)| Some((target.parse::<u16>().ok()?, rate)))
+            .collect::<Vec<_>>();
+        estimates.sort_by_key(|(target, _)| *target);
+
+        // Use the estimate for the longest target that still confirms in time, or the shortest
+        // target if none does
+        let sats_per_vb = estimates
+            .iter()
+            .rev()
+            .find(|(target, _)| *target <= confirmation_target)
+            .or_else(|| estimates.first())
+            .map(|(_, rate)| *rate);
+
+        Ok(sats_per_vb.map(|sats_per_vb| Feerate {
+            sats_per_kvb: (sats_per_vb * 1000.0).ceil() as u64,
+        }))
+    }
+
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        let response = self
+            .client
+            .post(format!("{}/tx", self.url))
+            .body(serialize_hex(transaction))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(RpcError::TransactionRejected(response.text().await?))
+        }
+    }
+
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        _script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError> {
+        let status = match self.get(&format!("/tx/{}/status", out_point.txid)).await? {
+            Some(response) => response.json::<TxStatus>().await?,
+            None => return Ok(None),
+        };
+        if !status.confirmed {
+            return Ok(None);
+        }
+
+        let outspend = self
+            .get(&format!(
+                "/tx/{}/outspend/{}",
+                out_point.txid, out_point.vout
+            ))
+            .await?
+            .ok_or(RpcError::Unavailable)?
+            .json::<OutSpend>()
+            .await?;
+        if outspend.spent {
+            return Ok(None);
+        }
+
+        let raw_tx = self
+            .get(&format!("/tx/{}/hex", out_point.txid))
+            .await?
+            .ok_or(RpcError::Unavailable)?
+            .text()
+            .await?;
+        let tx: Transaction = Vec::<u8>::from_hex(raw_tx.trim())
+            .ok()
+            .and_then(|raw_tx| deserialize(&raw_tx).ok())
+            .ok_or_else(|| RpcError::MalformedResponse("Invalid transaction".to_owned()))?;
+        Ok(tx.output.get(out_point.vout as usize).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoind::esplora::EsploraClient;
+    use crate::bitcoind::BitcoindRpc;
+    use crate::Feerate;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves canned responses keyed by `"<METHOD> <path>"`, unknown paths return 404. Request
+    /// bodies are recorded so tests can check what was posted.
+    async fn mock_esplora(
+        routes: HashMap<String, String>,
+    ) -> (EsploraClient, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = bodies.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let (head, body) = loop {
+                    let read = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let head = text[..end].to_owned();
+                        let content_length = head
+                            .lines()
+                            .filter_map(|line| {
+                                let (name, value) = line.split_at(line.find(':')?);
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    value[1..].trim().parse::<usize>().ok()
+                                } else {
+                                    None
+                                }
+                            })
+                            .next()
+                            .unwrap_or(0);
+                        if text.len() >= end + 4 + content_length {
+                            break (head, text[end + 4..].to_owned());
+                        }
+                    }
+                };
+
+                let route = head.split(' ').take(2).collect::<Vec<_>>().join(" ");
+                recorded.lock().unwrap().push(body);
+                let (status, body) = match routes.get(&route) {
+                    Some(body) => ("200 OK", body.clone()),
+                    None => ("404 Not Found", "not found".to_owned()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (EsploraClient::new(&url), bodies)
+    }
+
+    #[tokio::test]
+    async fn query_esplora() {
+        let block_hash = BlockHash::hash(b"block 3");
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let other_tx = Transaction {
+            lock_time: 1,
+            ..tx.clone()
+        };
+
+        let other_txid = other_tx.txid();
+        let routes = vec![
+            (
+                "GET /block-height/0".to_owned(),
+                genesis_block(Network::Regtest).block_hash().to_string(),
+            ),
+            ("GET /blocks/tip/height".to_owned(), "5".to_owned()),
+            ("GET /block-height/3".to_owned(), block_hash.to_string()),
+            (
+                format!("GET /block/{}/txids", block_hash),
+                format!("[\"{}\"]", tx.txid()),
+            ),
+            (
+                "GET /fee-estimates".to_owned(),
+                "{\"1\": 20.5, \"6\": 10.0, \"144\": 1.0}".to_owned(),
+            ),
+            ("POST /tx".to_owned(), tx.txid().to_string()),
+            (
+                format!("GET /tx/{}/status", tx.txid()),
+                "{\"confirmed\": true, \"block_height\": 3}".to_owned(),
+            ),
+            (
+                format!("GET /tx/{}/outspend/0", tx.txid()),
+                "{\"spent\": false}".to_owned(),
+            ),
+            (
+                format!("GET /tx/{}/hex", tx.txid()),
+                bitcoin::consensus::encode::serialize_hex(&tx),
+            ),
+            (
+                format!("GET /tx/{}/status", other_txid),
+                "{\"confirmed\": false}".to_owned(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let (client, bodies) = mock_esplora(routes).await;
+
+        assert_eq!(client.get_network().await.unwrap(), Network::Regtest);
+        assert_eq!(client.get_block_height().await.unwrap(), 5);
+        assert_eq!(client.get_block_hash(3).await.unwrap(), block_hash);
+        assert!(client.get_block_hash(6).await.is_err());
+        assert_eq!(
+            client
+                .find_transactions_in_block(&block_hash, 3, &[tx.clone(), other_tx])
+                .await
+                .unwrap(),
+            vec![tx.txid()]
+        );
+        assert_eq!(
+            client.get_fee_rate(24).await.unwrap(),
+            Some(Feerate {
+                sats_per_kvb: 10000
+            })
+        );
+        assert_eq!(
+            client.get_fee_rate(1).await.unwrap(),
+            Some(Feerate {
+                sats_per_kvb: 20500
+            })
+        );
+
+        assert_eq!(
+            client
+                .get_unspent_output(&OutPoint::new(tx.txid(), 0), &tx.output[0].script_pubkey)
+                .await
+                .unwrap(),
+            Some(tx.output[0].clone())
+        );
+        assert_eq!(
+            client
+                .get_unspent_output(&OutPoint::new(other_txid, 0), &Script::new())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            client
+                .get_unspent_output(&OutPoint::new(Txid::hash(b"unknown"), 0), &Script::new())
+                .await
+                .unwrap(),
+            None
+        );
+
+        bodies.lock().unwrap().clear();
+        client.submit_transaction(&tx).await.unwrap();
+        assert_eq!(
+            *bodies.lock().unwrap(),
+            vec![bitcoin::consensus::encode::serialize_hex(&tx)]
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use crate::bitcoind::{filter_transactions, BitcoindRpc, RpcError};
+use crate::Feerate;
+use async_trait::async_trait;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::util::merkleblock::PartialMerkleTree;
+use bitcoin::{
+    BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxMerkleNode, TxOut, Txid,
+};
+use minimint_api::encoding::Decodable;
+use minimint_api::TxOutProof;
+use std::sync::Mutex;
+
+/// In-memory regtest chain implementing [`BitcoindRpc`] that tests can mine blocks on, e.g. to
+/// exercise the wallet's consensus logic without running bitcoind
+pub struct FakeBitcoindRpc {
+    network: Network,
+    state: Mutex<FakeChain>,
+}
+
+#[derive(Default)]
+struct FakeChain {
+    blocks: Vec<FakeBlock>,
+    mempool: Vec<Transaction>,
+    fee_rate: Option<Feerate>,
+    failures: u32,
+    /// Number of blocks ever mined, makes blocks replacing invalidated ones get new hashes
+    mined: u64,
+}
+
+struct FakeBlock {
+    header: BlockHeader,
+    /// Starts with the coinbase transaction
+    transactions: Vec<Transaction>,
+}
+
+impl FakeBlock {
+    fn hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    fn txids(&self) -> Vec<Txid> {
+        self.transactions.iter().map(|tx| tx.txid()).collect()
+    }
+}
+
+impl FakeBitcoindRpc {
+    /// Creates a chain that only contains a genesis block
+    pub fn new() -> FakeBitcoindRpc {
+        let fake = FakeBitcoindRpc {
+            network: Network::Regtest,
+            state: Default::default(),
+        };
+        fake.mine_blocks(1);
+        fake
+    }
+
+    /// Mines `count` blocks, the first one includes all transactions from the mempool. Returns the
+    /// hashes of the new blocks.
+    pub fn mine_blocks(&self, count: u64) -> Vec<BlockHash> {
+        let mut state = self.state.lock().unwrap();
+        (0..count)
+            .map(|_| {
+                let height = state.blocks.len() as u64;
+                let prev_blockhash = state
+                    .blocks
+                    .last()
+                    .map(|block| block.hash())
+                    .unwrap_or_default();
+                state.mined += 1;
+
+                let coinbase = Transaction {
+                    version: 1,
+                    lock_time: 0,
+                    input: vec![TxIn {
+                        previous_output: OutPoint::null(),
+                        script_sig: Builder::new()
+                            .push_int(height as i64)
+                            .push_int(state.mined as i64)
+                            .into_script(),
+                        sequence: u32::MAX,
+                        witness: vec![],
+                    }],
+                    output: vec![],
+                };
+                let mut transactions = vec![coinbase];
+                transactions.append(&mut state.mempool);
+
+                let txids = transactions.iter().map(|tx| tx.txid()).collect::<Vec<_>>();
+                let header = BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: merkle_root(&txids),
+                    time: height as u32,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                };
+                let block = FakeBlock {
+                    header,
+                    transactions,
+                };
+                let hash = block.hash();
+                state.blocks.push(block);
+                hash
+            })
+            .collect()
+    }
+
+    /// Removes the last `count` blocks to simulate a reorg, their transactions are put back into
+    /// the mempool. The genesis block can't be removed.
+    pub fn invalidate_blocks(&self, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        let remaining = std::cmp::max(state.blocks.len().saturating_sub(count as usize), 1);
+        // Coinbase transactions vanish together with their block
+        let transactions = state
+            .blocks
+            .drain(remaining..)
+            .flat_map(|block| block.transactions.into_iter().skip(1))
+            .collect::<Vec<_>>();
+        state.mempool.splice(0..0, transactions);
+    }
+
+    /// Sets the fee rate returned by [`BitcoindRpc::get_fee_rate`], `None` simulates a node that
+    /// can't estimate fees
+    pub fn set_fee_rate(&self, fee_rate: Option<Feerate>) {
+        self.state.lock().unwrap().fee_rate = fee_rate;
+    }
+
+    /// Lets the next `count` calls fail with [`RpcError::Unavailable`]
+    pub fn fail_next_calls(&self, count: u32) {
+        self.state.lock().unwrap().failures = count;
+    }
+
+    /// Transactions submitted since the last block was mined
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.state.lock().unwrap().mempool.clone()
+    }
+
+    /// Proves that the confirmed transaction `txid` is part of its block, like bitcoind's
+    /// `gettxoutproof`
+    pub fn txout_proof(&self, txid: Txid) -> Option<TxOutProof> {
+        let state = self.state.lock().unwrap();
+        let block = state
+            .blocks
+            .iter()
+            .find(|block| block.transactions.iter().any(|tx| tx.txid() == txid))?;
+
+        let txids = block.txids();
+        let matches = txids.iter().map(|id| *id == txid).collect::<Vec<_>>();
+        let mut proof = bitcoin::consensus::serialize(&block.header);
+        proof.extend(bitcoin::consensus::serialize(
+            &PartialMerkleTree::from_txids(&txids, &matches),
+        ));
+        Some(TxOutProof::consensus_decode(&proof[..]).expect("Proof belongs to the header"))
+    }
+
+    fn check_available(&self) -> Result<std::sync::MutexGuard<FakeChain>, RpcError> {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(RpcError::Unavailable);
+        }
+        Ok(state)
+    }
+}
+
+impl Default for FakeBitcoindRpc {
+    fn default() -> Self {
+        FakeBitcoindRpc::new()
+    }
+}
+
+#[async_trait]
+impl BitcoindRpc for FakeBitcoindRpc {
+    async fn get_network(&self) -> Result<Network, RpcError> {
+        self.check_available()?;
+        Ok(self.network)
+    }
+
+    async fn get_block_height(&self) -> Result<u64, RpcError> {
+        let state = self.check_available()?;
+        Ok(state.blocks.len() as u64 - 1)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        let state = self.check_available()?;
+        state
+            .blocks
+            .get(height as usize)
+            .map(|block| block.hash())
+            .ok_or(RpcError::UnknownBlockHeight(height))
+    }
+
+    async fn find_transactions_in_block(
+        &self,
+        hash: &BlockHash,
+        _height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError> {
+        let state = self.check_available()?;
+        let block_txids = state
+            .blocks
+            .iter()
+            .find(|block| &block.hash() == hash)
+            .map(|block| block.txids())
+            .ok_or(RpcError::UnknownBlock(*hash))?;
+        Ok(filter_transactions(transactions, &block_txids))
+    }
+
+    async fn get_fee_rate(&self, _confirmation_target: u16) -> Result<Option<Feerate>, RpcError> {
+        let state = self.check_available()?;
+        Ok(state.fee_rate)
+    }
+
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        let mut state = self.check_available()?;
+        let txid = transaction.txid();
+        let known = state
+            .mempool
+            .iter()
+            .chain(
+                state
+                    .blocks
+                    .iter()
+                    .flat_map(|block| block.transactions.iter()),
+            )
+            .any(|tx| tx.txid() == txid);
+        if known {
+            return Err(RpcError::TransactionRejected(format!(
+                "transaction {} already known",
+                txid
+            )));
+        }
+
+        state.mempool.push(transaction.clone());
+        Ok(())
+    }
+
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        _script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError> {
+        let state = self.check_available()?;
+        let mut confirmed = state
+            .blocks
+            .iter()
+            .flat_map(|block| block.transactions.iter());
+        let output = confirmed
+            .clone()
+            .find(|tx| tx.txid() == out_point.txid)
+            .and_then(|tx| tx.output.get(out_point.vout as usize).cloned());
+        let spent = confirmed.any(|tx| {
+            tx.input
+                .iter()
+                .any(|input| input.previous_output == *out_point)
+        });
+        Ok(output.filter(|_| !spent))
+    }
+}
+
+fn merkle_root(txids: &[Txid]) -> TxMerkleNode {
+    PartialMerkleTree::from_txids(txids, &vec![false; txids.len()])
+        .extract_matches(&mut vec![], &mut vec![])
+        .expect("Tree was built from these transactions")
+}
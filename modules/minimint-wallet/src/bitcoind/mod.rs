@@ -0,0 +1,296 @@
+pub mod bitcoin_core;
+pub mod electrum;
+pub mod esplora;
+pub mod fake;
+
+use crate::Feerate;
+use async_trait::async_trait;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::{BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use std::future::Future;
+use std::ops::Range;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{error, warn};
+
+/// Initial delay before retrying a failed call, doubles with every further failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Upper bound for the delay between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Number of consecutive failures after which a failing call is logged as an error to alert the
+/// operator instead of just a warning
+const ALERT_AFTER_FAILURES: u32 = 5;
+
+/// Bitcoin chain data and transaction broadcasting required by the wallet
+#[async_trait]
+pub trait BitcoindRpc: Send + Sync {
+    /// Network the backend operates on, used to check it against our config
+    async fn get_network(&self) -> Result<Network, RpcError>;
+
+    /// Height of the current chain tip
+    async fn get_block_height(&self) -> Result<u64, RpcError>;
+
+    /// Hash of the block at `height` in the best chain
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError>;
+
+    /// Hashes of the blocks at `heights` in the best chain. Backends should override this if they
+    /// can fetch multiple blocks in one request.
+    async fn get_block_hashes(&self, heights: Range<u64>) -> Result<Vec<BlockHash>, RpcError> {
+        let mut block_hashes = Vec::with_capacity((heights.end - heights.start) as usize);
+        for height in heights {
+            block_hashes.push(self.get_block_hash(height).await?);
+        }
+        Ok(block_hashes)
+    }
+
+    /// Ids of those `transactions` that are included in the block `hash` at `height`
+    async fn find_transactions_in_block(
+        &self,
+        hash: &BlockHash,
+        height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError>;
+
+    /// Estimated fee rate for a transaction to confirm within `confirmation_target` blocks, `None`
+    /// if the backend can't estimate fees (e.g. on a fresh regtest chain)
+    async fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, RpcError>;
+
+    /// Submits `transaction` to the mempool and relays it to the network
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError>;
+
+    /// Output at `out_point` if it is confirmed and unspent, `None` if it doesn't exist or was
+    /// spent. `script_pubkey` is the script the output is expected to have, backends that index
+    /// outputs by script need it to find the output.
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError>;
+}
+
+/// Ids of those `transactions` that are contained in `block_txids`
+pub(crate) fn filter_transactions(transactions: &[Transaction], block_txids: &[Txid]) -> Vec<Txid> {
+    transactions
+        .iter()
+        .map(|tx| tx.txid())
+        .filter(|txid| block_txids.contains(txid))
+        .collect()
+}
+
+/// Determines the network a backend operates on by the hash of its genesis block
+pub(crate) fn network_from_genesis(genesis_hash: &BlockHash) -> Result<Network, RpcError> {
+    [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .iter()
+    .copied()
+    .find(|&network| genesis_block(network).block_hash() == *genesis_hash)
+    .ok_or_else(|| RpcError::UnknownNetwork(genesis_hash.to_string()))
+}
+
+/// Wraps a [`BitcoindRpc`] and retries failed queries with exponential backoff till they succeed.
+///
+/// Most of the chain data is needed to reach consensus, so giving up isn't an option. Instead we
+/// keep retrying while complaining loudly so the operator notices that the backend is down.
+/// Submitting transactions isn't retried since rejections are expected (e.g. if the transaction
+/// is already confirmed) and the wallet rebroadcasts periodically anyway. Neither are UTXO lookups,
+/// which are only used for audits outside of consensus.
+pub struct RetryClient<C> {
+    inner: C,
+    initial_backoff: Duration,
+}
+
+impl<C: BitcoindRpc> RetryClient<C> {
+    pub fn new(inner: C) -> Self {
+        RetryClient {
+            inner,
+            initial_backoff: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Sets the delay before the first retry, mostly useful to speed up tests
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    async fn retry<'a, T, F, R>(&'a self, name: &str, call: F) -> T
+    where
+        F: Fn(&'a C) -> R,
+        R: Future<Output = Result<T, RpcError>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut failures = 0;
+        loop {
+            match call(&self.inner).await {
+                Ok(result) => return result,
+                Err(e) => {
+                    failures += 1;
+                    if failures >= ALERT_AFTER_FAILURES {
+                        error!(
+                            "Bitcoin backend call {} failed {} times in a row, retrying in {:?}: {}",
+                            name, failures, backoff, e
+                        );
+                    } else {
+                        warn!(
+                            "Bitcoin backend call {} failed, retrying in {:?}: {}",
+                            name, backoff, e
+                        );
+                    }
+                }
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+}
+
+#[async_trait]
+impl<C: BitcoindRpc> BitcoindRpc for RetryClient<C> {
+    async fn get_network(&self) -> Result<Network, RpcError> {
+        Ok(self.retry("get_network", |rpc| rpc.get_network()).await)
+    }
+
+    async fn get_block_height(&self) -> Result<u64, RpcError> {
+        Ok(self
+            .retry("get_block_height", |rpc| rpc.get_block_height())
+            .await)
+    }
+
+    async fn get_block_hash(&self, height: u64) -> Result<BlockHash, RpcError> {
+        Ok(self
+            .retry("get_block_hash", |rpc| rpc.get_block_hash(height))
+            .await)
+    }
+
+    async fn get_block_hashes(&self, heights: Range<u64>) -> Result<Vec<BlockHash>, RpcError> {
+        Ok(self
+            .retry("get_block_hashes", |rpc| {
+                rpc.get_block_hashes(heights.clone())
+            })
+            .await)
+    }
+
+    async fn find_transactions_in_block(
+        &self,
+        hash: &BlockHash,
+        height: u64,
+        transactions: &[Transaction],
+    ) -> Result<Vec<Txid>, RpcError> {
+        Ok(self
+            .retry("find_transactions_in_block", |rpc| {
+                rpc.find_transactions_in_block(hash, height, transactions)
+            })
+            .await)
+    }
+
+    async fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, RpcError> {
+        Ok(self
+            .retry("get_fee_rate", |rpc| rpc.get_fee_rate(confirmation_target))
+            .await)
+    }
+
+    async fn submit_transaction(&self, transaction: &Transaction) -> Result<(), RpcError> {
+        self.inner.submit_transaction(transaction).await
+    }
+
+    async fn get_unspent_output(
+        &self,
+        out_point: &OutPoint,
+        script_pubkey: &Script,
+    ) -> Result<Option<TxOut>, RpcError> {
+        self.inner
+            .get_unspent_output(out_point, script_pubkey)
+            .await
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    #[error("Bitcoind RPC error: {0}")]
+    Bitcoind(bitcoincore_rpc_async::Error),
+    #[error("Esplora error: {0}")]
+    Esplora(reqwest::Error),
+    #[error("Electrum error: {0}")]
+    Electrum(String),
+    #[error("Connection error: {0}")]
+    Io(std::io::Error),
+    #[error("Malformed backend response: {0}")]
+    MalformedResponse(String),
+    #[error("Unknown bitcoin network: {0}")]
+    UnknownNetwork(String),
+    #[error("No block at height {0}")]
+    UnknownBlockHeight(u64),
+    #[error("Unknown block {0}")]
+    UnknownBlock(BlockHash),
+    #[error("Transaction was rejected: {0}")]
+    TransactionRejected(String),
+    #[error("Backend temporarily unavailable")]
+    Unavailable,
+}
+
+impl From<bitcoincore_rpc_async::Error> for RpcError {
+    fn from(e: bitcoincore_rpc_async::Error) -> Self {
+        RpcError::Bitcoind(e)
+    }
+}
+
+impl From<reqwest::Error> for RpcError {
+    fn from(e: reqwest::Error) -> Self {
+        RpcError::Esplora(e)
+    }
+}
+
+impl From<std::io::Error> for RpcError {
+    fn from(e: std::io::Error) -> Self {
+        RpcError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> Self {
+        RpcError::MalformedResponse(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoind::fake::FakeBitcoindRpc;
+    use crate::bitcoind::{BitcoindRpc, RetryClient};
+    use bitcoin::{Script, Transaction, TxOut};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let client =
+            RetryClient::new(FakeBitcoindRpc::new()).with_initial_backoff(Duration::from_millis(1));
+        let hashes = client.inner.mine_blocks(3);
+
+        client.inner.fail_next_calls(6);
+        assert_eq!(client.get_block_height().await.unwrap(), 3);
+
+        client.inner.fail_next_calls(2);
+        assert_eq!(client.get_block_hash(2).await.unwrap(), hashes[1]);
+
+        // Submitting transactions is never retried
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        client.inner.fail_next_calls(1);
+        assert!(client.submit_transaction(&tx).await.is_err());
+        assert!(client.submit_transaction(&tx).await.is_ok());
+        assert_eq!(client.inner.mempool(), vec![tx]);
+    }
+}
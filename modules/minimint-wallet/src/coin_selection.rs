@@ -0,0 +1,466 @@
+use crate::db::UTXOKey;
+use crate::{Feerate, SpendableUTXO};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use std::cmp::Reverse;
+
+/// Maximum number of steps the branch-and-bound search takes before we fall back to simpler
+/// strategies, bounds the time spent in a consensus epoch
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Everything needed to select UTXOs funding a transaction
+#[derive(Debug, Clone)]
+pub struct CoinSelectionParams {
+    /// Value the inputs have to provide apart from their own fees, i.e. all outputs plus the fee
+    /// of the transaction without inputs
+    pub target: bitcoin::Amount,
+    pub fee_rate: Feerate,
+    /// Fee rate we expect to pay on average. Spending more inputs now is cheaper than spending
+    /// them later if `fee_rate` is below it, so we consolidate UTXOs in that case.
+    pub long_term_fee_rate: Feerate,
+    /// Maximum weight a single input adds to the transaction
+    pub input_weight: usize,
+    /// Cost of creating a change output and spending it later, excess value below it is better
+    /// spent on fees
+    pub cost_of_change: bitcoin::Amount,
+    pub max_inputs: usize,
+}
+
+struct Candidate {
+    utxo: (UTXOKey, SpendableUTXO),
+    /// Value of the UTXO minus the fee for spending it at the current fee rate
+    effective_value: u64,
+    /// Pseudo-random but deterministic order derived from the randomness beacon
+    order: sha256::Hash,
+}
+
+/// Selects UTXOs worth at least `params.target` after paying for their own inputs.
+///
+/// All peers have to select the same UTXOs, so the selection only depends on the UTXO set, the
+/// parameters and the consensus `randomness` which breaks ties and orders the random draw. We first
+/// look for a selection not needing change using branch-and-bound and otherwise pick the less
+/// wasteful one of a random draw and selecting the largest UTXOs first. When fees are low additional
+/// small UTXOs are consolidated into the change output. Returns `None` if the target can't be
+/// reached with at most `params.max_inputs` inputs.
+pub fn select_coins(
+    utxos: Vec<(UTXOKey, SpendableUTXO)>,
+    params: &CoinSelectionParams,
+    randomness: &[u8; 32],
+) -> Option<Vec<(UTXOKey, SpendableUTXO)>> {
+    let input_fee = params.fee_rate.calculate_fee(params.input_weight);
+    let mut candidates = utxos
+        .into_iter()
+        .filter_map(|(key, utxo)| {
+            // UTXOs that cost more to spend than they are worth are never selected
+            let effective_value = utxo.amount.checked_sub(input_fee)?.as_sat();
+            if effective_value == 0 {
+                return None;
+            }
+
+            let mut engine = sha256::Hash::engine();
+            engine.input(randomness);
+            engine.input(&serialize(&key.0));
+            Some(Candidate {
+                utxo: (key, utxo),
+                effective_value,
+                order: sha256::Hash::from_engine(engine),
+            })
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|candidate| (Reverse(candidate.effective_value), candidate.order));
+
+    let mut selection = match branch_and_bound(&candidates, params) {
+        Some(selection) => selection,
+        None => {
+            let mut random_order = (0..candidates.len()).collect::<Vec<_>>();
+            random_order.sort_by_key(|&idx| candidates[idx].order);
+            let random_draw = accumulate(
+                &candidates,
+                random_order,
+                params.target.as_sat() + params.cost_of_change.as_sat(),
+                params.max_inputs,
+            );
+            let largest_first = accumulate(
+                &candidates,
+                0..candidates.len(),
+                params.target.as_sat(),
+                params.max_inputs,
+            );
+
+            vec![random_draw, largest_first]
+                .into_iter()
+                .flatten()
+                .min_by_key(|selection| waste(&candidates, selection, params))?
+        }
+    };
+
+    if params.fee_rate <= params.long_term_fee_rate {
+        // Smallest UTXOs first, they are the most expensive ones to spend relative to their value
+        let consolidated = (0..candidates.len())
+            .rev()
+            .filter(|idx| !selection.contains(idx))
+            .take(params.max_inputs.saturating_sub(selection.len()))
+            .collect::<Vec<_>>();
+        selection.extend(consolidated);
+    }
+
+    selection.sort_unstable();
+    let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+    Some(
+        selection
+            .into_iter()
+            .map(|idx| {
+                candidates[idx]
+                    .take()
+                    .expect("Every candidate is selected at most once")
+                    .utxo
+            })
+            .collect(),
+    )
+}
+
+/// Searches for the least wasteful selection of `candidates` (sorted by descending effective
+/// value) that exceeds the target by less than the cost of change, so no change output is needed.
+/// Returns the indices of the selected candidates.
+fn branch_and_bound(candidates: &[Candidate], params: &CoinSelectionParams) -> Option<Vec<usize>> {
+    let target = params.target.as_sat();
+    let upper_bound = target + params.cost_of_change.as_sat();
+    let input_waste = input_waste(params);
+
+    let mut available = candidates
+        .iter()
+        .map(|candidate| candidate.effective_value)
+        .sum::<u64>();
+    let mut value = 0;
+    let mut selection: Vec<usize> = Vec::new();
+    let mut best: Option<(i64, Vec<usize>)> = None;
+
+    // Depth-first search that always tries including the candidate at `idx` before excluding it
+    let mut idx = 0;
+    for _ in 0..BNB_MAX_TRIES {
+        let current_waste = selection.len() as i64 * input_waste;
+        let backtrack = if value + available < target
+            || value > upper_bound
+            || (input_waste > 0
+                && best
+                    .as_ref()
+                    .map_or(false, |(best_waste, _)| current_waste > *best_waste))
+        {
+            true
+        } else if value >= target {
+            let waste = current_waste + (value - target) as i64;
+            if best
+                .as_ref()
+                .map_or(true, |(best_waste, _)| waste <= *best_waste)
+            {
+                best = Some((waste, selection.clone()));
+            }
+            true
+        } else {
+            selection.len() >= params.max_inputs || idx >= candidates.len()
+        };
+
+        if backtrack {
+            let last_included = match selection.last() {
+                Some(&last_included) => last_included,
+                None => break,
+            };
+
+            // Candidates skipped after the last included one become available again
+            while idx > last_included + 1 {
+                idx -= 1;
+                available += candidates[idx].effective_value;
+            }
+
+            // Continue with the branch excluding the last included candidate
+            selection.pop();
+            value -= candidates[last_included].effective_value;
+            idx = last_included + 1;
+        } else {
+            let candidate = &candidates[idx];
+            available -= candidate.effective_value;
+
+            // Excluding a candidate and including an equivalent one instead leads to the same
+            // results, so we don't have to explore that branch again
+            let equivalent_to_excluded = idx > 0
+                && selection.last() != Some(&(idx - 1))
+                && candidates[idx - 1].effective_value == candidate.effective_value;
+            if !equivalent_to_excluded {
+                selection.push(idx);
+                value += candidate.effective_value;
+            }
+            idx += 1;
+        }
+    }
+
+    best.map(|(_, selection)| selection)
+}
+
+/// Selects candidates in the given `order` till their effective value reaches `target`
+fn accumulate(
+    candidates: &[Candidate],
+    order: impl IntoIterator<Item = usize>,
+    target: u64,
+    max_inputs: usize,
+) -> Option<Vec<usize>> {
+    let mut value = 0;
+    let mut selection = Vec::new();
+    for idx in order.into_iter().take(max_inputs) {
+        if value >= target {
+            break;
+        }
+        value += candidates[idx].effective_value;
+        selection.push(idx);
+    }
+
+    if value >= target {
+        Some(selection)
+    } else {
+        None
+    }
+}
+
+/// Fees a selection costs compared to an ideal one: inputs paid at a higher than the long-term fee
+/// rate plus either the excess value going to fees or the cost of a change output
+fn waste(candidates: &[Candidate], selection: &[usize], params: &CoinSelectionParams) -> i64 {
+    let value = selection
+        .iter()
+        .map(|&idx| candidates[idx].effective_value)
+        .sum::<u64>();
+    let excess = value.saturating_sub(params.target.as_sat());
+    let excess_cost = std::cmp::min(excess, params.cost_of_change.as_sat());
+
+    selection.len() as i64 * input_waste(params) + excess_cost as i64
+}
+
+/// Difference between spending an input now and at the long-term fee rate
+fn input_waste(params: &CoinSelectionParams) -> i64 {
+    params.fee_rate.calculate_fee(params.input_weight).as_sat() as i64
+        - params
+            .long_term_fee_rate
+            .calculate_fee(params.input_weight)
+            .as_sat() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoind::fake::FakeBitcoindRpc;
+    use crate::coin_selection::{select_coins, CoinSelectionParams};
+    use crate::config::WalletConfig;
+    use crate::db::UTXOKey;
+    use crate::{Feerate, PendingPegOut, RoundConsensus, SpendableUTXO, Wallet};
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Address, Amount, BlockHash, OutPoint, Script, Txid};
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::db::mem_impl::MemDatabase;
+    use minimint_api::db::Database;
+    use minimint_api::PeerId;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn utxo(idx: u32, amount: u64) -> (UTXOKey, SpendableUTXO) {
+        (
+            UTXOKey(OutPoint::new(Txid::hash(&idx.to_be_bytes()), idx)),
+            SpendableUTXO {
+                tweak: vec![],
+                amount: Amount::from_sat(amount),
+                script_pubkey: Script::new(),
+            },
+        )
+    }
+
+    fn params(target: u64, fee_rate: u64) -> CoinSelectionParams {
+        CoinSelectionParams {
+            target: Amount::from_sat(target),
+            fee_rate: Feerate {
+                sats_per_kvb: fee_rate,
+            },
+            long_term_fee_rate: Feerate { sats_per_kvb: 1000 },
+            input_weight: 1000,
+            cost_of_change: Amount::from_sat(500),
+            max_inputs: 5,
+        }
+    }
+
+    fn selected_amounts(selection: &[(UTXOKey, SpendableUTXO)]) -> Vec<u64> {
+        let mut amounts = selection
+            .iter()
+            .map(|(_, utxo)| utxo.amount.as_sat())
+            .collect::<Vec<_>>();
+        amounts.sort_unstable();
+        amounts
+    }
+
+    #[test]
+    fn exact_match_avoids_change() {
+        // Every input costs 2000 sat at this fee rate
+        let utxos = vec![
+            utxo(0, 100_000),
+            utxo(1, 32_000),
+            utxo(2, 12_000),
+            utxo(3, 7_000),
+        ];
+        let selection = select_coins(utxos, &params(40_000, 2000), &[0; 32]).unwrap();
+        assert_eq!(selected_amounts(&selection), vec![12_000, 32_000]);
+    }
+
+    #[test]
+    fn respects_max_inputs() {
+        let utxos = (0..10).map(|idx| utxo(idx, 10_000)).collect::<Vec<_>>();
+        assert!(select_coins(utxos.clone(), &params(70_000, 2000), &[0; 32]).is_none());
+
+        let selection = select_coins(utxos, &params(30_000, 2000), &[0; 32]).unwrap();
+        assert!(selection.len() <= 5);
+    }
+
+    #[test]
+    fn consolidate_when_fees_are_low() {
+        let utxos = vec![
+            utxo(0, 100_000),
+            utxo(1, 3_000),
+            utxo(2, 4_000),
+            utxo(3, 800),
+        ];
+
+        // The 800 sat UTXO isn't worth spending at any of these fee rates
+        let selection = select_coins(utxos.clone(), &params(50_000, 5000), &[0; 32]).unwrap();
+        assert_eq!(selected_amounts(&selection), vec![100_000]);
+
+        let selection = select_coins(utxos, &params(50_000, 1000), &[0; 32]).unwrap();
+        assert_eq!(selected_amounts(&selection), vec![3_000, 4_000, 100_000]);
+    }
+
+    /// Property test: selections only depend on the inputs, not on the order the UTXOs are listed
+    /// in, so every peer ends up with the same selection, and they always cover the target
+    #[test]
+    fn selection_is_deterministic_and_sufficient() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for _ in 0..200 {
+            let utxos = (0..rng.gen_range(1, 30))
+                .map(|idx| utxo(idx, rng.gen_range(500, 200_000)))
+                .collect::<Vec<_>>();
+            let params = CoinSelectionParams {
+                max_inputs: rng.gen_range(1, 10),
+                ..params(rng.gen_range(1_000, 500_000), rng.gen_range(500, 5000))
+            };
+            let randomness: [u8; 32] = rng.gen();
+
+            let mut shuffled = utxos.clone();
+            shuffled.shuffle(&mut rng);
+
+            let selection = select_coins(utxos, &params, &randomness);
+            let other_selection = select_coins(shuffled, &params, &randomness);
+            let outpoints = |selection: &Option<Vec<(UTXOKey, SpendableUTXO)>>| {
+                selection
+                    .as_ref()
+                    .map(|selection| selection.iter().map(|(key, _)| key.0).collect::<Vec<_>>())
+            };
+            assert_eq!(outpoints(&selection), outpoints(&other_selection));
+
+            if let Some(selection) = selection {
+                assert!(selection.len() <= params.max_inputs);
+                let input_fee = params.fee_rate.calculate_fee(params.input_weight).as_sat();
+                let effective_value = selection
+                    .iter()
+                    .map(|(_, utxo)| utxo.amount.as_sat() - input_fee)
+                    .sum::<u64>();
+                assert!(effective_value >= params.target.as_sat());
+            }
+        }
+    }
+
+    /// Property test: peers list their UTXOs in arbitrary order, yet select the same coins and
+    /// create byte-identical unsigned peg-out transactions from them, otherwise their signatures
+    /// wouldn't combine
+    #[tokio::test]
+    async fn peers_create_identical_unsigned_txs() {
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let (cfgs, _) =
+            WalletConfig::trusted_dealer_gen(&peers, 1, &(), rand::rngs::OsRng::new().unwrap());
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let mut wallets = Vec::new();
+        for (_, cfg) in cfgs {
+            wallets.push(
+                Wallet::new_with_bitcoind(cfg, Arc::new(MemDatabase::new()), bitcoind.clone())
+                    .await
+                    .unwrap(),
+            );
+        }
+        let destination = Address::from_str("bc1qkuzm3093vc7t9q80ul4p5sydkg39sk8gm0park")
+            .unwrap()
+            .script_pubkey();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut funded = 0;
+        for _ in 0..50 {
+            let utxos = (0..rng.gen_range(1, 100))
+                .map(|idx| {
+                    let tweak: [u8; 32] = rng.gen();
+                    (
+                        UTXOKey(OutPoint::new(Txid::hash(&tweak), idx)),
+                        SpendableUTXO {
+                            tweak: tweak.to_vec(),
+                            amount: Amount::from_sat(rng.gen_range(1_000, 1_000_000)),
+                            script_pubkey: Script::new(),
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+            let peg_outs = (0..rng.gen_range(1, 5))
+                .map(|_| PendingPegOut {
+                    destination: destination.clone(),
+                    amount: Amount::from_sat(rng.gen_range(1_000, 2_000_000)),
+                    pending_since_block: 0,
+                    priority: false,
+                })
+                .collect::<Vec<_>>();
+            let consensus = RoundConsensus {
+                block_height: 0,
+                block_hash: BlockHash::hash(b"block"),
+                fee_rate: Feerate {
+                    sats_per_kvb: rng.gen_range(500, 20_000),
+                },
+                randomness_beacon: rng.gen(),
+            };
+
+            let mut unsigned_txs = Vec::new();
+            for wallet in &wallets {
+                let mut shuffled = utxos.clone();
+                shuffled.shuffle(&mut rng);
+                for (key, utxo) in &shuffled {
+                    wallet.db.insert_entry(key, utxo).unwrap();
+                }
+
+                let mut available_utxos = wallet.available_utxos();
+                available_utxos.shuffle(&mut rng);
+                let params = wallet.coin_selection_params(&peg_outs, &consensus);
+                let unsigned_tx =
+                    select_coins(available_utxos, &params, &consensus.randomness_beacon)
+                        .and_then(|selection| {
+                            wallet.offline_wallet().create_tx(
+                                peg_outs.clone(),
+                                selection,
+                                consensus.fee_rate,
+                                &consensus.randomness_beacon,
+                            )
+                        })
+                        .map(|psbt| serialize(&psbt.global.unsigned_tx));
+                unsigned_txs.push(unsigned_tx);
+
+                for (key, _) in &utxos {
+                    wallet
+                        .db
+                        .remove_entry::<_, SpendableUTXO>(key)
+                        .unwrap()
+                        .unwrap();
+                }
+            }
+            assert!(unsigned_txs.iter().all(|tx| *tx == unsigned_txs[0]));
+            funded += unsigned_txs[0].is_some() as usize;
+        }
+        assert!(funded > 0);
+    }
+}
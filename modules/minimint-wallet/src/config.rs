@@ -1,7 +1,8 @@
 use crate::Feerate;
+use async_trait::async_trait;
 use bitcoin::secp256k1::rand::{CryptoRng, RngCore};
 use bitcoin::Network;
-use minimint_api::config::GenerateConfig;
+use minimint_api::config::{ConfigGenConnections, ConfigGenError, GenerateConfig};
 use minimint_api::{CompressedPublicKey, PeerId, PegInDescriptor};
 use miniscript::descriptor::Wsh;
 use serde::{Deserialize, Serialize};
@@ -13,22 +14,148 @@ pub struct WalletConfig {
     pub peg_in_descriptor: PegInDescriptor,
     pub peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
     pub peg_in_key: secp256k1::SecretKey,
+    /// Number of peers that have to propose the same block before the wallet follows the chain up
+    /// to it
+    pub threshold: usize,
     pub finalty_delay: u32,
+    /// Block height the wallet starts following the chain from instead of the genesis block. Peg-ins
+    /// confirmed at or below it can't be claimed.
+    pub checkpoint_height: u32,
+    /// Number of blocks after which a peg-out transaction that didn't confirm yet gets replaced by
+    /// one paying the current consensus fee rate
+    pub fee_bump_delay: u32,
     pub default_fee: Feerate,
-    pub btc_rpc_address: String,
-    pub btc_rpc_user: String,
-    pub btc_rpc_pass: String,
+    /// Fee rate we expect to pay long-term. While the consensus fee rate is at or below it peg-out
+    /// transactions consolidate small UTXOs, above it they spend as few inputs as possible.
+    pub consolidation_fee_rate: Feerate,
+    /// Maximum number of inputs a peg-out transaction may spend
+    pub max_peg_out_inputs: usize,
+    pub peg_out_policy: PegOutPolicy,
+    pub bitcoin_backend: BitcoinBackendConfig,
+}
+
+/// Decides which peg-outs are accepted and when they are batched into a peg-out transaction,
+/// trading off latency against the fees paid by the federation
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PegOutPolicy {
+    /// Confirmation target in blocks the fee rate of peg-out transactions is estimated for
+    pub confirmation_target: u16,
+    /// A peg-out transaction is created once the urgency, the sum over all pending peg-outs of the
+    /// number of blocks each has been waiting, exceeds this threshold. E.g. 10 peg-outs, each
+    /// waiting for 10 blocks, would cross a threshold of 99.
+    pub urgency_threshold: u32,
+    /// Maximum number of blocks a peg-out waits for a transaction regardless of the urgency
+    pub max_wait_blocks: u32,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub min_amount: bitcoin::Amount,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub max_amount: bitcoin::Amount,
+    /// Maximum number of peg-outs paid by a single transaction, the remaining ones are paid by
+    /// later transactions
+    pub max_outputs: usize,
+    /// Minimum extra fee a priority peg-out has to pay, priority peg-outs are included in the next
+    /// peg-out transaction
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub priority_fee: bitcoin::Amount,
+}
+
+/// Source of bitcoin chain data that is also used to broadcast our transactions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BitcoinBackendConfig {
+    /// JSON-RPC interface of a bitcoind full node
+    Bitcoind {
+        address: String,
+        user: String,
+        pass: String,
+    },
+    /// Esplora HTTP API, e.g. `https://blockstream.info/api`
+    Esplora { url: String },
+    /// Electrum server, given as `host:port` (plain TCP)
+    Electrum { address: String },
+}
+
+impl Default for BitcoinBackendConfig {
+    fn default() -> Self {
+        BitcoinBackendConfig::Bitcoind {
+            address: "127.0.0.1:18443".to_string(),
+            user: "bitcoin".to_string(),
+            pass: "bitcoin".to_string(),
+        }
+    }
+}
+
+impl Default for PegOutPolicy {
+    fn default() -> Self {
+        PegOutPolicy {
+            confirmation_target: 24,
+            urgency_threshold: 100,
+            max_wait_blocks: 144,
+            min_amount: bitcoin::Amount::from_sat(10_000),
+            max_amount: bitcoin::Amount::from_sat(1_000_000_000),
+            max_outputs: 100,
+            priority_fee: bitcoin::Amount::from_sat(20_000),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WalletClientConfig {
     pub peg_in_descriptor: PegInDescriptor,
     pub network: Network,
+    pub peg_out_policy: PegOutPolicy,
+    /// Keys guardians sign reserves reports with
+    pub peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+    /// Number of guardians that have to sign a reserves report for it to be trusted
+    pub threshold: usize,
 }
 
+impl WalletConfig {
+    /// Config of the peer holding `peg_in_key` with the parameters all peers start out with,
+    /// regardless of how the keys were generated
+    fn new(
+        peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+        peg_in_key: secp256k1::SecretKey,
+        threshold: usize,
+    ) -> Result<WalletConfig, miniscript::Error> {
+        let peg_in_descriptor = PegInDescriptor::Wsh(Wsh::new_sortedmulti(
+            threshold,
+            peer_peg_in_keys.values().cloned().collect(),
+        )?);
+
+        Ok(WalletConfig {
+            network: Network::Regtest,
+            peg_in_descriptor,
+            peer_peg_in_keys,
+            peg_in_key,
+            threshold,
+            finalty_delay: 10,
+            checkpoint_height: 0,
+            fee_bump_delay: 6,
+            default_fee: Feerate { sats_per_kvb: 2000 },
+            consolidation_fee_rate: Feerate { sats_per_kvb: 1000 },
+            max_peg_out_inputs: 100,
+            peg_out_policy: Default::default(),
+            bitcoin_backend: Default::default(),
+        })
+    }
+
+    fn client_config(&self) -> WalletClientConfig {
+        WalletClientConfig {
+            peg_in_descriptor: self.peg_in_descriptor.clone(),
+            network: self.network,
+            peg_out_policy: self.peg_out_policy.clone(),
+            peer_peg_in_keys: self.peer_peg_in_keys.clone(),
+            threshold: self.threshold,
+        }
+    }
+}
+
+#[async_trait(?Send)]
 impl GenerateConfig for WalletConfig {
     type Params = ();
     type ClientConfig = WalletClientConfig;
+    type ConfigMessage = CompressedPublicKey;
 
     fn trusted_dealer_gen(
         peers: &[PeerId],
@@ -42,45 +169,61 @@ impl GenerateConfig for WalletConfig {
             .iter()
             .map(|&id| (id, secp.generate_keypair(&mut rng)))
             .collect::<Vec<_>>();
-
-        let peg_in_descriptor = PegInDescriptor::Wsh(
-            Wsh::new_sortedmulti(
-                peers.len() - max_evil,
-                btc_pegin_keys
-                    .iter()
-                    .map(|(_, (_, pk))| CompressedPublicKey { key: *pk })
-                    .collect(),
-            )
-            .unwrap(),
-        );
+        let peer_peg_in_keys = btc_pegin_keys
+            .iter()
+            .map(|(peer_id, (_, pk))| (*peer_id, CompressedPublicKey { key: *pk }))
+            .collect::<BTreeMap<_, _>>();
 
         let wallet_cfg = btc_pegin_keys
             .iter()
             .map(|(id, (sk, _))| {
-                let cfg = WalletConfig {
-                    network: Network::Regtest,
-                    peg_in_descriptor: peg_in_descriptor.clone(), // TODO: remove redundancy?
-                    peer_peg_in_keys: btc_pegin_keys
-                        .iter()
-                        .map(|(peer_id, (_, pk))| (*peer_id, CompressedPublicKey { key: *pk }))
-                        .collect(),
-                    peg_in_key: *sk,
-                    finalty_delay: 10,
-                    default_fee: Feerate { sats_per_kvb: 2000 },
-                    btc_rpc_address: "127.0.0.1:18443".to_string(),
-                    btc_rpc_user: "bitcoin".to_string(),
-                    btc_rpc_pass: "bitcoin".to_string(),
-                };
-
+                let cfg = WalletConfig::new(peer_peg_in_keys.clone(), *sk, peers.len() - max_evil)
+                    .expect("Invalid peg-in keys");
                 (*id, cfg)
             })
-            .collect();
+            .collect::<BTreeMap<_, _>>();
 
-        let client_cfg = WalletClientConfig {
-            peg_in_descriptor: peg_in_descriptor,
-            network: Network::Regtest,
-        };
+        let client_cfg = wallet_cfg
+            .values()
+            .next()
+            .expect("No peers")
+            .client_config();
 
         (wallet_cfg, client_cfg)
     }
+
+    async fn distributed_gen<'a>(
+        connections: &'a mut (impl ConfigGenConnections<Self::ConfigMessage> + 'a),
+        our_id: &'a PeerId,
+        peers: &'a [PeerId],
+        max_evil: usize,
+        _params: &'a Self::Params,
+        mut rng: impl RngCore + CryptoRng + 'a,
+    ) -> Result<(Self, Self::ClientConfig), ConfigGenError> {
+        let secp = secp256k1::Secp256k1::new();
+        let (sk, pk) = secp.generate_keypair(&mut rng);
+        let our_key = CompressedPublicKey { key: pk };
+
+        let other_peers = peers
+            .iter()
+            .filter(|&peer| peer != our_id)
+            .copied()
+            .collect::<Vec<_>>();
+        connections.send(&other_peers, our_key.clone()).await;
+
+        let mut peer_peg_in_keys = BTreeMap::new();
+        peer_peg_in_keys.insert(*our_id, our_key);
+        while peer_peg_in_keys.len() < peers.len() {
+            let (peer, key) = connections.receive().await;
+            if !peers.contains(&peer) || peer_peg_in_keys.insert(peer, key).is_some() {
+                return Err(ConfigGenError::UnexpectedMessage(peer));
+            }
+        }
+
+        let wallet_cfg = WalletConfig::new(peer_peg_in_keys, sk, peers.len() - max_evil)
+            .map_err(|e| ConfigGenError::KeyGen(e.to_string()))?;
+        let client_cfg = wallet_cfg.client_config();
+
+        Ok((wallet_cfg, client_cfg))
+    }
 }
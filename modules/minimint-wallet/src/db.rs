@@ -1,3 +1,4 @@
+use crate::SpendableUTXO;
 use bitcoin::{BlockHash, OutPoint, Transaction, Txid};
 use minimint_api::db::DatabaseKeyPrefixConst;
 use minimint_api::encoding::{Decodable, Encodable};
@@ -9,7 +10,13 @@ const DB_PREFIX_PEDNING_PEGOUT: u8 = 0x33;
 const DB_PREFIX_UNSIGNED_TRANSACTION: u8 = 0x34;
 const DB_PREFIX_PENDING_TRANSACTION: u8 = 0x35;
 const DB_PREFIX_PEG_OUT_TX_SIG_CI: u8 = 0x36;
+const DB_PREFIX_PEG_OUT_TX: u8 = 0x37;
+const DB_PREFIX_CONFIRMED_TRANSACTION: u8 = 0x38;
+const DB_PREFIX_REPLACED_TRANSACTION: u8 = 0x39;
+const DB_PREFIX_PEG_IN: u8 = 0x3A;
+const DB_PREFIX_ORPHANED_PEG_IN: u8 = 0x3B;
 
+/// Height of a block up to the consensus height, only blocks of the consensus chain are kept
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct BlockHashKey(pub BlockHash);
 
@@ -17,6 +24,13 @@ impl DatabaseKeyPrefixConst for BlockHashKey {
     const DB_PREFIX: u8 = DB_PREFIX_BLOCK_HASH;
 }
 
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct BlockHashPrefixKey;
+
+impl DatabaseKeyPrefixConst for BlockHashPrefixKey {
+    const DB_PREFIX: u8 = DB_PREFIX_BLOCK_HASH;
+}
+
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct UTXOKey(pub OutPoint);
 
@@ -59,6 +73,13 @@ impl DatabaseKeyPrefixConst for UnsignedTransactionKey {
     const DB_PREFIX: u8 = DB_PREFIX_UNSIGNED_TRANSACTION;
 }
 
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct UnsignedTransactionPrefixKey;
+
+impl DatabaseKeyPrefixConst for UnsignedTransactionPrefixKey {
+    const DB_PREFIX: u8 = DB_PREFIX_UNSIGNED_TRANSACTION;
+}
+
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct PendingTransactionKey(pub Txid);
 
@@ -87,8 +108,73 @@ impl DatabaseKeyPrefixConst for PegOutTxSignatureCIPrefix {
     const DB_PREFIX: u8 = DB_PREFIX_PEG_OUT_TX_SIG_CI;
 }
 
+/// Bitcoin transaction a peg-out was included in
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct PegOutTxKey(pub minimint_api::transaction::OutPoint);
+
+impl DatabaseKeyPrefixConst for PegOutTxKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PEG_OUT_TX;
+}
+
+/// Block height at which one of our pending transactions was confirmed
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct ConfirmedTransactionKey(pub Txid);
+
+impl DatabaseKeyPrefixConst for ConfirmedTransactionKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CONFIRMED_TRANSACTION;
+}
+
+/// Transaction paying a higher fee that was created to replace a stuck pending transaction
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct ReplacedTransactionKey(pub Txid);
+
+impl DatabaseKeyPrefixConst for ReplacedTransactionKey {
+    const DB_PREFIX: u8 = DB_PREFIX_REPLACED_TRANSACTION;
+}
+
+/// Block and transaction of a claimed peg-in, needed to re-validate it if the block gets orphaned
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct PegInKey(pub OutPoint);
+
+impl DatabaseKeyPrefixConst for PegInKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PEG_IN;
+}
+
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct PegInPrefixKey;
+
+impl DatabaseKeyPrefixConst for PegInPrefixKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PEG_IN;
+}
+
+/// Peg-in UTXO whose block was orphaned by a reorg, it can't be spent till it confirms again
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct OrphanedPegInKey(pub OutPoint);
+
+impl DatabaseKeyPrefixConst for OrphanedPegInKey {
+    const DB_PREFIX: u8 = DB_PREFIX_ORPHANED_PEG_IN;
+}
+
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct OrphanedPegInPrefixKey;
+
+impl DatabaseKeyPrefixConst for OrphanedPegInPrefixKey {
+    const DB_PREFIX: u8 = DB_PREFIX_ORPHANED_PEG_IN;
+}
+
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct PegIn {
+    /// Block the peg-in transaction was confirmed in
+    pub block_hash: BlockHash,
+    pub transaction: Transaction,
+}
+
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct PendingTransaction {
     pub tx: Transaction,
     pub tweak: Option<Vec<u8>>,
+    /// Inputs of `tx`, needed to create a replacement paying a higher fee
+    pub spent_utxos: Vec<(UTXOKey, SpendableUTXO)>,
+    /// Consensus block height at which the transaction was fully signed
+    pub pending_since_block: u32,
 }
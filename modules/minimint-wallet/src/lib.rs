@@ -1,14 +1,25 @@
+pub mod bitcoind;
+mod coin_selection;
 pub mod config;
 mod db;
-
-use crate::config::WalletConfig;
+pub mod prefetch;
+
+use crate::bitcoind::bitcoin_core::BitcoindClient;
+use crate::bitcoind::electrum::ElectrumClient;
+use crate::bitcoind::esplora::EsploraClient;
+use crate::bitcoind::{BitcoindRpc, RetryClient, RpcError};
+use crate::coin_selection::{select_coins, CoinSelectionParams};
+use crate::config::{BitcoinBackendConfig, WalletConfig};
 use crate::db::{
-    BlockHashKey, PegOutTxSignatureCI, PegOutTxSignatureCIPrefix, PendingPegOutKey,
-    PendingPegOutPrefixKey, PendingTransaction, PendingTransactionKey, PendingTransactionPrefixKey,
-    RoundConsensusKey, UTXOKey, UTXOPrefixKey, UnsignedTransactionKey,
+    BlockHashKey, BlockHashPrefixKey, ConfirmedTransactionKey, OrphanedPegInKey,
+    OrphanedPegInPrefixKey, PegIn, PegInKey, PegInPrefixKey, PegOutTxKey, PegOutTxSignatureCI,
+    PegOutTxSignatureCIPrefix, PendingPegOutKey, PendingPegOutPrefixKey, PendingTransaction,
+    PendingTransactionKey, PendingTransactionPrefixKey, ReplacedTransactionKey, RoundConsensusKey,
+    UTXOKey, UTXOPrefixKey, UnsignedTransactionKey, UnsignedTransactionPrefixKey,
 };
+use crate::prefetch::{prefetch_block_hashes, BlockHashCache};
 use async_trait::async_trait;
-use bitcoin::hashes::hex::ToHex;
+use bitcoin::consensus::encode::serialize_hex;
 use bitcoin::hashes::{sha256, Hash as BitcoinHash, HashEngine, Hmac, HmacEngine};
 use bitcoin::secp256k1::{All, Secp256k1};
 use bitcoin::util::bip143::SigHashCache;
@@ -17,12 +28,15 @@ use bitcoin::util::psbt::{Global, Input, PartiallySignedTransaction};
 use bitcoin::{
     Address, AddressType, BlockHash, Network, Script, SigHashType, Transaction, TxIn, TxOut, Txid,
 };
-use bitcoincore_rpc_async::{Auth, RpcApi};
 use itertools::Itertools;
 use minimint_api::db::batch::{BatchItem, BatchTx};
 use minimint_api::db::{Database, RawDatabase};
 use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::transaction::{OutPoint, PegOut};
+use minimint_api::misbehavior::{MisbehaviorLog, PeerFault};
+use minimint_api::module::Audit;
+use minimint_api::outcome::PegOutOutcome;
+use minimint_api::reserves::{ReserveUtxo, ReservesReport, SignedReservesReport};
+use minimint_api::transaction::{OutPoint, PegOut, PegOutFees};
 use minimint_api::{
     CompressedPublicKey, FederationModule, PeerId, PegInProof, PegInProofError, Tweakable,
 };
@@ -31,18 +45,30 @@ use miniscript::{Descriptor, DescriptorTrait, TranslatePk2};
 use rand::{CryptoRng, Rng, RngCore};
 use secp256k1::{Message, Signature};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::hash::Hasher;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::time::Duration;
 use tracing::{debug, error, info, trace, warn};
 
-pub const CONFIRMATION_TARGET: u16 = 24;
+/// Sequence number of peg-out transaction inputs, signals replaceability (BIP 125) so we can bump
+/// the fee of transactions that got stuck
+const RBF_SEQUENCE: u32 = 0xFFFFFFFD;
+
+/// Fee rate in sat/vbyte a replacement transaction has to pay on top of the fee of the replaced
+/// transaction to be relayed by bitcoind
+const MIN_RELAY_FEE_INCREMENT: u64 = 1;
+
+/// Interval in which the hashes of new blocks are fetched ahead of consensus
+const PREFETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait before checking again if our bitcoind switched to the consensus chain
+const CHAIN_MISMATCH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
 
-/// The urgency of doing a peg-out is defined as the sum over all pending peg-outs of the amount of
-/// BTC blocks that have been mined since the peg-out was created. E.g. 10 transactions, each
-/// waiting for 10 blocks, would cross a minimum urgency threshold of 100.  
-pub const MIN_PEG_OUT_URGENCY: u32 = 100;
+/// How long to wait before asking our bitcoind again after it failed or didn't know a consensus
+/// block yet
+const BITCOIND_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 pub type PartialSig = Vec<u8>;
 
@@ -54,7 +80,9 @@ pub enum WalletConsensusItem {
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct RoundConsensusItem {
-    block_height: u32, // FIXME: use block hash instead, but needs more complicated verification logic
+    block_height: u32,
+    /// Hash of the block at `block_height`, lets peers notice if their bitcoind is on another chain
+    block_hash: BlockHash,
     fee_rate: Feerate,
     randomness: [u8; 32],
 }
@@ -68,6 +96,7 @@ pub struct PegOutSignatureItem {
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub struct RoundConsensus {
     block_height: u32,
+    block_hash: BlockHash,
     fee_rate: Feerate,
     randomness_beacon: [u8; 32],
 }
@@ -75,13 +104,17 @@ pub struct RoundConsensus {
 pub struct Wallet {
     cfg: WalletConfig,
     secp: Secp256k1<All>,
-    btc_rpc: bitcoincore_rpc_async::Client,
+    btc_rpc: Arc<dyn BitcoindRpc>,
     db: Arc<dyn RawDatabase>,
+    block_hash_cache: Arc<BlockHashCache>,
+    misbehavior: MisbehaviorLog,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Encodable, Decodable)]
 pub struct SpendableUTXO {
-    pub tweak: musig::PubKey,
+    /// Encoded contract the federation's descriptor was tweaked with, either a peg-in's tweak key
+    /// or the random tweak of a change output
+    pub tweak: Vec<u8>,
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     pub amount: bitcoin::Amount,
     // FIXME: why do we save the script pub key? We can derive it from the tweak and the descriptor
@@ -95,6 +128,8 @@ pub struct PendingPegOut {
     #[serde(with = "bitcoin::util::amount::serde::as_sat")]
     amount: bitcoin::Amount,
     pending_since_block: u32,
+    /// Priority peg-outs paid an extra fee to be included in the next peg-out transaction
+    priority: bool,
 }
 
 struct StatelessWallet<'a> {
@@ -126,50 +161,26 @@ impl FederationModule for Wallet {
     type Error = WalletError;
     type TxInput = PegInProof;
     type TxOutput = PegOut;
-    // TODO: implement outcome
-    type TxOutputOutcome = ();
+    type TxOutputOutcome = PegOutOutcome;
     type ConsensusItem = WalletConsensusItem;
 
     async fn consensus_proposal<'a>(
         &'a self,
-        mut rng: impl RngCore + CryptoRng + 'a,
+        rng: impl RngCore + CryptoRng + 'a,
     ) -> Vec<Self::ConsensusItem> {
-        // TODO: implement retry logic in case bitcoind is temporarily unreachable
-        let our_network_height = self.btc_rpc.get_block_count().await.unwrap() as u32;
-        let our_target_height = our_network_height.saturating_sub(self.cfg.finalty_delay);
-
-        // In case the wallet just got created the height is not committed to the DB yet but will
-        // be set to 0 first, so we can assume that here.
-        let last_consensus_height = self.consensus_height().unwrap_or(0);
-
-        let proposed_height = if our_target_height >= last_consensus_height {
-            our_target_height
-        } else {
-            warn!(
-                "The block height shrunk, new proposal would be {}, but we are sticking to the last consensus height {}.",
-                our_target_height,
-                last_consensus_height
-            );
-            last_consensus_height
+        // Without our bitcoind we can't propose a block, the other peers' proposals still let the
+        // federation make progress
+        let round_ci = match self.round_consensus_proposal(rng).await {
+            Ok(proposal) => Some(WalletConsensusItem::RoundConsensus(proposal)),
+            Err(e) => {
+                error!(
+                    "Querying our bitcoind failed, not proposing a block this round: {}",
+                    e
+                );
+                None
+            }
         };
 
-        let fee_rate = self
-            .btc_rpc
-            .estimate_smart_fee(CONFIRMATION_TARGET, None)
-            .await
-            .unwrap() // TODO: implement retry logic in case bitcoind is temporarily unreachable
-            .fee_rate
-            .map(|per_kb| Feerate {
-                sats_per_kvb: per_kb.as_sat(),
-            })
-            .unwrap_or(self.cfg.default_fee);
-
-        let round_ci = WalletConsensusItem::RoundConsensus(RoundConsensusItem {
-            block_height: proposed_height,
-            fee_rate,
-            randomness: rng.gen(),
-        });
-
         self.db
             .find_by_prefix::<_, PegOutTxSignatureCI, Vec<Signature>>(&PegOutTxSignatureCIPrefix)
             .map(|res| {
@@ -179,7 +190,7 @@ impl FederationModule for Wallet {
                     signature: val,
                 })
             })
-            .chain(std::iter::once(round_ci))
+            .chain(round_ci)
             .collect()
     }
 
@@ -199,30 +210,58 @@ impl FederationModule for Wallet {
         } = consensus_items.into_iter().unzip_wallet_consensus_item();
 
         // Apply signatures to peg-out tx
+        let mut faults = Vec::new();
         for (peer, sig) in peg_out_signatures {
             if let Err(e) =
                 self.process_peg_out_signature(batch.subtransaction(), peer.into(), &sig)
             {
-                warn!("Error processing peer {}'s peg-out signature: {}", peer, e)
+                warn!("Error processing peer {}'s peg-out signature: {}", peer, e);
+                // Unknown or duplicate signatures can also stem from honest peers lagging behind
+                if matches!(
+                    e,
+                    ProcessPegOutSigError::WrongSignatureCount(..)
+                        | ProcessPegOutSigError::MalformedSignature(_)
+                        | ProcessPegOutSigError::InvalidSignature
+                ) {
+                    faults.push((peer, PeerFault::InvalidPegOutSignature));
+                }
             };
         }
+        self.misbehavior.record(batch.subtransaction(), faults);
+
+        // Every peer gets one vote on the round consensus, proposals are counted per peer
+        let round_consensus = round_consensus
+            .into_iter()
+            .unique_by(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
 
         // FIXME: also warn on less than 1/3, that should never happen
-        // Make sure we have enough contributions to continue
+        // Peers whose bitcoind failed don't propose a block, without any proposals we stick to the
+        // last round consensus
         if round_consensus.is_empty() {
-            panic!("No proposals were submitted this round");
+            warn!("No peer proposed a block this round, keeping the last round consensus");
+            batch.commit();
+            return;
         }
 
         let fee_proposals = round_consensus.iter().map(|(_, rc)| rc.fee_rate).collect();
         let fee_rate = self.process_fee_proposals(fee_proposals).await;
 
-        let height_proposals = round_consensus
+        let block_proposals = round_consensus
             .iter()
-            .map(|(_, rc)| rc.block_height)
+            .map(|(_, rc)| (rc.block_height, rc.block_hash))
             .collect();
-        let block_height = self
-            .process_block_height_proposals(batch.subtransaction(), height_proposals)
-            .await;
+        let (block_height, block_hash) = match self
+            .process_block_proposals(batch.subtransaction(), block_proposals)
+            .await
+        {
+            Some(block) => block,
+            None => {
+                warn!("No block was agreed on yet, not starting to follow the chain this round");
+                batch.commit();
+                return;
+            }
+        };
 
         let randomness_contributions = round_consensus
             .iter()
@@ -232,6 +271,7 @@ impl FederationModule for Wallet {
 
         let round_consensus = RoundConsensus {
             block_height,
+            block_hash,
             fee_rate,
             randomness_beacon,
         };
@@ -247,12 +287,17 @@ impl FederationModule for Wallet {
 
         input.verify(&self.secp, &self.cfg.peg_in_descriptor)?;
 
-        if self
+        let claimed = self
             .db
             .get_value::<_, SpendableUTXO>(&UTXOKey(input.outpoint()))
             .expect("DB error")
             .is_some()
-        {
+            || self
+                .db
+                .get_value::<_, SpendableUTXO>(&OrphanedPegInKey(input.outpoint()))
+                .expect("DB error")
+                .is_some();
+        if claimed {
             return Err(WalletError::PegInAlreadyClaimed);
         }
 
@@ -270,11 +315,18 @@ impl FederationModule for Wallet {
         batch.append_insert_new(
             UTXOKey(input.outpoint()),
             SpendableUTXO {
-                tweak: input.tweak_contract_key().clone(),
+                tweak: input.tweak_contract_key().to_bytes().to_vec(),
                 amount: bitcoin::Amount::from_sat(input.tx_output().value),
                 script_pubkey: input.tx_output().script_pubkey.clone(),
             },
         );
+        batch.append_insert_new(
+            PegInKey(input.outpoint()),
+            PegIn {
+                block_hash: input.proof_block(),
+                transaction: input.transaction().clone(),
+            },
+        );
 
         batch.commit();
         Ok(amount)
@@ -290,7 +342,36 @@ impl FederationModule for Wallet {
                 output.recipient.network,
             ));
         }
-        Ok(output.amount.into())
+
+        let policy = &self.cfg.peg_out_policy;
+        if output.amount < policy.min_amount {
+            return Err(WalletError::PegOutTooSmall(
+                output.amount,
+                policy.min_amount,
+            ));
+        }
+        if output.amount > policy.max_amount {
+            return Err(WalletError::PegOutTooLarge(
+                output.amount,
+                policy.max_amount,
+            ));
+        }
+        if output.priority_fee != bitcoin::Amount::from_sat(0)
+            && output.priority_fee < policy.priority_fee
+        {
+            return Err(WalletError::PriorityFeeTooLow(
+                output.priority_fee,
+                policy.priority_fee,
+            ));
+        }
+
+        // The federation mustn't pay the on-chain fees of peg-outs from its reserves
+        let required_fee = self.peg_out_fees(&output.recipient).fee;
+        if output.fee < required_fee {
+            return Err(WalletError::PegOutFeeTooLow(output.fee, required_fee));
+        }
+
+        Ok((output.amount + output.priority_fee + output.fee).into())
     }
 
     fn apply_output<'a>(
@@ -300,9 +381,10 @@ impl FederationModule for Wallet {
         out_point: minimint_api::transaction::OutPoint,
     ) -> Result<minimint_api::Amount, Self::Error> {
         let amount = self.validate_output(output)?;
+        let priority = output.priority_fee != bitcoin::Amount::from_sat(0);
         debug!(
-            "Queuing peg-out of {} BTC to {}",
-            output.amount, output.recipient
+            "Queuing peg-out of {} BTC to {} (priority: {})",
+            output.amount, output.recipient, priority
         );
         batch.append_insert_new(
             PendingPegOutKey(out_point),
@@ -312,6 +394,7 @@ impl FederationModule for Wallet {
                 pending_since_block: self
                     .consensus_height()
                     .expect("Wallet should be initialized at this point"),
+                priority,
             },
         );
         batch.commit();
@@ -329,112 +412,242 @@ impl FederationModule for Wallet {
         };
 
         // Check if we should create a peg-out transaction
-        let (peg_out_ids, pending_peg_outs): (
-            Vec<minimint_api::transaction::OutPoint>,
-            Vec<PendingPegOut>,
-        ) = self.pending_peg_outs().into_iter().unzip();
+        let policy = &self.cfg.peg_out_policy;
+        let mut pending_peg_outs = self.pending_peg_outs();
+        // The consensus height may have shrunk after a deep reorg
+        let waiting_blocks = |peg_out: &PendingPegOut| {
+            round_consensus
+                .block_height
+                .saturating_sub(peg_out.pending_since_block)
+        };
         let urgency = pending_peg_outs
             .iter()
-            .map(|peg_out| round_consensus.block_height - peg_out.pending_since_block)
+            .map(|(_, peg_out)| waiting_blocks(peg_out))
             .sum::<u32>();
+        let peg_out_due = urgency > policy.urgency_threshold
+            || pending_peg_outs.iter().any(|(_, peg_out)| {
+                peg_out.priority || waiting_blocks(peg_out) >= policy.max_wait_blocks
+            });
 
         trace!(
-            "Pending peg outs: {}, urgency: {}, urgency threshold: {}",
+            "Pending peg outs: {}, urgency: {}, urgency threshold: {}, peg-out due: {}",
             pending_peg_outs.len(),
             urgency,
-            MIN_PEG_OUT_URGENCY
+            policy.urgency_threshold,
+            peg_out_due
         );
 
+        // Priority peg-outs are paid first, then the ones waiting the longest. The sort is stable,
+        // so all peers agree on the order of peg-outs that compare equal.
+        pending_peg_outs
+            .sort_by_key(|(_, peg_out)| (!peg_out.priority, peg_out.pending_since_block));
+        pending_peg_outs.truncate(policy.max_outputs);
+        let (peg_out_ids, pending_peg_outs): (
+            Vec<minimint_api::transaction::OutPoint>,
+            Vec<PendingPegOut>,
+        ) = pending_peg_outs.into_iter().unzip();
+
         // We only want to peg out if we have a real randomness beacon after the first consensus round
         let peg_out_ready = self.current_round_consensus().is_some(); // TODO: maybe destructure instead?
-        if urgency > MIN_PEG_OUT_URGENCY && peg_out_ready {
-            let mut psbt = self
-                .create_peg_out_tx(pending_peg_outs, round_consensus)
-                .await;
-            let txid = psbt.global.unsigned_tx.txid();
+        if peg_out_due && peg_out_ready {
+            match self
+                .create_peg_out_tx(pending_peg_outs, round_consensus.clone())
+                .await
+            {
+                Some(mut psbt) => {
+                    let txid = psbt.global.unsigned_tx.txid();
 
-            info!(
-                "Signing peg out tx {} containing {} peg outs",
-                txid,
-                peg_out_ids.len()
-            );
-            let sigs = psbt
-                .inputs
-                .iter_mut()
-                .map(|input| {
-                    assert_eq!(
-                        input.partial_sigs.len(),
-                        1,
-                        "There was already more than one (our) or no signatures in input"
+                    info!(
+                        "Signing peg out tx {} containing {} peg outs",
+                        txid,
+                        peg_out_ids.len()
                     );
+                    let sigs = take_our_signatures(&mut psbt);
 
-                    // TODO: don't put sig into PSBT in the first place
-                    // We actually take out our own signature so everyone finalizes the tx in the
-                    // same epoch.
-                    let sig = std::mem::take(&mut input.partial_sigs)
-                        .into_values()
-                        .next()
-                        .expect("asserted previously");
-
-                    // We drop SIGHASH_ALL, because we always use that and it is only present in the
-                    // PSBT for compatibility with other tools.
-                    secp256k1::Signature::from_der(&sig[..sig.len() - 1])
-                        .expect("we serialized it ourselves that way")
-                })
-                .collect::<Vec<_>>();
-
-            batch.append_from_iter(
-                peg_out_ids
-                    .into_iter()
-                    .map(|peg_out| BatchItem::delete(PendingPegOutKey(peg_out))),
-            );
-            batch.append_insert_new(UnsignedTransactionKey(psbt.global.unsigned_tx.txid()), psbt);
-            batch.append_insert_new(PegOutTxSignatureCI(txid), sigs);
+                    batch.append_from_iter(
+                        peg_out_ids
+                            .iter()
+                            .map(|peg_out| BatchItem::delete(PendingPegOutKey(*peg_out))),
+                    );
+                    // Remember which transaction pays each peg-out so clients can follow its progress
+                    batch.append_from_iter(
+                        peg_out_ids
+                            .into_iter()
+                            .map(|peg_out| BatchItem::insert_new(PegOutTxKey(peg_out), txid)),
+                    );
+                    // The inputs must not be selected again by later peg-out transactions
+                    batch.append_from_iter(
+                        psbt.global
+                            .unsigned_tx
+                            .input
+                            .iter()
+                            .map(|input| BatchItem::delete(UTXOKey(input.previous_output))),
+                    );
+                    batch.append_insert_new(UnsignedTransactionKey(txid), psbt);
+                    batch.append_insert_new(PegOutTxSignatureCI(txid), sigs);
+                }
+                None => {
+                    warn!(
+                        "Not enough funds to create a transaction paying {} peg outs",
+                        peg_out_ids.len()
+                    );
+                }
+            }
         }
+
+        self.bump_stuck_transactions(batch.subtransaction(), &round_consensus);
         batch.commit();
     }
 
-    fn output_status(&self, _out_point: OutPoint) -> Option<Self::TxOutputOutcome> {
-        // TODO: return BTC tx id once included in peg-out tx
-        Some(())
+    fn output_status(&self, out_point: OutPoint) -> Option<Self::TxOutputOutcome> {
+        if self
+            .db
+            .get_value::<_, PendingPegOut>(&PendingPegOutKey(out_point))
+            .expect("DB error")
+            .is_some()
+        {
+            return Some(PegOutOutcome::Queued);
+        }
+
+        let mut txid = self
+            .db
+            .get_value::<_, Txid>(&PegOutTxKey(out_point))
+            .expect("DB error")?;
+
+        // If the transaction was replaced to bump its fee either of them may confirm
+        loop {
+            if let Some(block_height) = self
+                .db
+                .get_value::<_, u32>(&ConfirmedTransactionKey(txid))
+                .expect("DB error")
+            {
+                return Some(PegOutOutcome::Confirmed { txid, block_height });
+            }
+
+            match self
+                .db
+                .get_value::<_, Txid>(&ReplacedTransactionKey(txid))
+                .expect("DB error")
+            {
+                Some(replacement) => txid = replacement,
+                None => break,
+            }
+        }
+
+        let is_broadcast = self
+            .db
+            .get_value::<_, PendingTransaction>(&PendingTransactionKey(txid))
+            .expect("DB error")
+            .is_some();
+        if is_broadcast {
+            Some(PegOutOutcome::Broadcast(txid))
+        } else {
+            Some(PegOutOutcome::Signing(txid))
+        }
+    }
+
+    /// Our UTXOs, including the change of unconfirmed peg-out transactions and peg-ins whose block
+    /// was orphaned, are assets since they are expected to confirm (again). Peg-outs that weren't
+    /// included in a transaction yet are liabilities.
+    fn audit(&self) -> Audit {
+        let utxos = self
+            .available_utxos()
+            .into_iter()
+            .map(|(_, utxo)| utxo.amount.as_sat())
+            .sum::<u64>();
+        let orphaned_peg_ins = self
+            .orphaned_peg_ins()
+            .into_iter()
+            .map(|(_, utxo, _)| utxo.amount.as_sat())
+            .sum::<u64>();
+        let assets = utxos + orphaned_peg_ins + self.unconfirmed_change().as_sat();
+
+        let liabilities = self
+            .pending_peg_outs()
+            .into_iter()
+            .map(|(_, peg_out)| peg_out.amount.as_sat())
+            .sum::<u64>();
+
+        Audit {
+            assets: minimint_api::Amount::from_sat(assets),
+            liabilities: minimint_api::Amount::from_sat(liabilities),
+        }
     }
 }
 
 impl Wallet {
     pub async fn new(cfg: WalletConfig, db: Arc<dyn RawDatabase>) -> Result<Wallet, WalletError> {
-        let broadcaster_cfg = cfg.clone();
-        let broadcaster_db = db.clone();
-        tokio::spawn(async move {
-            // FIXME: become resilient against bitcoind outages
-            let btc_rpc = bitcoincore_rpc_async::Client::new(
-                broadcaster_cfg.btc_rpc_address.clone(),
-                Auth::UserPass(
-                    broadcaster_cfg.btc_rpc_user.clone(),
-                    broadcaster_cfg.btc_rpc_pass.clone(),
-                ),
-            )
-            .await
-            .expect("Failed to connect to bitcoind");
-
-            broadcast_pending_tx(broadcaster_db, btc_rpc).await;
-        });
+        let btc_rpc: Arc<dyn BitcoindRpc> = match &cfg.bitcoin_backend {
+            BitcoinBackendConfig::Bitcoind {
+                address,
+                user,
+                pass,
+            } => {
+                let bitcoind = BitcoindClient::new(address, user, pass).await?;
+                Arc::new(RetryClient::new(bitcoind))
+            }
+            BitcoinBackendConfig::Esplora { url } => {
+                Arc::new(RetryClient::new(EsploraClient::new(url)))
+            }
+            BitcoinBackendConfig::Electrum { address } => {
+                Arc::new(RetryClient::new(ElectrumClient::new(address)))
+            }
+        };
 
-        let btc_rpc = bitcoincore_rpc_async::Client::new(
-            cfg.btc_rpc_address.clone(),
-            Auth::UserPass(cfg.btc_rpc_user.clone(), cfg.btc_rpc_pass.clone()),
-        )
-        .await?;
+        Wallet::new_with_bitcoind(cfg, db, btc_rpc).await
+    }
 
-        let bitcoind_net = get_network(&btc_rpc).await?;
+    /// Creates a wallet that uses `btc_rpc` to follow the bitcoin chain and broadcast transactions
+    pub async fn new_with_bitcoind(
+        cfg: WalletConfig,
+        db: Arc<dyn RawDatabase>,
+        btc_rpc: Arc<dyn BitcoindRpc>,
+    ) -> Result<Wallet, WalletError> {
+        let bitcoind_net = btc_rpc.get_network().await?;
         if bitcoind_net != cfg.network {
             return Err(WalletError::WrongNetwork(cfg.network, bitcoind_net));
         }
 
+        let broadcaster_db = db.clone();
+        let broadcaster_rpc = btc_rpc.clone();
+        tokio::spawn(async move {
+            loop {
+                broadcast_pending_tx(broadcaster_db.as_ref(), broadcaster_rpc.as_ref()).await;
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+
+        let block_hash_cache = Arc::new(BlockHashCache::default());
+        let prefetcher_cache = block_hash_cache.clone();
+        let prefetcher_db = db.clone();
+        let prefetcher_rpc = btc_rpc.clone();
+        let checkpoint_height = cfg.checkpoint_height;
+        let finalty_delay = cfg.finalty_delay;
+        tokio::spawn(async move {
+            loop {
+                let consensus_height =
+                    consensus_height(prefetcher_db.as_ref()).unwrap_or(checkpoint_height);
+                if let Err(e) = prefetch_block_hashes(
+                    &prefetcher_cache,
+                    prefetcher_rpc.as_ref(),
+                    consensus_height,
+                    finalty_delay,
+                )
+                .await
+                {
+                    warn!("Prefetching block hashes failed: {}", e);
+                }
+                tokio::time::sleep(PREFETCH_INTERVAL).await;
+            }
+        });
+
         let wallet = Wallet {
             cfg,
             secp: Default::default(),
             btc_rpc,
+            misbehavior: MisbehaviorLog::new(db.clone()),
             db,
+            block_hash_cache,
         };
 
         Ok(wallet)
@@ -522,7 +735,6 @@ impl Wallet {
             }
         }
 
-        // FIXME: actually recognize change UTXOs on maturity
         // We need to save the change output's tweak key to be able to access the funds later on.
         // The tweak is extracted here because the psbt is moved next and not available anymore
         // when the tweak is actually needed in the end to be put into the batch on success.
@@ -531,6 +743,8 @@ impl Wallet {
             .iter()
             .flat_map(|output| output.proprietary.get(&proprietary_tweak_key()).cloned())
             .next();
+        // The same goes for the inputs which we need in case we have to replace the transaction
+        let spent_utxos = spent_utxos(&psbt);
 
         match miniscript::psbt::finalize(&mut psbt, &self.secp) {
             Ok(()) => {}
@@ -562,13 +776,18 @@ impl Wallet {
 
         // We were able to finalize the transaction, so we will delete the PSBT and instead keep the
         // extracted tx for periodic transmission and to accept the change into our wallet
-        // eventually once it confirms.
+        // eventually once it confirms. Our signature isn't needed by anyone anymore.
         batch.append_delete(UnsignedTransactionKey(signature.txid));
+        batch.append_maybe_delete(PegOutTxSignatureCI(signature.txid));
         batch.append_insert_new(
             PendingTransactionKey(signature.txid),
             PendingTransaction {
                 tx,
                 tweak: change_tweak,
+                spent_utxos,
+                pending_since_block: self
+                    .consensus_height()
+                    .expect("Wallet should be initialized at this point"),
             },
         );
         batch.commit();
@@ -587,32 +806,181 @@ impl Wallet {
             .expect("We checked before that proposals aren't empty")
     }
 
+    /// Agrees on the block at the median proposed height and syncs our view of the chain up to it.
+    /// The block is only accepted if at least `threshold` peers proposed the same hash for it, so
+    /// faulty peers can't make us follow a block our bitcoind doesn't know. Otherwise we stick to
+    /// the last consensus block, which is `None` before the wallet agreed on any block. If the
+    /// consensus chain was reorged below our last consensus height the orphaned blocks are rolled
+    /// back first, a lower median height on the same chain is ignored. Syncing is retried till it
+    /// succeeds since all peers have to agree on the outcome.
+    ///
     /// # Panics
     /// * If proposals is empty
-    async fn process_block_height_proposals<'a>(
+    async fn process_block_proposals<'a>(
         &self,
-        batch: BatchTx<'a>,
-        mut proposals: Vec<u32>,
-    ) -> u32 {
+        mut batch: BatchTx<'a>,
+        proposals: Vec<(u32, BlockHash)>,
+    ) -> Option<(u32, BlockHash)> {
         assert!(!proposals.is_empty());
 
-        proposals.sort();
-        let median_proposal = proposals[proposals.len() / 2];
+        let mut heights = proposals
+            .iter()
+            .map(|(height, _)| *height)
+            .collect::<Vec<_>>();
+        heights.sort_unstable();
+        let median_height = heights[heights.len() / 2];
 
-        let consensus_height = self.consensus_height().unwrap_or(0);
+        // More than half of the peers make up a threshold, so at most one hash can reach it
+        let median_hash = proposals
+            .iter()
+            .filter(|(height, _)| *height == median_height)
+            .map(|(_, hash)| *hash)
+            .counts()
+            .into_iter()
+            .find(|(_, votes)| *votes >= self.cfg.threshold)
+            .map(|(hash, _)| hash);
+        let median_hash = match median_hash {
+            Some(hash) => hash,
+            None => {
+                warn!(
+                    "Less than {} peers proposed the same block at median height {}, sticking to the last consensus block",
+                    self.cfg.threshold, median_height
+                );
+                return self
+                    .current_round_consensus()
+                    .map(|rc| (rc.block_height, rc.block_hash));
+            }
+        };
 
-        if median_proposal >= consensus_height {
-            debug!("Setting consensus block height to {}", median_proposal);
-            self.sync_up_to_consensus_heigh(batch, median_proposal)
+        loop {
+            let result = self
+                .sync_to_block(batch.subtransaction(), median_height, median_hash)
                 .await;
+            match result {
+                Ok(consensus_block) => {
+                    batch.commit();
+                    return Some(consensus_block);
+                }
+                Err(e) => {
+                    error!(
+                        "Syncing to consensus block {} at height {} failed, retrying: {}",
+                        median_hash, median_height, e
+                    );
+                    tokio::time::sleep(BITCOIND_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Syncs our view of the chain up to the block `median_hash` at `median_height`, see
+    /// [`Wallet::process_block_proposals`]. Nothing is written to `batch` if our bitcoind fails.
+    async fn sync_to_block<'a>(
+        &self,
+        mut batch: BatchTx<'a>,
+        median_height: u32,
+        median_hash: BlockHash,
+    ) -> Result<(u32, BlockHash), RpcError> {
+        let consensus = self.current_round_consensus();
+        let consensus_height = consensus
+            .as_ref()
+            .map_or(self.cfg.checkpoint_height, |rc| rc.block_height);
+
+        let (common_height, block_hashes) = loop {
+            // Everything we learn about the chain from our bitcoind has to match the consensus chain
+            self.wait_for_block(median_height, median_hash).await?;
+
+            let common_height = self
+                .find_common_height(std::cmp::min(median_height, consensus_height))
+                .await?;
+
+            if median_height < consensus_height && common_height == median_height {
+                let consensus =
+                    consensus.expect("Peers never propose heights below the checkpoint");
+                warn!(
+                    "Median proposed consensus block height shrunk from {} to {}, sticking to the last consensus block",
+                    consensus_height, median_height
+                );
+                return Ok((consensus.block_height, consensus.block_hash));
+            }
+
+            let block_hashes = self
+                .fetch_block_hashes(common_height + 1, median_height)
+                .await?;
+            if block_hashes
+                .last()
+                .map_or(true, |hash| *hash == median_hash)
+            {
+                break (common_height, block_hashes);
+            }
+            warn!("Our bitcoind switched chains while syncing, trying again");
+            self.block_hash_cache.clear();
+        };
+
+        let orphaned_peg_ins = if common_height < consensus_height {
+            error!(
+                "Deep reorg: consensus blocks above height {} were orphaned, rolling back from height {}",
+                common_height, consensus_height
+            );
+            self.roll_back_blocks(&mut batch, common_height)
         } else {
-            panic!(
-                   "Median proposed consensus block height shrunk from {} to {}, the federation is broken",
-                   consensus_height, median_proposal
-               );
+            vec![]
+        };
+
+        if !block_hashes.is_empty() {
+            debug!("Setting consensus block height to {}", median_height);
+            self.sync_up_to_consensus_heigh(
+                batch.subtransaction(),
+                common_height,
+                &block_hashes,
+                orphaned_peg_ins,
+            )
+            .await?;
         }
+        batch.commit();
+        self.block_hash_cache.prune(median_height);
+
+        Ok((median_height, median_hash))
+    }
+
+    /// Block height and fee rate we propose based on our bitcoind's view of the chain
+    async fn round_consensus_proposal(
+        &self,
+        mut rng: impl RngCore + CryptoRng,
+    ) -> Result<RoundConsensusItem, RpcError> {
+        let our_network_height = self.btc_rpc.get_block_height().await? as u32;
+        let our_target_height = our_network_height.saturating_sub(self.cfg.finalty_delay);
+
+        // In case the wallet just got created the height is not committed to the DB yet but will
+        // be set to the checkpoint height first, so we can assume that here.
+        let last_consensus_height = self
+            .consensus_height()
+            .unwrap_or(self.cfg.checkpoint_height);
+
+        let proposed_height = if our_target_height >= last_consensus_height {
+            our_target_height
+        } else {
+            warn!(
+                "The block height shrunk, new proposal would be {}, but we are sticking to the last consensus height {}.",
+                our_target_height,
+                last_consensus_height
+            );
+            last_consensus_height
+        };
+
+        let block_hash = self.btc_rpc.get_block_hash(proposed_height as u64).await?;
+
+        let fee_rate = self
+            .btc_rpc
+            .get_fee_rate(self.cfg.peg_out_policy.confirmation_target)
+            .await?
+            .unwrap_or(self.cfg.default_fee);
 
-        median_proposal
+        Ok(RoundConsensusItem {
+            block_height: proposed_height,
+            block_hash,
+            fee_rate,
+            randomness: rng.gen(),
+        })
     }
 
     pub fn current_round_consensus(&self) -> Option<RoundConsensus> {
@@ -622,82 +990,607 @@ impl Wallet {
     }
 
     pub fn consensus_height(&self) -> Option<u32> {
-        self.current_round_consensus().map(|rc| rc.block_height)
+        consensus_height(self.db.as_ref())
     }
 
-    async fn sync_up_to_consensus_heigh<'a>(&self, mut batch: BatchTx<'a>, new_height: u32) {
-        let old_height = self.consensus_height().unwrap_or(0);
-        if new_height < old_height {
-            info!(
-                "Nothing to sync, new height ({}) is lower than old height ({}), doing nothing.",
-                new_height, old_height
-            );
-            return;
+    /// Fee a peg-out to `recipient` has to pay at the current consensus fee rate. It covers the
+    /// peg-out's output and one input, so peg-out transactions don't spend more inputs than
+    /// peg-outs are paying for unless we consolidate UTXOs.
+    pub fn peg_out_fees(&self, recipient: &Address) -> PegOutFees {
+        let fee_rate = self
+            .current_round_consensus()
+            .map_or(self.cfg.default_fee, |consensus| consensus.fee_rate);
+        let wallet = self.offline_wallet();
+        let weight = wallet.output_weight(&recipient.script_pubkey()) + wallet.max_input_weight();
+        PegOutFees {
+            sats_per_kvb: fee_rate.sats_per_kvb,
+            fee: fee_rate.calculate_fee(weight),
         }
+    }
 
-        if new_height == old_height {
-            debug!("Height didn't change, still at {}", old_height);
-            return;
-        }
+    /// Lists the federation's UTXOs at the current consensus height together with the e-cash
+    /// `liabilities` reported by the mint and signs the report with our peg-in key. Returns `None`
+    /// if the wallet didn't reach consensus on a block height yet.
+    pub fn reserves_report(
+        &self,
+        liabilities: minimint_api::Amount,
+    ) -> Option<SignedReservesReport> {
+        let block_height = self.consensus_height()?;
+        let utxos = self
+            .available_utxos()
+            .into_iter()
+            .map(|(UTXOKey(out_point), utxo)| ReserveUtxo {
+                out_point,
+                tweak: utxo.tweak,
+                amount: utxo.amount,
+            })
+            .collect();
+        let report = ReservesReport {
+            block_height,
+            utxos,
+            liabilities,
+        };
 
-        info!(
-            "New consensus height {}, syncing up ({} blocks to go)",
-            new_height,
-            new_height - old_height
-        );
+        let our_key = secp256k1::PublicKey::from_secret_key(&self.secp, &self.cfg.peg_in_key);
+        let peer = *self
+            .cfg
+            .peer_peg_in_keys
+            .iter()
+            .find(|(_, key)| key.key == our_key)
+            .expect("Our peg-in key is part of the config")
+            .0;
+        let signature = self.secp.sign(&report.message(), &self.cfg.peg_in_key);
+
+        Some(SignedReservesReport {
+            report,
+            peer,
+            signature,
+        })
+    }
 
-        batch.reserve((new_height - old_height) as usize + 1);
-        for height in (old_height + 1)..=(new_height) {
-            if height % 100 == 0 {
-                debug!("Caught up to block {}", height);
+    /// Waits till our bitcoind knows the block `hash` at `height`. If our bitcoind lags behind we
+    /// wait for it to catch up. If it follows another chain than the rest of the federation we
+    /// can't process any blocks without falling out of consensus, so we stall and alert the
+    /// operator instead.
+    async fn wait_for_block(&self, height: u32, hash: BlockHash) -> Result<(), RpcError> {
+        loop {
+            match self.btc_rpc.get_block_hash(height as u64).await {
+                Ok(our_hash) if our_hash == hash => return Ok(()),
+                Ok(our_hash) => {
+                    error!(
+                        "Our bitcoind is on a different chain than the federation: block {} at height {} instead of {}, waiting for it to switch chains",
+                        our_hash, height, hash
+                    );
+                    tokio::time::sleep(CHAIN_MISMATCH_RETRY_INTERVAL).await;
+                }
+                Err(RpcError::UnknownBlockHeight(_)) => {
+                    warn!(
+                        "Our bitcoind doesn't know the consensus block {} at height {} yet, waiting for it to catch up",
+                        hash, height
+                    );
+                    tokio::time::sleep(BITCOIND_RETRY_INTERVAL).await;
+                }
+                Err(e) => return Err(e),
             }
-
-            // TODO: use batching for mainnet syncing
-            trace!("Fetching block hash for block {}", height);
-            // TODO: implement retying failed RPC commands till they succeed while loudly complaining to alert the operator
-            let block_hash = self
-                .btc_rpc
-                .get_block_hash(height as u64)
-                .await
-                .expect("Ignoring failure here would throw us out of consensus");
-            batch.append_insert_new(
-                BlockHashKey(BlockHash::from_inner(block_hash.into_inner())),
-                (),
-            );
         }
-        batch.commit();
     }
 
-    fn block_is_known(&self, block_hash: BlockHash) -> bool {
-        self.db
-            .get_value::<_, ()>(&BlockHashKey(block_hash))
-            .expect("DB error")
-            .is_some()
+    /// Highest block at or below `height` that is part of both our bitcoind's chain and the
+    /// consensus chain we synced so far, blocks at or below the checkpoint are assumed to be final
+    async fn find_common_height(&self, mut height: u32) -> Result<u32, RpcError> {
+        while height > self.cfg.checkpoint_height {
+            let block_hash = self.btc_rpc.get_block_hash(height as u64).await?;
+            if self.block_height(block_hash) == Some(height) {
+                break;
+            }
+            height -= 1;
+        }
+        Ok(height)
     }
 
-    pub fn pending_peg_outs(&self) -> Vec<(OutPoint, PendingPegOut)> {
-        self.db
-            .find_by_prefix::<_, PendingPegOutKey, PendingPegOut>(&PendingPegOutPrefixKey)
-            .map_ok(|(key, peg_out)| (key.0, peg_out))
-            .collect::<Result<_, _>>()
-            .expect("DB error")
+    /// Hashes of the blocks from height `first` up to and including `last`, taken from the
+    /// prefetched blocks as far as possible
+    async fn fetch_block_hashes(&self, first: u32, last: u32) -> Result<Vec<BlockHash>, RpcError> {
+        let mut block_hashes = self.block_hash_cache.get(first, last);
+
+        // Make sure the cached blocks weren't orphaned since they were fetched
+        if let Some(cached_hash) = block_hashes.last() {
+            let cached_height = first + block_hashes.len() as u32 - 1;
+            let block_hash = self.btc_rpc.get_block_hash(cached_height as u64).await?;
+            if block_hash != *cached_hash {
+                debug!("Cached block {} was orphaned, ignoring cache", cached_hash);
+                self.block_hash_cache.clear();
+                block_hashes.clear();
+            }
+        }
+
+        let missing_from = first + block_hashes.len() as u32;
+        trace!(
+            "Fetching block hashes {} to {} ({} prefetched)",
+            missing_from,
+            last,
+            block_hashes.len()
+        );
+        block_hashes
+            .extend(prefetch::fetch_block_hashes(self.btc_rpc.as_ref(), missing_from, last).await?);
+        Ok(block_hashes)
     }
 
-    async fn create_peg_out_tx(
+    /// Forgets all blocks above `height` after they were orphaned by a reorg and takes peg-ins
+    /// confirmed in them out of our spendable UTXOs till they confirm again. Returns these peg-ins.
+    fn roll_back_blocks(
         &self,
-        pending_peg_outs: Vec<PendingPegOut>,
-        consensus: RoundConsensus,
-    ) -> PartiallySignedTransaction {
-        let wallet = self.offline_wallet();
-        let mut psbt = wallet.create_tx(
-            pending_peg_outs,
-            self.available_utxos(),
-            consensus.fee_rate,
-            &consensus.randomness_beacon,
+        batch: &mut BatchTx,
+        height: u32,
+    ) -> Vec<(bitcoin::OutPoint, SpendableUTXO, PegIn)> {
+        let orphaned_blocks = self
+            .db
+            .find_by_prefix::<_, BlockHashKey, u32>(&BlockHashPrefixKey)
+            .filter_ok(|(_, block_height)| *block_height > height)
+            .map_ok(|(key, _)| key.0)
+            .collect::<Result<HashSet<_>, _>>()
+            .expect("DB error");
+        batch.append_from_iter(
+            orphaned_blocks
+                .iter()
+                .map(|block_hash| BatchItem::delete(BlockHashKey(*block_hash))),
         );
-        // TODO: extract sigs and do stuff?!
+
+        let affected_peg_ins = self
+            .db
+            .find_by_prefix::<_, PegInKey, PegIn>(&PegInPrefixKey)
+            .filter_ok(|(_, peg_in)| orphaned_blocks.contains(&peg_in.block_hash))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("DB error");
+
+        let mut orphaned_peg_ins = Vec::new();
+        for (PegInKey(out_point), peg_in) in affected_peg_ins {
+            match self
+                .db
+                .get_value::<_, SpendableUTXO>(&UTXOKey(out_point))
+                .expect("DB error")
+            {
+                Some(utxo) => {
+                    error!(
+                        "Block of peg-in {} was orphaned, it can't be spent till it confirms again",
+                        out_point
+                    );
+                    batch.append_delete(UTXOKey(out_point));
+                    batch.append_insert_new(OrphanedPegInKey(out_point), utxo.clone());
+                    orphaned_peg_ins.push((out_point, utxo, peg_in));
+                }
+                None => {
+                    error!(
+                        "Block of peg-in {} was orphaned after we spent it, the spending transaction will be invalid unless it confirms again",
+                        out_point
+                    );
+                }
+            }
+        }
+        orphaned_peg_ins
+    }
+
+    /// Records `block_hashes` as the consensus chain's blocks following `base_height` and processes
+    /// our transactions and orphaned peg-ins confirmed in them. `newly_orphaned` are peg-ins that
+    /// were orphaned in the same batch and thus aren't visible in the DB yet.
+    async fn sync_up_to_consensus_heigh<'a>(
+        &self,
+        mut batch: BatchTx<'a>,
+        base_height: u32,
+        block_hashes: &[BlockHash],
+        newly_orphaned: Vec<(bitcoin::OutPoint, SpendableUTXO, PegIn)>,
+    ) -> Result<(), RpcError> {
+        info!(
+            "New consensus height {}, syncing up ({} blocks to go)",
+            base_height as usize + block_hashes.len(),
+            block_hashes.len()
+        );
+
+        // Transactions we are waiting for to confirm, only if there are any we need to look at the
+        // contents of the new blocks
+        let mut pending_transactions = self.pending_transactions();
+        let mut orphaned_peg_ins = self.orphaned_peg_ins();
+        orphaned_peg_ins.extend(newly_orphaned);
+
+        batch.reserve(block_hashes.len());
+        for (height, block_hash) in ((base_height + 1)..).zip(block_hashes.iter().copied()) {
+            if !pending_transactions.is_empty() || !orphaned_peg_ins.is_empty() {
+                trace!("Looking for our transactions in block {}", height);
+                let transactions = pending_transactions
+                    .values()
+                    .map(|pending| pending.tx.clone())
+                    .chain(
+                        orphaned_peg_ins
+                            .iter()
+                            .map(|(_, _, peg_in)| peg_in.transaction.clone()),
+                    )
+                    .unique_by(|tx| tx.txid())
+                    .collect::<Vec<_>>();
+                let txids = self
+                    .btc_rpc
+                    .find_transactions_in_block(&block_hash, height as u64, &transactions)
+                    .await?;
+                // Our consensus height already trails the chain tip by `finalty_delay` blocks, so
+                // every transaction we find here is buried deeply enough to consider it final
+                for txid in txids {
+                    if let Some(pending_tx) = pending_transactions.remove(&txid) {
+                        info!("Transaction {} confirmed in block {}", txid, height);
+                        self.recognize_change_utxo(&mut batch, &pending_tx);
+                        self.remove_conflicting_transactions(
+                            &mut batch,
+                            &pending_tx.tx,
+                            &mut pending_transactions,
+                        );
+                        batch.append_delete(PendingTransactionKey(txid));
+                        batch.append_insert_new(ConfirmedTransactionKey(txid), height);
+                    }
+
+                    let (confirmed, still_orphaned) = orphaned_peg_ins
+                        .into_iter()
+                        .partition::<Vec<_>, _>(|(out_point, _, _)| out_point.txid == txid);
+                    orphaned_peg_ins = still_orphaned;
+                    for (out_point, utxo, peg_in) in confirmed {
+                        info!(
+                            "Orphaned peg-in {} confirmed again in block {}",
+                            out_point, height
+                        );
+                        batch.append_delete(OrphanedPegInKey(out_point));
+                        batch.append_insert_new(UTXOKey(out_point), utxo);
+                        batch.append_insert(
+                            PegInKey(out_point),
+                            PegIn {
+                                block_hash,
+                                ..peg_in
+                            },
+                        );
+                    }
+                }
+            }
+
+            batch.append_insert_new(BlockHashKey(block_hash), height);
+        }
+        batch.commit();
+        Ok(())
+    }
+
+    /// Peg-ins whose block was orphaned and that didn't confirm again yet
+    fn orphaned_peg_ins(&self) -> Vec<(bitcoin::OutPoint, SpendableUTXO, PegIn)> {
+        self.db
+            .find_by_prefix::<_, OrphanedPegInKey, SpendableUTXO>(&OrphanedPegInPrefixKey)
+            .map_ok(|(OrphanedPegInKey(out_point), utxo)| {
+                let peg_in = self
+                    .db
+                    .get_value::<_, PegIn>(&PegInKey(out_point))
+                    .expect("DB error")
+                    .expect("Every orphaned peg-in has a peg-in entry");
+                (out_point, utxo, peg_in)
+            })
+            .collect::<Result<_, _>>()
+            .expect("DB error")
+    }
+
+    /// Value of the change outputs of peg-out transactions that didn't confirm yet. Their inputs
+    /// were already removed from our UTXOs and the peg-outs they pay from the pending peg-outs, so
+    /// only the change is still owned by the federation. Replaced transactions are skipped since
+    /// their replacement spends the same inputs.
+    fn unconfirmed_change(&self) -> bitcoin::Amount {
+        let is_replaced = |txid: Txid| {
+            self.db
+                .get_value::<_, Txid>(&ReplacedTransactionKey(txid))
+                .expect("DB error")
+                .is_some()
+        };
+
+        let unsigned_change = self
+            .db
+            .find_by_prefix::<_, UnsignedTransactionKey, PartiallySignedTransaction>(
+                &UnsignedTransactionPrefixKey,
+            )
+            .map(|res| res.expect("DB error"))
+            .filter(|(UnsignedTransactionKey(txid), _)| !is_replaced(*txid))
+            .flat_map(|(_, psbt)| {
+                psbt.global
+                    .unsigned_tx
+                    .output
+                    .into_iter()
+                    .zip(psbt.outputs.into_iter())
+                    .filter(|(_, output)| output.proprietary.contains_key(&proprietary_tweak_key()))
+                    .map(|(tx_out, _)| tx_out.value)
+                    .collect::<Vec<_>>()
+            })
+            .sum::<u64>();
+
+        let wallet = self.offline_wallet();
+        let pending_change = self
+            .pending_transactions()
+            .into_iter()
+            .filter(|(txid, _)| !is_replaced(*txid))
+            .filter_map(|(_, pending)| {
+                let change_script = wallet.derive_script(pending.tweak.as_ref()?);
+                pending
+                    .tx
+                    .output
+                    .iter()
+                    .find(|output| output.script_pubkey == change_script)
+                    .map(|output| output.value)
+            })
+            .sum::<u64>();
+
+        bitcoin::Amount::from_sat(unsigned_change + pending_change)
+    }
+
+    /// Transactions that were fully signed but not seen in a block yet
+    fn pending_transactions(&self) -> BTreeMap<Txid, PendingTransaction> {
+        self.db
+            .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
+                &PendingTransactionPrefixKey,
+            )
+            .map_ok(|(key, tx)| (key.0, tx))
+            .collect::<Result<_, _>>()
+            .expect("DB error")
+    }
+
+    /// Forgets about all transactions that spend any of the inputs of the confirmed transaction
+    /// `tx`, i.e. replacements of it or the transaction it replaced. Since replacements spend the
+    /// exact same inputs no UTXOs are lost this way.
+    fn remove_conflicting_transactions(
+        &self,
+        batch: &mut BatchTx,
+        tx: &Transaction,
+        pending_transactions: &mut BTreeMap<Txid, PendingTransaction>,
+    ) {
+        let spends_same_inputs = |other: &Transaction| {
+            other.txid() != tx.txid()
+                && other.input.iter().any(|input| {
+                    tx.input
+                        .iter()
+                        .any(|our| our.previous_output == input.previous_output)
+                })
+        };
+
+        let conflicting_pending = pending_transactions
+            .iter()
+            .filter(|(_, pending)| spends_same_inputs(&pending.tx))
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+        for txid in conflicting_pending {
+            debug!(
+                "Dropping transaction {} conflicting with {}",
+                txid,
+                tx.txid()
+            );
+            pending_transactions.remove(&txid);
+            batch.append_delete(PendingTransactionKey(txid));
+        }
+
+        let conflicting_unsigned = self
+            .db
+            .find_by_prefix::<_, UnsignedTransactionKey, PartiallySignedTransaction>(
+                &UnsignedTransactionPrefixKey,
+            )
+            .map_ok(|(key, psbt)| (key.0, psbt))
+            .filter_ok(|(_, psbt)| spends_same_inputs(&psbt.global.unsigned_tx))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("DB error");
+        for (txid, _) in conflicting_unsigned {
+            debug!(
+                "Dropping unsigned transaction {} conflicting with {}",
+                txid,
+                tx.txid()
+            );
+            batch.append_delete(UnsignedTransactionKey(txid));
+            batch.append_maybe_delete(PegOutTxSignatureCI(txid));
+        }
+    }
+
+    /// Replaces pending transactions that didn't confirm for `fee_bump_delay` blocks with ones
+    /// paying the current consensus fee rate if that results in a higher fee
+    fn bump_stuck_transactions(&self, mut batch: BatchTx, round_consensus: &RoundConsensus) {
+        let stuck_transactions = self
+            .db
+            .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
+                &PendingTransactionPrefixKey,
+            )
+            .filter_ok(|(_, pending)| {
+                round_consensus.block_height
+                    >= pending.pending_since_block + self.cfg.fee_bump_delay
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .expect("DB error");
+
+        for (PendingTransactionKey(txid), pending) in stuck_transactions {
+            // Only the latest replacement may be replaced again
+            if self
+                .db
+                .get_value::<_, Txid>(&ReplacedTransactionKey(txid))
+                .expect("DB error")
+                .is_some()
+            {
+                continue;
+            }
+
+            let mut psbt = match self.create_replacement_tx(&pending, round_consensus.fee_rate) {
+                Some(psbt) => psbt,
+                None => {
+                    trace!(
+                        "Fee rate {} is too low to replace stuck transaction {}",
+                        round_consensus.fee_rate.sats_per_kvb,
+                        txid
+                    );
+                    continue;
+                }
+            };
+            let replacement_txid = psbt.global.unsigned_tx.txid();
+
+            info!(
+                "Signing replacement {} for stuck transaction {} at fee rate {}",
+                replacement_txid, txid, round_consensus.fee_rate.sats_per_kvb
+            );
+            let sigs = take_our_signatures(&mut psbt);
+
+            batch.append_insert_new(ReplacedTransactionKey(txid), replacement_txid);
+            batch.append_insert_new(UnsignedTransactionKey(replacement_txid), psbt);
+            batch.append_insert_new(PegOutTxSignatureCI(replacement_txid), sigs);
+        }
+        batch.commit();
+    }
+
+    /// Creates a signed PSBT paying the same peg-outs from the same inputs as `pending` at
+    /// `fee_rate`. Returns `None` if the replacement wouldn't pay enough additional fees to be
+    /// relayed.
+    fn create_replacement_tx(
+        &self,
+        pending: &PendingTransaction,
+        fee_rate: Feerate,
+    ) -> Option<PartiallySignedTransaction> {
+        // Without change output we already spend everything on fees
+        let change_tweak = pending.tweak.as_ref()?;
+
+        let wallet = self.offline_wallet();
+        let change_script = wallet.derive_script(change_tweak);
+        let peg_outs = pending
+            .tx
+            .output
+            .iter()
+            .filter(|output| output.script_pubkey != change_script)
+            .map(|output| PendingPegOut {
+                destination: output.script_pubkey.clone(),
+                amount: bitcoin::Amount::from_sat(output.value),
+                pending_since_block: pending.pending_since_block,
+                priority: false,
+            })
+            .collect();
+
+        let mut psbt = wallet.create_tx(
+            peg_outs,
+            pending.spent_utxos.clone(),
+            fee_rate,
+            change_tweak,
+        )?;
+
+        // Both transactions have to spend exactly the same inputs, otherwise the inputs left out
+        // would be lost if the replacement confirms
+        let replacement = &psbt.global.unsigned_tx;
+        if replacement.input.len() != pending.tx.input.len() {
+            return None;
+        }
+
+        let input_value = pending
+            .spent_utxos
+            .iter()
+            .map(|(_, utxo)| utxo.amount.as_sat())
+            .sum::<u64>();
+        let fee = |tx: &Transaction| {
+            input_value - tx.output.iter().map(|output| output.value).sum::<u64>()
+        };
+        let min_fee =
+            fee(&pending.tx) + MIN_RELAY_FEE_INCREMENT * (pending.tx.get_weight() as u64 + 3) / 4;
+        if fee(replacement) < min_fee {
+            return None;
+        }
+
+        wallet.sign_psbt(&mut psbt);
+        Some(psbt)
+    }
+
+    /// Adds the change output of a confirmed peg-out transaction to our spendable UTXOs
+    fn recognize_change_utxo(&self, batch: &mut BatchTx, pending_tx: &PendingTransaction) {
+        let tweak = match &pending_tx.tweak {
+            Some(tweak) => tweak,
+            None => return,
+        };
+
+        let change_script = self.offline_wallet().derive_script(tweak);
+        let change = pending_tx
+            .tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == change_script);
+
+        if let Some((idx, output)) = change {
+            let out_point = bitcoin::OutPoint::new(pending_tx.tx.txid(), idx as u32);
+            debug!(
+                "Adding change output {} worth {} sat to our UTXOs",
+                out_point, output.value
+            );
+            batch.append_insert_new(
+                UTXOKey(out_point),
+                SpendableUTXO {
+                    tweak: tweak.clone(),
+                    amount: bitcoin::Amount::from_sat(output.value),
+                    script_pubkey: change_script,
+                },
+            );
+        }
+    }
+
+    fn block_is_known(&self, block_hash: BlockHash) -> bool {
+        self.block_height(block_hash).is_some()
+    }
+
+    /// Height of `block_hash` if it's part of the consensus chain
+    fn block_height(&self, block_hash: BlockHash) -> Option<u32> {
+        self.db
+            .get_value::<_, u32>(&BlockHashKey(block_hash))
+            .expect("DB error")
+    }
+
+    pub fn pending_peg_outs(&self) -> Vec<(OutPoint, PendingPegOut)> {
+        self.db
+            .find_by_prefix::<_, PendingPegOutKey, PendingPegOut>(&PendingPegOutPrefixKey)
+            .map_ok(|(key, peg_out)| (key.0, peg_out))
+            .collect::<Result<_, _>>()
+            .expect("DB error")
+    }
+
+    async fn create_peg_out_tx(
+        &self,
+        pending_peg_outs: Vec<PendingPegOut>,
+        consensus: RoundConsensus,
+    ) -> Option<PartiallySignedTransaction> {
+        let wallet = self.offline_wallet();
+        let params = self.coin_selection_params(&pending_peg_outs, &consensus);
+
+        // All peers select the same UTXOs since they agreed on the UTXO set and randomness beacon
+        let utxos = select_coins(
+            self.available_utxos(),
+            &params,
+            &consensus.randomness_beacon,
+        )?;
+        let mut psbt = wallet.create_tx(
+            pending_peg_outs,
+            utxos,
+            consensus.fee_rate,
+            &consensus.randomness_beacon,
+        )?;
         wallet.sign_psbt(&mut psbt);
-        psbt
+        Some(psbt)
+    }
+
+    /// Parameters for selecting the UTXOs funding a peg-out transaction that pays
+    /// `pending_peg_outs` at the fee rate agreed on in `consensus`
+    fn coin_selection_params(
+        &self,
+        pending_peg_outs: &[PendingPegOut],
+        consensus: &RoundConsensus,
+    ) -> CoinSelectionParams {
+        let wallet = self.offline_wallet();
+        let change_script = wallet.derive_script(&consensus.randomness_beacon);
+        let input_weight = wallet.max_input_weight();
+        CoinSelectionParams {
+            target: StatelessWallet::peg_out_amount(pending_peg_outs)
+                + consensus
+                    .fee_rate
+                    .calculate_fee(wallet.base_weight(pending_peg_outs, &change_script)),
+            fee_rate: consensus.fee_rate,
+            long_term_fee_rate: self.cfg.consolidation_fee_rate,
+            input_weight,
+            // A change output has to be spent later on and shouldn't be dust
+            cost_of_change: self.cfg.consolidation_fee_rate.calculate_fee(input_weight)
+                + bitcoin::Amount::from_sat(change_script.dust_value()),
+            max_inputs: self.cfg.max_peg_out_inputs,
+        }
     }
 
     fn available_utxos(&self) -> Vec<(UTXOKey, SpendableUTXO)> {
@@ -717,80 +1610,80 @@ impl Wallet {
 }
 
 impl<'a> StatelessWallet<'a> {
-    fn create_tx(
-        &self,
-        outputs: Vec<PendingPegOut>,
-        mut utxos: Vec<(UTXOKey, SpendableUTXO)>,
-        feerate: Feerate,
-        change_tweak: &[u8],
-    ) -> PartiallySignedTransaction {
-        // When building a transaction we need to take care of two things:
-        //  * We need enough input amount to fund all outputs
-        //  * We need to keep an eye on the tx weight so we can factor the fees into out calculation
-
-        // We first calculate the total amount of outputs without change. We need at least that much
-        // plus fees in input amounts.
-        let peg_out_amount = outputs
+    /// Total amount paid to `outputs`
+    fn peg_out_amount(outputs: &[PendingPegOut]) -> bitcoin::Amount {
+        outputs
             .iter()
             .map(|peg_out| peg_out.amount)
             .fold1(|a, b| a + b)
-            .expect("We always peg out to at least one address");
+            .expect("We always peg out to at least one address")
+    }
 
-        // We then go on to calculate the base size of the transaction `total_weight` and the
-        // maximum weight per added input which we will add every time we select an input.
-        let change_script = self.derive_script(change_tweak);
+    /// Weight of a transaction paying `outputs` and change to `change_script` before adding any
+    /// inputs
+    fn base_weight(&self, outputs: &[PendingPegOut], change_script: &Script) -> usize {
         let out_weight: usize = outputs
             .iter()
-            .map(|out| out.destination.len() * 4 + 1 + 32)
+            .map(|out| self.output_weight(&out.destination))
             .sum::<usize>()
             // Add change script weight, it's very likely to be needed if not we just overpay in fees
-            + 1 // script len varint, 1 byte for all addresses we accept
-            + change_script.len() * 4 // script len
-            + 32; // value
-        let mut total_weight = 16 + // version
+            + self.output_weight(change_script);
+        16 + // version
             12 + // up to 2**16-1 inputs
             12 + // up to 2**16-1 outputs
             out_weight + // weight of all outputs
-            16; // lock time
-        let max_input_weight = self
-            .descriptor
+            16 // lock time
+    }
+
+    /// Weight an output paying to `script` adds to a transaction
+    fn output_weight(&self, script: &Script) -> usize {
+        script.len() * 4 // script
+            + 1 // script len varint, 1 byte for all addresses we accept
+            + 32 // value
+    }
+
+    /// Maximum weight every input spending one of our UTXOs adds to a transaction
+    fn max_input_weight(&self) -> usize {
+        self.descriptor
             .max_satisfaction_weight()
-            .expect("is satisfyable") + 
+            .expect("is satisfyable") +
             128 + // TxOutHash
             16 + // TxOutIndex
-            16; // sequence
+            16 // sequence
+    }
 
-        // Finally we initialize our accumulator for selected input amounts
-        let mut total_selected_value = bitcoin::Amount::from_sat(0);
+    /// Creates a PSBT spending all `utxos` to `outputs` and paying the remaining value minus fees
+    /// back to the change script derived from `change_tweak`. Returns `None` if the UTXOs can't
+    /// fund the outputs. The UTXOs have to be selected beforehand, e.g. using
+    /// [`coin_selection::select_coins`].
+    fn create_tx(
+        &self,
+        outputs: Vec<PendingPegOut>,
+        utxos: Vec<(UTXOKey, SpendableUTXO)>,
+        feerate: Feerate,
+        change_tweak: &[u8],
+    ) -> Option<PartiallySignedTransaction> {
+        let peg_out_amount = Self::peg_out_amount(&outputs);
+        let change_script = self.derive_script(change_tweak);
+        let total_selected_value = utxos
+            .iter()
+            .map(|(_, utxo)| utxo.amount)
+            .fold(bitcoin::Amount::from_sat(0), |a, b| a + b);
 
-        // When selecting UTXOs we employ a very primitive algorithm:
-        //  1. Sort UTXOs by amount, least to biggest
-        //  2. Keep selecting UTXOs as long as we still lack input value to pay both all outputs
-        //     plus the fee
-        utxos.sort_by_key(|(_, utxo)| utxo.amount);
-        let selected_utxos = utxos
-            .into_iter()
-            .take_while(|(_, utxo)| {
-                let fee = feerate.calculate_fee(total_weight);
-                if total_selected_value < (peg_out_amount + fee) {
-                    total_selected_value += utxo.amount;
-                    total_weight += max_input_weight;
-                    true
-                } else {
-                    false
-                }
-            })
-            .collect::<Vec<_>>();
+        // Fees are calculated per input the same way coin selection does, so a selection covering
+        // its target is always sufficient to fund the transaction
+        let fees = feerate.calculate_fee(self.base_weight(&outputs, &change_script))
+            + feerate.calculate_fee(self.max_input_weight()) * utxos.len() as u64;
 
         // We might have selected too much value on the input side, so we need to pay the remainder
         // back to ourselves.
-        let fees = feerate.calculate_fee(total_weight);
-        let change = total_selected_value - fees - peg_out_amount;
+        let change = total_selected_value.checked_sub(fees + peg_out_amount)?;
         let change_output = if change >= bitcoin::Amount::from_sat(change_script.dust_value()) {
             Some(PendingPegOut {
                 destination: change_script,
                 amount: change,
                 pending_since_block: 0,
+                priority: false,
             })
         } else {
             None
@@ -798,7 +1691,7 @@ impl<'a> StatelessWallet<'a> {
 
         info!(
             "Creating peg-out tx with {} inputs of value {} BTC, {} peg-outs of value {} paying {} BTC in fees (fee rate {}) and a change amount of {} BTC",
-            selected_utxos.len(),
+            utxos.len(),
             total_selected_value.as_btc(),
             outputs.len(),
             peg_out_amount.as_btc(),
@@ -810,12 +1703,12 @@ impl<'a> StatelessWallet<'a> {
         let transaction = Transaction {
             version: 2,
             lock_time: 0,
-            input: selected_utxos
+            input: utxos
                 .iter()
                 .map(|(utxo_key, _utxo)| TxIn {
                     previous_output: utxo_key.0,
                     script_sig: Default::default(),
-                    sequence: 0xFFFFFFFF,
+                    sequence: RBF_SEQUENCE,
                     witness: vec![],
                 })
                 .collect(),
@@ -839,7 +1732,7 @@ impl<'a> StatelessWallet<'a> {
                 proprietary: Default::default(),
                 unknown: Default::default(),
             },
-            inputs: selected_utxos
+            inputs: utxos
                 .into_iter()
                 .map(|(_utxo_key, utxo)| Input {
                     non_witness_utxo: None,
@@ -860,12 +1753,9 @@ impl<'a> StatelessWallet<'a> {
                     sha256_preimages: Default::default(),
                     hash160_preimages: Default::default(),
                     hash256_preimages: Default::default(),
-                    proprietary: vec![(
-                        proprietary_tweak_key(),
-                        bincode::serialize(&utxo.tweak).unwrap(),
-                    )]
-                    .into_iter()
-                    .collect(),
+                    proprietary: vec![(proprietary_tweak_key(), utxo.tweak)]
+                        .into_iter()
+                        .collect(),
                     unknown: Default::default(),
                 })
                 .collect(),
@@ -881,7 +1771,7 @@ impl<'a> StatelessWallet<'a> {
                 .collect(),
         };
 
-        psbt
+        Some(psbt)
     }
 
     fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) {
@@ -965,14 +1855,55 @@ impl<'a> StatelessWallet<'a> {
     }
 }
 
-async fn get_network(rpc_client: &bitcoincore_rpc_async::Client) -> Result<Network, WalletError> {
-    let bc = rpc_client.get_blockchain_info().await?;
-    match bc.chain.as_str() {
-        "main" => Ok(Network::Bitcoin),
-        "test" => Ok(Network::Testnet),
-        "regtest" => Ok(Network::Regtest),
-        _ => Err(WalletError::UnknownNetwork(bc.chain)),
-    }
+/// Takes our own signatures out of a freshly signed `psbt` so they can be shared as a consensus
+/// item. This way everyone finalizes the transaction in the same epoch.
+fn take_our_signatures(psbt: &mut PartiallySignedTransaction) -> Vec<secp256k1::Signature> {
+    psbt.inputs
+        .iter_mut()
+        .map(|input| {
+            assert_eq!(
+                input.partial_sigs.len(),
+                1,
+                "There was already more than one (our) or no signatures in input"
+            );
+
+            // TODO: don't put sig into PSBT in the first place
+            let sig = std::mem::take(&mut input.partial_sigs)
+                .into_values()
+                .next()
+                .expect("asserted previously");
+
+            // We drop SIGHASH_ALL, because we always use that and it is only present in the
+            // PSBT for compatibility with other tools.
+            secp256k1::Signature::from_der(&sig[..sig.len() - 1])
+                .expect("we serialized it ourselves that way")
+        })
+        .collect()
+}
+
+/// Reconstructs the UTXOs spent by `psbt` from its input metadata
+fn spent_utxos(psbt: &PartiallySignedTransaction) -> Vec<(UTXOKey, SpendableUTXO)> {
+    psbt.global
+        .unsigned_tx
+        .input
+        .iter()
+        .zip(psbt.inputs.iter())
+        .map(|(tx_input, psbt_input)| {
+            let utxo = psbt_input.witness_utxo.as_ref().expect("Missing UTXO");
+            (
+                UTXOKey(tx_input.previous_output),
+                SpendableUTXO {
+                    tweak: psbt_input
+                        .proprietary
+                        .get(&proprietary_tweak_key())
+                        .expect("we saved it with a tweak")
+                        .clone(),
+                    amount: bitcoin::Amount::from_sat(utxo.value),
+                    script_pubkey: utxo.script_pubkey.clone(),
+                },
+            )
+        })
+        .collect()
 }
 
 fn proprietary_tweak_key() -> ProprietaryKey {
@@ -983,6 +1914,14 @@ fn proprietary_tweak_key() -> ProprietaryKey {
     }
 }
 
+/// Reads the wallet's consensus block height from `db`. This allows other modules sharing the
+/// database to use it without needing access to the [`Wallet`].
+pub fn consensus_height(db: &dyn RawDatabase) -> Option<u32> {
+    db.get_value::<_, RoundConsensus>(&RoundConsensusKey)
+        .expect("DB error")
+        .map(|rc| rc.block_height)
+}
+
 pub fn is_address_valid_for_network(address: &Address, network: Network) -> bool {
     match (address.network, address.address_type()) {
         (Network::Testnet, Some(AddressType::P2pkh))
@@ -994,32 +1933,26 @@ pub fn is_address_valid_for_network(address: &Address, network: Network) -> bool
     }
 }
 
-async fn broadcast_pending_tx(db: Arc<dyn RawDatabase>, rpc: bitcoincore_rpc_async::Client) {
-    loop {
-        let pending_tx = db
-            .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
-                &PendingTransactionPrefixKey,
-            )
-            .collect::<Result<Vec<_>, _>>()
-            .expect("DB error");
-
-        for (_, PendingTransaction { tx, .. }) in pending_tx {
-            let mut raw_tx = Vec::new();
-            tx.consensus_encode(&mut raw_tx)
-                .expect("Nothing can go wrong with a vec");
+/// Submits all pending transactions to the bitcoin network, this is done periodically in case
+/// they drop out of the mempool
+async fn broadcast_pending_tx(db: &dyn RawDatabase, rpc: &dyn BitcoindRpc) {
+    let pending_tx = db
+        .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
+            &PendingTransactionPrefixKey,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .expect("DB error");
 
-            trace!(
-                "Broadcasting peg-out tx {} (weight {})",
-                tx.txid(),
-                tx.get_weight()
-            );
-            trace!("Transaction: {}", raw_tx.to_hex());
-            if let Err(e) = rpc.send_raw_transaction(&raw_tx).await {
-                // FIXME: resubmit periodically, also in case it drops out of the mempool
-                trace!("Could not submit peg out transaction: {}", e);
-            }
+    for (_, PendingTransaction { tx, .. }) in pending_tx {
+        trace!(
+            "Broadcasting peg-out tx {} (weight {})",
+            tx.txid(),
+            tx.get_weight()
+        );
+        trace!("Transaction: {}", serialize_hex(&tx));
+        if let Err(e) = rpc.submit_transaction(&tx).await {
+            trace!("Could not submit peg out transaction: {}", e);
         }
-        tokio::time::sleep(Duration::from_secs(10)).await;
     }
 }
 
@@ -1044,15 +1977,21 @@ pub enum WalletError {
     #[error("Connected bitcoind is on wrong network, expected {0}, got {1}")]
     WrongNetwork(Network, Network),
     #[error("Error querying bitcoind: {0}")]
-    RpcErrot(bitcoincore_rpc_async::Error),
-    #[error("Unknown bitcoin network: {0}")]
-    UnknownNetwork(String),
+    RpcError(RpcError),
     #[error("Unknown block hash in peg-in proof: {0}")]
     UnknownPegInProofBlock(BlockHash),
     #[error("Invalid peg-in proof: {0}")]
     PegInProofError(PegInProofError),
     #[error("The peg-in was already claimed")]
     PegInAlreadyClaimed,
+    #[error("Peg-out amount {0} is below the minimum of {1}")]
+    PegOutTooSmall(bitcoin::Amount, bitcoin::Amount),
+    #[error("Peg-out amount {0} is above the maximum of {1}")]
+    PegOutTooLarge(bitcoin::Amount, bitcoin::Amount),
+    #[error("Priority fee {0} is below the required {1}")]
+    PriorityFeeTooLow(bitcoin::Amount, bitcoin::Amount),
+    #[error("Peg-out fee {0} is below the required {1}")]
+    PegOutFeeTooLow(bitcoin::Amount, bitcoin::Amount),
 }
 
 #[derive(Debug, Error)]
@@ -1069,9 +2008,9 @@ pub enum ProcessPegOutSigError {
     DuplicateSignature,
 }
 
-impl From<bitcoincore_rpc_async::Error> for WalletError {
-    fn from(e: bitcoincore_rpc_async::Error) -> Self {
-        WalletError::RpcErrot(e)
+impl From<RpcError> for WalletError {
+    fn from(e: RpcError) -> Self {
+        WalletError::RpcError(e)
     }
 }
 
@@ -1084,15 +2023,502 @@ impl From<PegInProofError> for WalletError {
 #[cfg(test)]
 mod tests {
     use super::Feerate;
-    use crate::db::UTXOKey;
-    use crate::{PendingPegOut, SpendableUTXO, StatelessWallet};
+    use crate::bitcoind::fake::FakeBitcoindRpc;
+    use crate::bitcoind::BitcoindRpc;
+    use crate::config::WalletConfig;
+    use crate::db::{
+        ConfirmedTransactionKey, OrphanedPegInKey, PegIn, PegInKey, PendingTransactionKey, UTXOKey,
+    };
+    use crate::{
+        broadcast_pending_tx, take_our_signatures, PendingPegOut, PendingTransaction,
+        RoundConsensus, SpendableUTXO, StatelessWallet, Wallet, WalletConsensusItem, WalletError,
+    };
     use bitcoin::hashes::Hash as BitcoinHash;
-    use bitcoin::{Address, Amount, OutPoint, TxOut};
-    use minimint_api::{CompressedPublicKey, Tweakable};
+    use bitcoin::{
+        Address, Amount, BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid,
+    };
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::db::batch::DbBatch;
+    use minimint_api::db::mem_impl::MemDatabase;
+    use minimint_api::db::Database;
+    use minimint_api::module::Audit;
+    use minimint_api::outcome::PegOutOutcome;
+    use minimint_api::transaction::PegOut;
+    use minimint_api::{CompressedPublicKey, FederationModule, PeerId, Tweakable};
     use miniscript::descriptor::Wsh;
     use miniscript::policy::Concrete;
     use miniscript::{Descriptor, DescriptorTrait, Segwitv0};
+    use rand::{Rng, SeedableRng};
     use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn wallet_with_fake_chain() -> (Wallet, Arc<FakeBitcoindRpc>) {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        let (cfgs, _) = WalletConfig::trusted_dealer_gen(&[PeerId::from(0)], 0, &(), rng);
+        let cfg = cfgs.into_iter().next().unwrap().1;
+
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let wallet = Wallet::new_with_bitcoind(cfg, Arc::new(MemDatabase::new()), bitcoind.clone())
+            .await
+            .unwrap();
+        (wallet, bitcoind)
+    }
+
+    /// Runs a consensus epoch in which we are the only peer
+    async fn run_consensus_epoch(wallet: &Wallet) -> Vec<WalletConsensusItem> {
+        let proposal = wallet
+            .consensus_proposal(rand::rngs::OsRng::new().unwrap())
+            .await;
+
+        let mut batch = DbBatch::new();
+        wallet
+            .begin_consensus_epoch(
+                batch.transaction(),
+                proposal
+                    .iter()
+                    .cloned()
+                    .map(|item| (PeerId::from(0), item))
+                    .collect(),
+                rand::rngs::OsRng::new().unwrap(),
+            )
+            .await;
+        wallet.db.apply_batch(batch).unwrap();
+
+        let mut batch = DbBatch::new();
+        wallet
+            .end_consensus_epoch(batch.transaction(), rand::rngs::OsRng::new().unwrap())
+            .await;
+        wallet.db.apply_batch(batch).unwrap();
+
+        proposal
+    }
+
+    #[tokio::test]
+    async fn consensus_follows_chain() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        let block_hashes = bitcoind.mine_blocks(20);
+        bitcoind.set_fee_rate(Some(Feerate { sats_per_kvb: 5000 }));
+
+        let proposal = run_consensus_epoch(&wallet).await;
+        let round_consensus = proposal
+            .into_iter()
+            .find_map(|item| match item {
+                WalletConsensusItem::RoundConsensus(rc) => Some(rc),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(round_consensus.block_height, 20 - wallet.cfg.finalty_delay);
+        assert_eq!(round_consensus.fee_rate, Feerate { sats_per_kvb: 5000 });
+
+        let consensus = wallet.current_round_consensus().unwrap();
+        assert_eq!(consensus.block_height, 10);
+        assert_eq!(consensus.fee_rate, Feerate { sats_per_kvb: 5000 });
+        // Block 1 is at index 0
+        assert!(block_hashes[..10]
+            .iter()
+            .all(|hash| wallet.block_is_known(*hash)));
+        assert!(!wallet.block_is_known(block_hashes[10]));
+
+        // Without fee estimates we fall back to the default fee rate
+        bitcoind.set_fee_rate(None);
+        bitcoind.mine_blocks(1);
+        run_consensus_epoch(&wallet).await;
+        let consensus = wallet.current_round_consensus().unwrap();
+        assert_eq!(consensus.block_height, 11);
+        assert_eq!(consensus.fee_rate, wallet.cfg.default_fee);
+        assert!(wallet.block_is_known(block_hashes[10]));
+    }
+
+    #[tokio::test]
+    async fn broadcast_and_confirm_pending_transaction() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        run_consensus_epoch(&wallet).await;
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        wallet
+            .db
+            .insert_entry(
+                &PendingTransactionKey(tx.txid()),
+                &PendingTransaction {
+                    tx: tx.clone(),
+                    tweak: None,
+                    spent_utxos: vec![],
+                    pending_since_block: 0,
+                },
+            )
+            .unwrap();
+
+        broadcast_pending_tx(wallet.db.as_ref(), bitcoind.as_ref()).await;
+        assert_eq!(bitcoind.mempool(), vec![tx.clone()]);
+
+        // The transaction is only considered confirmed once the consensus height reaches its block
+        bitcoind.mine_blocks(wallet.cfg.finalty_delay as u64);
+        run_consensus_epoch(&wallet).await;
+        assert!(wallet
+            .db
+            .get_value::<_, u32>(&ConfirmedTransactionKey(tx.txid()))
+            .unwrap()
+            .is_none());
+
+        bitcoind.mine_blocks(1);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(
+            wallet
+                .db
+                .get_value::<_, u32>(&ConfirmedTransactionKey(tx.txid()))
+                .unwrap(),
+            Some(1)
+        );
+        assert!(wallet
+            .db
+            .get_value::<_, PendingTransaction>(&PendingTransactionKey(tx.txid()))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn peg_out_policy() {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        let (cfgs, _) = WalletConfig::trusted_dealer_gen(&[PeerId::from(0)], 0, &(), rng);
+        let mut cfg = cfgs.into_iter().next().unwrap().1;
+        cfg.peg_out_policy.max_outputs = 2;
+        cfg.peg_out_policy.max_wait_blocks = 5;
+        let policy = cfg.peg_out_policy.clone();
+
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let wallet = Wallet::new_with_bitcoind(cfg, Arc::new(MemDatabase::new()), bitcoind.clone())
+            .await
+            .unwrap();
+        bitcoind.mine_blocks(20);
+        run_consensus_epoch(&wallet).await;
+
+        let recipient = Address::p2wsh(&Script::new(), Network::Regtest);
+        let fee = wallet.peg_out_fees(&recipient).fee;
+        let peg_out = |amount: u64, priority_fee: u64| PegOut {
+            recipient: recipient.clone(),
+            amount: Amount::from_sat(amount),
+            priority_fee: Amount::from_sat(priority_fee),
+            fee,
+        };
+
+        assert!(matches!(
+            wallet.validate_output(&peg_out(policy.min_amount.as_sat() - 1, 0)),
+            Err(WalletError::PegOutTooSmall(_, _))
+        ));
+        assert!(matches!(
+            wallet.validate_output(&peg_out(policy.max_amount.as_sat() + 1, 0)),
+            Err(WalletError::PegOutTooLarge(_, _))
+        ));
+        assert!(matches!(
+            wallet.validate_output(&peg_out(50_000, policy.priority_fee.as_sat() - 1)),
+            Err(WalletError::PriorityFeeTooLow(_, _))
+        ));
+        assert!(matches!(
+            wallet.validate_output(&PegOut {
+                fee: fee - Amount::from_sat(1),
+                ..peg_out(50_000, 0)
+            }),
+            Err(WalletError::PegOutFeeTooLow(_, _))
+        ));
+        // Peg-outs have to be funded with their fees
+        assert_eq!(
+            wallet
+                .validate_output(&peg_out(50_000, policy.priority_fee.as_sat()))
+                .unwrap(),
+            (Amount::from_sat(50_000) + policy.priority_fee + fee).into()
+        );
+
+        // Enough funds for two peg-out transactions
+        for idx in 0..2u8 {
+            wallet
+                .db
+                .insert_entry(
+                    &UTXOKey(OutPoint::new(Txid::hash(&[idx]), 0)),
+                    &SpendableUTXO {
+                        tweak: vec![idx],
+                        amount: Amount::from_sat(10_000_000),
+                        script_pubkey: Script::new(),
+                    },
+                )
+                .unwrap();
+        }
+
+        let out_points = (0..3)
+            .map(|out_idx| minimint_api::transaction::OutPoint {
+                txid: Default::default(),
+                out_idx,
+            })
+            .collect::<Vec<_>>();
+        let mut batch = DbBatch::new();
+        for (out_point, priority_fee) in
+            out_points.iter().zip(&[0, 0, policy.priority_fee.as_sat()])
+        {
+            wallet
+                .apply_output(
+                    batch.transaction(),
+                    &peg_out(50_000, *priority_fee),
+                    *out_point,
+                )
+                .unwrap();
+        }
+        wallet.db.apply_batch(batch).unwrap();
+        let status = |out_point| wallet.output_status(out_point).unwrap();
+
+        // The priority peg-out is due immediately, only one of the regular ones fits into the
+        // same transaction
+        run_consensus_epoch(&wallet).await;
+        assert!(matches!(status(out_points[2]), PegOutOutcome::Signing(_)));
+        assert!(matches!(status(out_points[0]), PegOutOutcome::Signing(_)));
+        assert_eq!(status(out_points[1]), PegOutOutcome::Queued);
+
+        // The remaining peg-out is far below the urgency threshold, but can't wait any longer
+        bitcoind.mine_blocks(policy.max_wait_blocks as u64 - 1);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(status(out_points[1]), PegOutOutcome::Queued);
+
+        bitcoind.mine_blocks(1);
+        run_consensus_epoch(&wallet).await;
+        assert!(matches!(status(out_points[1]), PegOutOutcome::Signing(_)));
+    }
+
+    #[tokio::test]
+    async fn lower_median_height_keeps_consensus() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        let block_hashes = bitcoind.mine_blocks(20);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(10));
+
+        let mut batch = DbBatch::new();
+        let consensus_block = wallet
+            .process_block_proposals(batch.transaction(), vec![(8, block_hashes[7])])
+            .await;
+        assert_eq!(consensus_block, Some((10, block_hashes[9])));
+    }
+
+    #[tokio::test]
+    async fn bogus_block_hash_needs_threshold() {
+        let rng = rand::rngs::OsRng::new().unwrap();
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let (cfgs, _) = WalletConfig::trusted_dealer_gen(&peers, 1, &(), rng);
+        let cfg = cfgs.into_iter().next().unwrap().1;
+        assert_eq!(cfg.threshold, 3);
+
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let wallet = Wallet::new_with_bitcoind(cfg, Arc::new(MemDatabase::new()), bitcoind.clone())
+            .await
+            .unwrap();
+        let block_hashes = bitcoind.mine_blocks(20);
+        let bogus_hash = BlockHash::from_inner([42; 32]);
+
+        let run_epoch = |proposals: Vec<(u32, BlockHash)>| {
+            let items = peers
+                .iter()
+                .zip(proposals)
+                .map(|(peer, (block_height, block_hash))| {
+                    let item = RoundConsensusItem {
+                        block_height,
+                        block_hash,
+                        fee_rate: wallet.cfg.default_fee,
+                        randomness: [0; 32],
+                    };
+                    (*peer, WalletConsensusItem::RoundConsensus(item))
+                })
+                // A peer proposing twice still only gets one vote
+                .chain(std::iter::once((
+                    peers[3],
+                    WalletConsensusItem::RoundConsensus(RoundConsensusItem {
+                        block_height: 10,
+                        block_hash: bogus_hash,
+                        fee_rate: wallet.cfg.default_fee,
+                        randomness: [0; 32],
+                    }),
+                )))
+                .collect::<Vec<_>>();
+            let wallet = &wallet;
+            async move {
+                let mut batch = DbBatch::new();
+                wallet
+                    .begin_consensus_epoch(
+                        batch.transaction(),
+                        items,
+                        rand::rngs::OsRng::new().unwrap(),
+                    )
+                    .await;
+                wallet.db.apply_batch(batch).unwrap();
+            }
+        };
+
+        // One peer proposing a bogus hash can't keep the others from agreeing on a block
+        run_epoch(vec![
+            (10, block_hashes[9]),
+            (10, bogus_hash),
+            (10, block_hashes[9]),
+            (10, block_hashes[9]),
+        ])
+        .await;
+        let consensus = wallet.current_round_consensus().unwrap();
+        assert_eq!(
+            (consensus.block_height, consensus.block_hash),
+            (10, block_hashes[9])
+        );
+
+        // If it withholds its vote the others don't reach the threshold at the median height and
+        // we stick to the last consensus block
+        run_epoch(vec![
+            (12, block_hashes[11]),
+            (12, bogus_hash),
+            (12, block_hashes[11]),
+            (13, block_hashes[12]),
+        ])
+        .await;
+        let consensus = wallet.current_round_consensus().unwrap();
+        assert_eq!(
+            (consensus.block_height, consensus.block_hash),
+            (10, block_hashes[9])
+        );
+        assert!(!wallet.block_is_known(block_hashes[11]));
+    }
+
+    #[tokio::test]
+    async fn bitcoind_failure_skips_block_proposal() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        bitcoind.mine_blocks(20);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(10));
+
+        bitcoind.mine_blocks(5);
+        bitcoind.fail_next_calls(u32::MAX);
+        let proposal = run_consensus_epoch(&wallet).await;
+        bitcoind.fail_next_calls(0);
+        assert!(!proposal
+            .iter()
+            .any(|item| matches!(item, WalletConsensusItem::RoundConsensus(_))));
+        assert_eq!(wallet.consensus_height(), Some(10));
+
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(15));
+    }
+
+    #[tokio::test]
+    async fn waits_for_lagging_bitcoind() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        // A fresh fake chain mines the same blocks as ours
+        let block_hashes = FakeBitcoindRpc::new().mine_blocks(20);
+        bitcoind.mine_blocks(15);
+
+        let catch_up = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            bitcoind.mine_blocks(5);
+        };
+        let mut batch = DbBatch::new();
+        let (consensus_block, ()) = tokio::join!(
+            wallet.process_block_proposals(batch.transaction(), vec![(20, block_hashes[19])]),
+            catch_up
+        );
+        assert_eq!(consensus_block, Some((20, block_hashes[19])));
+        wallet.db.apply_batch(batch).unwrap();
+        assert!(block_hashes.iter().all(|hash| wallet.block_is_known(*hash)));
+    }
+
+    #[tokio::test]
+    async fn deep_reorg_orphans_peg_in() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        let block_hashes = bitcoind.mine_blocks(20);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(10));
+
+        // Pretend a peg-in confirmed in block 5 was claimed
+        let peg_in_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 42000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let out_point = OutPoint::new(peg_in_tx.txid(), 0);
+        let utxo = SpendableUTXO {
+            tweak: vec![],
+            amount: Amount::from_sat(42000),
+            script_pubkey: Script::new(),
+        };
+        wallet.db.insert_entry(&UTXOKey(out_point), &utxo).unwrap();
+        wallet
+            .db
+            .insert_entry(
+                &PegInKey(out_point),
+                &PegIn {
+                    block_hash: block_hashes[4],
+                    transaction: peg_in_tx.clone(),
+                },
+            )
+            .unwrap();
+
+        // Replace blocks 4 to 20 with a chain not containing the peg-in
+        bitcoind.invalidate_blocks(17);
+        let new_block_hashes = bitcoind.mine_blocks(17);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(10));
+        assert_eq!(
+            wallet.current_round_consensus().unwrap().block_hash,
+            new_block_hashes[6]
+        );
+        assert!(block_hashes[..3]
+            .iter()
+            .all(|hash| wallet.block_is_known(*hash)));
+        assert!(block_hashes[3..]
+            .iter()
+            .all(|hash| !wallet.block_is_known(*hash)));
+        assert!(new_block_hashes[..7]
+            .iter()
+            .all(|hash| wallet.block_is_known(*hash)));
+        assert!(wallet
+            .db
+            .get_value::<_, SpendableUTXO>(&UTXOKey(out_point))
+            .unwrap()
+            .is_none());
+        assert!(wallet
+            .db
+            .get_value::<_, SpendableUTXO>(&OrphanedPegInKey(out_point))
+            .unwrap()
+            .is_some());
+
+        // Once the peg-in confirms again on the new chain it becomes spendable again
+        bitcoind.submit_transaction(&peg_in_tx).await.unwrap();
+        let confirmation_block = bitcoind.mine_blocks(1)[0];
+        bitcoind.mine_blocks(wallet.cfg.finalty_delay as u64);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.consensus_height(), Some(21));
+        assert!(wallet
+            .db
+            .get_value::<_, SpendableUTXO>(&UTXOKey(out_point))
+            .unwrap()
+            .is_some());
+        assert!(wallet
+            .db
+            .get_value::<_, SpendableUTXO>(&OrphanedPegInKey(out_point))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            wallet
+                .db
+                .get_value::<_, PegIn>(&PegInKey(out_point))
+                .unwrap()
+                .unwrap()
+                .block_hash,
+            confirmation_block
+        );
+    }
 
     #[test]
     fn sign_tx() {
@@ -1123,6 +2549,7 @@ mod tests {
                 .script_pubkey(),
             amount: Amount::from_sat(42),
             pending_since_block: 0,
+            priority: false,
         }];
 
         let tweak = musig::SecKey::random(musig::rng_adapt::RngAdaptor(&mut rng)).to_public();
@@ -1133,18 +2560,20 @@ mod tests {
                 1,
             )),
             SpendableUTXO {
-                tweak: tweak.clone(),
+                tweak: tweak.to_bytes().to_vec(),
                 amount: Amount::from_sat(42000),
                 script_pubkey: tweaked.script_pubkey(),
             },
         )];
 
-        let mut psbt = wallet.create_tx(
-            peg_outs,
-            utxos,
-            Feerate { sats_per_kvb: 4000 },
-            &CHANGE_TWEAK,
-        );
+        let mut psbt = wallet
+            .create_tx(
+                peg_outs,
+                utxos,
+                Feerate { sats_per_kvb: 4000 },
+                &CHANGE_TWEAK,
+            )
+            .unwrap();
         wallet.sign_psbt(&mut psbt);
         miniscript::psbt::finalize(&mut psbt, &ctx).unwrap();
         let tx = miniscript::psbt::extract(&psbt, &ctx).unwrap();
@@ -1157,4 +2586,293 @@ mod tests {
         })
         .unwrap()
     }
+
+    #[test]
+    fn spend_change() {
+        const CHANGE_TWEAK: [u8; 32] = [42u8; 32];
+
+        let ctx = secp256k1::Secp256k1::new();
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let (sec_key, pub_key) = ctx.generate_keypair(&mut rng);
+
+        let descriptor = Descriptor::Wsh(
+            Wsh::new(
+                Concrete::Key(CompressedPublicKey::new(pub_key))
+                    .compile::<Segwitv0>()
+                    .unwrap(),
+            )
+            .unwrap(),
+        );
+
+        let wallet = StatelessWallet {
+            descriptor: &descriptor,
+            secret_key: &sec_key,
+            secp: &ctx,
+        };
+
+        let peg_out = || PendingPegOut {
+            destination: Address::from_str("bc1qkuzm3093vc7t9q80ul4p5sydkg39sk8gm0park")
+                .unwrap()
+                .script_pubkey(),
+            amount: Amount::from_sat(42),
+            pending_since_block: 0,
+            priority: false,
+        };
+
+        let tweak = musig::SecKey::random(musig::rng_adapt::RngAdaptor(&mut rng)).to_public();
+        let tweaked = descriptor.tweak(&tweak, &ctx);
+        let utxos = vec![(
+            UTXOKey(OutPoint::new(
+                BitcoinHash::from_slice(&[1u8; 32]).unwrap(),
+                1,
+            )),
+            SpendableUTXO {
+                tweak: tweak.to_bytes().to_vec(),
+                amount: Amount::from_sat(42000),
+                script_pubkey: tweaked.script_pubkey(),
+            },
+        )];
+
+        let psbt = wallet
+            .create_tx(
+                vec![peg_out()],
+                utxos,
+                Feerate { sats_per_kvb: 4000 },
+                &CHANGE_TWEAK,
+            )
+            .unwrap();
+        let tx = psbt.global.unsigned_tx;
+
+        // The change output has to be recognizable from its tweak alone
+        let change_script = wallet.derive_script(&CHANGE_TWEAK);
+        let (change_idx, change) = tx
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, output)| output.script_pubkey == change_script)
+            .expect("Transaction has change");
+
+        let change_utxo = vec![(
+            UTXOKey(OutPoint::new(tx.txid(), change_idx as u32)),
+            SpendableUTXO {
+                tweak: CHANGE_TWEAK.to_vec(),
+                amount: Amount::from_sat(change.value),
+                script_pubkey: change_script.clone(),
+            },
+        )];
+
+        let mut psbt = wallet
+            .create_tx(
+                vec![peg_out()],
+                change_utxo,
+                Feerate { sats_per_kvb: 4000 },
+                &[43u8; 32],
+            )
+            .unwrap();
+        wallet.sign_psbt(&mut psbt);
+        miniscript::psbt::finalize(&mut psbt, &ctx).unwrap();
+        let spending_tx = miniscript::psbt::extract(&psbt, &ctx).unwrap();
+
+        spending_tx
+            .verify(|_| {
+                Some(TxOut {
+                    value: change.value,
+                    script_pubkey: change_script.clone(),
+                })
+            })
+            .unwrap()
+    }
+
+    /// Property test: every peer has to produce the same peg-out transaction from the same UTXO
+    /// set and round consensus, otherwise the signatures of different peers can't be combined
+    #[tokio::test]
+    async fn peers_create_identical_peg_out_txs() {
+        let peers = (0..4).map(PeerId::from).collect::<Vec<_>>();
+        let (cfgs, _) =
+            WalletConfig::trusted_dealer_gen(&peers, 1, &(), rand::rngs::OsRng::new().unwrap());
+        let bitcoind = Arc::new(FakeBitcoindRpc::new());
+        let mut wallets = Vec::new();
+        for (_, cfg) in cfgs {
+            wallets.push(
+                Wallet::new_with_bitcoind(cfg, Arc::new(MemDatabase::new()), bitcoind.clone())
+                    .await
+                    .unwrap(),
+            );
+        }
+        let max_inputs = wallets[0].cfg.max_peg_out_inputs;
+        let destination = Address::from_str("bc1qkuzm3093vc7t9q80ul4p5sydkg39sk8gm0park")
+            .unwrap()
+            .script_pubkey();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(21);
+        for _ in 0..50 {
+            let utxos = (0..rng.gen_range(1, 150))
+                .map(|idx| {
+                    let tweak: [u8; 32] = rng.gen();
+                    (
+                        UTXOKey(OutPoint::new(Txid::hash(&tweak), idx)),
+                        SpendableUTXO {
+                            tweak: tweak.to_vec(),
+                            amount: Amount::from_sat(rng.gen_range(1_000, 1_000_000)),
+                            script_pubkey: Script::new(),
+                        },
+                    )
+                })
+                .collect::<Vec<_>>();
+            let peg_outs = (0..rng.gen_range(1, 5))
+                .map(|_| PendingPegOut {
+                    destination: destination.clone(),
+                    amount: Amount::from_sat(rng.gen_range(1_000, 2_000_000)),
+                    pending_since_block: 0,
+                    priority: false,
+                })
+                .collect::<Vec<_>>();
+            let consensus = RoundConsensus {
+                block_height: 0,
+                block_hash: BlockHash::hash(b"block"),
+                fee_rate: Feerate {
+                    sats_per_kvb: rng.gen_range(500, 20_000),
+                },
+                randomness_beacon: rng.gen(),
+            };
+
+            let mut psbts = Vec::new();
+            for wallet in &wallets {
+                for (key, utxo) in &utxos {
+                    wallet.db.insert_entry(key, utxo).unwrap();
+                }
+                let psbt = wallet
+                    .create_peg_out_tx(peg_outs.clone(), consensus.clone())
+                    .await
+                    .map(|mut psbt| {
+                        take_our_signatures(&mut psbt);
+                        psbt
+                    });
+                psbts.push(psbt);
+                for (key, _) in &utxos {
+                    wallet
+                        .db
+                        .remove_entry::<_, SpendableUTXO>(key)
+                        .unwrap()
+                        .unwrap();
+                }
+            }
+            assert!(psbts.iter().all(|psbt| *psbt == psbts[0]));
+
+            if let Some(psbt) = &psbts[0] {
+                let tx = &psbt.global.unsigned_tx;
+                assert!(tx.input.len() <= max_inputs);
+
+                let input_value = psbt
+                    .inputs
+                    .iter()
+                    .map(|input| input.witness_utxo.as_ref().unwrap().value)
+                    .sum::<u64>();
+                let output_value = tx.output.iter().map(|out| out.value).sum::<u64>();
+                let peg_out_value = peg_outs
+                    .iter()
+                    .map(|peg_out| peg_out.amount.as_sat())
+                    .sum::<u64>();
+                assert!(output_value >= peg_out_value);
+                assert!(input_value > output_value);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sign_reserves_report() {
+        let (wallet, _bitcoind) = wallet_with_fake_chain().await;
+        assert!(wallet
+            .reserves_report(minimint_api::Amount::from_sat(0))
+            .is_none());
+        run_consensus_epoch(&wallet).await;
+
+        let utxo_key = UTXOKey(OutPoint::new(Txid::hash(b"peg-in"), 1));
+        let tweak = b"tweak".to_vec();
+        wallet
+            .db
+            .insert_entry(
+                &utxo_key,
+                &SpendableUTXO {
+                    tweak: tweak.clone(),
+                    amount: Amount::from_sat(50_000),
+                    script_pubkey: Script::new(),
+                },
+            )
+            .unwrap();
+
+        let liabilities = minimint_api::Amount::from_sat(42_000);
+        let signed = wallet.reserves_report(liabilities).unwrap();
+        assert_eq!(signed.peer, PeerId::from(0));
+        assert_eq!(signed.report.liabilities, liabilities);
+        assert_eq!(signed.report.assets(), Amount::from_sat(50_000));
+        assert_eq!(signed.report.utxos[0].out_point, utxo_key.0);
+        assert_eq!(signed.report.utxos[0].tweak, tweak);
+
+        let peer_key = &wallet.cfg.peer_peg_in_keys[&signed.peer];
+        assert!(wallet
+            .secp
+            .verify(&signed.report.message(), &signed.signature, &peer_key.key)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn audit_follows_peg_out() {
+        let (wallet, bitcoind) = wallet_with_fake_chain().await;
+        bitcoind.mine_blocks(20);
+        run_consensus_epoch(&wallet).await;
+
+        wallet
+            .db
+            .insert_entry(
+                &UTXOKey(OutPoint::new(Txid::hash(b"peg-in"), 0)),
+                &SpendableUTXO {
+                    tweak: vec![],
+                    amount: Amount::from_sat(1_000_000),
+                    script_pubkey: Script::new(),
+                },
+            )
+            .unwrap();
+        let recipient = Address::p2wsh(&Script::new(), Network::Regtest);
+        let peg_out = PegOut {
+            recipient: recipient.clone(),
+            amount: Amount::from_sat(100_000),
+            priority_fee: wallet.cfg.peg_out_policy.priority_fee,
+            fee: wallet.peg_out_fees(&recipient).fee,
+        };
+        let mut batch = DbBatch::new();
+        wallet
+            .apply_output(
+                batch.transaction(),
+                &peg_out,
+                minimint_api::transaction::OutPoint {
+                    txid: Default::default(),
+                    out_idx: 0,
+                },
+            )
+            .unwrap();
+        wallet.db.apply_batch(batch).unwrap();
+        assert_eq!(
+            wallet.audit(),
+            Audit {
+                assets: Amount::from_sat(1_000_000).into(),
+                liabilities: Amount::from_sat(100_000).into(),
+            }
+        );
+
+        // Once the priority peg-out is included in a transaction only its change is left
+        run_consensus_epoch(&wallet).await;
+        let audit = wallet.audit();
+        assert_eq!(audit.liabilities, minimint_api::Amount::ZERO);
+        assert!(audit.assets > minimint_api::Amount::ZERO);
+        assert!(audit.assets < Amount::from_sat(900_000).into());
+
+        // The change stays on the books while the transaction is signed and confirmed
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.audit(), audit);
+        broadcast_pending_tx(wallet.db.as_ref(), bitcoind.as_ref()).await;
+        bitcoind.mine_blocks(wallet.cfg.finalty_delay as u64 + 1);
+        run_consensus_epoch(&wallet).await;
+        assert_eq!(wallet.audit(), audit);
+    }
 }
@@ -0,0 +1,137 @@
+use crate::bitcoind::{BitcoindRpc, RpcError};
+use bitcoin::BlockHash;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Number of block hashes requested from the bitcoin backend at once
+pub const BLOCK_HASH_BATCH_SIZE: u32 = 1000;
+
+/// Hashes of blocks above the consensus height that were fetched from our bitcoind in the
+/// background, so syncing up to a new consensus height doesn't have to wait for the backend.
+///
+/// The cached blocks are only a hint, they aren't part of the consensus chain till the federation
+/// agreed on them and may be orphaned before that.
+#[derive(Debug, Default)]
+pub struct BlockHashCache {
+    hashes: Mutex<BTreeMap<u32, BlockHash>>,
+}
+
+impl BlockHashCache {
+    /// Cached hashes of the contiguous blocks starting at height `first` up to at most `last`
+    pub fn get(&self, first: u32, last: u32) -> Vec<BlockHash> {
+        let hashes = self.hashes.lock().unwrap();
+        (first..=last)
+            .map(|height| hashes.get(&height).copied())
+            .take_while(Option::is_some)
+            .flatten()
+            .collect()
+    }
+
+    /// Caches `block_hashes` as the hashes of the blocks starting at height `first`
+    pub fn insert(&self, first: u32, block_hashes: Vec<BlockHash>) {
+        let mut hashes = self.hashes.lock().unwrap();
+        hashes.extend((first..).zip(block_hashes));
+    }
+
+    /// Forgets blocks up to and including `height`, e.g. once consensus reached it
+    pub fn prune(&self, height: u32) {
+        let mut hashes = self.hashes.lock().unwrap();
+        *hashes = hashes.split_off(&(height + 1));
+    }
+
+    /// Forgets all blocks, e.g. because some of them were orphaned
+    pub fn clear(&self) {
+        self.hashes.lock().unwrap().clear();
+    }
+
+    fn highest(&self) -> Option<(u32, BlockHash)> {
+        self.hashes
+            .lock()
+            .unwrap()
+            .iter()
+            .next_back()
+            .map(|(height, hash)| (*height, *hash))
+    }
+}
+
+/// Fetches the hashes of the blocks from height `first` up to and including `last` in batches of
+/// [`BLOCK_HASH_BATCH_SIZE`]
+pub async fn fetch_block_hashes(
+    rpc: &dyn BitcoindRpc,
+    first: u32,
+    last: u32,
+) -> Result<Vec<BlockHash>, RpcError> {
+    let mut block_hashes = Vec::with_capacity((last + 1).saturating_sub(first) as usize);
+    let mut start = first;
+    while start <= last {
+        let end = std::cmp::min(start.saturating_add(BLOCK_HASH_BATCH_SIZE), last + 1);
+        block_hashes.extend(rpc.get_block_hashes(start as u64..end as u64).await?);
+        start = end;
+    }
+    Ok(block_hashes)
+}
+
+/// Fills `cache` with the hashes of all blocks above `consensus_height` that consensus can reach
+/// given our bitcoind's tip, i.e. up to `finalty_delay` blocks below it
+pub async fn prefetch_block_hashes(
+    cache: &BlockHashCache,
+    rpc: &dyn BitcoindRpc,
+    consensus_height: u32,
+    finalty_delay: u32,
+) -> Result<(), RpcError> {
+    cache.prune(consensus_height);
+
+    // The cached blocks may have been orphaned since we fetched them
+    if let Some((height, hash)) = cache.highest() {
+        if rpc.get_block_hash(height as u64).await? != hash {
+            warn!("Cached block {} was orphaned, clearing block cache", hash);
+            cache.clear();
+        }
+    }
+
+    let tip = rpc.get_block_height().await? as u32;
+    let target_height = tip.saturating_sub(finalty_delay);
+    let mut first = cache
+        .highest()
+        .map_or(consensus_height, |(height, _)| height)
+        + 1;
+
+    while first <= target_height {
+        let last = std::cmp::min(first + BLOCK_HASH_BATCH_SIZE - 1, target_height);
+        let block_hashes = fetch_block_hashes(rpc, first, last).await?;
+        cache.insert(first, block_hashes);
+        debug!("Prefetched block hashes up to block {}", last);
+        first = last + 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bitcoind::fake::FakeBitcoindRpc;
+    use crate::prefetch::{prefetch_block_hashes, BlockHashCache};
+
+    #[tokio::test]
+    async fn prefetch_until_finalty_delay() {
+        let bitcoind = FakeBitcoindRpc::new();
+        let block_hashes = bitcoind.mine_blocks(30);
+        let cache = BlockHashCache::default();
+
+        prefetch_block_hashes(&cache, &bitcoind, 5, 10)
+            .await
+            .unwrap();
+        assert_eq!(cache.get(6, 30), block_hashes[5..20].to_vec());
+        assert!(cache.get(1, 5).is_empty());
+
+        // Orphaned blocks are replaced
+        bitcoind.invalidate_blocks(12);
+        let new_block_hashes = bitcoind.mine_blocks(13);
+        prefetch_block_hashes(&cache, &bitcoind, 10, 10)
+            .await
+            .unwrap();
+        let mut expected = block_hashes[10..18].to_vec();
+        expected.extend_from_slice(&new_block_hashes[..3]);
+        assert_eq!(cache.get(11, 31), expected);
+    }
+}
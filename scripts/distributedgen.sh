@@ -0,0 +1,46 @@
+#!/usr/bin/env bash
+# Runs a distributed config generation between SIZE local peers and checks that all of them ended
+# up with the same client config. Each peer writes its config to its own directory cfg/dkg-<id>.
+
+set -euo pipefail
+
+SIZE="${1:-4}"
+shift || true
+TIERS="${@:-1 10 100 1000 10000 100000 1000000}"
+
+cargo build --release --bin distributedgen
+
+# Every peer creates its TLS certificate and hands it to all other peers
+for ((ID=0; ID<SIZE; ID++)); do
+  mkdir -p "cfg/dkg-$ID"
+  target/release/distributedgen create-cert "cfg/dkg-$ID" "$ID"
+done
+for ((ID=0; ID<SIZE; ID++)); do
+  for ((PEER=0; PEER<SIZE; PEER++)); do
+    cp "cfg/dkg-$PEER/tls-$PEER.cert" "cfg/dkg-$ID/"
+  done
+done
+
+PIDS=()
+for ((ID=0; ID<SIZE; ID++)); do
+  echo "starting config generation of peer $ID"
+  (target/release/distributedgen run "cfg/dkg-$ID" "$ID" --nodes "$SIZE" $TIERS 2>&1 | sed -e "s/^/peer $ID: /" ) &
+  PIDS+=($!)
+done
+
+for PID in "${PIDS[@]}"; do
+  wait "$PID"
+done
+
+for ((ID=0; ID<SIZE; ID++)); do
+  if [ ! -f "cfg/dkg-$ID/server-$ID.json" ]; then
+    echo "Peer $ID did not generate a server config"
+    exit 1
+  fi
+  if ! cmp -s "cfg/dkg-0/client.json" "cfg/dkg-$ID/client.json"; then
+    echo "Client config of peer $ID differs from the one of peer 0"
+    exit 1
+  fi
+done
+
+echo "All $SIZE peers generated the same client config"